@@ -0,0 +1,12 @@
+-- Add down migration script here
+ALTER TABLE script
+DROP CONSTRAINT proper_limits;
+
+ALTER TABLE script
+DROP COLUMN timeout;
+
+ALTER TABLE script
+DROP COLUMN memory_limit;
+
+ALTER TABLE script
+DROP COLUMN cpu_limit;
@@ -0,0 +1,16 @@
+-- Add up migration script here
+ALTER TABLE script
+ADD COLUMN timeout INTEGER;
+
+ALTER TABLE script
+ADD COLUMN memory_limit INTEGER;
+
+ALTER TABLE script
+ADD COLUMN cpu_limit INTEGER;
+
+ALTER TABLE script
+ADD CONSTRAINT proper_limits CHECK (
+    (timeout IS NULL OR timeout > 0) AND
+    (memory_limit IS NULL OR memory_limit > 0) AND
+    (cpu_limit IS NULL OR cpu_limit > 0)
+);
@@ -0,0 +1,31 @@
+-- Add up migration script here
+CREATE TABLE script_draft (
+    workspace_id VARCHAR(50) NOT NULL REFERENCES workspace(id),
+    path VARCHAR(255) NOT NULL,
+    value JSONB NOT NULL,
+    created_by VARCHAR(50) NOT NULL,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT now(),
+    extra_perms JSONB NOT NULL DEFAULT '{}',
+    PRIMARY KEY (workspace_id, path)
+);
+
+GRANT ALL ON script_draft TO app;
+GRANT ALL ON script_draft TO admin;
+ALTER TABLE script_draft ENABLE ROW LEVEL SECURITY;
+
+CREATE POLICY see_own ON script_draft FOR ALL
+USING (SPLIT_PART(script_draft.path, '/', 1) = 'u' AND SPLIT_PART(script_draft.path, '/', 2) = current_setting('session.user'));
+
+CREATE POLICY see_member ON script_draft FOR ALL
+USING (SPLIT_PART(script_draft.path, '/', 1) = 'g' AND SPLIT_PART(script_draft.path, '/', 2) = any(regexp_split_to_array(current_setting('session.groups'), ',')::text[]));
+
+CREATE POLICY see_extra_perms_user ON script_draft FOR ALL
+USING (extra_perms ? CONCAT('u/', current_setting('session.user')))
+WITH CHECK ((extra_perms ->> CONCAT('u/', current_setting('session.user')))::boolean);
+
+CREATE POLICY see_extra_perms_groups ON script_draft FOR ALL
+USING (extra_perms ?| regexp_split_to_array(current_setting('session.pgroups'), ',')::text[])
+WITH CHECK (exists(
+    SELECT key, value FROM jsonb_each_text(extra_perms) 
+    WHERE SPLIT_PART(key, '/', 1) = 'g' AND key = ANY(regexp_split_to_array(current_setting('session.pgroups'), ',')::text[])
+    AND value::boolean));
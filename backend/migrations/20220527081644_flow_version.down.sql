@@ -0,0 +1,4 @@
+-- Add down migration script here
+ALTER TABLE completed_job DROP COLUMN flow_version;
+ALTER TABLE queue DROP COLUMN flow_version;
+DROP TABLE flow_version;
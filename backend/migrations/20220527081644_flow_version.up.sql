@@ -0,0 +1,34 @@
+-- Add up migration script here
+CREATE TABLE flow_version (
+    id BIGSERIAL PRIMARY KEY,
+    workspace_id VARCHAR(50) NOT NULL REFERENCES workspace(id),
+    path VARCHAR(255) NOT NULL,
+    summary TEXT NOT NULL,
+    description TEXT NOT NULL,
+    value JSONB NOT NULL,
+    schema JSONB,
+    created_by VARCHAR(50) NOT NULL,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT now(),
+    -- the versions follow the flow when its path changes
+    FOREIGN KEY (workspace_id, path) REFERENCES flow(workspace_id, path) ON UPDATE CASCADE ON DELETE CASCADE
+);
+
+CREATE INDEX flow_version_path_idx ON flow_version (workspace_id, path);
+
+GRANT ALL ON flow_version TO app;
+GRANT ALL ON flow_version TO admin;
+GRANT USAGE, SELECT ON SEQUENCE flow_version_id_seq TO app;
+GRANT USAGE, SELECT ON SEQUENCE flow_version_id_seq TO admin;
+ALTER TABLE flow_version ENABLE ROW LEVEL SECURITY;
+
+-- a version is visible to whoever can see the flow it belongs to
+CREATE POLICY see_flow ON flow_version FOR ALL
+USING (EXISTS (
+    SELECT 1 FROM flow
+    WHERE flow.workspace_id = flow_version.workspace_id AND flow.path = flow_version.path));
+
+INSERT INTO flow_version (workspace_id, path, summary, description, value, schema, created_by, created_at)
+SELECT workspace_id, path, summary, description, value, schema, edited_by, edited_at FROM flow;
+
+ALTER TABLE queue ADD COLUMN flow_version BIGINT;
+ALTER TABLE completed_job ADD COLUMN flow_version BIGINT;
@@ -0,0 +1,2 @@
+-- Add up migration script here
+ALTER TABLE script ADD COLUMN is_library BOOLEAN NOT NULL DEFAULT false;
@@ -0,0 +1,3 @@
+-- Add up migration script here
+ALTER TABLE workspace_settings ADD COLUMN git_sync_repo TEXT;
+ALTER TABLE workspace_settings ADD COLUMN git_sync_branch TEXT;
@@ -0,0 +1,13 @@
+-- Add up migration script here
+-- the previous key of a workspace during the rollback window of a key rotation, in plaintext like
+-- workspace_key. It is superuser-only: neither app nor admin are granted access, only the rotation
+-- endpoints read it, through the connection of the server. The row is deleted, and the key with
+-- it, once the key is discarded, rolled back to or expired
+
+CREATE TABLE workspace_previous_key (
+    workspace_id VARCHAR(50) PRIMARY KEY REFERENCES workspace(id),
+    key VARCHAR(255) NOT NULL,
+    rotated_by VARCHAR(50) NOT NULL,
+    rotated_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT now(),
+    expires_at TIMESTAMP WITH TIME ZONE NOT NULL
+);
@@ -0,0 +1,3 @@
+-- Add up migration script here
+
+ALTER TABLE variable ADD COLUMN external JSONB;
@@ -0,0 +1,71 @@
+-- Add up migration script here
+CREATE TABLE variable_version (
+    id BIGSERIAL PRIMARY KEY,
+    workspace_id VARCHAR(50) NOT NULL REFERENCES workspace(id),
+    path VARCHAR(255) NOT NULL,
+    value VARCHAR(4012) NOT NULL,
+    is_secret BOOLEAN NOT NULL,
+    description VARCHAR(255) NOT NULL,
+    external JSONB,
+    created_by VARCHAR(50) NOT NULL,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT now(),
+    FOREIGN KEY (workspace_id, path) REFERENCES variable(workspace_id, path)
+        ON UPDATE CASCADE ON DELETE CASCADE
+);
+
+CREATE TABLE resource_version (
+    id BIGSERIAL PRIMARY KEY,
+    workspace_id VARCHAR(50) NOT NULL REFERENCES workspace(id),
+    path VARCHAR(255) NOT NULL,
+    value JSONB,
+    description TEXT,
+    resource_type VARCHAR(50) NOT NULL,
+    created_by VARCHAR(50) NOT NULL,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT now(),
+    FOREIGN KEY (workspace_id, path) REFERENCES resource(workspace_id, path)
+        ON UPDATE CASCADE ON DELETE CASCADE
+);
+
+CREATE INDEX variable_version_path_idx ON variable_version (workspace_id, path);
+CREATE INDEX resource_version_path_idx ON resource_version (workspace_id, path);
+
+GRANT ALL ON variable_version TO app;
+GRANT ALL ON variable_version TO admin;
+GRANT USAGE, SELECT ON SEQUENCE variable_version_id_seq TO app;
+GRANT USAGE, SELECT ON SEQUENCE variable_version_id_seq TO admin;
+ALTER TABLE variable_version ENABLE ROW LEVEL SECURITY;
+
+GRANT ALL ON resource_version TO app;
+GRANT ALL ON resource_version TO admin;
+GRANT USAGE, SELECT ON SEQUENCE resource_version_id_seq TO app;
+GRANT USAGE, SELECT ON SEQUENCE resource_version_id_seq TO admin;
+ALTER TABLE resource_version ENABLE ROW LEVEL SECURITY;
+
+-- renames and deletions of a variable or resource are carried to its history by the foreign keys
+-- a version is visible to whoever can see the variable or resource it belongs to
+CREATE POLICY see_variable ON variable_version FOR ALL
+USING (EXISTS (
+    SELECT 1 FROM variable
+    WHERE variable.workspace_id = variable_version.workspace_id AND variable.path = variable_version.path));
+
+CREATE POLICY see_resource ON resource_version FOR ALL
+USING (EXISTS (
+    SELECT 1 FROM resource
+    WHERE resource.workspace_id = resource_version.workspace_id AND resource.path = resource_version.path));
+
+-- the current values are the first versions, attributed to their last editor when known
+INSERT INTO variable_version (workspace_id, path, value, is_secret, description, external, created_by)
+SELECT workspace_id, path, value, is_secret, description, external, COALESCE((
+    SELECT username FROM audit
+    WHERE audit.workspace_id = variable.workspace_id AND audit.resource = variable.path
+        AND audit.operation IN ('variables.create', 'variables.update')
+    ORDER BY audit.id DESC LIMIT 1), 'unknown')
+FROM variable;
+
+INSERT INTO resource_version (workspace_id, path, value, description, resource_type, created_by)
+SELECT workspace_id, path, value, description, resource_type, COALESCE((
+    SELECT username FROM audit
+    WHERE audit.workspace_id = resource.workspace_id AND audit.resource = resource.path
+        AND audit.operation IN ('resources.create', 'resources.update')
+    ORDER BY audit.id DESC LIMIT 1), 'unknown')
+FROM resource;
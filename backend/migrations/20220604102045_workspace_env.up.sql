@@ -0,0 +1,3 @@
+-- Add up migration script here
+
+ALTER TABLE workspace_settings ADD COLUMN extra_env JSONB NOT NULL DEFAULT '[]';
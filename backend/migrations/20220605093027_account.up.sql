@@ -0,0 +1,31 @@
+-- Add up migration script here
+
+-- OAuth accounts connected to a workspace whose access token, stored in the variable at `path`,
+-- can be refreshed. The refresh token is encrypted with the key of the workspace. Failed refreshes
+-- are retried with a backoff, until `refresh_failures` reaches its maximum
+CREATE TABLE account (
+    workspace_id VARCHAR(50) NOT NULL REFERENCES workspace(id),
+    path VARCHAR(255) NOT NULL,
+    client VARCHAR(50) NOT NULL,
+    refresh_token VARCHAR(1500) NOT NULL,
+    expires_at TIMESTAMP WITH TIME ZONE,
+    refreshed_at TIMESTAMP WITH TIME ZONE,
+    refresh_error TEXT,
+    refresh_failures INTEGER NOT NULL DEFAULT 0,
+    retry_at TIMESTAMP WITH TIME ZONE,
+    PRIMARY KEY (workspace_id, path),
+    FOREIGN KEY (workspace_id, path) REFERENCES variable(workspace_id, path)
+        ON UPDATE CASCADE ON DELETE CASCADE
+);
+
+CREATE INDEX account_expires_at_idx ON account (expires_at);
+
+GRANT ALL ON account TO app;
+GRANT ALL ON account TO admin;
+ALTER TABLE account ENABLE ROW LEVEL SECURITY;
+
+-- an account is visible to whoever can see the variable holding its access token
+CREATE POLICY see_variable ON account FOR ALL
+USING (EXISTS (
+    SELECT 1 FROM variable
+    WHERE variable.workspace_id = account.workspace_id AND variable.path = account.path));
@@ -0,0 +1,9 @@
+-- Add down migration script here
+ALTER TABLE queue
+DROP COLUMN timeout;
+
+ALTER TABLE queue
+DROP COLUMN memory_limit;
+
+ALTER TABLE queue
+DROP COLUMN cpu_limit;
@@ -0,0 +1,10 @@
+-- Add up migration script here
+-- the limits of a preview, which has no script row to read them from
+ALTER TABLE queue
+ADD COLUMN timeout INTEGER;
+
+ALTER TABLE queue
+ADD COLUMN memory_limit INTEGER;
+
+ALTER TABLE queue
+ADD COLUMN cpu_limit INTEGER;
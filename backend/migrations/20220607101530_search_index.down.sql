@@ -0,0 +1,5 @@
+-- Add down migration script here
+DROP INDEX script_search_idx;
+DROP INDEX flow_search_idx;
+DROP INDEX resource_search_idx;
+DROP INDEX variable_search_idx;
@@ -0,0 +1,13 @@
+-- Add up migration script here
+-- the documents searched by the workspace search, the expressions must stay the ones of its query
+CREATE INDEX script_search_idx ON script
+USING GIN (to_tsvector('simple', path || ' ' || summary || ' ' || description || ' ' || content));
+
+CREATE INDEX flow_search_idx ON flow
+USING GIN (to_tsvector('simple', path || ' ' || summary || ' ' || description));
+
+CREATE INDEX resource_search_idx ON resource
+USING GIN (to_tsvector('simple', path || ' ' || resource_type || ' ' || coalesce(description, '')));
+
+CREATE INDEX variable_search_idx ON variable
+USING GIN (to_tsvector('simple', path || ' ' || description));
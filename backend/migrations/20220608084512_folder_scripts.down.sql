@@ -0,0 +1,4 @@
+-- Add down migration script here
+DROP POLICY see_folder ON script;
+ALTER TABLE script DROP CONSTRAINT proper_id;
+ALTER TABLE script ADD CONSTRAINT proper_id CHECK (path ~ '^[ug](\/[\w-]+){2,}$');
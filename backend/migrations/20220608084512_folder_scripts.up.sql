@@ -0,0 +1,8 @@
+-- Add up migration script here
+-- scripts can live in a folder of the workspace, f/<folder>/..., to share libraries with every
+-- member, e.g. `from f.shared.utils import x`. Writing them takes an admin or extra permissions
+ALTER TABLE script DROP CONSTRAINT proper_id;
+ALTER TABLE script ADD CONSTRAINT proper_id CHECK (path ~ '^[ugf](\/[\w-]+){2,}$');
+
+CREATE POLICY see_folder ON script FOR SELECT
+USING (SPLIT_PART(script.path, '/', 1) = 'f');
@@ -0,0 +1,18 @@
+// connection probe of the http resource type, run by the test connection endpoint
+export async function main(value: any) {
+  const headers: Record<string, string> = { ...(value.headers ?? {}) };
+  if (value.token) {
+    headers["Authorization"] = `Bearer ${value.token}`;
+  }
+  const response = await fetch(value.url ?? value.base_url, {
+    method: value.method ?? "GET",
+    headers,
+  });
+  const body = await response.text();
+  if (!response.ok) {
+    throw new Error(
+      `${response.status} ${response.statusText}: ${body.slice(0, 1000)}`,
+    );
+  }
+  return { success: true, status: response.status };
+}
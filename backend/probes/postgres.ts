@@ -0,0 +1,24 @@
+// connection probe of the postgres resource type, run by the test connection endpoint
+import { Client } from "https://deno.land/x/postgres@v0.16.1/mod.ts";
+
+export async function main(value: any) {
+  const sslmode = value.sslmode ?? "prefer";
+  const client = new Client({
+    hostname: value.host ?? "localhost",
+    port: value.port ?? 5432,
+    user: value.user,
+    password: value.password,
+    database: value.dbname,
+    tls: {
+      enabled: !["disable", "allow"].includes(sslmode),
+      enforce: ["require", "verify-ca", "verify-full"].includes(sslmode),
+    },
+  });
+  await client.connect();
+  try {
+    const version = await client.queryArray("SELECT version()");
+    return { success: true, server: version.rows[0][0] };
+  } finally {
+    await client.end();
+  }
+}
@@ -0,0 +1,21 @@
+// connection probe of the s3 resource type, run by the test connection endpoint. Listing a
+// single object checks the endpoint, the credentials and the bucket at once
+import { S3Client } from "https://deno.land/x/s3_lite_client@0.2.0/mod.ts";
+
+export async function main(value: any) {
+  const client = new S3Client({
+    endPoint: value.endPoint ?? value.endpoint,
+    port: value.port,
+    useSSL: value.useSSL ?? value.use_ssl ?? true,
+    region: value.region ?? "us-east-1",
+    bucket: value.bucket,
+    accessKey: value.accessKey ?? value.access_key,
+    secretKey: value.secretKey ?? value.secret_key,
+    pathStyle: value.pathStyle ?? value.path_style ?? true,
+  });
+  // deno-lint-ignore no-unused-vars
+  for await (const object of client.listObjects({ maxResults: 1 })) {
+    break;
+  }
+  return { success: true, bucket: value.bucket };
+}
@@ -0,0 +1,63 @@
+// connection probe of the smtp resource type, run by the test connection endpoint. It greets the
+// server, upgrades to tls when asked to and authenticates if credentials are given, without
+// sending any email
+const encoder = new TextEncoder();
+const decoder = new TextDecoder();
+
+async function reply(conn: Deno.Conn, expected: string): Promise<string> {
+  const buf = new Uint8Array(4096);
+  let text = "";
+  // multiline replies use `250-` for all lines but the last one
+  while (
+    !(text.endsWith("\r\n") && /^\d{3} /.test(text.trimEnd().split("\r\n").pop()!))
+  ) {
+    const n = await conn.read(buf);
+    if (n === null) {
+      throw new Error(`connection closed by the server after: ${text}`);
+    }
+    text += decoder.decode(buf.subarray(0, n));
+  }
+  if (!text.startsWith(expected)) {
+    throw new Error(`unexpected reply from the server: ${text.trim()}`);
+  }
+  return text;
+}
+
+async function command(
+  conn: Deno.Conn,
+  line: string,
+  expected: string,
+): Promise<string> {
+  await conn.write(encoder.encode(`${line}\r\n`));
+  return await reply(conn, expected);
+}
+
+export async function main(value: any) {
+  const hostname = value.host ?? "localhost";
+  const port = value.port ?? 25;
+  let conn: Deno.Conn = port == 465
+    ? await Deno.connectTls({ hostname, port })
+    : await Deno.connect({ hostname, port });
+  try {
+    await reply(conn, "220");
+    const ehlo = await command(conn, "EHLO windmill", "250");
+    if (port != 465 && (value.tls ?? ehlo.includes("STARTTLS"))) {
+      await command(conn, "STARTTLS", "220");
+      conn = await Deno.startTls(conn as Deno.TcpConn, { hostname });
+      await command(conn, "EHLO windmill", "250");
+    }
+    if (value.user) {
+      await command(conn, "AUTH LOGIN", "334");
+      await command(conn, btoa(value.user), "334");
+      await command(conn, btoa(value.password ?? ""), "235");
+    }
+    await command(conn, "QUIT", "221");
+  } finally {
+    try {
+      conn.close();
+    } catch {
+      // already closed by the server
+    }
+  }
+  return { success: true };
+}
@@ -42,6 +42,67 @@ pub async fn setup_app_user(db: &DB, password: &str) -> Result<(), Error> {
 
     Ok(())
 }
+/// the database of `DATABASE_URL`, migrated, for the tests that need one. They are ignored by
+/// default and run with `DATABASE_URL=... cargo test -- --ignored`
+#[cfg(test)]
+pub async fn test_db() -> DB {
+    let url = std::env::var("DATABASE_URL")
+        .expect("DATABASE_URL must be set to run the tests that need a database");
+    let db = connect(&url).await.unwrap();
+    migrate(&db).await.unwrap();
+    db
+}
+
+/// a workspace of its own for a test, owned by alice, to delete with `delete_test_workspace`
+#[cfg(test)]
+pub async fn create_test_workspace(db: &DB) -> String {
+    let w_id = format!("test-{}", uuid::Uuid::new_v4().to_simple());
+    sqlx::query("INSERT INTO workspace (id, name, owner) VALUES ($1, $1, 'alice')")
+        .bind(&w_id)
+        .execute(db)
+        .await
+        .unwrap();
+    sqlx::query(
+        "INSERT INTO workspace_key (workspace_id, kind, key) VALUES ($1, 'cloud', 'test-key')",
+    )
+    .bind(&w_id)
+    .execute(db)
+    .await
+    .unwrap();
+    w_id
+}
+
+#[cfg(test)]
+pub async fn delete_test_workspace(db: &DB, w_id: &str) {
+    for table in [
+        "audit",
+        "queue",
+        "account",
+        "variable_version",
+        "variable",
+        "resource_version",
+        "resource",
+        "schedule",
+        "flow",
+        "script_draft",
+        "script",
+        "workspace_settings",
+        "workspace_previous_key",
+        "workspace_key",
+    ] {
+        sqlx::query(&format!("DELETE FROM {table} WHERE workspace_id = $1"))
+            .bind(w_id)
+            .execute(db)
+            .await
+            .unwrap();
+    }
+    sqlx::query("DELETE FROM workspace WHERE id = $1")
+        .bind(w_id)
+        .execute(db)
+        .await
+        .unwrap();
+}
+
 #[derive(Clone)]
 pub struct UserDB {
     db: DB,
@@ -0,0 +1,337 @@
+/*
+ * Author & Copyright: Ruben Fiszel 2021
+ * This file and its contents are licensed under the AGPLv3 License.
+ * Please see the included NOTICE for copyright information and
+ * LICENSE-AGPL for a copy of the license.
+ */
+
+//! External sources of variables. The value of a variable with an external source is never
+//! stored, it is read from its provider each time the variable is read, by a user or by a job.
+//! Providers are configured on the server and the workers through environment variables:
+//! `VAULT_ADDR`, `VAULT_TOKEN`, `VAULT_MOUNT` and `VAULT_KV_VERSION` for Vault,
+//! `EXTERNAL_SECRETS_DIR` for files.
+//!
+//! A workspace only reaches its own secrets: the secrets of workspace `w_id` are under `w_id/` of
+//! the Vault engine and of the secrets directory, and its env sources are the environment
+//! variables `WM_SECRET_<w_id>__<name>`. The credentials of the providers and the env sources are
+//! kept out of the env of the jobs, see `credentials_env`.
+
+use std::path::{Component, Path};
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::{Error, Result};
+
+const DEFAULT_EXTERNAL_SECRETS_DIR: &str = "/run/secrets";
+const DEFAULT_VAULT_MOUNT: &str = "secret";
+const EXTERNAL_ENV_PREFIX: &str = "WM_SECRET_";
+const VAULT_TOKEN_ENV: &str = "VAULT_TOKEN";
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(tag = "provider", rename_all = "lowercase")]
+pub enum ExternalSource {
+    /// field `key` of the secret at `path` of the workspace in a Vault-compatible key/value engine
+    Vault { path: String, key: String },
+    /// content of the file at `path` of the workspace in `EXTERNAL_SECRETS_DIR`
+    /// (default `/run/secrets`)
+    File { path: String },
+    /// environment variable `WM_SECRET_<w_id>__<name>` of the server and the workers
+    Env { name: String },
+}
+
+impl ExternalSource {
+    /// reject the sources that would give access to more than the secrets meant to be shared
+    pub fn validate(&self) -> Result<()> {
+        match self {
+            ExternalSource::Vault { path, key } => {
+                if key.is_empty() || !is_vault_path(path) {
+                    return Err(Error::BadRequest(format!(
+                        "a vault source needs a key and a path relative to the secrets of the workspace, got {path}"
+                    )));
+                }
+            }
+            ExternalSource::File { path } => {
+                if !is_relative(path) {
+                    return Err(Error::BadRequest(format!(
+                        "the path of a file source must be relative to the secrets of the workspace, got {path}"
+                    )));
+                }
+            }
+            ExternalSource::Env { name } => {
+                // a name without `__` nor a leading `_` tells the workspace id apart from the name
+                let valid = !name.is_empty()
+                    && name
+                        .split('_')
+                        .all(|x| !x.is_empty() && x.chars().all(|c| c.is_ascii_alphanumeric()));
+                if !valid {
+                    return Err(Error::BadRequest(format!(
+                        "the name of an env source must be made of letters and digits separated by single underscores, got {name}"
+                    )));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// read the value of the source for workspace `w_id`
+    pub async fn resolve(&self, w_id: &str) -> Result<String> {
+        self.validate()?;
+        match self {
+            ExternalSource::Vault { path, key } => resolve_vault(w_id, path, key).await,
+            ExternalSource::File { path } => {
+                let dir = std::env::var("EXTERNAL_SECRETS_DIR")
+                    .unwrap_or_else(|_| DEFAULT_EXTERNAL_SECRETS_DIR.to_string());
+                resolve_file(Path::new(&dir), w_id, path).await
+            }
+            ExternalSource::Env { name } => {
+                let var = env_source_var(w_id, name);
+                std::env::var(&var).map_err(|_| {
+                    Error::ExecutionErr(format!("environment variable {var} is not set"))
+                })
+            }
+        }
+    }
+}
+
+fn is_relative(path: &str) -> bool {
+    !path.is_empty()
+        && Path::new(path)
+            .components()
+            .all(|x| matches!(x, Component::Normal(_)))
+}
+
+/// a vault path ends up in a url, where a percent-encoded `..`, a query, a fragment or a backslash
+/// could still lead out of the secrets of the workspace
+fn is_vault_path(path: &str) -> bool {
+    is_relative(path) && !path.contains(&['%', '?', '#', '\\'][..])
+}
+
+fn env_source_var(w_id: &str, name: &str) -> String {
+    format!("{EXTERNAL_ENV_PREFIX}{w_id}__{name}")
+}
+
+/// the environment variables that jobs must not inherit from the worker: the credentials of the
+/// providers and the values of the env sources of every workspace
+pub fn credentials_env() -> Vec<String> {
+    std::env::vars()
+        .map(|(name, _)| name)
+        .filter(|name| name == VAULT_TOKEN_ENV || name.starts_with(EXTERNAL_ENV_PREFIX))
+        .collect()
+}
+
+async fn resolve_file(dir: &Path, w_id: &str, path: &str) -> Result<String> {
+    let content = tokio::fs::read_to_string(dir.join(w_id).join(path))
+        .await
+        .map_err(|e| Error::ExecutionErr(format!("could not read secret file {path}: {e}")))?;
+    Ok(content.trim_end_matches(&['\r', '\n'][..]).to_string())
+}
+
+/// the secrets of the workspace are under `w_id/` of the engine mounted at `VAULT_MOUNT`
+/// (default `secret`), a kv v2 engine unless `VAULT_KV_VERSION` is `1`
+fn vault_url(addr: &str, mount: &str, kv_version: &str, w_id: &str, path: &str) -> String {
+    let mount = mount.trim_matches('/');
+    let data = if kv_version == "1" { "" } else { "data/" };
+    format!(
+        "{}/v1/{mount}/{data}{w_id}/{path}",
+        addr.trim_end_matches('/')
+    )
+}
+
+async fn resolve_vault(w_id: &str, path: &str, key: &str) -> Result<String> {
+    let addr = std::env::var("VAULT_ADDR")
+        .map_err(|_| Error::BadConfig("VAULT_ADDR is not set".to_string()))?;
+    let token = std::env::var(VAULT_TOKEN_ENV)
+        .map_err(|_| Error::BadConfig("VAULT_TOKEN is not set".to_string()))?;
+    let url = vault_url(
+        &addr,
+        &std::env::var("VAULT_MOUNT").unwrap_or_else(|_| DEFAULT_VAULT_MOUNT.to_string()),
+        &std::env::var("VAULT_KV_VERSION").unwrap_or_default(),
+        w_id,
+        path,
+    );
+    let res = reqwest::Client::new()
+        .get(&url)
+        .header("X-Vault-Token", token)
+        .send()
+        .await
+        .map_err(|e| Error::ExecutionErr(format!("could not reach vault: {e}")))?;
+    if !res.status().is_success() {
+        return Err(Error::ExecutionErr(format!(
+            "vault secret {path} could not be read: {}",
+            res.status()
+        )));
+    }
+    let body = res
+        .json::<serde_json::Value>()
+        .await
+        .map_err(|e| Error::ExecutionErr(format!("invalid vault response: {e}")))?;
+    vault_value(&body, path, key)
+}
+
+fn vault_value(body: &serde_json::Value, path: &str, key: &str) -> Result<String> {
+    // the kv v2 engine nests the secret along with its metadata
+    let data = match body.get("data") {
+        Some(data) if data.get("metadata").is_some() => data.get("data"),
+        data => data,
+    };
+    match data.and_then(|x| x.get(key)) {
+        Some(serde_json::Value::String(s)) => Ok(s.clone()),
+        Some(v) => Ok(v.to_string()),
+        None => Err(Error::ExecutionErr(format!(
+            "vault secret {path} has no key {key}"
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_validate() {
+        for source in [
+            ExternalSource::Vault {
+                path: "db/prod".to_string(),
+                key: "password".to_string(),
+            },
+            ExternalSource::File {
+                path: "db/password".to_string(),
+            },
+            ExternalSource::Env {
+                name: "GITHUB_TOKEN".to_string(),
+            },
+            ExternalSource::Env {
+                name: "token2".to_string(),
+            },
+        ] {
+            assert!(source.validate().is_ok(), "{source:?}");
+        }
+        for source in [
+            ExternalSource::Vault {
+                path: "db/prod".to_string(),
+                key: "".to_string(),
+            },
+            ExternalSource::Vault {
+                path: "".to_string(),
+                key: "password".to_string(),
+            },
+            ExternalSource::Vault {
+                path: "../other/db".to_string(),
+                key: "password".to_string(),
+            },
+            ExternalSource::Vault {
+                path: "/sys/mounts".to_string(),
+                key: "password".to_string(),
+            },
+            // `%2e%2e` is a `..` segment once in the url
+            ExternalSource::Vault {
+                path: "%2e%2e/other/db".to_string(),
+                key: "password".to_string(),
+            },
+            ExternalSource::Vault {
+                path: "db\\..\\..\\other\\db".to_string(),
+                key: "password".to_string(),
+            },
+            ExternalSource::Vault {
+                path: "db?list=true".to_string(),
+                key: "password".to_string(),
+            },
+            ExternalSource::Vault {
+                path: "db#prod".to_string(),
+                key: "password".to_string(),
+            },
+            ExternalSource::File {
+                path: "".to_string(),
+            },
+            ExternalSource::File {
+                path: "/etc/passwd".to_string(),
+            },
+            ExternalSource::File {
+                path: "../other/password".to_string(),
+            },
+            ExternalSource::File {
+                path: "db/../../other/password".to_string(),
+            },
+            ExternalSource::Env {
+                name: "".to_string(),
+            },
+            ExternalSource::Env {
+                name: "_TOKEN".to_string(),
+            },
+            ExternalSource::Env {
+                name: "TOKEN_".to_string(),
+            },
+            // would read the variables of workspace `other__x`
+            ExternalSource::Env {
+                name: "x__TOKEN".to_string(),
+            },
+            ExternalSource::Env {
+                name: "DATABASE-URL".to_string(),
+            },
+        ] {
+            assert!(source.validate().is_err(), "{source:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_resolve_file() {
+        let dir = tempfile::TempDir::new().unwrap();
+        tokio::fs::create_dir_all(dir.path().join("alice/db"))
+            .await
+            .unwrap();
+        tokio::fs::create_dir_all(dir.path().join("bob"))
+            .await
+            .unwrap();
+        tokio::fs::write(dir.path().join("alice/db/password"), "s3cret\n")
+            .await
+            .unwrap();
+        tokio::fs::write(dir.path().join("bob/password"), "bob-s3cret")
+            .await
+            .unwrap();
+
+        assert_eq!(
+            resolve_file(dir.path(), "alice", "db/password")
+                .await
+                .unwrap(),
+            "s3cret"
+        );
+        assert!(resolve_file(dir.path(), "alice", "password").await.is_err());
+        assert_eq!(
+            resolve_file(dir.path(), "bob", "password").await.unwrap(),
+            "bob-s3cret"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_resolve_env() {
+        std::env::set_var("WM_SECRET_test-env__GITHUB_TOKEN", "gh-s3cret");
+        let source = ExternalSource::Env {
+            name: "GITHUB_TOKEN".to_string(),
+        };
+        assert_eq!(source.resolve("test-env").await.unwrap(), "gh-s3cret");
+        assert!(source.resolve("test-other").await.is_err());
+        assert!(credentials_env().contains(&"WM_SECRET_test-env__GITHUB_TOKEN".to_string()));
+    }
+
+    #[test]
+    fn test_vault() {
+        assert_eq!(
+            vault_url("http://127.0.0.1:8200/", "secret", "", "alice", "db/prod"),
+            "http://127.0.0.1:8200/v1/secret/data/alice/db/prod"
+        );
+        assert_eq!(
+            vault_url("http://127.0.0.1:8200", "/kv/", "1", "alice", "db/prod"),
+            "http://127.0.0.1:8200/v1/kv/alice/db/prod"
+        );
+
+        let v1 = json!({ "data": { "password": "s3cret", "port": 5432 } });
+        let v2 =
+            json!({ "data": { "data": { "password": "s3cret" }, "metadata": { "version": 3 } } });
+        assert_eq!(vault_value(&v1, "db/prod", "password").unwrap(), "s3cret");
+        assert_eq!(vault_value(&v1, "db/prod", "port").unwrap(), "5432");
+        assert_eq!(vault_value(&v2, "db/prod", "password").unwrap(), "s3cret");
+        assert!(vault_value(&v2, "db/prod", "user").is_err());
+    }
+}
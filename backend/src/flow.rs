@@ -16,13 +16,15 @@ use axum::{
 };
 use serde::{Deserialize, Serialize};
 use sql_builder::SqlBuilder;
-use sqlx::FromRow;
+use sqlx::{FromRow, Postgres, Transaction};
 
 use crate::{
     audit::{audit_log, ActionKind},
     db::UserDB,
     error::{Error, JsonResult, Result},
-    scripts::Schema,
+    git_sync,
+    jobs::check_hash_for_path,
+    scripts::{get_transitive_libraries, unified_diff, Schema, ScriptHash, ScriptLang},
     users::Authed,
     utils::{Pagination, StripPath},
 };
@@ -34,6 +36,11 @@ pub fn workspaced_service() -> Router {
         .route("/update/*path", post(update_flow))
         .route("/archive/*path", post(archive_flow_by_path))
         .route("/get/*path", get(get_flow_by_path))
+        .route("/history/p/*path", get(get_flow_history))
+        .route("/dependencies/p/*path", get(get_flow_dependencies))
+        .route("/get/v/:version", get(get_flow_version))
+        .route("/diff/:version/:other_version", get(diff_flow_versions))
+        .route("/rollback/v/:version", post(rollback_flow))
 }
 
 #[derive(FromRow, Serialize)]
@@ -50,6 +57,34 @@ pub struct Flow {
     pub extra_perms: serde_json::Value,
 }
 
+#[derive(FromRow, Serialize)]
+pub struct FlowVersion {
+    pub id: i64,
+    pub workspace_id: String,
+    pub path: String,
+    pub summary: String,
+    pub description: String,
+    pub value: serde_json::Value,
+    pub schema: Option<Schema>,
+    pub created_by: String,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(FromRow, Serialize)]
+pub struct FlowHistory {
+    pub id: i64,
+    pub summary: String,
+    pub created_by: String,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(Serialize)]
+pub struct FlowDependencies {
+    pub flows: Vec<String>,
+    pub scripts: Vec<String>,
+    pub libraries: Vec<String>,
+}
+
 #[derive(FromRow, Deserialize)]
 pub struct NewFlow {
     pub path: String,
@@ -88,8 +123,14 @@ pub enum InputTransform {
     rename_all(serialize = "lowercase", deserialize = "lowercase")
 )]
 pub enum FlowModuleValue {
-    Script { path: String },
-    Flow { path: String },
+    Script {
+        path: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        hash: Option<ScriptHash>,
+    },
+    Flow {
+        path: String,
+    },
 }
 
 #[derive(Deserialize)]
@@ -158,6 +199,7 @@ async fn create_flow(
 ) -> Result<String> {
     // cron::Schedule::from_str(&ns.schedule).map_err(|e| error::Error::BadRequest(e.to_string()))?;
     let mut tx = user_db.begin(&authed).await?;
+    check_pinned_hashes(&mut tx, &w_id, &nf.value).await?;
 
     sqlx::query!(
         "INSERT INTO flow (workspace_id, path, summary, description, value, edited_by, edited_at, schema) VALUES ($1, $2, $3, $4, $5, $6, $7, $8::text::json)",
@@ -168,10 +210,11 @@ async fn create_flow(
         nf.value,
         &authed.username,
         &chrono::Utc::now(),
-        nf.schema.and_then(|x| serde_json::to_string(&x.0).ok()),
+        nf.schema.as_ref().and_then(|x| serde_json::to_string(&x.0).ok()),
     )
     .execute(&mut tx)
     .await?;
+    insert_flow_version(&mut tx, &w_id, &nf, &authed.username).await?;
 
     audit_log(
         &mut tx,
@@ -189,7 +232,9 @@ async fn create_flow(
     )
     .await?;
 
+    let git_sync = git_sync::flow_commit(&mut tx, &w_id, &nf.path, None).await?;
     tx.commit().await?;
+    git_sync::spawn_commit(git_sync, &authed);
     Ok(nf.path.to_string())
 }
 
@@ -200,9 +245,10 @@ async fn update_flow(
     Json(nf): Json<NewFlow>,
 ) -> Result<String> {
     let mut tx = user_db.begin(&authed).await?;
+    check_pinned_hashes(&mut tx, &w_id, &nf.value).await?;
 
     let flow_path = flow_path.to_path();
-    let schema = nf.schema.map(|x| x.0);
+    let schema = nf.schema.as_ref().map(|x| &x.0);
     let flow = sqlx::query_scalar!(
         "UPDATE flow SET path = $1, summary = $2, description = $3, value = $4, edited_by = $5, edited_at = $6, schema = $7 WHERE path = $8 AND workspace_id = $9 RETURNING path",
         nf.path,
@@ -218,6 +264,8 @@ async fn update_flow(
     .fetch_optional(&mut tx)
     .await?;
     crate::utils::not_found_if_none(flow, "Flow", flow_path)?;
+    // on a rename, the previous versions follow the flow through the foreign key of flow_version
+    insert_flow_version(&mut tx, &w_id, &nf, &authed.username).await?;
 
     audit_log(
         &mut tx,
@@ -235,10 +283,61 @@ async fn update_flow(
     )
     .await?;
 
+    let git_sync = git_sync::flow_commit(&mut tx, &w_id, &nf.path, Some(flow_path)).await?;
     tx.commit().await?;
+    git_sync::spawn_commit(git_sync, &authed);
     Ok(nf.path.to_string())
 }
 
+/// every create or update of a flow is kept as an immutable version
+pub async fn insert_flow_version<'c>(
+    db: &mut Transaction<'c, Postgres>,
+    w_id: &str,
+    nf: &NewFlow,
+    username: &str,
+) -> Result<i64> {
+    let id = sqlx::query_scalar!(
+        "INSERT INTO flow_version (workspace_id, path, summary, description, value, schema, \
+         created_by) VALUES ($1, $2, $3, $4, $5, $6, $7) RETURNING id",
+        w_id,
+        nf.path,
+        nf.summary,
+        nf.description,
+        nf.value,
+        nf.schema.as_ref().map(|x| &x.0),
+        username
+    )
+    .fetch_one(db)
+    .await?;
+    Ok(id)
+}
+
+impl FlowValue {
+    /// all the modules of the flow, including the failure module
+    pub fn all_modules(&self) -> impl Iterator<Item = &FlowModule> {
+        self.modules.iter().chain(self.failure_module.iter())
+    }
+}
+
+pub async fn check_pinned_hashes<'c>(
+    db: &mut Transaction<'c, Postgres>,
+    w_id: &str,
+    value: &serde_json::Value,
+) -> Result<()> {
+    if let Ok(flow) = serde_json::from_value::<FlowValue>(value.clone()) {
+        for module in flow.all_modules() {
+            if let FlowModuleValue::Script {
+                path,
+                hash: Some(hash),
+            } = &module.value
+            {
+                check_hash_for_path(db, w_id, path, hash).await?;
+            }
+        }
+    }
+    Ok(())
+}
+
 async fn get_flow_by_path(
     authed: Authed,
     Extension(user_db): Extension<UserDB>,
@@ -260,6 +359,219 @@ async fn get_flow_by_path(
     Ok(Json(flow))
 }
 
+async fn get_flow_history(
+    authed: Authed,
+    Extension(user_db): Extension<UserDB>,
+    Path((w_id, path)): Path<(String, StripPath)>,
+) -> JsonResult<Vec<FlowHistory>> {
+    let path = path.to_path();
+    let mut tx = user_db.begin(&authed).await?;
+    let history = sqlx::query_as!(
+        FlowHistory,
+        "SELECT id, summary, created_by, created_at FROM flow_version \
+         WHERE path = $1 AND (workspace_id = $2 OR workspace_id = 'starter') ORDER BY id DESC",
+        path,
+        w_id
+    )
+    .fetch_all(&mut tx)
+    .await?;
+    tx.commit().await?;
+
+    if history.is_empty() {
+        return Err(Error::NotFound(format!("Flow not found at name {}", path)));
+    }
+    Ok(Json(history))
+}
+
+async fn get_flow_version_internal<'c>(
+    db: &mut Transaction<'c, Postgres>,
+    w_id: &str,
+    version: i64,
+) -> Result<FlowVersion> {
+    let version_o = sqlx::query_as!(
+        FlowVersion,
+        "SELECT id, workspace_id, path, summary, description, value, schema as \"schema: Schema\", \
+         created_by, created_at FROM flow_version WHERE id = $1 AND (workspace_id = $2 OR \
+         workspace_id = 'starter')",
+        version,
+        w_id
+    )
+    .fetch_optional(db)
+    .await?;
+    crate::utils::not_found_if_none(version_o, "FlowVersion", version.to_string())
+}
+
+async fn get_flow_version(
+    authed: Authed,
+    Extension(user_db): Extension<UserDB>,
+    Path((w_id, version)): Path<(String, i64)>,
+) -> JsonResult<FlowVersion> {
+    let mut tx = user_db.begin(&authed).await?;
+    let flow_version = get_flow_version_internal(&mut tx, &w_id, version).await?;
+    tx.commit().await?;
+    Ok(Json(flow_version))
+}
+
+fn flow_version_to_string(flow_version: &FlowVersion) -> Result<String> {
+    serde_json::to_string_pretty(&serde_json::json!({
+        "summary": flow_version.summary,
+        "description": flow_version.description,
+        "value": flow_version.value,
+        "schema": flow_version.schema,
+    }))
+    .map_err(|e| Error::InternalErr(e.to_string()))
+}
+
+async fn diff_flow_versions(
+    authed: Authed,
+    Extension(user_db): Extension<UserDB>,
+    Path((w_id, version, other_version)): Path<(String, i64, i64)>,
+) -> Result<String> {
+    let mut tx = user_db.begin(&authed).await?;
+    let flow_version = get_flow_version_internal(&mut tx, &w_id, version).await?;
+    let other_flow_version = get_flow_version_internal(&mut tx, &w_id, other_version).await?;
+    tx.commit().await?;
+
+    Ok(unified_diff(
+        &flow_version_to_string(&flow_version)?,
+        &flow_version_to_string(&other_flow_version)?,
+        &format!("{}@{}", flow_version.path, version),
+        &format!("{}@{}", other_flow_version.path, other_version),
+    ))
+}
+
+async fn rollback_flow(
+    authed: Authed,
+    Extension(user_db): Extension<UserDB>,
+    Path((w_id, version)): Path<(String, i64)>,
+) -> Result<String> {
+    let mut tx = user_db.begin(&authed).await?;
+    let flow_version = get_flow_version_internal(&mut tx, &w_id, version).await?;
+    let nf = NewFlow {
+        path: flow_version.path,
+        summary: flow_version.summary,
+        description: flow_version.description,
+        value: flow_version.value,
+        schema: flow_version.schema,
+    };
+    check_pinned_hashes(&mut tx, &w_id, &nf.value).await?;
+
+    let flow = sqlx::query_scalar!(
+        "UPDATE flow SET summary = $1, description = $2, value = $3, edited_by = $4, \
+         edited_at = $5, schema = $6 WHERE path = $7 AND workspace_id = $8 RETURNING path",
+        nf.summary,
+        nf.description,
+        nf.value,
+        &authed.username,
+        &chrono::Utc::now(),
+        nf.schema.as_ref().map(|x| &x.0),
+        nf.path,
+        w_id
+    )
+    .fetch_optional(&mut tx)
+    .await?;
+    crate::utils::not_found_if_none(flow, "Flow", &nf.path)?;
+    let new_version = insert_flow_version(&mut tx, &w_id, &nf, &authed.username).await?;
+
+    audit_log(
+        &mut tx,
+        &authed.username,
+        "flows.rollback",
+        ActionKind::Update,
+        &w_id,
+        Some(&nf.path),
+        Some(
+            [
+                ("rollback_to", version.to_string().as_str()),
+                ("version", new_version.to_string().as_str()),
+            ]
+            .into(),
+        ),
+    )
+    .await?;
+
+    let git_sync = git_sync::flow_commit(&mut tx, &w_id, &nf.path, None).await?;
+    tx.commit().await?;
+    git_sync::spawn_commit(git_sync, &authed);
+    Ok(format!(
+        "Flow {} rolled back to version {version} as version {new_version}",
+        nf.path
+    ))
+}
+
+/// the flows, scripts and libraries a flow depends on, through its steps and their sub-flows
+async fn get_flow_dependencies(
+    authed: Authed,
+    Extension(user_db): Extension<UserDB>,
+    Path((w_id, path)): Path<(String, StripPath)>,
+) -> JsonResult<FlowDependencies> {
+    let path = path.to_path();
+    let mut tx = user_db.begin(&authed).await?;
+
+    let mut flows = vec![];
+    let mut scripts = vec![];
+    let mut to_visit = vec![path.to_string()];
+    while let Some(flow_path) = to_visit.pop() {
+        if flows.contains(&flow_path) {
+            continue;
+        }
+        let value_o = sqlx::query_scalar!(
+            "SELECT value FROM flow WHERE path = $1 AND (workspace_id = $2 OR workspace_id = \
+             'starter')",
+            flow_path,
+            w_id
+        )
+        .fetch_optional(&mut tx)
+        .await?;
+        let value = crate::utils::not_found_if_none(value_o, "Flow", &flow_path)?;
+        let flow = serde_json::from_value::<FlowValue>(value).map_err(|err| {
+            Error::InternalErr(format!(
+                "could not convert json to flow for {flow_path}: {err:?}"
+            ))
+        })?;
+        for module in flow.all_modules() {
+            match &module.value {
+                FlowModuleValue::Script { path, .. } if !scripts.contains(path) => {
+                    scripts.push(path.clone())
+                }
+                FlowModuleValue::Flow { path } => to_visit.push(path.clone()),
+                _ => (),
+            }
+        }
+        flows.push(flow_path);
+    }
+    flows.retain(|x| x != path);
+
+    let mut libraries = vec![];
+    for script_path in &scripts {
+        let script_o = sqlx::query!(
+            "SELECT language as \"language: ScriptLang\", content FROM script WHERE path = $1 \
+             AND (workspace_id = $2 OR workspace_id = 'starter') AND archived = false \
+             AND deleted = false",
+            script_path,
+            w_id
+        )
+        .fetch_optional(&mut tx)
+        .await?;
+        if let Some(script) = script_o {
+            for library in
+                get_transitive_libraries(&mut tx, &w_id, &script.language, &script.content).await?
+            {
+                if !libraries.contains(&library.path) {
+                    libraries.push(library.path);
+                }
+            }
+        }
+    }
+    tx.commit().await?;
+
+    Ok(Json(FlowDependencies {
+        flows,
+        scripts,
+        libraries,
+    }))
+}
+
 async fn archive_flow_by_path(
     authed: Authed,
     Extension(user_db): Extension<UserDB>,
@@ -286,7 +598,15 @@ async fn archive_flow_by_path(
         Some([("workspace", w_id.as_str())].into()),
     )
     .await?;
+    let git_sync = git_sync::removal_commit(
+        &mut tx,
+        &w_id,
+        format!("Archive flow {path}"),
+        vec![format!("flows/{path}.json")],
+    )
+    .await?;
     tx.commit().await?;
+    git_sync::spawn_commit(git_sync, &authed);
 
     Ok(format!("Flow {path} archived"))
 }
@@ -296,6 +616,7 @@ mod tests {
 
     // Note this useful idiom: importing names from outer (for mod tests) scope.
     use super::*;
+    use crate::db::{create_test_workspace, delete_test_workspace, test_db};
 
     #[test]
     fn test_serialize() -> anyhow::Result<()> {
@@ -311,6 +632,7 @@ mod tests {
                 input_transform: hm,
                 value: FlowModuleValue::Script {
                     path: "test".to_string(),
+                    hash: Some(ScriptHash(42)),
                 },
             }],
             failure_module: Some(FlowModule {
@@ -323,4 +645,81 @@ mod tests {
         println!("{}", serde_json::json!(fv).to_string());
         Ok(())
     }
+
+    /// the dependencies of a flow include those of its sub-flows, once each, and the libraries
+    /// imported by its scripts
+    #[tokio::test]
+    #[ignore = "needs DATABASE_URL, see test_db"]
+    async fn test_flow_dependencies() {
+        let db = test_db().await;
+        let w_id = create_test_workspace(&db).await;
+        for (hash, path, content, is_library) in [
+            (1, "u/alice/lib", "def helper():\n    return 1\n", true),
+            (2, "u/alice/step", "import u.alice.lib\n", false),
+        ] {
+            sqlx::query(
+                "INSERT INTO script (workspace_id, hash, path, summary, description, content, \
+                 created_by, is_library) VALUES ($1, $2, $3, '', '', $4, 'alice', $5)",
+            )
+            .bind(&w_id)
+            .bind(hash)
+            .bind(path)
+            .bind(content)
+            .bind(is_library)
+            .execute(&db)
+            .await
+            .unwrap();
+        }
+        let step = serde_json::json!({
+            "input_transform": {},
+            "value": {"type": "script", "path": "u/alice/step"},
+        });
+        let sub_flow = serde_json::json!({
+            "input_transform": {},
+            "value": {"type": "flow", "path": "u/alice/sub"},
+        });
+        for (path, value) in [
+            (
+                "u/alice/flow",
+                serde_json::json!({"modules": [step.clone(), sub_flow], "failure_module": null}),
+            ),
+            (
+                "u/alice/sub",
+                serde_json::json!({"modules": [], "failure_module": step}),
+            ),
+        ] {
+            sqlx::query(
+                "INSERT INTO flow (workspace_id, path, summary, description, value, edited_by) \
+                 VALUES ($1, $2, '', '', $3, 'alice')",
+            )
+            .bind(&w_id)
+            .bind(path)
+            .bind(value)
+            .execute(&db)
+            .await
+            .unwrap();
+        }
+
+        let authed = Authed {
+            email: None,
+            username: "alice".to_string(),
+            is_admin: true,
+            groups: vec![],
+        };
+        let Json(dependencies) = get_flow_dependencies(
+            authed,
+            Extension(UserDB::new(db.clone())),
+            Path((
+                w_id.clone(),
+                serde_json::from_value::<StripPath>(serde_json::json!("/u/alice/flow")).unwrap(),
+            )),
+        )
+        .await
+        .unwrap();
+        assert_eq!(dependencies.flows, vec!["u/alice/sub"]);
+        assert_eq!(dependencies.scripts, vec!["u/alice/step"]);
+        assert_eq!(dependencies.libraries, vec!["u/alice/lib"]);
+
+        delete_test_workspace(&db, &w_id).await;
+    }
 }
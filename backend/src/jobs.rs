@@ -20,6 +20,7 @@ use crate::{
     error,
     error::Error,
     flow::{FlowModuleValue, FlowValue, InputTransform},
+    json_schema,
     schedule::get_schedule_opt,
     scripts::ScriptHash,
     users::{owner_to_token_owner, Authed},
@@ -47,6 +48,7 @@ pub fn workspaced_service() -> Router {
         .route("/run/p/*script_path", post(run_job_by_path))
         .route("/run/h/:hash", post(run_job_by_hash))
         .route("/run/preview", post(run_preview_job))
+        .route("/run/draft/*script_path", post(run_draft_job))
         .route("/run/preview_flow", post(run_preview_flow_job))
         .route("/list", get(list_jobs))
         .route("/queue/list", get(list_queue_jobs))
@@ -85,6 +87,10 @@ pub struct QueuedJob {
     pub raw_flow: Option<serde_json::Value>,
     pub is_flow_step: bool,
     pub language: Option<ScriptLang>,
+    pub flow_version: Option<i64>,
+    pub timeout: Option<i32>,
+    pub memory_limit: Option<i32>,
+    pub cpu_limit: Option<i32>,
 }
 
 #[derive(Debug, sqlx::FromRow, Serialize)]
@@ -114,6 +120,7 @@ struct CompletedJob {
     raw_flow: Option<serde_json::Value>,
     is_flow_step: bool,
     language: Option<ScriptLang>,
+    flow_version: Option<i64>,
 }
 
 #[derive(Deserialize, Clone, Copy)]
@@ -140,7 +147,8 @@ pub async fn run_flow_by_path(
     Query(run_query): Query<RunJobQuery>,
 ) -> error::Result<(StatusCode, String)> {
     let flow_path = flow_path.to_path();
-    let tx = user_db.begin(&authed).await?;
+    let mut tx = user_db.begin(&authed).await?;
+    let args = validate_flow_args(&mut tx, &w_id, flow_path, args).await?;
     let (uuid, tx) = push(
         tx,
         &w_id,
@@ -168,6 +176,7 @@ pub async fn run_job_by_path(
     let script_path = script_path.to_path();
     let mut tx = user_db.begin(&authed).await?;
     let script_hash = get_latest_hash_for_path(&mut tx, &w_id, script_path).await?;
+    let args = validate_script_args(&mut tx, &w_id, &script_hash, script_path, args).await?;
     let (uuid, tx) = push(
         tx,
         &w_id,
@@ -208,6 +217,32 @@ pub async fn get_latest_hash_for_path<'c>(
     Ok(ScriptHash(script_hash))
 }
 
+/// check that a pinned hash is a non-deleted version of the script at `script_path`
+pub async fn check_hash_for_path<'c>(
+    db: &mut Transaction<'c, Postgres>,
+    w_id: &str,
+    script_path: &str,
+    hash: &ScriptHash,
+) -> error::Result<()> {
+    let exists = sqlx::query_scalar!(
+        "SELECT EXISTS(SELECT 1 FROM script WHERE hash = $1 AND path = $2 AND \
+         (workspace_id = $3 OR workspace_id = 'starter') AND deleted = false)",
+        hash.0,
+        script_path,
+        w_id
+    )
+    .fetch_one(db)
+    .await?
+    .unwrap_or(false);
+
+    if !exists {
+        return Err(Error::BadRequest(format!(
+            "{hash} is not a version of the script at path {script_path}"
+        )));
+    }
+    Ok(())
+}
+
 pub async fn run_job_by_hash(
     authed: Authed,
     Extension(user_db): Extension<UserDB>,
@@ -218,6 +253,7 @@ pub async fn run_job_by_hash(
     let hash = script_hash.0;
     let mut tx = user_db.begin(&authed).await?;
     let path = get_path_for_hash(&mut tx, &w_id, hash).await?;
+    let args = validate_script_args(&mut tx, &w_id, &script_hash, &path, args).await?;
     let (uuid, tx) = push(
         tx,
         &w_id,
@@ -260,6 +296,7 @@ async fn run_preview_job(
     Json(preview): Json<Preview>,
     Query(sch_query): Query<RunJobQuery>,
 ) -> error::Result<(StatusCode, String)> {
+    crate::scripts::check_limits(preview.timeout, preview.memory_limit, preview.cpu_limit)?;
     let tx = user_db.begin(&authed).await?;
     let (uuid, tx) = push(
         tx,
@@ -268,6 +305,9 @@ async fn run_preview_job(
             content: preview.content,
             path: preview.path,
             language: preview.language,
+            timeout: preview.timeout,
+            memory_limit: preview.memory_limit,
+            cpu_limit: preview.cpu_limit,
         }),
         preview.args,
         &authed.username,
@@ -282,6 +322,42 @@ async fn run_preview_job(
     Ok((StatusCode::CREATED, uuid.to_string()))
 }
 
+pub async fn run_draft_job(
+    authed: Authed,
+    Extension(user_db): Extension<UserDB>,
+    Path((w_id, script_path)): Path<(String, StripPath)>,
+    axum::Json(args): axum::Json<Option<Map<String, Value>>>,
+    Query(run_query): Query<RunJobQuery>,
+) -> error::Result<(StatusCode, String)> {
+    let script_path = script_path.to_path();
+    let mut tx = user_db.begin(&authed).await?;
+    let draft = crate::scripts::get_draft(&mut tx, &w_id, script_path).await?;
+    // the draft is not deployed yet, its args are validated against its own schema
+    let args = validate_args(draft.schema.map(|x| x.0), args, &draft.path)?;
+    let (uuid, tx) = push(
+        tx,
+        &w_id,
+        JobPayload::Code(RawCode {
+            content: draft.content,
+            path: Some(draft.path),
+            language: draft.language,
+            timeout: draft.timeout,
+            memory_limit: draft.memory_limit,
+            cpu_limit: draft.cpu_limit,
+        }),
+        args,
+        &authed.username,
+        owner_to_token_owner(&authed.username, false),
+        run_query.get_scheduled_for(),
+        None,
+        None,
+        false,
+    )
+    .await?;
+    tx.commit().await?;
+    Ok((StatusCode::CREATED, uuid.to_string()))
+}
+
 async fn run_preview_flow_job(
     authed: Authed,
     Extension(user_db): Extension<UserDB>,
@@ -422,6 +498,7 @@ async fn list_jobs(
             "flow_status",
             "is_flow_step",
             "language",
+            "flow_version",
         ],
     );
     let sqlc = list_completed_jobs_query(
@@ -456,6 +533,7 @@ async fn list_jobs(
             "flow_status",
             "is_flow_step",
             "language",
+            "flow_version",
         ],
     );
     let sql = format!(
@@ -571,6 +649,7 @@ async fn list_completed_jobs(
             "null as raw_flow",
             "is_flow_step",
             "language",
+            "flow_version",
         ],
     )
     .sql()?;
@@ -865,6 +944,7 @@ struct UnifiedJob {
     flow_status: Option<serde_json::Value>,
     is_flow_step: bool,
     language: Option<ScriptLang>,
+    flow_version: Option<i64>,
 }
 
 impl From<UnifiedJob> for Job {
@@ -896,6 +976,7 @@ impl From<UnifiedJob> for Job {
                 raw_flow: None,
                 is_flow_step: uj.is_flow_step,
                 language: uj.language,
+                flow_version: uj.flow_version,
             }),
             "QueuedJob" => Job::QueuedJob(QueuedJob {
                 workspace_id: uj.workspace_id,
@@ -922,6 +1003,10 @@ impl From<UnifiedJob> for Job {
                 raw_flow: None,
                 is_flow_step: uj.is_flow_step,
                 language: uj.language,
+                flow_version: uj.flow_version,
+                timeout: None,
+                memory_limit: None,
+                cpu_limit: None,
             }),
             t => panic!("job type {} not valid", t),
         }
@@ -933,9 +1018,12 @@ struct CancelJob {
 }
 
 pub struct RawCode {
-    content: String,
-    path: Option<String>,
-    language: ScriptLang,
+    pub content: String,
+    pub path: Option<String>,
+    pub language: ScriptLang,
+    pub timeout: Option<i32>,
+    pub memory_limit: Option<i32>,
+    pub cpu_limit: Option<i32>,
 }
 
 #[derive(Deserialize)]
@@ -944,6 +1032,9 @@ struct Preview {
     path: Option<String>,
     args: Option<Map<String, Value>>,
     language: ScriptLang,
+    timeout: Option<i32>,
+    memory_limit: Option<i32>,
+    cpu_limit: Option<i32>,
 }
 
 #[derive(Deserialize)]
@@ -970,6 +1061,67 @@ pub enum JobPayload {
     },
 }
 
+/// validate the args of a job against the schema of its script or flow and fill in the defaults
+fn validate_args(
+    schema: Option<Value>,
+    args: Option<Map<String, Value>>,
+    path: &str,
+) -> error::Result<Option<Map<String, Value>>> {
+    let schema = match schema {
+        Some(schema) if schema.get("properties").is_some() => schema,
+        _ => return Ok(args),
+    };
+    let mut args = args.unwrap_or_default();
+    let errors = json_schema::validate_and_fill_defaults(&schema, &mut args);
+    if errors.is_empty() {
+        Ok(Some(args))
+    } else {
+        Err(Error::BadRequest(format!(
+            "invalid args for {path}:\n{}",
+            json_schema::format_errors(&errors)
+        )))
+    }
+}
+
+/// validate the args of a run of the script at `hash` against its schema and fill in the defaults.
+/// Only the api entry points validate: jobs pushed by the workers (flow steps, next runs of
+/// schedules) keep the args they were given, so a schema change never stops them
+pub async fn validate_script_args<'c>(
+    db: &mut Transaction<'c, Postgres>,
+    w_id: &str,
+    hash: &ScriptHash,
+    path: &str,
+    args: Option<Map<String, Value>>,
+) -> error::Result<Option<Map<String, Value>>> {
+    let schema = sqlx::query_scalar!(
+        "SELECT schema FROM script WHERE hash = $1 AND (workspace_id = $2 OR workspace_id = 'starter')",
+        hash.0,
+        w_id
+    )
+    .fetch_optional(db)
+    .await?
+    .flatten();
+    validate_args(schema, args, path)
+}
+
+/// validate the args of a run of the flow at `path` against its schema and fill in the defaults
+pub async fn validate_flow_args<'c>(
+    db: &mut Transaction<'c, Postgres>,
+    w_id: &str,
+    path: &str,
+    args: Option<Map<String, Value>>,
+) -> error::Result<Option<Map<String, Value>>> {
+    let schema = sqlx::query_scalar!(
+        "SELECT schema FROM flow WHERE path = $1 AND (workspace_id = $2 OR workspace_id = 'starter')",
+        path,
+        w_id
+    )
+    .fetch_optional(db)
+    .await?
+    .flatten();
+    validate_args(schema, args, path)
+}
+
 pub async fn push<'c>(
     mut tx: Transaction<'c, Postgres>,
     workspace_id: &str,
@@ -983,7 +1135,6 @@ pub async fn push<'c>(
     is_flow_step: bool,
 ) -> Result<(Uuid, Transaction<'c, Postgres>), Error> {
     let scheduled_for = scheduled_for_o.unwrap_or_else(chrono::Utc::now);
-    let args_json = args.map(serde_json::Value::Object);
     let job_id: Uuid = Ulid::new().into();
 
     let rate_limiting_queue = sqlx::query_scalar!(
@@ -1016,57 +1167,94 @@ pub async fn push<'c>(
         }
     }
 
-    let (script_hash, script_path, raw_code, job_kind, raw_flow, language) = match job_payload {
-        JobPayload::ScriptHash { hash, path } => {
-            let language =  sqlx::query_scalar!("SELECT language as \"language: ScriptLang\" FROM script WHERE hash = $1 AND (workspace_id = $2 OR workspace_id = 'starter')", hash.0, workspace_id)
-            .fetch_one(&mut tx)
-            .await?;
-            (
-                Some(hash.0),
-                Some(path),
+    let (script_hash, script_path, raw_code, job_kind, raw_flow, language, flow_version, limits) =
+        match job_payload {
+            JobPayload::ScriptHash { hash, path } => {
+                let language = sqlx::query_scalar!(
+                    "SELECT language as \"language: ScriptLang\" FROM script WHERE hash = $1 AND (workspace_id = $2 OR workspace_id = 'starter')",
+                    hash.0,
+                    workspace_id
+                )
+                .fetch_one(&mut tx)
+                .await?;
+                (
+                    Some(hash.0),
+                    Some(path),
+                    None,
+                    JobKind::Script,
+                    None,
+                    Some(language),
+                    None,
+                    (None, None, None),
+                )
+            }
+            JobPayload::Code(RawCode {
+                content,
+                path,
+                language,
+                timeout,
+                memory_limit,
+                cpu_limit,
+            }) => (
                 None,
-                JobKind::Script,
+                path,
+                Some(content),
+                JobKind::Preview,
                 None,
                 Some(language),
-            )
-        }
-        JobPayload::Code(RawCode {
-            content,
-            path,
-            language,
-        }) => (
-            None,
-            path,
-            Some(content),
-            JobKind::Preview,
-            None,
-            Some(language),
-        ),
-        JobPayload::Dependencies { hash, dependencies } => (
-            Some(hash.0),
-            None,
-            Some(dependencies.join("\n")),
-            JobKind::Dependencies,
-            None,
-            Some(ScriptLang::Python3),
-        ),
-        JobPayload::RawFlow { value, path } => {
-            (None, path, None, JobKind::FlowPreview, Some(value), None)
-        }
-        JobPayload::Flow(flow) => {
-            let value_json = sqlx::query_scalar!("SELECT value FROM flow WHERE path = $1 AND (workspace_id = $2 OR workspace_id = 'starter')", 
-            flow, workspace_id)
+                None,
+                (timeout, memory_limit, cpu_limit),
+            ),
+            JobPayload::Dependencies { hash, dependencies } => (
+                Some(hash.0),
+                None,
+                Some(dependencies.join("\n")),
+                JobKind::Dependencies,
+                None,
+                Some(ScriptLang::Python3),
+                None,
+                (None, None, None),
+            ),
+            JobPayload::RawFlow { value, path } => (
+                None,
+                path,
+                None,
+                JobKind::FlowPreview,
+                Some(value),
+                None,
+                None,
+                (None, None, None),
+            ),
+            JobPayload::Flow(flow) => {
+                let row = sqlx::query!(
+                    "SELECT value, (SELECT max(id) FROM flow_version WHERE flow_version.path = flow.path \
+                     AND flow_version.workspace_id = flow.workspace_id) as version \
+                     FROM flow WHERE path = $1 AND (workspace_id = $2 OR workspace_id = 'starter')",
+                    flow,
+                    workspace_id
+                )
                 .fetch_optional(&mut tx)
                 .await?
                 .ok_or_else(|| Error::InternalErr(format!("not found flow at path {:?}", flow)))?;
-            let value = serde_json::from_value::<FlowValue>(value_json).map_err(|err| {
-                Error::InternalErr(format!(
-                    "could not convert json to flow for {flow}: {err:?}"
-                ))
-            })?;
-            (None, Some(flow), None, JobKind::Flow, Some(value), None)
-        }
-    };
+                let value = serde_json::from_value::<FlowValue>(row.value).map_err(|err| {
+                    Error::InternalErr(format!(
+                        "could not convert json to flow for {flow}: {err:?}"
+                    ))
+                })?;
+                (
+                    None,
+                    Some(flow),
+                    None,
+                    JobKind::Flow,
+                    Some(value),
+                    None,
+                    row.version,
+                    (None, None, None),
+                )
+            }
+        };
+
+    let args_json = args.map(serde_json::Value::Object);
 
     let flow_status = raw_flow.as_ref().map(|f| FlowStatus {
         step: 0,
@@ -1078,8 +1266,9 @@ pub async fn push<'c>(
     let uuid = sqlx::query_scalar!(
         "INSERT INTO queue
             (workspace_id, id, parent_job, created_by, permissioned_as, scheduled_for, 
-                script_hash, script_path, raw_code, args, job_kind, schedule_path, raw_flow, flow_status, is_flow_step, language)
-            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, $16) RETURNING id",
+                script_hash, script_path, raw_code, args, job_kind, schedule_path, raw_flow, flow_status, is_flow_step, language, flow_version, \
+                timeout, memory_limit, cpu_limit)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, $16, $17, $18, $19, $20) RETURNING id",
         workspace_id,
         job_id,
         parent_job,
@@ -1095,7 +1284,11 @@ pub async fn push<'c>(
         raw_flow.map(|f| serde_json::json!(f)),
         flow_status.map(|f| serde_json::json!(f)),
         is_flow_step,
-        language: ScriptLang
+        language: ScriptLang,
+        flow_version,
+        limits.0,
+        limits.1,
+        limits.2
     )
     .fetch_one(&mut tx)
     .await?;
@@ -1172,8 +1365,8 @@ pub async fn add_completed_job(
             (workspace_id, id, parent_job, created_by, created_at, duration, success, script_hash, script_path, \
         args, result, logs, 
             raw_code, canceled, canceled_by, canceled_reason, job_kind, schedule_path, permissioned_as, flow_status, raw_flow, \
-            is_flow_step)
-            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, $16, $17, $18, $19, $20, $21, $22) \
+            is_flow_step, flow_version)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, $16, $17, $18, $19, $20, $21, $22, $23) \
         ON CONFLICT (id) DO UPDATE SET success = $7, result = $11, logs = concat(cj.logs, $12) \
         RETURNING id",
         queued_job.workspace_id,
@@ -1197,7 +1390,8 @@ pub async fn add_completed_job(
         queued_job.permissioned_as,
         queued_job.flow_status,
         queued_job.raw_flow,
-        queued_job.is_flow_step
+        queued_job.is_flow_step,
+        queued_job.flow_version
     )
     .fetch_one(db)
     .await?;
@@ -1445,9 +1639,16 @@ async fn push_next_flow_job(
         let module = &flow.modules[i];
         let mut tx = db.begin().await?;
         let job_payload = match &module.value {
-            FlowModuleValue::Script { path: script_path } => {
-                let script_hash =
-                    get_latest_hash_for_path(&mut tx, &job.workspace_id, script_path).await?;
+            FlowModuleValue::Script {
+                path: script_path,
+                hash,
+            } => {
+                let script_hash = match hash {
+                    Some(hash) => *hash,
+                    None => {
+                        get_latest_hash_for_path(&mut tx, &job.workspace_id, script_path).await?
+                    }
+                };
                 JobPayload::ScriptHash {
                     hash: script_hash,
                     path: script_path.to_owned(),
@@ -0,0 +1,280 @@
+/*
+ * Author & Copyright: Ruben Fiszel 2021
+ * This file and its contents are licensed under the AGPLv3 License.
+ * Please see the included NOTICE for copyright information and
+ * LICENSE-AGPL for a copy of the license.
+ */
+
+//! Validation of json values against the subset of json schema generated by the frontend
+//! (type, enum, required, properties, items, nullable, default).
+
+use serde::Serialize;
+use serde_json::{Map, Value};
+
+const FUNCTION_CALL_DEFAULT: &str = "<function call>";
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct FieldError {
+    pub field: String,
+    pub message: String,
+}
+
+pub fn format_errors(errors: &[FieldError]) -> String {
+    errors
+        .iter()
+        .map(|e| format!("- {}: {}", e.field, e.message))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// validate `args` against an object `schema`, inserting the defaults of the missing properties
+pub fn validate_and_fill_defaults(
+    schema: &Value,
+    args: &mut Map<String, Value>,
+) -> Vec<FieldError> {
+    let mut errors = vec![];
+    fill_defaults(schema, args);
+    validate_object(schema, args, "", &|_, _| false, &mut errors);
+    errors
+}
+
+/// validate a value against a schema of any type, without filling the defaults. `opaque` tells
+/// from the schema of a property and a string value whether that value cannot be checked, e.g. an
+/// encrypted secret field
+pub fn validate(
+    schema: &Value,
+    value: &Value,
+    opaque: &dyn Fn(&Value, &str) -> bool,
+) -> Vec<FieldError> {
+    let mut errors = vec![];
+    validate_value(schema, value, "", opaque, &mut errors);
+    for error in errors.iter_mut().filter(|x| x.field.is_empty()) {
+        error.field = "value".to_string();
+    }
+    errors
+}
+
+fn fill_defaults(schema: &Value, args: &mut Map<String, Value>) {
+    if let Some(properties) = schema.get("properties").and_then(|x| x.as_object()) {
+        for (key, property) in properties {
+            if let Some(default) = property.get("default") {
+                // defaults computed by a function call are left to the script itself
+                if !args.contains_key(key)
+                    && !default.is_null()
+                    && default.as_str() != Some(FUNCTION_CALL_DEFAULT)
+                {
+                    args.insert(key.clone(), default.clone());
+                }
+            }
+        }
+    }
+}
+
+fn field_name(parent: &str, key: &str) -> String {
+    if parent.is_empty() {
+        key.to_string()
+    } else {
+        format!("{parent}.{key}")
+    }
+}
+
+fn validate_object(
+    schema: &Value,
+    obj: &Map<String, Value>,
+    path: &str,
+    opaque: &dyn Fn(&Value, &str) -> bool,
+    errors: &mut Vec<FieldError>,
+) {
+    let required = schema
+        .get("required")
+        .and_then(|x| x.as_array())
+        .map(|x| x.iter().filter_map(|x| x.as_str()).collect::<Vec<_>>())
+        .unwrap_or_default();
+    for key in &required {
+        if !obj.contains_key(*key) {
+            errors.push(FieldError {
+                field: field_name(path, key),
+                message: "is required".to_string(),
+            });
+        }
+    }
+    if let Some(properties) = schema.get("properties").and_then(|x| x.as_object()) {
+        for (key, value) in obj {
+            if let Some(property) = properties.get(key) {
+                let field = field_name(path, key);
+                if value.is_null() {
+                    let nullable = property
+                        .get("nullable")
+                        .and_then(|x| x.as_bool())
+                        .unwrap_or(false);
+                    if !nullable && required.contains(&key.as_str()) {
+                        errors.push(FieldError {
+                            field,
+                            message: "must not be null".to_string(),
+                        });
+                    }
+                } else {
+                    validate_value(property, value, &field, opaque, errors);
+                }
+            }
+        }
+    }
+}
+
+fn validate_value(
+    schema: &Value,
+    value: &Value,
+    path: &str,
+    opaque: &dyn Fn(&Value, &str) -> bool,
+    errors: &mut Vec<FieldError>,
+) {
+    // variables and resources are only resolved by the worker at execution time
+    if let Some(s) = value.as_str() {
+        if s.starts_with("$var:")
+            || s.starts_with("$res:")
+            || s == FUNCTION_CALL_DEFAULT
+            || opaque(schema, s)
+        {
+            return;
+        }
+    }
+    if value.is_null()
+        && schema
+            .get("nullable")
+            .and_then(|x| x.as_bool())
+            .unwrap_or(false)
+    {
+        return;
+    }
+
+    let typ = schema.get("type").and_then(|x| x.as_str()).unwrap_or("");
+    let valid_type = match typ {
+        "string" => value.is_string(),
+        "integer" => value.is_i64() || value.is_u64(),
+        "number" => value.is_number(),
+        "boolean" => value.is_boolean(),
+        "object" => value.is_object(),
+        "array" => value.is_array(),
+        _ => true,
+    };
+    if !valid_type {
+        errors.push(FieldError {
+            field: path.to_string(),
+            message: format!("expected a value of type {typ}, got {value}"),
+        });
+        return;
+    }
+
+    if let Some(variants) = schema.get("enum").and_then(|x| x.as_array()) {
+        if !variants.is_empty() && !variants.contains(value) {
+            errors.push(FieldError {
+                field: path.to_string(),
+                message: format!("{value} is not one of {}", Value::Array(variants.clone())),
+            });
+        }
+    }
+
+    match value {
+        Value::Object(obj) => validate_object(schema, obj, path, opaque, errors),
+        Value::Array(items) => {
+            if let Some(items_schema) = schema.get("items") {
+                for (i, item) in items.iter().enumerate() {
+                    validate_value(items_schema, item, &format!("{path}[{i}]"), opaque, errors);
+                }
+            }
+        }
+        _ => (),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_validate_and_fill_defaults() -> anyhow::Result<()> {
+        let schema = json!({
+            "$schema": "https://json-schema.org/draft/2020-12/schema",
+            "type": "object",
+            "properties": {
+                "name": { "type": "string", "default": "Nicolas Bourbaki" },
+                "age": { "type": "integer" },
+                "color": { "type": "string", "enum": ["red", "blue"] },
+                "tags": { "type": "array", "items": { "type": "string" } },
+                "db": { "type": "object" }
+            },
+            "required": ["age", "color"]
+        });
+
+        let mut args = json!({ "age": 3, "color": "red", "db": "$res:u/bot/db" })
+            .as_object()
+            .unwrap()
+            .clone();
+        assert_eq!(validate_and_fill_defaults(&schema, &mut args), vec![]);
+        assert_eq!(args.get("name"), Some(&json!("Nicolas Bourbaki")));
+
+        let mut args = json!({ "age": "3", "color": "green", "tags": ["a", 1] })
+            .as_object()
+            .unwrap()
+            .clone();
+        let fields = validate_and_fill_defaults(&schema, &mut args)
+            .into_iter()
+            .map(|x| x.field)
+            .collect::<Vec<_>>();
+        assert_eq!(fields, vec!["age", "color", "tags[1]"]);
+        Ok(())
+    }
+    #[test]
+    fn test_validate() -> anyhow::Result<()> {
+        let schema = json!({
+            "type": "object",
+            "properties": {
+                "host": { "type": "string" },
+                "port": { "type": "integer" },
+                "password": { "type": "string" }
+            },
+            "required": ["host", "port"]
+        });
+
+        let value = json!({ "host": "localhost", "port": 5432, "password": "$var:g/all/pg" });
+        assert_eq!(validate(&schema, &value, &|_, _| false), vec![]);
+
+        let value = json!({ "port": "5432" });
+        let fields = validate(&schema, &value, &|_, _| false)
+            .into_iter()
+            .map(|x| x.field)
+            .collect::<Vec<_>>();
+        assert_eq!(fields, vec!["host", "port"]);
+
+        assert_eq!(
+            validate(&schema, &json!("localhost"), &|_, _| false)
+                .into_iter()
+                .map(|x| x.field)
+                .collect::<Vec<_>>(),
+            vec!["value"]
+        );
+
+        // only the properties the predicate accepts are left unchecked
+        let opaque = |property: &Value, s: &str| {
+            property.get("secret") == Some(&json!(true)) && s.starts_with("$encrypted:")
+        };
+        let schema = json!({
+            "type": "object",
+            "properties": {
+                "port": { "type": "integer" },
+                "password": { "type": "integer", "secret": true }
+            }
+        });
+        let value = json!({ "port": 5432, "password": "$encrypted:abc" });
+        assert_eq!(validate(&schema, &value, &opaque), vec![]);
+        let value = json!({ "port": "$encrypted:abc" });
+        let fields = validate(&schema, &value, &opaque)
+            .into_iter()
+            .map(|x| x.field)
+            .collect::<Vec<_>>();
+        assert_eq!(fields, vec!["port"]);
+        Ok(())
+    }
+}
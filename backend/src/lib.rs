@@ -28,16 +28,20 @@ mod client;
 mod db;
 mod email;
 mod error;
+mod external_secrets;
 mod flow;
+mod git_sync;
 mod granular_acls;
 mod groups;
 mod jobs;
 mod js_eval;
+mod json_schema;
 mod oauth2;
 mod parser;
 mod resources;
 mod schedule;
 mod scripts;
+mod search;
 mod static_assets;
 mod users;
 mod utils;
@@ -49,6 +53,7 @@ mod workspaces;
 use error::Error;
 
 pub use crate::email::EmailSender;
+pub use crate::oauth2::{build_connect_providers, ConnectProviders};
 use crate::{db::UserDB, utils::rd_string};
 
 const GIT_VERSION: &str = git_version!(args = ["--tag", "--always"], fallback = "unknown-version");
@@ -154,6 +159,7 @@ pub async fn run_server(
     addr: SocketAddr,
     base_url: &str,
     es: EmailSender,
+    connect_providers: Arc<ConnectProviders>,
     mut rx: tokio::sync::broadcast::Receiver<()>,
 ) -> anyhow::Result<()> {
     let user_db = UserDB::new(db.clone());
@@ -179,6 +185,7 @@ pub async fn run_server(
         .layer(Extension(user_db))
         .layer(Extension(auth_cache.clone()))
         .layer(Extension(basic_clients))
+        .layer(Extension(connect_providers))
         .layer(Extension(BaseUrl(base_url.to_string())))
         .layer(CookieManagerLayer::new());
     // build our application with a route
@@ -205,7 +212,9 @@ pub async fn run_server(
                         .nest("/audit", audit::workspaced_service())
                         .nest("/acls", granular_acls::workspaced_service())
                         .nest("/workspaces", workspaces::workspaced_service())
-                        .nest("/flows", flow::workspaced_service()),
+                        .nest("/flows", flow::workspaced_service())
+                        .nest("/git_sync", git_sync::workspaced_service())
+                        .nest("/search", search::workspaced_service()),
                 )
                 .nest("/workspaces", workspaces::global_service())
                 .nest(
@@ -247,15 +256,28 @@ pub async fn run_server(
     Ok(())
 }
 
-pub fn monitor_db(db: &DB, timeout: i32, tx: tokio::sync::broadcast::Sender<()>) {
+pub fn monitor_db(
+    db: &DB,
+    timeout: i32,
+    base_url: &str,
+    connect_providers: Arc<ConnectProviders>,
+    tx: tokio::sync::broadcast::Sender<()>,
+) -> anyhow::Result<()> {
     let db1 = db.clone();
     let db2 = db.clone();
+    let db3 = db.clone();
+    let base_url = base_url.to_string();
 
     let rx1 = tx.subscribe();
     let rx2 = tx.subscribe();
+    let rx3 = tx.subscribe();
 
     tokio::spawn(async move { worker::restart_zombie_jobs_periodically(&db1, timeout, rx1).await });
     tokio::spawn(async move { users::delete_expired_items_perdiodically(&db2, rx2).await });
+    tokio::spawn(async move {
+        oauth2::refresh_accounts_periodically(&db3, &connect_providers, &base_url, rx3).await
+    });
+    Ok(())
 }
 
 pub async fn run_workers(
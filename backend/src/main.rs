@@ -5,7 +5,7 @@
  * LICENSE-AGPL for a copy of the license.
  */
 
-use std::net::SocketAddr;
+use std::{net::SocketAddr, sync::Arc};
 
 use dotenv::dotenv;
 
@@ -28,6 +28,9 @@ async fn main() -> anyhow::Result<()> {
         windmill::migrate_db(&db).await?;
     }
 
+    // shared by the server, which connects the accounts, and the monitor, which refreshes them
+    let connect_providers = Arc::new(windmill::build_connect_providers()?);
+
     let (tx, rx) = tokio::sync::broadcast::channel::<()>(3);
     let shutdown_signal = windmill::shutdown_signal(tx.clone());
 
@@ -50,6 +53,7 @@ async fn main() -> anyhow::Result<()> {
                         server: "smtp.gmail.com".to_string(),
                         password: std::env::var("SMTP_PASSWORD").unwrap_or("NOPASS".to_string()),
                     },
+                    connect_providers.clone(),
                     rx,
                 )
                 .await?;
@@ -83,7 +87,13 @@ async fn main() -> anyhow::Result<()> {
 
         let monitor_f = async {
             if monitor_mode {
-                windmill::monitor_db(&db, timeout, tx.clone());
+                windmill::monitor_db(
+                    &db,
+                    timeout,
+                    &std::env::var("BASE_URL").unwrap_or("http://localhost".to_string()),
+                    connect_providers.clone(),
+                    tx.clone(),
+                )?;
             }
             Ok(()) as anyhow::Result<()>
         };
@@ -1,3 +1,4 @@
+use std::collections::HashMap;
 use std::fmt::Debug;
 
 use std::sync::Arc;
@@ -7,16 +8,20 @@ use axum::body::Bytes;
 use axum::extract::{Extension, FromRequest, Path, Query, RequestParts};
 use axum::response::Redirect;
 use axum::routing::{get, post};
-use axum::{async_trait, Router};
+use axum::{async_trait, Json, Router};
 use futures::TryFutureExt;
 use hyper::StatusCode;
+use magic_crypt::{MagicCrypt256, MagicCryptTrait};
 use oauth2::basic::{
     BasicClient, BasicErrorResponse, BasicRevocationErrorResponse, BasicTokenIntrospectionResponse,
     BasicTokenType,
 };
 use oauth2::reqwest::async_http_client;
 use oauth2::{helpers, TokenType};
-use oauth2::{AccessToken, Client as OClient, RefreshToken, StandardRevocableToken};
+use oauth2::{
+    AccessToken, Client as OClient, ExtraTokenFields, RefreshToken, StandardRevocableToken,
+    StandardTokenResponse,
+};
 // Alternatively, this can be `oauth2::curl::http_client` or a custom client.
 use oauth2::{
     AuthUrl, AuthorizationCode, ClientId, ClientSecret, CsrfToken, RedirectUrl, Scope,
@@ -31,8 +36,9 @@ use crate::audit::{audit_log, ActionKind};
 use crate::db::{UserDB, DB};
 use crate::error::{self, to_anyhow, Error, Result};
 use crate::jobs::{get_latest_hash_for_path, JobPayload};
+use crate::resources::insert_resource_version;
 use crate::users::{Authed, LoginType};
-use crate::variables::build_crypt;
+use crate::variables::{build_crypt, build_crypt_for_write, insert_variable_version};
 use crate::workspaces::WorkspaceSettings;
 use crate::{jobs, BasicClientsMap};
 use crate::{variables, BaseUrl};
@@ -41,6 +47,7 @@ pub fn global_service() -> Router {
     Router::new()
         .route("/login/:client", get(login))
         .route("/login_callback/:client", get(login_callback))
+        .route("/list_connects", get(list_connects))
         .route(
             "/slack_command",
             post(slack_command).route_layer(axum::middleware::from_extractor::<SlackSig>()),
@@ -72,60 +79,28 @@ pub fn build_gh_client(client_id: &str, client_secret: &str, base_uri: &str) ->
     )
 }
 
-pub fn build_connect_client(w_id: &str, client_name: &str, base_uri: &str) -> Result<BasicClient> {
-    let (auth_str, token_str) = match client_name {
-        "gmail" => ("", ""),
-        "slack" => (
-            "https://slack.com/oauth/authorize",
-            "https://slack.com/api/oauth.access",
-        ),
-        _ => Err(Error::BadRequest(format!("unrecognized client!")))?,
-    };
-
-    let auth_url = AuthUrl::new(auth_str.to_string()).expect("Invalid authorization endpoint URL");
-    let token_url = TokenUrl::new(token_str.to_string()).expect("Invalid token endpoint URL");
-
-    // Set up the config for the Github OAuth2 process.
-    Ok(BasicClient::new(
-        ClientId::new(
-            std::env::var(&format!("{}_OAUTH_CLIENT_ID", client_name.to_uppercase()))
-                .ok()
-                .ok_or(Error::BadRequest(format!(
-                    "client id for {} not in env",
-                    client_name
-                )))?,
-        ),
-        Some(ClientSecret::new(
-            std::env::var(&format!(
-                "{}_OAUTH_CLIENT_SECRET",
-                client_name.to_uppercase()
-            ))
-            .ok()
-            .ok_or(Error::BadRequest(format!(
-                "client secret for {} not in env",
-                client_name
-            )))?,
-        )),
-        auth_url,
-        Some(token_url),
-    )
-    .set_redirect_uri(
-        RedirectUrl::new(format!(
-            "{base_uri}/api/w/{w_id}/oauth/connect_callback/{client_name}"
-        ))
-        .unwrap(),
-    ))
-}
-
-type SlackClient = OClient<
+type OAuthClient<TR> = OClient<
     BasicErrorResponse,
-    SlackTokenResponse,
+    TR,
     BasicTokenType,
     BasicTokenIntrospectionResponse,
     StandardRevocableToken,
     BasicRevocationErrorResponse,
 >;
 
+type SlackClient = OAuthClient<SlackTokenResponse>;
+
+/// fields of a token response beyond the standard ones, kept to be mapped to the resource
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct RawTokenFields {
+    #[serde(flatten)]
+    fields: serde_json::Map<String, serde_json::Value>,
+}
+
+impl ExtraTokenFields for RawTokenFields {}
+
+type ConnectClient = OAuthClient<StandardTokenResponse<RawTokenFields, BasicTokenType>>;
+
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct SlackTokenResponse {
     access_token: AccessToken,
@@ -196,36 +171,193 @@ where
     }
 }
 
-pub fn build_slack_client(w_id: &str, client_name: &str, base_uri: &str) -> Result<SlackClient> {
-    let (auth_str, token_str) = (
-        "https://slack.com/oauth/authorize",
-        "https://slack.com/api/oauth.access",
-    );
+/// OAuth provider that resources can be connected to. Providers are defined in the json file at
+/// `OAUTH_CONNECT_CONFIG`, an object from the name of the provider to its definition, slack is
+/// built-in. The client id and secret fall back to the env vars `{NAME}_OAUTH_CLIENT_ID` and
+/// `{NAME}_OAUTH_CLIENT_SECRET`.
+#[derive(Deserialize, Clone, Debug)]
+pub struct ConnectProvider {
+    auth_url: String,
+    token_url: String,
+    #[serde(default)]
+    scopes: Vec<String>,
+    /// additional query parameters of the authorization url, e.g. `access_type`
+    #[serde(default)]
+    extra_params: HashMap<String, String>,
+    client_id: Option<String>,
+    client_secret: Option<String>,
+    /// resource type of the connected resource, the name of the provider by default
+    resource_type: Option<String>,
+    /// value of the connected resource, see `map_token_response`
+    #[serde(default = "default_resource_value")]
+    resource_value: serde_json::Value,
+}
+
+fn default_resource_value() -> serde_json::Value {
+    serde_json::json!({ "token": "$token" })
+}
 
-    let auth_url = AuthUrl::new(auth_str.to_string()).expect("Invalid authorization endpoint URL");
-    let token_url = TokenUrl::new(token_str.to_string()).expect("Invalid token endpoint URL");
+fn builtin_connect_providers() -> HashMap<String, ConnectProvider> {
+    [(
+        "slack".to_string(),
+        ConnectProvider {
+            auth_url: "https://slack.com/oauth/authorize".to_string(),
+            token_url: "https://slack.com/api/oauth.access".to_string(),
+            scopes: vec!["bot".to_string(), "commands".to_string()],
+            extra_params: HashMap::new(),
+            client_id: None,
+            client_secret: None,
+            resource_type: None,
+            resource_value: default_resource_value(),
+        },
+    )]
+    .into()
+}
 
-    // Set up the config for the Github OAuth2 process.
-    Ok(SlackClient::new(
-        ClientId::new(
-            std::env::var(&format!("{}_OAUTH_CLIENT_ID", client_name.to_uppercase()))
-                .ok()
-                .ok_or(Error::BadRequest(format!(
-                    "client id for {} not in env",
-                    client_name
-                )))?,
+pub type ConnectProviders = HashMap<String, ConnectProvider>;
+
+/// the built-in providers and the ones of `OAUTH_CONNECT_CONFIG`, read once at startup
+pub fn build_connect_providers() -> Result<ConnectProviders> {
+    match std::env::var("OAUTH_CONNECT_CONFIG") {
+        Ok(path) => {
+            let content = std::fs::read_to_string(&path).map_err(|e| {
+                Error::BadConfig(format!(
+                    "could not read the oauth connect config {path}: {e}"
+                ))
+            })?;
+            parse_connect_providers(&content)
+                .map_err(|e| Error::BadConfig(format!("invalid oauth connect config {path}: {e}")))
+        }
+        Err(_) => Ok(builtin_connect_providers()),
+    }
+}
+
+fn parse_connect_providers(content: &str) -> std::result::Result<ConnectProviders, String> {
+    let configured: ConnectProviders = serde_json::from_str(content).map_err(|e| e.to_string())?;
+    for (name, provider) in configured.iter() {
+        check_resource_value(&provider.resource_value)
+            .map_err(|e| format!("resource value of {name}: {e}"))?;
+    }
+    let mut providers = builtin_connect_providers();
+    providers.extend(configured);
+    Ok(providers)
+}
+
+/// the tokens of the response are secrets, the resource references the variable holding the
+/// access token with `$token` instead of copying them
+fn check_resource_value(template: &serde_json::Value) -> std::result::Result<(), String> {
+    match template {
+        serde_json::Value::String(s) => match s.strip_prefix("$response:") {
+            Some(pointer)
+                if pointer.is_empty() || pointer.split('/').any(|x| x.ends_with("token")) =>
+            {
+                Err(format!(
+                    "{s} would copy a token of the response, use $token instead"
+                ))
+            }
+            _ => Ok(()),
+        },
+        serde_json::Value::Object(o) => o.values().try_for_each(check_resource_value),
+        serde_json::Value::Array(a) => a.iter().try_for_each(check_resource_value),
+        _ => Ok(()),
+    }
+}
+
+pub fn get_connect_provider<'a>(
+    providers: &'a ConnectProviders,
+    client_name: &str,
+) -> Result<&'a ConnectProvider> {
+    providers
+        .get(client_name)
+        .ok_or_else(|| Error::BadRequest(format!("unrecognized client {client_name}")))
+}
+
+/// build the value of a connected resource from the `resource_value` of its provider: the
+/// strings `$token` are replaced by a reference to the variable holding the access token and the
+/// strings `$response:<json pointer>` by the field of the token response at that pointer. The
+/// pointers to the tokens of the response are refused when the providers are read, the fields
+/// that are objects or arrays, which may enclose them, and the `secrets` of the response are
+/// refused here
+fn map_token_response(
+    template: &serde_json::Value,
+    variable_path: &str,
+    response: &serde_json::Value,
+    secrets: &[&str],
+) -> Result<serde_json::Value> {
+    let value = match template {
+        serde_json::Value::String(s) if s == "$token" => {
+            serde_json::Value::String(format!("$var:{variable_path}"))
+        }
+        serde_json::Value::String(s) if s.starts_with("$response:") => {
+            match response.pointer(s.trim_start_matches("$response:")) {
+                Some(serde_json::Value::Object(_) | serde_json::Value::Array(_)) => {
+                    return Err(Error::BadConfig(format!(
+                        "{s} is not a single value of the token response, it could copy a token"
+                    )))
+                }
+                Some(serde_json::Value::String(x)) if secrets.contains(&x.as_str()) => {
+                    return Err(Error::BadConfig(format!(
+                        "{s} would copy a token of the response, use $token instead"
+                    )))
+                }
+                Some(v) => v.clone(),
+                None => serde_json::Value::Null,
+            }
+        }
+        serde_json::Value::Object(o) => serde_json::Value::Object(
+            o.iter()
+                .map(|(k, v)| {
+                    map_token_response(v, variable_path, response, secrets).map(|v| (k.clone(), v))
+                })
+                .collect::<Result<_>>()?,
+        ),
+        serde_json::Value::Array(a) => serde_json::Value::Array(
+            a.iter()
+                .map(|v| map_token_response(v, variable_path, response, secrets))
+                .collect::<Result<_>>()?,
         ),
-        Some(ClientSecret::new(
+        v => v.clone(),
+    };
+    Ok(value)
+}
+
+fn build_client<TR: TokenResponse<BasicTokenType>>(
+    w_id: &str,
+    client_name: &str,
+    provider: &ConnectProvider,
+    base_uri: &str,
+) -> Result<OAuthClient<TR>> {
+    let auth_url = AuthUrl::new(provider.auth_url.clone()).map_err(|e| {
+        Error::BadConfig(format!("invalid authorization url for {client_name}: {e}"))
+    })?;
+    let token_url = TokenUrl::new(provider.token_url.clone())
+        .map_err(|e| Error::BadConfig(format!("invalid token url for {client_name}: {e}")))?;
+    let client_id = provider
+        .client_id
+        .clone()
+        .or_else(|| std::env::var(format!("{}_OAUTH_CLIENT_ID", client_name.to_uppercase())).ok())
+        .ok_or(Error::BadRequest(format!(
+            "client id for {} not configured",
+            client_name
+        )))?;
+    let client_secret = provider
+        .client_secret
+        .clone()
+        .or_else(|| {
             std::env::var(&format!(
                 "{}_OAUTH_CLIENT_SECRET",
                 client_name.to_uppercase()
             ))
             .ok()
-            .ok_or(Error::BadRequest(format!(
-                "client secret for {} not in env",
-                client_name
-            )))?,
-        )),
+        })
+        .ok_or(Error::BadRequest(format!(
+            "client secret for {} not configured",
+            client_name
+        )))?;
+
+    Ok(OAuthClient::<TR>::new(
+        ClientId::new(client_id),
+        Some(ClientSecret::new(client_secret)),
         auth_url,
         Some(token_url),
     )
@@ -237,18 +369,48 @@ pub fn build_slack_client(w_id: &str, client_name: &str, base_uri: &str) -> Resu
     ))
 }
 
+pub fn build_slack_client(
+    w_id: &str,
+    client_name: &str,
+    provider: &ConnectProvider,
+    base_uri: &str,
+) -> Result<SlackClient> {
+    build_client(w_id, client_name, provider, base_uri)
+}
+
+pub fn build_connect_client(
+    w_id: &str,
+    client_name: &str,
+    provider: &ConnectProvider,
+    base_uri: &str,
+) -> Result<ConnectClient> {
+    build_client(w_id, client_name, provider, base_uri)
+}
+
+async fn list_connects(
+    Extension(providers): Extension<Arc<ConnectProviders>>,
+) -> error::Result<Json<Vec<String>>> {
+    let mut names = providers.keys().cloned().collect::<Vec<_>>();
+    names.sort();
+    Ok(Json(names))
+}
+
 async fn connect(
     Path((w_id, client_name)): Path<(String, String)>,
     Extension(base_url): Extension<BaseUrl>,
+    Extension(providers): Extension<Arc<ConnectProviders>>,
     cookies: Cookies,
 ) -> error::Result<Redirect> {
-    let client = build_connect_client(&w_id, &client_name, &base_url.0)?;
+    let provider = get_connect_provider(&providers, &client_name)?;
+    let client = build_connect_client(&w_id, &client_name, provider, &base_url.0)?;
 
-    let (authorize_url, csrf_state) = client
+    let mut request = client
         .authorize_url(CsrfToken::new_random)
-        .add_scope(Scope::new("bot".to_string()))
-        .add_scope(Scope::new("commands".to_string()))
-        .url();
+        .add_scopes(provider.scopes.iter().map(|x| Scope::new(x.clone())));
+    for (name, value) in provider.extra_params.iter() {
+        request = request.add_extra_param(name, value);
+    }
+    let (authorize_url, csrf_state) = request.url();
 
     let csrf = csrf_state.secret().to_string();
     let mut cookie = Cookie::new("csrf", csrf);
@@ -261,6 +423,7 @@ async fn disconnect(
     authed: Authed,
     Path((w_id, client_name)): Path<(String, String)>,
     Extension(user_db): Extension<UserDB>,
+    Extension(providers): Extension<Arc<ConnectProviders>>,
 ) -> error::Result<String> {
     let mut tx = user_db.begin(&authed).await?;
 
@@ -274,10 +437,17 @@ async fn disconnect(
             .execute(&mut tx)
             .await?;
         }
-        _ => Err(error::Error::BadRequest(format!(
-            "Not recognized client name {client_name}"
-        )))?,
+        _ => {
+            get_connect_provider(&providers, &client_name)?;
+        }
     }
+    sqlx::query!(
+        "DELETE FROM account WHERE workspace_id = $1 AND client = $2",
+        &w_id,
+        &client_name
+    )
+    .execute(&mut tx)
+    .await?;
     tx.commit().await?;
     Ok(format!("{client_name} disconnected"))
 }
@@ -317,6 +487,7 @@ async fn connect_callback(
     cookies: Cookies,
     Extension(user_db): Extension<UserDB>,
     Extension(base_url): Extension<BaseUrl>,
+    Extension(providers): Extension<Arc<ConnectProviders>>,
 ) -> error::Result<Redirect> {
     if let Some(error) = query.error {
         return Ok(Redirect::to(&format!(
@@ -339,11 +510,14 @@ async fn connect_callback(
 
     let mut tx = user_db.begin(&authed).await?;
 
-    let mc = build_crypt(&mut tx, &w_id).await?;
+    let mc = build_crypt_for_write(&mut tx, &w_id).await?;
 
+    let provider = get_connect_provider(&providers, &client_name)?;
+    let mut refresh = None;
+    let mut response = serde_json::Value::Null;
     let token_res = match client_name.as_str() {
         "slack" => {
-            let t = build_slack_client(&w_id, &client_name, &base_url.0)?
+            let t = build_slack_client(&w_id, &client_name, provider, &base_url.0)?
                 .exchange_code(code)
                 .request_async(async_http_client)
                 .await;
@@ -370,23 +544,33 @@ async fn connect_callback(
                 )
                 .execute(&mut tx)
                 .await?;
+                response = serde_json::to_value(&token).unwrap_or(serde_json::Value::Null);
                 Ok(token.bot.bot_access_token.to_owned())
             } else {
                 Err(t.unwrap_err())
             }
         }
         _ => {
-            build_connect_client(&w_id, &client_name, &base_url.0)?
+            build_connect_client(&w_id, &client_name, provider, &base_url.0)?
                 .exchange_code(code)
                 .request_async(async_http_client)
-                .map_ok(|t| t.access_token().secret().to_owned())
+                .map_ok(|t| {
+                    refresh = t
+                        .refresh_token()
+                        .map(|x| (x.secret().to_owned(), t.expires_in()));
+                    response = serde_json::to_value(&t).unwrap_or(serde_json::Value::Null);
+                    t.access_token().secret().to_owned()
+                })
                 .await
         }
     };
 
     if let Ok(token) = token_res {
-        tracing::info!("{token}");
         let variable_path = &format!("g/all/{}_token", &client_name);
+        let mut secrets = vec![token.as_str()];
+        secrets.extend(refresh.as_ref().map(|(x, _)| x.as_str()));
+        let resource_value =
+            map_token_response(&provider.resource_value, variable_path, &response, &secrets)?;
         sqlx::query!(
             "INSERT INTO variable
             (workspace_id, path, value, is_secret, description)
@@ -404,12 +588,40 @@ async fn connect_callback(
             VALUES ($1, $2, $3, $4, $5) ON CONFLICT (workspace_id, path) DO UPDATE SET value = $3",
             &w_id,
             variable_path,
-            serde_json::json!({ "token": format!("$var:{variable_path}") }),
+            resource_value,
             format!("OAuth2 token for {client_name}"),
-            &client_name
+            provider.resource_type.as_ref().unwrap_or(&client_name)
         )
         .execute(&mut tx)
         .await?;
+        insert_variable_version(&mut tx, &w_id, variable_path, &authed.username).await?;
+        insert_resource_version(&mut tx, &w_id, variable_path, &authed.username).await?;
+        match refresh {
+            Some((refresh_token, expires_in)) => {
+                sqlx::query!(
+                    "INSERT INTO account (workspace_id, path, client, refresh_token, expires_at) \
+                     VALUES ($1, $2, $3, $4, $5) ON CONFLICT (workspace_id, path) DO UPDATE \
+                     SET client = $3, refresh_token = $4, expires_at = $5, refreshed_at = NULL, \
+                     refresh_error = NULL, refresh_failures = 0, retry_at = NULL",
+                    &w_id,
+                    variable_path,
+                    &client_name,
+                    variables::encrypt(&mc, refresh_token),
+                    expires_at(expires_in)
+                )
+                .execute(&mut tx)
+                .await?;
+            }
+            None => {
+                sqlx::query!(
+                    "DELETE FROM account WHERE workspace_id = $1 AND path = $2",
+                    &w_id,
+                    variable_path
+                )
+                .execute(&mut tx)
+                .await?;
+            }
+        }
         audit_log(
             &mut tx,
             &authed.username,
@@ -433,6 +645,264 @@ async fn connect_callback(
     }
 }
 
+/// access tokens are refreshed when they expire within this margin
+const REFRESH_MARGIN_SECS: i64 = 300;
+const REFRESH_INTERVAL_SECS: u64 = 60;
+const REFRESH_TIMEOUT_SECS: u64 = 30;
+/// an account being refreshed is not claimed again before this delay
+const REFRESH_CLAIM_SECS: u64 = 2 * REFRESH_TIMEOUT_SECS;
+/// a failed refresh is retried after `REFRESH_BACKOFF_SECS`, doubled at each failure. The refresh
+/// of an account stops after `MAX_REFRESH_FAILURES` failures, until it is connected again
+const REFRESH_BACKOFF_SECS: i64 = 60;
+const MAX_REFRESH_FAILURES: i32 = 5;
+
+#[derive(sqlx::FromRow)]
+struct Account {
+    workspace_id: String,
+    path: String,
+    client: String,
+    refresh_token: String,
+    refresh_failures: i32,
+}
+
+/// state of the OAuth account behind a resource, without its tokens
+#[derive(sqlx::FromRow, Serialize)]
+pub struct AccountState {
+    pub client: String,
+    pub expires_at: Option<chrono::DateTime<chrono::Utc>>,
+    pub refreshed_at: Option<chrono::DateTime<chrono::Utc>>,
+    pub refresh_error: Option<String>,
+}
+
+fn expires_at(expires_in: Option<Duration>) -> Option<chrono::DateTime<chrono::Utc>> {
+    expires_in
+        .and_then(|x| chrono::Duration::from_std(x).ok())
+        .map(|x| chrono::Utc::now() + x)
+}
+
+fn refresh_backoff_secs(failures: i32) -> i64 {
+    REFRESH_BACKOFF_SECS << (failures.clamp(1, MAX_REFRESH_FAILURES) - 1)
+}
+
+pub async fn get_account_state<'c>(
+    tx: &mut sqlx::Transaction<'c, sqlx::Postgres>,
+    w_id: &str,
+    path: &str,
+) -> Result<Option<AccountState>> {
+    let state = sqlx::query_as!(
+        AccountState,
+        "SELECT client, expires_at, refreshed_at, refresh_error FROM account \
+         WHERE workspace_id = $1 AND path = $2",
+        w_id,
+        path
+    )
+    .fetch_optional(tx)
+    .await?;
+    Ok(state)
+}
+
+/// claim an account whose access token expires soon, by pushing its next refresh past the time its
+/// refresh takes, so that the other instances of the server do not refresh it concurrently
+async fn claim_expiring_account(db: &DB) -> Result<Option<Account>> {
+    let account = sqlx::query_as!(
+        Account,
+        "UPDATE account SET retry_at = now() + make_interval(secs => $1) \
+         WHERE (workspace_id, path) IN ( \
+            SELECT workspace_id, path FROM account \
+            WHERE expires_at <= now() + make_interval(secs => $2) AND refresh_failures < $3 \
+            AND (retry_at IS NULL OR retry_at <= now()) \
+            FOR UPDATE SKIP LOCKED \
+            LIMIT 1 \
+         ) \
+         RETURNING workspace_id, path, client, refresh_token, refresh_failures",
+        REFRESH_CLAIM_SECS as f64,
+        REFRESH_MARGIN_SECS as f64,
+        MAX_REFRESH_FAILURES
+    )
+    .fetch_optional(db)
+    .await?;
+    Ok(account)
+}
+
+/// refresh the access tokens that expire soon. A failed refresh is recorded on the account and
+/// retried with a backoff
+pub async fn refresh_expiring_accounts(db: &DB, providers: &ConnectProviders, base_url: &str) {
+    loop {
+        let account = match claim_expiring_account(db).await {
+            Ok(Some(account)) => account,
+            Ok(None) => return,
+            Err(e) => {
+                tracing::error!("Error claiming an account to refresh: {e}");
+                return;
+            }
+        };
+        if let Err(e) = refresh_account(db, &account, providers, base_url).await {
+            tracing::error!(
+                workspace = %account.workspace_id,
+                path = %account.path,
+                "Error refreshing account: {e}"
+            );
+            let _ = sqlx::query!(
+                "UPDATE account SET refresh_error = $1, refresh_failures = refresh_failures + 1, \
+                 retry_at = now() + make_interval(secs => $2) \
+                 WHERE workspace_id = $3 AND path = $4 AND refresh_token = $5",
+                e.to_string(),
+                refresh_backoff_secs(account.refresh_failures + 1) as f64,
+                &account.workspace_id,
+                &account.path,
+                &account.refresh_token
+            )
+            .execute(db)
+            .await;
+        }
+    }
+}
+
+/// no lock is held during the request to the provider: the refresh is only saved if the refresh
+/// token of the account is still the one it was made with, the account being connected again in
+/// the meantime otherwise
+async fn refresh_account(
+    db: &DB,
+    account: &Account,
+    providers: &ConnectProviders,
+    base_url: &str,
+) -> Result<()> {
+    let mut tx = db.begin().await?;
+    let mc = build_crypt(&mut tx, &account.workspace_id).await?;
+    tx.commit().await?;
+    let refresh_token = mc
+        .decrypt_base64_to_string(&account.refresh_token)
+        .map_err(|e| Error::InternalErr(format!("could not decrypt the refresh token: {e}")))?;
+    let provider = get_connect_provider(providers, &account.client)?;
+    let client = build_connect_client(&account.workspace_id, &account.client, provider, base_url)?;
+    let refresh_token = RefreshToken::new(refresh_token);
+    let request = client
+        .exchange_refresh_token(&refresh_token)
+        .request_async(async_http_client);
+    let token = tokio::time::timeout(Duration::from_secs(REFRESH_TIMEOUT_SECS), request)
+        .await
+        .map_err(|_| {
+            Error::ExecutionErr(format!("refresh timed out after {REFRESH_TIMEOUT_SECS}s"))
+        })?
+        .map_err(|e| Error::ExecutionErr(format!("refresh rejected: {e}")))?;
+
+    let mut tx = db.begin().await?;
+    // the key may have been rotated during the request, the refresh tokens are compared decrypted
+    let mc = build_crypt_for_write(&mut tx, &account.workspace_id).await?;
+    let current = sqlx::query_scalar!(
+        "SELECT refresh_token FROM account WHERE workspace_id = $1 AND path = $2 FOR UPDATE",
+        &account.workspace_id,
+        &account.path
+    )
+    .fetch_optional(&mut tx)
+    .await?;
+    let unchanged = current
+        .and_then(|x| mc.decrypt_base64_to_string(x).ok())
+        .map(|x| &x == refresh_token.secret())
+        .unwrap_or(false);
+    if !unchanged {
+        // a refresh token rotated by the provider is lost with the refresh
+        tracing::warn!(
+            workspace = %account.workspace_id,
+            path = %account.path,
+            rotated = token.refresh_token().is_some(),
+            "Discarding the refresh of an account connected again during it"
+        );
+        return Ok(());
+    }
+    let refresh_token = token
+        .refresh_token()
+        .unwrap_or(&refresh_token)
+        .secret()
+        .to_owned();
+    sqlx::query!(
+        "UPDATE account SET refresh_token = $1, expires_at = $2, refreshed_at = now(), \
+         refresh_error = NULL, refresh_failures = 0, retry_at = NULL \
+         WHERE workspace_id = $3 AND path = $4",
+        variables::encrypt(&mc, refresh_token),
+        expires_at(token.expires_in()),
+        &account.workspace_id,
+        &account.path
+    )
+    .execute(&mut tx)
+    .await?;
+    // refreshes are not kept in the version history of the variable, they would drown it
+    sqlx::query!(
+        "UPDATE variable SET value = $1 WHERE workspace_id = $2 AND path = $3",
+        variables::encrypt(&mc, token.access_token().secret().to_owned()),
+        &account.workspace_id,
+        &account.path
+    )
+    .execute(&mut tx)
+    .await?;
+    audit_log(
+        &mut tx,
+        "oauth2",
+        "oauth2.refresh",
+        ActionKind::Update,
+        &account.workspace_id,
+        Some(&account.path),
+        None,
+    )
+    .await?;
+    tx.commit().await?;
+    Ok(())
+}
+
+/// re-encrypt with the key of `new` the refresh tokens of the accounts of a workspace encrypted
+/// with the key of `old`
+pub async fn reencrypt_accounts<'c>(
+    tx: &mut sqlx::Transaction<'c, sqlx::Postgres>,
+    w_id: &str,
+    old: &MagicCrypt256,
+    new: &MagicCrypt256,
+) -> Result<()> {
+    let accounts = sqlx::query!(
+        "SELECT path, refresh_token FROM account WHERE workspace_id = $1 FOR UPDATE",
+        w_id
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+    for account in accounts {
+        let refresh_token = old
+            .decrypt_base64_to_string(account.refresh_token)
+            .map_err(|e| {
+                Error::InternalErr(format!(
+                    "could not decrypt the refresh token of {}: {e}",
+                    account.path
+                ))
+            })?;
+        sqlx::query!(
+            "UPDATE account SET refresh_token = $1 WHERE workspace_id = $2 AND path = $3",
+            variables::encrypt(new, refresh_token),
+            w_id,
+            account.path
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+    Ok(())
+}
+
+pub async fn refresh_accounts_periodically(
+    db: &DB,
+    providers: &ConnectProviders,
+    base_url: &str,
+    mut rx: tokio::sync::broadcast::Receiver<()>,
+) {
+    loop {
+        refresh_expiring_accounts(db, providers, base_url).await;
+
+        tokio::select! {
+            _ = tokio::time::sleep(Duration::from_secs(REFRESH_INTERVAL_SECS)) => (),
+            _ = rx.recv() => {
+                println!("received killpill for refresh accounts");
+                break;
+            }
+        }
+    }
+}
+
 #[derive(Deserialize, Debug)]
 pub struct SlackCommand {
     team_id: String,
@@ -495,7 +965,8 @@ async fn slack_command(
     let mut tx = db.begin().await?;
     let settings = sqlx::query_as!(
         WorkspaceSettings,
-        "SELECT * FROM workspace_settings WHERE slack_team_id = $1",
+        "SELECT workspace_id, slack_team_id, slack_name, slack_command_script, git_sync_repo, git_sync_branch, extra_env \
+         FROM workspace_settings WHERE slack_team_id = $1",
         form.team_id,
     )
     .fetch_optional(&mut tx)
@@ -710,3 +1181,312 @@ async fn get_user_info(
     };
     Ok(email)
 }
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::db::{create_test_workspace, delete_test_workspace, test_db};
+
+    async fn insert_account(db: &DB, w_id: &str, path: &str, client: &str) {
+        let mut tx = db.begin().await.unwrap();
+        let mc = build_crypt(&mut tx, w_id).await.unwrap();
+        tx.commit().await.unwrap();
+        sqlx::query(
+            "INSERT INTO variable (workspace_id, path, value, is_secret) VALUES ($1, $2, '', true)",
+        )
+        .bind(w_id)
+        .bind(path)
+        .execute(db)
+        .await
+        .unwrap();
+        sqlx::query(
+            "INSERT INTO account (workspace_id, path, client, refresh_token, expires_at) \
+             VALUES ($1, $2, $3, $4, now())",
+        )
+        .bind(w_id)
+        .bind(path)
+        .bind(client)
+        .bind(variables::encrypt(&mc, "refresh-token".to_string()))
+        .execute(db)
+        .await
+        .unwrap();
+    }
+
+    /// the failures of the account, its last error and whether its next refresh is backed off
+    async fn refresh_state(db: &DB, w_id: &str, path: &str) -> (i32, Option<String>, bool) {
+        sqlx::query_as(
+            "SELECT refresh_failures, refresh_error, COALESCE(retry_at > now(), false) \
+             FROM account WHERE workspace_id = $1 AND path = $2",
+        )
+        .bind(w_id)
+        .bind(path)
+        .fetch_one(db)
+        .await
+        .unwrap()
+    }
+
+    async fn expire_backoff(db: &DB, w_id: &str, path: &str, failures: i32) {
+        sqlx::query(
+            "UPDATE account SET refresh_failures = $1, retry_at = now() \
+             WHERE workspace_id = $2 AND path = $3",
+        )
+        .bind(failures)
+        .bind(w_id)
+        .bind(path)
+        .execute(db)
+        .await
+        .unwrap();
+    }
+
+    /// a provider whose token endpoint answers every request with new tokens
+    fn mock_provider() -> ConnectProvider {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let app = Router::new().route(
+            "/token",
+            post(|| async {
+                Json(serde_json::json!({
+                    "access_token": "new-access-token",
+                    "token_type": "bearer",
+                    "expires_in": 3600,
+                    "refresh_token": "new-refresh-token"
+                }))
+            }),
+        );
+        tokio::spawn(
+            axum::Server::from_tcp(listener)
+                .unwrap()
+                .serve(app.into_make_service()),
+        );
+        ConnectProvider {
+            auth_url: format!("http://{addr}/authorize"),
+            token_url: format!("http://{addr}/token"),
+            scopes: vec![],
+            extra_params: HashMap::new(),
+            client_id: Some("client-id".to_string()),
+            client_secret: Some("client-secret".to_string()),
+            resource_type: None,
+            resource_value: default_resource_value(),
+        }
+    }
+
+    #[test]
+    fn test_refresh_backoff_secs() {
+        assert_eq!(refresh_backoff_secs(1), 60);
+        assert_eq!(refresh_backoff_secs(2), 120);
+        assert_eq!(refresh_backoff_secs(4), 480);
+        assert_eq!(refresh_backoff_secs(MAX_REFRESH_FAILURES), 960);
+        assert_eq!(refresh_backoff_secs(100), 960);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs DATABASE_URL, see test_db"]
+    async fn test_refresh_accounts() {
+        let db = test_db().await;
+        let w_id = create_test_workspace(&db).await;
+        insert_account(&db, &w_id, "g/all/mock_token", "mock").await;
+        let path = "g/all/unknown_token";
+        insert_account(&db, &w_id, path, "unknown").await;
+        let mut providers = builtin_connect_providers();
+        providers.insert("mock".to_string(), mock_provider());
+
+        refresh_expiring_accounts(&db, &providers, "http://localhost").await;
+
+        let mut tx = db.begin().await.unwrap();
+        let mc = build_crypt(&mut tx, &w_id).await.unwrap();
+        tx.commit().await.unwrap();
+        let (access_token, refresh_token, expiring): (String, String, bool) = sqlx::query_as(
+            "SELECT variable.value, account.refresh_token, \
+             account.expires_at <= now() + make_interval(secs => $3) \
+             FROM account JOIN variable USING (workspace_id, path) \
+             WHERE workspace_id = $1 AND path = $2",
+        )
+        .bind(&w_id)
+        .bind("g/all/mock_token")
+        .bind(REFRESH_MARGIN_SECS as f64)
+        .fetch_one(&db)
+        .await
+        .unwrap();
+        assert_eq!(
+            mc.decrypt_base64_to_string(access_token).unwrap(),
+            "new-access-token"
+        );
+        assert_eq!(
+            mc.decrypt_base64_to_string(refresh_token).unwrap(),
+            "new-refresh-token"
+        );
+        assert!(!expiring);
+        assert_eq!(
+            refresh_state(&db, &w_id, "g/all/mock_token").await,
+            (0, None, false)
+        );
+
+        let error = Some("Bad request: unrecognized client unknown".to_string());
+        assert_eq!(
+            refresh_state(&db, &w_id, path).await,
+            (1, error.clone(), true)
+        );
+
+        // not retried before the end of its backoff
+        refresh_expiring_accounts(&db, &providers, "http://localhost").await;
+        assert_eq!(
+            refresh_state(&db, &w_id, path).await,
+            (1, error.clone(), true)
+        );
+
+        expire_backoff(&db, &w_id, path, MAX_REFRESH_FAILURES - 1).await;
+        refresh_expiring_accounts(&db, &providers, "http://localhost").await;
+        assert_eq!(
+            refresh_state(&db, &w_id, path).await,
+            (MAX_REFRESH_FAILURES, error.clone(), true)
+        );
+
+        // nor after its last failure
+        expire_backoff(&db, &w_id, path, MAX_REFRESH_FAILURES).await;
+        refresh_expiring_accounts(&db, &providers, "http://localhost").await;
+        assert_eq!(
+            refresh_state(&db, &w_id, path).await,
+            (MAX_REFRESH_FAILURES, error, false)
+        );
+
+        // an account claimed by another instance is left to it
+        let claimed = "g/all/claimed_token";
+        insert_account(&db, &w_id, claimed, "mock").await;
+        while let Some(account) = claim_expiring_account(&db).await.unwrap() {
+            if account.workspace_id == w_id && account.path == claimed {
+                break;
+            }
+        }
+        refresh_expiring_accounts(&db, &providers, "http://localhost").await;
+        assert_eq!(refresh_state(&db, &w_id, claimed).await, (0, None, true));
+
+        delete_test_workspace(&db, &w_id).await;
+    }
+
+    #[test]
+    fn test_map_token_response() {
+        let response = serde_json::json!({
+            "access_token": "access-token",
+            "token_type": "bearer",
+            "team": { "id": "T1", "name": "windmill" },
+            "scopes": ["read", "write"]
+        });
+        let template = serde_json::json!({
+            "token": "$token",
+            "team": "$response:/team/name",
+            "scopes": ["$response:/scopes/0", "$response:/missing"],
+            "region": "eu",
+            "port": 443
+        });
+        assert_eq!(
+            map_token_response(&template, "g/all/slack_token", &response, &["access-token"])
+                .unwrap(),
+            serde_json::json!({
+                "token": "$var:g/all/slack_token",
+                "team": "windmill",
+                "scopes": ["read", null],
+                "region": "eu",
+                "port": 443
+            })
+        );
+
+        // the tokens are not always under a field named after them, as the bot token of slack
+        let response = serde_json::json!({
+            "bot": { "bot_user_id": "U1", "bot_access_token": "bot-token" },
+            "authed_user": { "id": "U2", "access_token": "user-token" },
+            "incoming_webhook": { "url": "user-token" }
+        });
+        for pointer in [
+            "$response:/bot",
+            "$response:/authed_user",
+            "$response:/incoming_webhook/url",
+        ] {
+            let template = serde_json::json!({ "value": [pointer] });
+            assert!(
+                map_token_response(&template, "g/all/slack_token", &response, &["user-token"])
+                    .is_err(),
+                "{pointer}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_connect_providers() {
+        let providers = parse_connect_providers(
+            r#"{
+                "github": {
+                    "auth_url": "https://github.com/login/oauth/authorize",
+                    "token_url": "https://github.com/login/oauth/access_token",
+                    "scopes": ["repo"],
+                    "resource_value": { "token": "$token", "type": "$response:/token_type" }
+                }
+            }"#,
+        )
+        .unwrap();
+        let mut names = providers.keys().cloned().collect::<Vec<_>>();
+        names.sort();
+        assert_eq!(names, vec!["github".to_string(), "slack".to_string()]);
+        assert_eq!(providers["github"].scopes, vec!["repo".to_string()]);
+
+        for pointer in [
+            "$response:/access_token",
+            "$response:/refresh_token",
+            "$response:/bot/bot_access_token",
+            "$response:",
+        ] {
+            let config = serde_json::json!({
+                "github": {
+                    "auth_url": "https://github.com/login/oauth/authorize",
+                    "token_url": "https://github.com/login/oauth/access_token",
+                    "resource_value": { "auth": [{ "token": pointer }] }
+                }
+            });
+            assert!(
+                parse_connect_providers(&config.to_string()).is_err(),
+                "{pointer}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    #[ignore = "needs DATABASE_URL, see test_db"]
+    async fn test_account_visibility() {
+        let db = test_db().await;
+        let w_id = create_test_workspace(&db).await;
+        insert_account(&db, &w_id, "u/alice/github_token", "github").await;
+        insert_account(&db, &w_id, "u/bob/github_token", "github").await;
+
+        let authed = Authed {
+            email: None,
+            username: "alice".to_string(),
+            is_admin: false,
+            groups: vec![],
+        };
+        let mut tx = UserDB::new(db.clone()).begin(&authed).await.unwrap();
+        assert!(get_account_state(&mut tx, &w_id, "u/alice/github_token")
+            .await
+            .unwrap()
+            .is_some());
+        assert!(get_account_state(&mut tx, &w_id, "u/bob/github_token")
+            .await
+            .unwrap()
+            .is_none());
+        // disconnecting only removes the accounts of the variables visible to the user
+        sqlx::query("DELETE FROM account WHERE workspace_id = $1 AND client = 'github'")
+            .bind(&w_id)
+            .execute(&mut tx)
+            .await
+            .unwrap();
+        tx.commit().await.unwrap();
+        let paths =
+            sqlx::query_scalar::<_, String>("SELECT path FROM account WHERE workspace_id = $1")
+                .bind(&w_id)
+                .fetch_all(&db)
+                .await
+                .unwrap();
+        assert_eq!(paths, vec!["u/bob/github_token".to_string()]);
+
+        delete_test_workspace(&db, &w_id).await;
+    }
+}
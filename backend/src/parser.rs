@@ -15,7 +15,9 @@ use serde_json::json;
 use crate::error;
 
 use rustpython_parser::{
-    ast::{ExpressionType, Located, Number, StatementType, StringGroup, Varargs},
+    ast::{
+        ExpressionType, Located, Number, Operator, Statement, StatementType, StringGroup, Varargs,
+    },
     parser,
 };
 #[derive(Serialize)]
@@ -25,20 +27,29 @@ pub struct MainArgSignature {
     pub args: Vec<Arg>,
 }
 
-#[derive(Serialize, Clone)]
+#[derive(Serialize, Clone, Debug, PartialEq)]
 #[serde(rename_all(serialize = "lowercase"))]
 pub enum Typ {
-    Str,
+    /// the optional list contains the allowed values (Literal or Enum)
+    Str(Option<Vec<String>>),
     Int,
     Float,
     Bool,
     Dict,
-    List,
+    List(Box<Typ>),
     Bytes,
     Datetime,
+    Object(Vec<ObjectProperty>),
+    Optional(Box<Typ>),
     Unknown,
 }
 
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct ObjectProperty {
+    pub key: String,
+    pub typ: Box<Typ>,
+}
+
 #[derive(Serialize, Clone)]
 pub struct Arg {
     pub name: String,
@@ -51,6 +62,7 @@ pub fn parse_python_signature(code: &str) -> error::Result<MainArgSignature> {
     let ast = parser::parse_program(code)
         .map_err(|e| error::Error::ExecutionErr(format!("Error parsing code: {}", e.to_string())))?
         .statements;
+    let classes = python_classes(&ast);
     let param = ast.into_iter().find_map(|x| match x {
         Located {
             location: _,
@@ -84,24 +96,9 @@ pub fn parse_python_signature(code: &str) -> error::Result<MainArgSignature> {
                     };
                     Arg {
                         name: x.arg,
-                        typ: x.annotation.map_or(Typ::Unknown, |e| match *e {
-                            Located {
-                                location: _,
-                                node: ExpressionType::Identifier { name },
-                            } => match name.as_ref() {
-                                "str" => Typ::Str,
-                                "float" => Typ::Float,
-                                "int" => Typ::Int,
-                                "bool" => Typ::Bool,
-                                "dict" => Typ::Dict,
-                                "list" => Typ::List,
-                                "bytes" => Typ::Bytes,
-                                "datetime" => Typ::Datetime,
-                                "datetime.datetime" => Typ::Datetime,
-                                _ => Typ::Unknown,
-                            },
-                            _ => Typ::Unknown,
-                        }),
+                        typ: x
+                            .annotation
+                            .map_or(Typ::Unknown, |e| python_typ(&e.node, &classes)),
                         has_default: default.is_some(),
                         default,
                     }
@@ -115,15 +112,182 @@ pub fn parse_python_signature(code: &str) -> error::Result<MainArgSignature> {
     }
 }
 
+fn python_typ(e: &ExpressionType, classes: &HashMap<String, Typ>) -> Typ {
+    match e {
+        ExpressionType::Identifier { name } | ExpressionType::Attribute { value: _, name } => {
+            python_name_to_typ(name, classes)
+        }
+        // forward references, e.g. `x: "MyDataclass"`
+        ExpressionType::String {
+            value: StringGroup::Constant { value },
+        } => python_name_to_typ(value, classes),
+        ExpressionType::Subscript { a, b } => match python_generic_name(&a.node) {
+            Some("List" | "list" | "Sequence" | "Set" | "set" | "Tuple" | "tuple") => {
+                let inner = match &b.node {
+                    ExpressionType::Tuple { elements } => elements
+                        .first()
+                        .map_or(Typ::Unknown, |x| python_typ(&x.node, classes)),
+                    x => python_typ(x, classes),
+                };
+                Typ::List(Box::new(inner))
+            }
+            Some("Dict" | "dict" | "Mapping") => Typ::Dict,
+            Some("Optional") => Typ::Optional(Box::new(python_typ(&b.node, classes))),
+            Some("Union") => match &b.node {
+                ExpressionType::Tuple { elements } => {
+                    python_union(elements.iter().map(|x| &x.node).collect(), classes)
+                }
+                x => python_typ(x, classes),
+            },
+            Some("Literal") => {
+                let literals = match &b.node {
+                    ExpressionType::Tuple { elements } => elements
+                        .iter()
+                        .map(|x| python_str_literal(&x.node))
+                        .collect(),
+                    x => python_str_literal(x).map(|x| vec![x]),
+                };
+                literals.map_or(Typ::Unknown, |x| Typ::Str(Some(x)))
+            }
+            _ => Typ::Unknown,
+        },
+        // PEP 604 unions, e.g. `int | None`
+        ExpressionType::Binop {
+            a,
+            op: Operator::BitOr,
+            b,
+        } => python_union(vec![&a.node, &b.node], classes),
+        _ => Typ::Unknown,
+    }
+}
+
+fn python_name_to_typ(name: &str, classes: &HashMap<String, Typ>) -> Typ {
+    match name {
+        "str" => Typ::Str(None),
+        "float" => Typ::Float,
+        "int" => Typ::Int,
+        "bool" => Typ::Bool,
+        "dict" | "Dict" => Typ::Dict,
+        "list" | "List" => Typ::List(Box::new(Typ::Unknown)),
+        "bytes" => Typ::Bytes,
+        "datetime" => Typ::Datetime,
+        _ => classes.get(name).cloned().unwrap_or(Typ::Unknown),
+    }
+}
+
+fn python_generic_name(e: &ExpressionType) -> Option<&str> {
+    match e {
+        ExpressionType::Identifier { name } | ExpressionType::Attribute { value: _, name } => {
+            Some(name.as_str())
+        }
+        _ => None,
+    }
+}
+
+fn python_str_literal(e: &ExpressionType) -> Option<String> {
+    match e {
+        ExpressionType::String {
+            value: StringGroup::Constant { value },
+        } => Some(value.clone()),
+        _ => None,
+    }
+}
+
+fn python_union(variants: Vec<&ExpressionType>, classes: &HashMap<String, Typ>) -> Typ {
+    let (nones, others): (Vec<_>, Vec<_>) = variants
+        .into_iter()
+        .partition(|x| matches!(x, ExpressionType::None));
+    let typ = match others.as_slice() {
+        [x] => python_typ(x, classes),
+        _ => Typ::Unknown,
+    };
+    if nones.is_empty() {
+        typ
+    } else {
+        Typ::Optional(Box::new(typ))
+    }
+}
+
+/// collect the top-level Enum subclasses, TypedDicts and dataclasses of a module
+fn python_classes(ast: &[Statement]) -> HashMap<String, Typ> {
+    let mut classes = HashMap::new();
+    for stmt in ast {
+        if let StatementType::ClassDef {
+            name,
+            body,
+            bases,
+            keywords: _,
+            decorator_list,
+        } = &stmt.node
+        {
+            let base_names = bases
+                .iter()
+                .filter_map(|x| python_generic_name(&x.node))
+                .collect::<Vec<_>>();
+            let is_dataclass = decorator_list.iter().any(|x| match &x.node {
+                ExpressionType::Call {
+                    function,
+                    args: _,
+                    keywords: _,
+                } => python_generic_name(&function.node) == Some("dataclass"),
+                x => python_generic_name(x) == Some("dataclass"),
+            });
+            let typ = if base_names
+                .iter()
+                .any(|x| matches!(*x, "Enum" | "IntEnum" | "StrEnum"))
+            {
+                let members = body
+                    .iter()
+                    .filter_map(|x| match &x.node {
+                        StatementType::Assign { targets, value: _ } => match targets.as_slice() {
+                            [Located {
+                                location: _,
+                                node: ExpressionType::Identifier { name },
+                            }] => Some(name.clone()),
+                            _ => None,
+                        },
+                        _ => None,
+                    })
+                    .collect();
+                Typ::Str(Some(members))
+            } else if is_dataclass || base_names.contains(&"TypedDict") {
+                let properties = body
+                    .iter()
+                    .filter_map(|x| match &x.node {
+                        StatementType::AnnAssign {
+                            target,
+                            annotation,
+                            value: _,
+                        } => match &target.node {
+                            ExpressionType::Identifier { name } => Some(ObjectProperty {
+                                key: name.clone(),
+                                typ: Box::new(python_typ(&annotation.node, &classes)),
+                            }),
+                            _ => None,
+                        },
+                        _ => None,
+                    })
+                    .collect();
+                Typ::Object(properties)
+            } else {
+                continue;
+            };
+            classes.insert(name.clone(), typ);
+        }
+    }
+    classes
+}
+
 use swc_common::sync::Lrc;
 use swc_common::{FileName, SourceMap};
 use swc_ecma_ast::{
-    AssignPat, BindingIdent, Decl, ExportDecl, FnDecl, Ident, ModuleDecl, ModuleItem, Pat,
-    TsKeywordTypeKind, TsType,
+    AssignPat, BindingIdent, Decl, ExportDecl, Expr, FnDecl, Ident, Lit, ModuleDecl, ModuleItem,
+    Pat, Stmt, TsEntityName, TsKeywordTypeKind, TsLit, TsType, TsTypeElement,
+    TsUnionOrIntersectionType,
 };
 use swc_ecma_parser::{lexer::Lexer, Parser, StringInput, Syntax, TsConfig};
 
-pub fn parse_deno_signature(code: &str) -> error::Result<MainArgSignature> {
+fn parse_deno_module(code: &str) -> error::Result<Vec<ModuleItem>> {
     let cm: Lrc<SourceMap> = Default::default();
     let fm = cm.new_source_file(FileName::Custom("test.ts".into()), code.into());
     let lexer = Lexer::new(
@@ -148,8 +312,14 @@ pub fn parse_deno_signature(code: &str) -> error::Result<MainArgSignature> {
             error::Error::ExecutionErr(format!("impossible to parse module: {err_s}\n{e:?}"))
         })?
         .body;
+    Ok(ast)
+}
+
+pub fn parse_deno_signature(code: &str) -> error::Result<MainArgSignature> {
+    let ast = parse_deno_module(code)?;
 
     // println!("{ast:?}");
+    let types = deno_type_decls(&ast);
     let params = ast.into_iter().find_map(|x| match x {
         ModuleItem::ModuleDecl(ModuleDecl::ExportDecl(ExportDecl {
             decl:
@@ -175,7 +345,7 @@ pub fn parse_deno_signature(code: &str) -> error::Result<MainArgSignature> {
                 .into_iter()
                 .map(|x| match x.pat {
                     Pat::Ident(ident) => {
-                        let (name, typ) = binding_ident_to_arg(&ident)?;
+                        let (name, typ) = binding_ident_to_arg(&ident, &types)?;
                         Ok(Arg {
                             name,
                             typ,
@@ -189,12 +359,19 @@ pub fn parse_deno_signature(code: &str) -> error::Result<MainArgSignature> {
                         right,
                         type_ann: _,
                     }) => {
-                        let (name, typ) =
-                            left.as_ident().map(binding_ident_to_arg).ok_or_else(|| {
+                        let (name, typ) = left
+                            .as_ident()
+                            .map(|x| binding_ident_to_arg(x, &types))
+                            .ok_or_else(|| {
                                 error::Error::ExecutionErr(format!(
                                     "Arg {left:?} has unexepected syntax"
                                 ))
                             })??;
+                        // a default value already makes the arg optional
+                        let typ = match typ {
+                            Typ::Optional(t) => *t,
+                            t => t,
+                        };
                         Ok(Arg {
                             name,
                             typ,
@@ -223,28 +400,180 @@ pub fn parse_deno_signature(code: &str) -> error::Result<MainArgSignature> {
 
 fn binding_ident_to_arg(
     BindingIdent { id, type_ann }: &BindingIdent,
+    types: &HashMap<String, Typ>,
 ) -> anyhow::Result<(String, Typ)> {
+    let typ = type_ann
+        .as_ref()
+        .map(|x| ts_type_to_typ(&x.type_ann, types))
+        .unwrap_or(Typ::Unknown);
     Ok((
         id.sym.to_string(),
-        type_ann
-            .as_ref()
-            .map(|x| match &*x.type_ann {
-                TsType::TsKeywordType(t) => match t.kind {
-                    TsKeywordTypeKind::TsObjectKeyword => Typ::Dict,
-                    TsKeywordTypeKind::TsBooleanKeyword => Typ::Bool,
-                    TsKeywordTypeKind::TsBigIntKeyword => Typ::Int,
-                    TsKeywordTypeKind::TsNumberKeyword => Typ::Float,
-                    TsKeywordTypeKind::TsStringKeyword => Typ::Str,
-                    _ => Typ::Unknown,
-                },
-                // TODO: we can do better here and extract the inner type of array
-                TsType::TsArrayType(_) => Typ::List,
-                _ => Typ::Unknown,
-            })
-            .unwrap_or(Typ::Unknown),
+        if id.optional {
+            Typ::Optional(Box::new(typ))
+        } else {
+            typ
+        },
     ))
 }
 
+fn ts_type_to_typ(t: &TsType, types: &HashMap<String, Typ>) -> Typ {
+    match t {
+        TsType::TsKeywordType(t) => match t.kind {
+            TsKeywordTypeKind::TsObjectKeyword => Typ::Dict,
+            TsKeywordTypeKind::TsBooleanKeyword => Typ::Bool,
+            TsKeywordTypeKind::TsBigIntKeyword => Typ::Int,
+            TsKeywordTypeKind::TsNumberKeyword => Typ::Float,
+            TsKeywordTypeKind::TsStringKeyword => Typ::Str(None),
+            _ => Typ::Unknown,
+        },
+        TsType::TsArrayType(t) => Typ::List(Box::new(ts_type_to_typ(&t.elem_type, types))),
+        TsType::TsParenthesizedType(t) => ts_type_to_typ(&t.type_ann, types),
+        TsType::TsLitType(t) => match &t.lit {
+            TsLit::Str(s) => Typ::Str(Some(vec![s.value.to_string()])),
+            TsLit::Number(_) => Typ::Float,
+            TsLit::Bool(_) => Typ::Bool,
+            _ => Typ::Unknown,
+        },
+        TsType::TsTypeLit(t) => Typ::Object(ts_members_to_properties(&t.members, types)),
+        TsType::TsUnionOrIntersectionType(TsUnionOrIntersectionType::TsUnionType(u)) => {
+            let (nulls, others): (Vec<_>, Vec<_>) = u.types.iter().partition(|x| {
+                matches!(
+                    &***x,
+                    TsType::TsKeywordType(k) if matches!(
+                        k.kind,
+                        TsKeywordTypeKind::TsNullKeyword | TsKeywordTypeKind::TsUndefinedKeyword
+                    )
+                )
+            });
+            let others = others
+                .into_iter()
+                .map(|x| ts_type_to_typ(x, types))
+                .collect::<Vec<_>>();
+            let typ = match others.as_slice() {
+                [typ] => typ.clone(),
+                // union of string literals, e.g. `"a" | "b"`
+                _ if !others.is_empty()
+                    && others.iter().all(|x| matches!(x, Typ::Str(Some(_)))) =>
+                {
+                    Typ::Str(Some(
+                        others
+                            .into_iter()
+                            .flat_map(|x| match x {
+                                Typ::Str(Some(v)) => v,
+                                _ => vec![],
+                            })
+                            .collect(),
+                    ))
+                }
+                _ => Typ::Unknown,
+            };
+            if nulls.is_empty() {
+                typ
+            } else {
+                Typ::Optional(Box::new(typ))
+            }
+        }
+        TsType::TsTypeRef(t) => match &t.type_name {
+            TsEntityName::Ident(Ident { sym, .. }) => match &*sym.to_string() {
+                "Date" => Typ::Datetime,
+                "Uint8Array" => Typ::Bytes,
+                "Record" => Typ::Dict,
+                "Array" => Typ::List(Box::new(
+                    t.type_params
+                        .as_ref()
+                        .and_then(|x| x.params.first())
+                        .map_or(Typ::Unknown, |x| ts_type_to_typ(x, types)),
+                )),
+                name => types.get(name).cloned().unwrap_or(Typ::Unknown),
+            },
+            _ => Typ::Unknown,
+        },
+        _ => Typ::Unknown,
+    }
+}
+
+fn ts_members_to_properties(
+    members: &[TsTypeElement],
+    types: &HashMap<String, Typ>,
+) -> Vec<ObjectProperty> {
+    members
+        .iter()
+        .filter_map(|x| match x {
+            TsTypeElement::TsPropertySignature(p) => {
+                let key = match &*p.key {
+                    Expr::Ident(Ident { sym, .. }) => sym.to_string(),
+                    Expr::Lit(Lit::Str(s)) => s.value.to_string(),
+                    _ => return None,
+                };
+                let typ = p
+                    .type_ann
+                    .as_ref()
+                    .map_or(Typ::Unknown, |x| ts_type_to_typ(&x.type_ann, types));
+                let typ = if p.optional {
+                    Typ::Optional(Box::new(typ))
+                } else {
+                    typ
+                };
+                Some(ObjectProperty {
+                    key,
+                    typ: Box::new(typ),
+                })
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+/// workspace paths of the libraries imported by a deno script, e.g. `import { x } from "/u/alice/lib.ts"`
+pub fn parse_deno_library_imports(code: &str) -> error::Result<Vec<String>> {
+    let re = Regex::new(r"^/([ugf](/[\w-]+){2,})\.ts$").unwrap();
+    let imports = parse_deno_module(code)?
+        .into_iter()
+        .filter_map(|x| match x {
+            ModuleItem::ModuleDecl(ModuleDecl::Import(import)) => {
+                Some(import.src.value.to_string())
+            }
+            ModuleItem::ModuleDecl(ModuleDecl::ExportAll(export)) => {
+                Some(export.src.value.to_string())
+            }
+            ModuleItem::ModuleDecl(ModuleDecl::ExportNamed(export)) => {
+                export.src.as_ref().map(|src| src.value.to_string())
+            }
+            _ => None,
+        })
+        .filter_map(|x| {
+            re.captures(&x)
+                .map(|x| x.get(1).unwrap().as_str().to_string())
+        })
+        .unique()
+        .collect();
+    Ok(imports)
+}
+
+/// collect the interfaces and type aliases declared at the top-level of a module
+fn deno_type_decls(ast: &[ModuleItem]) -> HashMap<String, Typ> {
+    let mut types = HashMap::new();
+    for item in ast {
+        let decl = match item {
+            ModuleItem::Stmt(Stmt::Decl(decl)) => decl,
+            ModuleItem::ModuleDecl(ModuleDecl::ExportDecl(ExportDecl { decl, .. })) => decl,
+            _ => continue,
+        };
+        match decl {
+            Decl::TsInterface(i) => {
+                let typ = Typ::Object(ts_members_to_properties(&i.body.body, &types));
+                types.insert(i.id.sym.to_string(), typ);
+            }
+            Decl::TsTypeAlias(a) => {
+                let typ = ts_type_to_typ(&a.type_ann, &types);
+                types.insert(a.id.sym.to_string(), typ);
+            }
+            _ => (),
+        }
+    }
+    types
+}
+
 const STDIMPORTS: [&str; 301] = [
     "__future__",
     "_abc",
@@ -643,13 +972,62 @@ pub fn parse_python_imports(code: &str) -> error::Result<Vec<String>> {
                 },
             })
             .flatten()
-            .filter(|x| !STDIMPORTS.contains(&x.as_str()))
+            .filter(|x| !STDIMPORTS.contains(&x.as_str()) && !is_library_root(x))
             .unique()
             .collect();
         Ok(imports)
     }
 }
 
+/// libraries are imported through their workspace path, starting with `u`, `g` or `f`
+fn is_library_root(module: &str) -> bool {
+    module == "u" || module == "g" || module == "f"
+}
+
+/// workspace paths of the libraries imported by a python script. The module of an import is the
+/// path of the library, e.g. `import u.alice.lib` or `from u.alice.lib import x`, except when it
+/// is only a user, group or folder, in which case the imported names are the libraries, e.g.
+/// `from u.alice import lib`
+pub fn parse_python_library_imports(code: &str) -> error::Result<Vec<String>> {
+    let ast = parser::parse_program(code)
+        .map_err(|e| error::Error::ExecutionErr(format!("Error parsing code: {e}")))?
+        .statements;
+    let modules = ast
+        .into_iter()
+        .filter_map(|x| match x.node {
+            StatementType::Import { names } => {
+                Some(names.into_iter().map(|x| x.symbol).collect::<Vec<_>>())
+            }
+            StatementType::ImportFrom {
+                level: 0,
+                module: Some(mod_),
+                names,
+            } => {
+                if mod_.split('.').count() == 2 {
+                    Some(
+                        names
+                            .into_iter()
+                            .filter(|x| x.symbol != "*")
+                            .map(|x| format!("{mod_}.{}", x.symbol))
+                            .collect(),
+                    )
+                } else {
+                    Some(vec![mod_])
+                }
+            }
+            _ => None,
+        })
+        .flatten()
+        .filter(|x| x.split('.').count() >= 3);
+
+    let imports = modules
+        .filter(|x| is_library_root(x.split('.').next().unwrap_or_default()))
+        .map(|x| x.replace('.', "/"))
+        .unique()
+        .collect();
+    Ok(imports)
+}
+
 #[cfg(test)]
 mod tests {
 
@@ -675,6 +1053,57 @@ def main(test1: str, name: datetime.datetime = datetime.now(), byte: bytes = byt
         Ok(())
     }
 
+    #[test]
+    fn test_parse_python_sig_rich_types() -> anyhow::Result<()> {
+        let code = "
+from enum import Enum
+from typing import List, Literal, Optional, TypedDict
+from dataclasses import dataclass
+
+class Color(Enum):
+    RED = 'red'
+    BLUE = 'blue'
+
+class Person(TypedDict):
+    name: str
+    age: Optional[int]
+
+@dataclass
+class Point:
+    x: float
+    y: float
+
+def main(a: List[str], b: Optional[int], c: Literal['x', 'y'], d: Color, e: Person, f: Point = None):
+    pass
+";
+        let args = parse_python_signature(code)?.args;
+        assert_eq!(args[0].typ, Typ::List(Box::new(Typ::Str(None))));
+        assert_eq!(args[1].typ, Typ::Optional(Box::new(Typ::Int)));
+        assert_eq!(
+            args[2].typ,
+            Typ::Str(Some(vec!["x".to_string(), "y".to_string()]))
+        );
+        assert_eq!(
+            args[3].typ,
+            Typ::Str(Some(vec!["RED".to_string(), "BLUE".to_string()]))
+        );
+        assert_eq!(
+            args[4].typ,
+            Typ::Object(vec![
+                ObjectProperty {
+                    key: "name".to_string(),
+                    typ: Box::new(Typ::Str(None))
+                },
+                ObjectProperty {
+                    key: "age".to_string(),
+                    typ: Box::new(Typ::Optional(Box::new(Typ::Int)))
+                },
+            ])
+        );
+        assert!(args[5].has_default);
+        Ok(())
+    }
+
     #[test]
     fn test_parse_python_imports() -> anyhow::Result<()> {
         //let code = "print(2 + 3, fd=sys.stderr)";
@@ -720,6 +1149,97 @@ def main():
         Ok(())
     }
 
+    #[test]
+    fn test_parse_python_library_imports() -> anyhow::Result<()> {
+        let code = "
+
+import wmill
+import u.alice.lib
+from g.all.shared import utils, format
+from g.all import shared, other
+from u.bob.helpers import x
+from f.shared.utils import y
+from f.team import lib
+
+def main():
+    pass
+
+";
+        assert_eq!(parse_python_imports(code)?, vec!["wmill"]);
+        assert_eq!(
+            parse_python_library_imports(code)?,
+            vec![
+                "u/alice/lib",
+                "g/all/shared",
+                "g/all/other",
+                "u/bob/helpers",
+                "f/shared/utils",
+                "f/team/lib"
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_deno_sig_rich_types() -> anyhow::Result<()> {
+        let code = "
+interface Person {
+    name: string
+    age?: number
+}
+
+type Color = \"red\" | \"blue\"
+
+export function main(a: Color, b: Person[], c: Date, d?: string, e: string[] | null, f: number = 3) {
+}
+";
+        let args = parse_deno_signature(code)?.args;
+        assert_eq!(
+            args[0].typ,
+            Typ::Str(Some(vec!["red".to_string(), "blue".to_string()]))
+        );
+        assert_eq!(
+            args[1].typ,
+            Typ::List(Box::new(Typ::Object(vec![
+                ObjectProperty {
+                    key: "name".to_string(),
+                    typ: Box::new(Typ::Str(None))
+                },
+                ObjectProperty {
+                    key: "age".to_string(),
+                    typ: Box::new(Typ::Optional(Box::new(Typ::Float)))
+                },
+            ])))
+        );
+        assert_eq!(args[2].typ, Typ::Datetime);
+        assert_eq!(args[3].typ, Typ::Optional(Box::new(Typ::Str(None))));
+        assert_eq!(
+            args[4].typ,
+            Typ::Optional(Box::new(Typ::List(Box::new(Typ::Str(None)))))
+        );
+        assert_eq!(args[5].typ, Typ::Float);
+        assert!(args[5].has_default);
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_deno_library_imports() -> anyhow::Result<()> {
+        let code = "
+import { x } from \"/u/alice/lib.ts\";
+import * as y from \"https://deno.land/x/y/mod.ts\";
+export { z } from \"/g/all/shared.ts\";
+import { w } from \"/f/team/lib.ts\";
+
+export function main() {
+}
+";
+        assert_eq!(
+            parse_deno_library_imports(code)?,
+            vec!["u/alice/lib", "g/all/shared", "f/team/lib"]
+        );
+        Ok(())
+    }
+
     #[test]
     fn test_parse_deno_sig() -> anyhow::Result<()> {
         let code = "
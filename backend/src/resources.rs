@@ -9,8 +9,14 @@ use crate::{
     audit::{audit_log, ActionKind},
     db::{UserDB, DB},
     error::{Error, JsonResult, Result},
-    users::Authed,
+    git_sync,
+    jobs::{push, JobPayload, RawCode},
+    json_schema::{self, FieldError},
+    oauth2::{get_account_state, AccountState},
+    scripts::ScriptLang,
+    users::{owner_to_token_owner, Authed},
     utils::{require_admin, Pagination, StripPath},
+    variables::{build_crypt, build_crypt_for_write, encrypt},
 };
 use axum::{
     extract::{Extension, Path, Query},
@@ -18,9 +24,18 @@ use axum::{
     Json, Router,
 };
 use hyper::StatusCode;
+use magic_crypt::{MagicCrypt256, MagicCryptTrait};
 use serde::{Deserialize, Serialize};
 use sql_builder::{bind::Bind, SqlBuilder};
-use sqlx::FromRow;
+use sqlx::{FromRow, Postgres, Transaction};
+
+/// prefix of the secret fields of a resource value, encrypted with the key of the workspace
+pub const ENCRYPTED_PREFIX: &str = "$encrypted:";
+
+const POSTGRES_PROBE: &str = include_str!("../probes/postgres.ts");
+const SMTP_PROBE: &str = include_str!("../probes/smtp.ts");
+const HTTP_PROBE: &str = include_str!("../probes/http.ts");
+const S3_PROBE: &str = include_str!("../probes/s3.ts");
 
 pub fn workspaced_service() -> Router {
     Router::new()
@@ -30,11 +45,16 @@ pub fn workspaced_service() -> Router {
         .route("/update/*path", post(update_resource))
         .route("/delete/*path", delete(delete_resource))
         .route("/create", post(create_resource))
+        .route("/test_connection", post(test_connection))
+        .route("/history/p/*path", get(get_resource_history))
+        .route("/get/v/:version", get(get_resource_version))
+        .route("/restore/v/:version", post(restore_resource))
         .route("/type/list", get(list_resource_types))
         .route("/type/listnames", get(list_resource_types_names))
         .route("/type/get/:name", get(get_resource_type))
         .route("/type/update/:name", post(update_resource_type))
         .route("/type/delete/:name", delete(delete_resource_type))
+        .route("/type/validate/:name", get(validate_resources_of_type))
         .route("/type/create", post(create_resource_type))
 }
 
@@ -69,6 +89,34 @@ pub struct Resource {
     pub extra_perms: serde_json::Value,
 }
 
+#[derive(FromRow, Serialize)]
+pub struct ResourceVersion {
+    pub id: i64,
+    pub workspace_id: String,
+    pub path: String,
+    pub value: Option<serde_json::Value>,
+    pub description: Option<String>,
+    pub resource_type: String,
+    pub created_by: String,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(FromRow, Serialize)]
+pub struct ResourceHistory {
+    pub id: i64,
+    pub created_by: String,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+}
+
+/// a resource along with the state of the OAuth account it holds the token of, if any
+#[derive(Serialize)]
+pub struct ResourceWithAccount {
+    #[serde(flatten)]
+    pub resource: Resource,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub account: Option<AccountState>,
+}
+
 #[derive(Deserialize)]
 pub struct CreateResource {
     pub path: String,
@@ -83,6 +131,18 @@ struct EditResource {
     value: Option<serde_json::Value>,
 }
 
+#[derive(Serialize)]
+pub struct ResourceValidation {
+    pub path: String,
+    pub errors: Vec<FieldError>,
+}
+
+#[derive(Deserialize)]
+struct TestConnection {
+    resource_type: String,
+    value: serde_json::Value,
+}
+
 #[derive(Deserialize)]
 pub struct ListResourceQuery {
     resource_type: Option<String>,
@@ -125,11 +185,17 @@ async fn list_resources(
     Ok(Json(rows))
 }
 
+#[derive(Deserialize)]
+struct GetResourceQuery {
+    decrypt_secret: Option<bool>,
+}
+
 async fn get_resource(
     authed: Authed,
     Extension(user_db): Extension<UserDB>,
+    Query(q): Query<GetResourceQuery>,
     Path((w_id, path)): Path<(String, StripPath)>,
-) -> JsonResult<Resource> {
+) -> JsonResult<ResourceWithAccount> {
     let path = path.to_path();
     let mut tx = user_db.begin(&authed).await?;
 
@@ -141,10 +207,24 @@ async fn get_resource(
     )
     .fetch_optional(&mut tx)
     .await?;
+
+    let mut resource = crate::utils::not_found_if_none(resource_o, "Resource", path)?;
+    if let Some(value) = &mut resource.value {
+        decrypt_resource_value(
+            &mut tx,
+            &authed.username,
+            &resource.workspace_id,
+            path,
+            value,
+            q.decrypt_secret.unwrap_or(true),
+            None,
+        )
+        .await?;
+    }
+    let account = get_account_state(&mut tx, &resource.workspace_id, path).await?;
     tx.commit().await?;
 
-    let resource = crate::utils::not_found_if_none(resource_o, "Resource", path)?;
-    Ok(Json(resource))
+    Ok(Json(ResourceWithAccount { resource, account }))
 }
 
 async fn get_resource_value(
@@ -155,16 +235,30 @@ async fn get_resource_value(
     let path = path.to_path();
     let mut tx = user_db.begin(&authed).await?;
 
-    let value_o = sqlx::query_scalar!(
-        "SELECT value from resource WHERE path = $1 AND (workspace_id = $2 OR workspace_id = 'starter')",
+    let resource_o = sqlx::query!(
+        "SELECT workspace_id, value from resource WHERE path = $1 AND (workspace_id = $2 OR workspace_id = 'starter')",
         path.to_owned(),
         &w_id
     )
     .fetch_optional(&mut tx)
     .await?;
+
+    let resource = crate::utils::not_found_if_none(resource_o, "Resource", path)?;
+    let mut value = resource.value;
+    if let Some(value) = &mut value {
+        decrypt_resource_value(
+            &mut tx,
+            &authed.username,
+            &resource.workspace_id,
+            path,
+            value,
+            true,
+            None,
+        )
+        .await?;
+    }
     tx.commit().await?;
 
-    let value = crate::utils::not_found_if_none(value_o, "Resource", path)?;
     Ok(Json(value))
 }
 
@@ -172,9 +266,17 @@ async fn create_resource(
     authed: Authed,
     Extension(user_db): Extension<UserDB>,
     Path(w_id): Path<String>,
-    Json(resource): Json<CreateResource>,
+    Json(mut resource): Json<CreateResource>,
 ) -> Result<(StatusCode, String)> {
     let mut tx = user_db.begin(&authed).await?;
+    prepare_resource_value(
+        &mut tx,
+        &w_id,
+        &resource.resource_type,
+        &resource.path,
+        &mut resource.value,
+    )
+    .await?;
 
     sqlx::query!(
         "INSERT INTO resource
@@ -188,6 +290,7 @@ async fn create_resource(
     )
     .execute(&mut tx)
     .await?;
+    insert_resource_version(&mut tx, &w_id, &resource.path, &authed.username).await?;
     audit_log(
         &mut tx,
         &authed.username,
@@ -198,7 +301,9 @@ async fn create_resource(
         None,
     )
     .await?;
+    let git_sync = git_sync::resource_commit(&mut tx, &w_id, &resource.path, None).await?;
     tx.commit().await?;
+    git_sync::spawn_commit(git_sync, &authed);
 
     Ok((
         StatusCode::CREATED,
@@ -231,7 +336,15 @@ async fn delete_resource(
         None,
     )
     .await?;
+    let git_sync = git_sync::removal_commit(
+        &mut tx,
+        &w_id,
+        format!("Delete resource {path}"),
+        vec![format!("resources/{path}.json")],
+    )
+    .await?;
     tx.commit().await?;
+    git_sync::spawn_commit(git_sync, &authed);
 
     Ok(format!("resource {} deleted", path))
 }
@@ -253,16 +366,31 @@ async fn update_resource(
     if let Some(npath) = &ns.path {
         sqlb.set_str("path", npath);
     }
-    if let Some(nvalue) = ns.value {
-        sqlb.set_str("value", nvalue.to_string());
-    }
     if let Some(ndesc) = ns.description {
         sqlb.set_str("description", ndesc);
     }
     let mut tx = user_db.begin(&authed).await?;
 
+    let mut nvalue = ns.value;
+    if nvalue.is_some() {
+        let resource_type = sqlx::query_scalar!(
+            "SELECT resource_type FROM resource WHERE path = $1 AND workspace_id = $2",
+            path,
+            &w_id
+        )
+        .fetch_optional(&mut tx)
+        .await?;
+        let resource_type = crate::utils::not_found_if_none(resource_type, "Resource", path)?;
+        prepare_resource_value(&mut tx, &w_id, &resource_type, path, &mut nvalue).await?;
+    }
+    if let Some(nvalue) = &nvalue {
+        sqlb.set_str("value", nvalue.to_string());
+    }
+
     let sql = sqlb.sql().map_err(|e| Error::InternalErr(e.to_string()))?;
     sqlx::query(&sql).execute(&mut tx).await?;
+    let npath = ns.path.as_deref().unwrap_or(path);
+    insert_resource_version(&mut tx, &w_id, npath, &authed.username).await?;
     audit_log(
         &mut tx,
         &authed.username,
@@ -273,7 +401,9 @@ async fn update_resource(
         None,
     )
     .await?;
+    let git_sync = git_sync::resource_commit(&mut tx, &w_id, npath, Some(path)).await?;
     tx.commit().await?;
+    git_sync::spawn_commit(git_sync, &authed);
 
     Ok(format!("resource {} updated (npath: {:?})", path, ns.path))
 }
@@ -384,7 +514,15 @@ async fn delete_resource_type(
         None,
     )
     .await?;
+    let git_sync = git_sync::removal_commit(
+        &mut tx,
+        &w_id,
+        format!("Delete resource type {name}"),
+        vec![format!("resource_types/{name}.json")],
+    )
+    .await?;
     tx.commit().await?;
+    git_sync::spawn_commit(git_sync, &authed);
 
     Ok(format!("resource_type {} deleted", name))
 }
@@ -400,7 +538,7 @@ async fn update_resource_type(
     let mut sqlb = SqlBuilder::update_table("resource_type");
     sqlb.and_where_eq("name", "?".bind(&name));
     sqlb.and_where_eq("workspace_id", "?".bind(&w_id));
-    if let Some(nschema) = ns.schema {
+    if let Some(nschema) = &ns.schema {
         sqlb.set_str("schema", nschema);
     }
     if let Some(ndesc) = ns.description {
@@ -410,6 +548,9 @@ async fn update_resource_type(
     let mut tx = user_db.begin(&authed).await?;
 
     sqlx::query(&sql).execute(&mut tx).await?;
+    if let Some(nschema) = ns.schema.as_ref().filter(|x| has_secret_fields(x)) {
+        encrypt_resources_of_type(&mut tx, &w_id, &name, nschema).await?;
+    }
     audit_log(
         &mut tx,
         &authed.username,
@@ -424,3 +565,741 @@ async fn update_resource_type(
 
     Ok(format!("resource_type {} updated", name))
 }
+
+/// check that a resource value, saved or not, can actually connect to its service. The probe of the
+/// resource type runs as a preview job on a worker, with the same sandboxing as any script, and
+/// its `$var:` and `$res:` references are resolved the same way. The job fails with the error of
+/// the connection if it cannot be established
+async fn test_connection(
+    authed: Authed,
+    Extension(user_db): Extension<UserDB>,
+    Path(w_id): Path<String>,
+    Json(tc): Json<TestConnection>,
+) -> Result<(StatusCode, String)> {
+    let probe = match tc.resource_type.as_str() {
+        "postgres" | "postgresql" => POSTGRES_PROBE,
+        "smtp" => SMTP_PROBE,
+        "http" => HTTP_PROBE,
+        "s3" => S3_PROBE,
+        _ => {
+            return Err(Error::BadRequest(format!(
+                "no connection test for resource type {}, expected one of postgres, smtp, http, s3",
+                tc.resource_type
+            )))
+        }
+    };
+    let mut args = serde_json::Map::new();
+    args.insert("value".to_string(), tc.value);
+
+    let tx = user_db.begin(&authed).await?;
+    let (uuid, tx) = push(
+        tx,
+        &w_id,
+        JobPayload::Code(RawCode {
+            content: probe.to_string(),
+            path: Some(format!("test_connection/{}", tc.resource_type)),
+            language: ScriptLang::Deno,
+            timeout: None,
+            memory_limit: None,
+            cpu_limit: None,
+        }),
+        Some(args),
+        &authed.username,
+        owner_to_token_owner(&authed.username, false),
+        None,
+        None,
+        None,
+        false,
+    )
+    .await?;
+    tx.commit().await?;
+
+    Ok((StatusCode::CREATED, uuid.to_string()))
+}
+
+/// schema of a resource type of the workspace or of the starter workspace, `None` if the resource
+/// type does not exist
+async fn get_resource_type_schema<'c>(
+    tx: &mut Transaction<'c, Postgres>,
+    w_id: &str,
+    name: &str,
+) -> Result<Option<Option<serde_json::Value>>> {
+    let schema = sqlx::query_scalar!(
+        "SELECT schema FROM resource_type WHERE name = $1 AND (workspace_id = $2 OR workspace_id = 'starter') \
+         ORDER BY workspace_id = 'starter' LIMIT 1",
+        name,
+        w_id
+    )
+    .fetch_optional(tx)
+    .await?;
+    Ok(schema)
+}
+
+/// reject a resource value that does not match the schema of its resource type, then encrypt the
+/// fields the schema marks as secret. Resource types without a schema accept any value
+pub async fn prepare_resource_value<'c>(
+    tx: &mut Transaction<'c, Postgres>,
+    w_id: &str,
+    resource_type: &str,
+    path: &str,
+    value: &mut Option<serde_json::Value>,
+) -> Result<()> {
+    let schema = get_resource_type_schema(tx, w_id, resource_type).await?;
+    let (schema, value) = match (schema.flatten(), value) {
+        (Some(schema), Some(value)) => (schema, value),
+        _ => return Ok(()),
+    };
+    let errors = json_schema::validate(&schema, value, &is_encrypted_secret);
+    if !errors.is_empty() {
+        return Err(Error::BadRequest(format!(
+            "invalid value for resource {path} of type {resource_type}:\n{}",
+            json_schema::format_errors(&errors)
+        )));
+    }
+    if has_secret_fields(&schema) {
+        let mc = build_crypt_for_write(tx, w_id).await?;
+        encrypt_secret_fields(&mc, &schema, value)?;
+    }
+    Ok(())
+}
+
+fn is_secret_field(property: &serde_json::Value) -> bool {
+    property
+        .get("secret")
+        .and_then(|x| x.as_bool())
+        .unwrap_or(false)
+}
+
+/// encrypted values of secret fields cannot be validated against the schema, the values of the
+/// other fields are validated even if they look encrypted
+fn is_encrypted_secret(property: &serde_json::Value, value: &str) -> bool {
+    is_secret_field(property) && value.starts_with(ENCRYPTED_PREFIX)
+}
+
+fn has_secret_fields(schema: &serde_json::Value) -> bool {
+    schema
+        .get("properties")
+        .and_then(|x| x.as_object())
+        .map(|properties| {
+            properties
+                .values()
+                .any(|x| is_secret_field(x) || has_secret_fields(x))
+        })
+        .unwrap_or(false)
+}
+
+/// encrypt in place the fields of `value` marked as secret in `schema`. References to variables
+/// and resources, as well as the fields already encrypted, are left as is. The fields already
+/// encrypted must be so with the key of the workspace, they would not decrypt otherwise
+fn encrypt_secret_fields(
+    mc: &MagicCrypt256,
+    schema: &serde_json::Value,
+    value: &mut serde_json::Value,
+) -> Result<()> {
+    let (properties, obj) = match (
+        schema.get("properties").and_then(|x| x.as_object()),
+        value.as_object_mut(),
+    ) {
+        (Some(properties), Some(obj)) => (properties, obj),
+        _ => return Ok(()),
+    };
+    for (key, field) in obj.iter_mut() {
+        let property = match properties.get(key) {
+            Some(property) => property,
+            None => continue,
+        };
+        if !is_secret_field(property) {
+            encrypt_secret_fields(mc, property, field)?;
+            continue;
+        }
+        if let Some(encrypted) = field
+            .as_str()
+            .and_then(|x| x.strip_prefix(ENCRYPTED_PREFIX))
+        {
+            mc.decrypt_base64_to_string(encrypted).map_err(|_| {
+                Error::BadRequest(format!(
+                    "{key} is not encrypted with the key of the workspace"
+                ))
+            })?;
+        }
+        let is_reference = field
+            .as_str()
+            .map(|x| {
+                x.starts_with("$var:") || x.starts_with("$res:") || x.starts_with(ENCRYPTED_PREFIX)
+            })
+            .unwrap_or(false);
+        if !field.is_null() && !is_reference {
+            *field = serde_json::Value::String(format!(
+                "{ENCRYPTED_PREFIX}{}",
+                encrypt(mc, field.to_string())
+            ));
+        }
+    }
+    Ok(())
+}
+
+pub fn has_encrypted_fields(value: &serde_json::Value) -> bool {
+    match value {
+        serde_json::Value::String(s) => s.starts_with(ENCRYPTED_PREFIX),
+        serde_json::Value::Object(obj) => obj.values().any(has_encrypted_fields),
+        serde_json::Value::Array(items) => items.iter().any(has_encrypted_fields),
+        _ => false,
+    }
+}
+
+/// replace in place every encrypted field of a resource value by its decrypted value, or by null
+/// without a key
+pub fn map_encrypted_fields(
+    mc: Option<&MagicCrypt256>,
+    value: &mut serde_json::Value,
+) -> Result<()> {
+    match value {
+        serde_json::Value::String(s) if s.starts_with(ENCRYPTED_PREFIX) => {
+            *value = match mc {
+                Some(mc) => {
+                    let decrypted = mc
+                        .decrypt_base64_to_string(&s[ENCRYPTED_PREFIX.len()..])
+                        .map_err(|e| {
+                            Error::InternalErr(format!("could not decrypt a secret field: {e}"))
+                        })?;
+                    serde_json::from_str(&decrypted).map_err(|e| {
+                        Error::InternalErr(format!("invalid decrypted secret field: {e}"))
+                    })?
+                }
+                None => serde_json::Value::Null,
+            };
+        }
+        serde_json::Value::Object(obj) => {
+            for field in obj.values_mut() {
+                map_encrypted_fields(mc, field)?;
+            }
+        }
+        serde_json::Value::Array(items) => {
+            for item in items {
+                map_encrypted_fields(mc, item)?;
+            }
+        }
+        _ => (),
+    }
+    Ok(())
+}
+
+/// put back in place the encrypted fields of `existing` that an export redacted to null in `value`
+pub fn keep_redacted_fields(existing: &serde_json::Value, value: &mut serde_json::Value) {
+    match (existing, value) {
+        (serde_json::Value::String(s), value @ serde_json::Value::Null)
+            if s.starts_with(ENCRYPTED_PREFIX) =>
+        {
+            *value = existing.clone();
+        }
+        (serde_json::Value::Object(existing), serde_json::Value::Object(obj)) => {
+            for (key, field) in obj.iter_mut() {
+                if let Some(existing) = existing.get(key) {
+                    keep_redacted_fields(existing, field);
+                }
+            }
+        }
+        (serde_json::Value::Array(existing), serde_json::Value::Array(items)) => {
+            for (existing, item) in existing.iter().zip(items.iter_mut()) {
+                keep_redacted_fields(existing, item);
+            }
+        }
+        _ => (),
+    }
+}
+
+/// re-encrypt in place with the key of `new` every encrypted field of a resource value
+pub fn reencrypt_fields(
+    path: &str,
+    old: &MagicCrypt256,
+    new: &MagicCrypt256,
+    value: &mut serde_json::Value,
+) -> Result<()> {
+    match value {
+        serde_json::Value::String(s) if s.starts_with(ENCRYPTED_PREFIX) => {
+            let decrypted = old
+                .decrypt_base64_to_string(&s[ENCRYPTED_PREFIX.len()..])
+                .map_err(|e| {
+                    Error::InternalErr(format!(
+                        "could not decrypt a secret field of resource {path}: {e}"
+                    ))
+                })?;
+            *s = format!("{ENCRYPTED_PREFIX}{}", encrypt(new, decrypted));
+        }
+        serde_json::Value::Object(obj) => {
+            for field in obj.values_mut() {
+                reencrypt_fields(path, old, new, field)?;
+            }
+        }
+        serde_json::Value::Array(items) => {
+            for item in items {
+                reencrypt_fields(path, old, new, item)?;
+            }
+        }
+        _ => (),
+    }
+    Ok(())
+}
+
+/// re-encrypt with the key of `new` the secret fields of every resource of a workspace encrypted
+/// with the key of `old`. Returns the number of resources re-encrypted
+pub async fn reencrypt_resources<'c>(
+    tx: &mut Transaction<'c, Postgres>,
+    w_id: &str,
+    old: &MagicCrypt256,
+    new: &MagicCrypt256,
+) -> Result<usize> {
+    let resources = sqlx::query!(
+        "SELECT path, value as \"value!\" FROM resource WHERE workspace_id = $1 \
+         AND value IS NOT NULL FOR UPDATE",
+        w_id
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+    let mut count = 0;
+    for mut resource in resources {
+        if !has_encrypted_fields(&resource.value) {
+            continue;
+        }
+        reencrypt_fields(&resource.path, old, new, &mut resource.value)?;
+        sqlx::query!(
+            "UPDATE resource SET value = $1 WHERE path = $2 AND workspace_id = $3",
+            resource.value,
+            resource.path,
+            w_id
+        )
+        .execute(&mut *tx)
+        .await?;
+        count += 1;
+    }
+
+    let versions = sqlx::query!(
+        "SELECT id, path, value as \"value!\" FROM resource_version WHERE workspace_id = $1 \
+         AND value IS NOT NULL FOR UPDATE",
+        w_id
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+    for mut version in versions {
+        if !has_encrypted_fields(&version.value) {
+            continue;
+        }
+        reencrypt_fields(&version.path, old, new, &mut version.value)?;
+        sqlx::query!(
+            "UPDATE resource_version SET value = $1 WHERE id = $2",
+            version.value,
+            version.id
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+    Ok(count)
+}
+
+/// every create, update or restore of a resource is kept as a version. Secret fields stay encrypted
+pub async fn insert_resource_version<'c>(
+    tx: &mut Transaction<'c, Postgres>,
+    w_id: &str,
+    path: &str,
+    username: &str,
+) -> Result<()> {
+    sqlx::query!(
+        "INSERT INTO resource_version (workspace_id, path, value, description, resource_type, \
+         created_by) SELECT workspace_id, path, value, description, resource_type, $3 \
+         FROM resource WHERE workspace_id = $1 AND path = $2",
+        w_id,
+        path,
+        username
+    )
+    .execute(tx)
+    .await?;
+    Ok(())
+}
+
+async fn get_resource_history(
+    authed: Authed,
+    Extension(user_db): Extension<UserDB>,
+    Path((w_id, path)): Path<(String, StripPath)>,
+) -> JsonResult<Vec<ResourceHistory>> {
+    let path = path.to_path();
+    let mut tx = user_db.begin(&authed).await?;
+    let history = sqlx::query_as!(
+        ResourceHistory,
+        "SELECT id, created_by, created_at FROM resource_version \
+         WHERE path = $1 AND workspace_id = $2 ORDER BY id DESC",
+        path,
+        &w_id
+    )
+    .fetch_all(&mut tx)
+    .await?;
+    tx.commit().await?;
+
+    if history.is_empty() {
+        return Err(Error::NotFound(format!(
+            "Resource not found at name {}",
+            path
+        )));
+    }
+    Ok(Json(history))
+}
+
+async fn get_resource_version_internal<'c>(
+    tx: &mut Transaction<'c, Postgres>,
+    w_id: &str,
+    version: i64,
+) -> Result<ResourceVersion> {
+    let version_o = sqlx::query_as!(
+        ResourceVersion,
+        "SELECT * FROM resource_version WHERE id = $1 AND workspace_id = $2",
+        version,
+        w_id
+    )
+    .fetch_optional(tx)
+    .await?;
+    crate::utils::not_found_if_none(version_o, "ResourceVersion", version.to_string())
+}
+
+/// the secret fields of versions are redacted, restoring the version is the way to read them
+async fn get_resource_version(
+    authed: Authed,
+    Extension(user_db): Extension<UserDB>,
+    Path((w_id, version)): Path<(String, i64)>,
+) -> JsonResult<ResourceVersion> {
+    let mut tx = user_db.begin(&authed).await?;
+    let mut resource_version = get_resource_version_internal(&mut tx, &w_id, version).await?;
+    tx.commit().await?;
+    if let Some(value) = &mut resource_version.value {
+        map_encrypted_fields(None, value)?;
+    }
+    Ok(Json(resource_version))
+}
+
+/// overwrite a resource with one of its versions. Like any update, it requires write access to the
+/// resource
+async fn restore_resource(
+    authed: Authed,
+    Extension(user_db): Extension<UserDB>,
+    Path((w_id, version)): Path<(String, i64)>,
+) -> Result<String> {
+    let mut tx = user_db.begin(&authed).await?;
+    let resource_version = get_resource_version_internal(&mut tx, &w_id, version).await?;
+    let path = resource_version.path;
+
+    let resource = sqlx::query_scalar!(
+        "UPDATE resource SET value = $1, description = $2, resource_type = $3 \
+         WHERE path = $4 AND workspace_id = $5 RETURNING path",
+        resource_version.value,
+        resource_version.description,
+        &resource_version.resource_type,
+        &path,
+        &w_id
+    )
+    .fetch_optional(&mut tx)
+    .await?;
+    crate::utils::not_found_if_none(resource, "Resource", &path)?;
+    insert_resource_version(&mut tx, &w_id, &path, &authed.username).await?;
+
+    audit_log(
+        &mut tx,
+        &authed.username,
+        "resources.restore",
+        ActionKind::Update,
+        &w_id,
+        Some(&path),
+        Some([("version", version.to_string().as_str())].into()),
+    )
+    .await?;
+    let git_sync = git_sync::resource_commit(&mut tx, &w_id, &path, None).await?;
+    tx.commit().await?;
+    git_sync::spawn_commit(git_sync, &authed);
+
+    Ok(format!("resource {} restored to version {}", path, version))
+}
+
+/// value of a resource read on behalf of a job, with the permissions of the job. `None` if the
+/// resource does not exist or is not visible
+pub async fn get_value_for_job<'c>(
+    tx: &mut Transaction<'c, Postgres>,
+    w_id: &str,
+    path: &str,
+    username: &str,
+    job_id: &str,
+) -> Result<Option<Option<serde_json::Value>>> {
+    let resource = sqlx::query!(
+        "SELECT workspace_id, value from resource WHERE path = $1 AND (workspace_id = $2 OR workspace_id = 'starter')",
+        path,
+        w_id
+    )
+    .fetch_optional(&mut *tx)
+    .await?;
+    let (resource_w_id, mut value) = match resource {
+        Some(resource) => (resource.workspace_id, resource.value),
+        None => return Ok(None),
+    };
+    if let Some(value) = &mut value {
+        decrypt_resource_value(
+            tx,
+            username,
+            &resource_w_id,
+            path,
+            value,
+            true,
+            Some(job_id),
+        )
+        .await?;
+    }
+    Ok(Some(value))
+}
+
+/// encrypt the fields newly marked as secret in the existing resources of a resource type
+async fn encrypt_resources_of_type<'c>(
+    tx: &mut Transaction<'c, Postgres>,
+    w_id: &str,
+    resource_type: &str,
+    schema: &serde_json::Value,
+) -> Result<()> {
+    let mc = build_crypt_for_write(tx, w_id).await?;
+    let resources = sqlx::query!(
+        "SELECT path, value as \"value!\" FROM resource WHERE resource_type = $1 AND workspace_id = $2 \
+         AND value IS NOT NULL",
+        resource_type,
+        w_id
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+    for resource in resources {
+        let mut encrypted = resource.value.clone();
+        encrypt_secret_fields(&mc, schema, &mut encrypted)?;
+        if encrypted != resource.value {
+            sqlx::query!(
+                "UPDATE resource SET value = $1 WHERE path = $2 AND workspace_id = $3",
+                encrypted,
+                resource.path,
+                w_id
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+    }
+    Ok(())
+}
+
+/// decrypt the secret fields of the value of a resource of workspace `w_id`, auditing the read when
+/// there is any, along with the job it is done for. With `decrypt` unset, the secret fields are
+/// redacted instead
+async fn decrypt_resource_value<'c>(
+    tx: &mut Transaction<'c, Postgres>,
+    username: &str,
+    w_id: &str,
+    path: &str,
+    value: &mut serde_json::Value,
+    decrypt: bool,
+    job_id: Option<&str>,
+) -> Result<()> {
+    if !has_encrypted_fields(value) {
+        return Ok(());
+    }
+    if !decrypt {
+        return map_encrypted_fields(None, value);
+    }
+    audit_log(
+        tx,
+        username,
+        "resources.decrypt_secret",
+        ActionKind::Execute,
+        w_id,
+        Some(path),
+        job_id.map(|job_id| [("job", job_id)].into()),
+    )
+    .await?;
+    let mc = build_crypt(tx, w_id).await?;
+    map_encrypted_fields(Some(&mc), value)
+}
+
+/// validate every resource of a type against its current schema, typically after the schema
+/// changed. Only the invalid resources are returned
+async fn validate_resources_of_type(
+    authed: Authed,
+    Extension(user_db): Extension<UserDB>,
+    Path((w_id, name)): Path<(String, String)>,
+) -> JsonResult<Vec<ResourceValidation>> {
+    let mut tx = user_db.begin(&authed).await?;
+    let schema = get_resource_type_schema(&mut tx, &w_id, &name).await?;
+    let schema = crate::utils::not_found_if_none(schema, "ResourceType", &name)?;
+
+    let resources = sqlx::query!(
+        "SELECT path, value FROM resource WHERE resource_type = $1 AND workspace_id = $2 ORDER BY path",
+        &name,
+        &w_id
+    )
+    .fetch_all(&mut tx)
+    .await?;
+    tx.commit().await?;
+
+    let invalid = match schema {
+        Some(schema) => resources
+            .into_iter()
+            .filter_map(|r| {
+                let errors =
+                    json_schema::validate(&schema, r.value.as_ref()?, &is_encrypted_secret);
+                if errors.is_empty() {
+                    None
+                } else {
+                    Some(ResourceValidation {
+                        path: r.path,
+                        errors,
+                    })
+                }
+            })
+            .collect(),
+        None => vec![],
+    };
+
+    Ok(Json(invalid))
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::db::{create_test_workspace, delete_test_workspace, test_db};
+    use serde_json::json;
+
+    #[test]
+    fn test_redacted_fields_round_trip() -> anyhow::Result<()> {
+        let existing = json!({
+            "host": "db.example.com",
+            "password": "$encrypted:abc",
+            "tls": { "key": "$encrypted:def", "ca": null },
+            "replicas": [{ "password": "$encrypted:ghi" }]
+        });
+
+        let mut exported = existing.clone();
+        map_encrypted_fields(None, &mut exported)?;
+        assert_eq!(
+            exported,
+            json!({
+                "host": "db.example.com",
+                "password": null,
+                "tls": { "key": null, "ca": null },
+                "replicas": [{ "password": null }]
+            })
+        );
+
+        let mut imported = exported.clone();
+        keep_redacted_fields(&existing, &mut imported);
+        assert_eq!(imported, existing);
+
+        // fields set by the archive win over the ones of the destination
+        let mut imported = json!({ "host": "other", "password": "new", "tls": null });
+        keep_redacted_fields(&existing, &mut imported);
+        assert_eq!(
+            imported,
+            json!({ "host": "other", "password": "new", "tls": null })
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_encrypt_secret_fields() -> anyhow::Result<()> {
+        let mc = magic_crypt::new_magic_crypt!("workspace-key", 256);
+        let other_mc = magic_crypt::new_magic_crypt!("other-key", 256);
+        let schema = json!({
+            "properties": {
+                "host": { "type": "string" },
+                "password": { "type": "string", "secret": true },
+                "tls": { "properties": { "key": { "type": "string", "secret": true } } }
+            }
+        });
+
+        let mut value = json!({
+            "host": "db.example.com",
+            "password": "s3cret",
+            "tls": { "key": "$var:u/alice/key" }
+        });
+        encrypt_secret_fields(&mc, &schema, &mut value)?;
+        let password = value["password"].as_str().unwrap();
+        assert_eq!(
+            mc.decrypt_base64_to_string(password.strip_prefix(ENCRYPTED_PREFIX).unwrap())?,
+            "\"s3cret\""
+        );
+        assert_eq!(value["host"], "db.example.com");
+        assert_eq!(value["tls"]["key"], "$var:u/alice/key");
+
+        // already encrypted with the key of the workspace
+        let mut again = value.clone();
+        encrypt_secret_fields(&mc, &schema, &mut again)?;
+        assert_eq!(again, value);
+
+        for encrypted in [
+            format!(
+                "{ENCRYPTED_PREFIX}{}",
+                encrypt(&other_mc, "\"s3cret\"".to_string())
+            ),
+            format!("{ENCRYPTED_PREFIX}not base64"),
+        ] {
+            let mut value = json!({ "password": encrypted.clone() });
+            assert!(encrypt_secret_fields(&mc, &schema, &mut value).is_err());
+            let mut value = json!({ "tls": { "key": encrypted } });
+            assert!(encrypt_secret_fields(&mc, &schema, &mut value).is_err());
+        }
+        Ok(())
+    }
+
+    /// the postgres probe is queued as a deno preview with the tested value as its argument,
+    /// resource types without a probe are refused
+    #[tokio::test]
+    #[ignore = "needs DATABASE_URL, see test_db"]
+    async fn test_connection_postgres_probe() {
+        let db = test_db().await;
+        let w_id = create_test_workspace(&db).await;
+        let authed = Authed {
+            email: None,
+            username: "alice".to_string(),
+            is_admin: false,
+            groups: vec![],
+        };
+        let value = json!({ "host": "db.example.com", "user": "alice", "dbname": "app" });
+        let test = |resource_type: &str| {
+            test_connection(
+                authed.clone(),
+                Extension(UserDB::new(db.clone())),
+                Path(w_id.clone()),
+                Json(TestConnection {
+                    resource_type: resource_type.to_string(),
+                    value: value.clone(),
+                }),
+            )
+        };
+
+        for resource_type in ["postgres", "postgresql"] {
+            let (status, id) = test(resource_type).await.unwrap();
+            assert_eq!(status, StatusCode::CREATED);
+            let (raw_code, script_path, language, args): (
+                Option<String>,
+                Option<String>,
+                Option<ScriptLang>,
+                Option<serde_json::Value>,
+            ) = sqlx::query_as(
+                "SELECT raw_code, script_path, language, args FROM queue WHERE id = $1 \
+                 AND workspace_id = $2 AND job_kind = 'preview'",
+            )
+            .bind(uuid::Uuid::parse_str(&id).unwrap())
+            .bind(&w_id)
+            .fetch_one(&db)
+            .await
+            .unwrap();
+            assert_eq!(raw_code.as_deref(), Some(POSTGRES_PROBE));
+            assert_eq!(
+                script_path,
+                Some(format!("test_connection/{resource_type}"))
+            );
+            assert_eq!(language, Some(ScriptLang::Deno));
+            assert_eq!(args, Some(json!({ "value": value })));
+        }
+
+        assert!(matches!(test("mysql").await, Err(Error::BadRequest(_))));
+
+        delete_test_workspace(&db, &w_id).await;
+    }
+}
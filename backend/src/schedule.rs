@@ -11,7 +11,8 @@ use crate::{
     audit::{audit_log, ActionKind},
     db::UserDB,
     error::{self, JsonResult, Result},
-    jobs::{self, push, JobPayload},
+    jobs::{self, check_hash_for_path, push, JobPayload},
+    scripts::ScriptHash,
     users::Authed,
     utils::{get_owner_from_path, Pagination, StripPath},
 };
@@ -52,6 +53,7 @@ pub struct Schedule {
     pub is_flow: bool,
     pub args: Option<serde_json::Value>,
     pub extra_perms: serde_json::Value,
+    pub script_hash: Option<ScriptHash>,
 }
 
 #[derive(Deserialize)]
@@ -62,6 +64,7 @@ pub struct NewSchedule {
     pub script_path: String,
     pub is_flow: bool,
     pub args: Option<serde_json::Value>,
+    pub script_hash: Option<ScriptHash>,
 }
 
 pub async fn push_scheduled_job<'c>(
@@ -93,13 +96,19 @@ pub async fn push_scheduled_job<'c>(
     let payload = if schedule.is_flow {
         JobPayload::Flow(schedule.script_path)
     } else {
+        let hash = match schedule.script_hash {
+            Some(hash) => hash,
+            None => {
+                jobs::get_latest_hash_for_path(
+                    &mut tx,
+                    &schedule.workspace_id,
+                    &schedule.script_path,
+                )
+                .await?
+            }
+        };
         JobPayload::ScriptHash {
-            hash: jobs::get_latest_hash_for_path(
-                &mut tx,
-                &schedule.workspace_id,
-                &schedule.script_path,
-            )
-            .await?,
+            hash,
             path: schedule.script_path,
         }
     };
@@ -119,6 +128,55 @@ pub async fn push_scheduled_job<'c>(
     Ok(tx)
 }
 
+/// check that a hash the schedule is pinned to is a version of the script it runs
+async fn check_script_hash<'c>(
+    tx: &mut Transaction<'c, Postgres>,
+    w_id: &str,
+    script_path: &str,
+    is_flow: bool,
+    script_hash: &Option<ScriptHash>,
+) -> Result<()> {
+    match script_hash {
+        Some(_) if is_flow => Err(error::Error::BadRequest(
+            "a schedule of a flow cannot be pinned to a script hash".to_string(),
+        )),
+        Some(hash) => check_hash_for_path(tx, w_id, script_path, hash).await,
+        None => Ok(()),
+    }
+}
+
+/// validate the args of a schedule when it is created or edited and fill in the defaults, the
+/// args to store. The runs it pushes later are not validated again so that a schedule keeps
+/// running after a schema change
+async fn validate_schedule_args<'c>(
+    tx: &mut Transaction<'c, Postgres>,
+    w_id: &str,
+    script_path: &str,
+    is_flow: bool,
+    script_hash: &Option<ScriptHash>,
+    args: &Option<Value>,
+) -> Result<Option<Value>> {
+    let args = match args {
+        Some(Value::Object(args_m)) => Some(args_m.clone()),
+        Some(Value::Null) | None => None,
+        Some(_) => {
+            return Err(error::Error::BadRequest(
+                "args of scripts needs to be dict".to_string(),
+            ))
+        }
+    };
+    let args = if is_flow {
+        jobs::validate_flow_args(tx, w_id, script_path, args).await?
+    } else {
+        let hash = match script_hash {
+            Some(hash) => *hash,
+            None => jobs::get_latest_hash_for_path(tx, w_id, script_path).await?,
+        };
+        jobs::validate_script_args(tx, w_id, &hash, script_path, args).await?
+    };
+    Ok(args.map(Value::Object))
+}
+
 async fn create_schedule(
     authed: Authed,
     Extension(user_db): Extension<UserDB>,
@@ -127,9 +185,21 @@ async fn create_schedule(
 ) -> Result<String> {
     cron::Schedule::from_str(&ns.schedule).map_err(|e| error::Error::BadRequest(e.to_string()))?;
     let mut tx = user_db.begin(&authed).await?;
+    check_script_hash(&mut tx, &w_id, &ns.script_path, ns.is_flow, &ns.script_hash).await?;
+    let args = validate_schedule_args(
+        &mut tx,
+        &w_id,
+        &ns.script_path,
+        ns.is_flow,
+        &ns.script_hash,
+        &ns.args,
+    )
+    .await?;
 
-    let schedule = sqlx::query_as!(Schedule,
-        "INSERT INTO schedule (workspace_id, path, schedule, offset_, edited_by, script_path, is_flow, args) VALUES ($1, $2, $3, $4, $5, $6, $7, $8) RETURNING *",
+    let schedule = sqlx::query_as!(
+        Schedule,
+        "INSERT INTO schedule (workspace_id, path, schedule, offset_, edited_by, script_path, is_flow, args, script_hash) \
+         VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9) RETURNING workspace_id, path, edited_by, edited_at, schedule, offset_, enabled, script_path, is_flow, args, extra_perms, script_hash as \"script_hash: ScriptHash\"",
         w_id,
         ns.path,
         ns.schedule,
@@ -137,7 +207,8 @@ async fn create_schedule(
         &authed.username,
         ns.script_path,
         ns.is_flow,
-        ns.args
+        args,
+        ns.script_hash.map(|x| x.0)
     )
     .fetch_one(&mut tx)
     .await?;
@@ -172,6 +243,7 @@ pub struct EditSchedule {
     pub script_path: String,
     pub is_flow: bool,
     pub args: Option<serde_json::Value>,
+    pub script_hash: Option<ScriptHash>,
 }
 
 async fn clear_schedule<'c>(db: &mut Transaction<'c, Postgres>, path: &str) -> Result<()> {
@@ -192,14 +264,27 @@ async fn edit_schedule(
     cron::Schedule::from_str(&es.schedule).map_err(|e| error::Error::BadRequest(e.to_string()))?;
 
     let mut tx = user_db.begin(&authed).await?;
+    check_script_hash(&mut tx, &w_id, &es.script_path, es.is_flow, &es.script_hash).await?;
+    let args = validate_schedule_args(
+        &mut tx,
+        &w_id,
+        &es.script_path,
+        es.is_flow,
+        &es.script_hash,
+        &es.args,
+    )
+    .await?;
 
     clear_schedule(&mut tx, path).await?;
-    let schedule = sqlx::query_as!(Schedule,
-        "UPDATE schedule SET schedule = $1, script_path = $2, is_flow = $3, args = $4 WHERE path = $5 AND workspace_id = $6 RETURNING *",
+    let schedule = sqlx::query_as!(
+        Schedule,
+        "UPDATE schedule SET schedule = $1, script_path = $2, is_flow = $3, args = $4, script_hash = $5 \
+         WHERE path = $6 AND workspace_id = $7 RETURNING workspace_id, path, edited_by, edited_at, schedule, offset_, enabled, script_path, is_flow, args, extra_perms, script_hash as \"script_hash: ScriptHash\"",
         es.schedule,
         es.script_path,
         es.is_flow,
-        es.args,
+        args,
+        es.script_hash.map(|x| x.0),
         path,
         w_id,
     )
@@ -244,7 +329,8 @@ async fn list_schedule(
 
     let rows = sqlx::query_as!(
         Schedule,
-        "SELECT * FROM schedule WHERE workspace_id = $1 ORDER BY edited_at desc LIMIT $2 OFFSET $3",
+        "SELECT workspace_id, path, edited_by, edited_at, schedule, offset_, enabled, script_path, is_flow, args, extra_perms, script_hash as \"script_hash: ScriptHash\" FROM schedule \
+         WHERE workspace_id = $1 ORDER BY edited_at desc LIMIT $2 OFFSET $3",
         w_id,
         per_page as i64,
         offset as i64
@@ -262,7 +348,7 @@ pub async fn get_schedule_opt<'c>(
 ) -> Result<Option<Schedule>> {
     let schedule_opt = sqlx::query_as!(
         Schedule,
-        "SELECT * FROM schedule WHERE path = $1 AND workspace_id = $2",
+        "SELECT workspace_id, path, edited_by, edited_at, schedule, offset_, enabled, script_path, is_flow, args, extra_perms, script_hash as \"script_hash: ScriptHash\" FROM schedule WHERE path = $1 AND workspace_id = $2",
         path,
         w_id
     )
@@ -323,7 +409,7 @@ pub async fn set_enabled(
 
     let schedule_o = sqlx::query_as!(
         Schedule,
-        "UPDATE schedule SET enabled = $1 WHERE path = $2 AND workspace_id = $3 RETURNING *",
+        "UPDATE schedule SET enabled = $1 WHERE path = $2 AND workspace_id = $3 RETURNING workspace_id, path, edited_by, edited_at, schedule, offset_, enabled, script_path, is_flow, args, extra_perms, script_hash as \"script_hash: ScriptHash\"",
         enabled,
         path,
         w_id
@@ -5,6 +5,7 @@
  * LICENSE-AGPL for a copy of the license.
  */
 
+use itertools::Itertools;
 use serde::Deserializer;
 use sql_builder::prelude::*;
 
@@ -12,7 +13,8 @@ use crate::{
     audit::{audit_log, ActionKind},
     db::{UserDB, DB},
     error::{Error, JsonResult, Result},
-    jobs, parser,
+    flow::{FlowModuleValue, FlowValue},
+    git_sync, jobs, parser,
     users::{owner_to_token_owner, truncate_token, Authed, Tokened},
     utils::{require_admin, Pagination, StripPath},
 };
@@ -27,12 +29,15 @@ use serde_json::{json, to_string_pretty};
 use sql_builder::SqlBuilder;
 use sqlx::{FromRow, Postgres, Transaction};
 use std::{
-    collections::hash_map::DefaultHasher,
+    borrow::Cow,
+    collections::{hash_map::DefaultHasher, HashMap, HashSet},
     fmt::Display,
     hash::{Hash, Hasher},
 };
 
 const MAX_HASH_HISTORY_LENGTH_STORED: usize = 20;
+const DIFF_CONTEXT_LINES: usize = 3;
+const MIN_MEMORY_LIMIT_MB: i32 = 64;
 
 pub fn global_service() -> Router {
     Router::new()
@@ -53,6 +58,15 @@ pub fn workspaced_service() -> Router {
         .route("/delete/h/:hash", post(delete_script_by_hash))
         .route("/get/h/:hash", get(get_script_by_hash))
         .route("/deployment_status/h/:hash", get(get_deployment_status))
+        .route("/outdated_pins", get(list_outdated_pins))
+        .route("/used_by/p/*path", get(get_script_usage))
+        .route("/history/p/*path", get(get_script_history))
+        .route("/diff/:hash/:other_hash", get(diff_scripts))
+        .route("/rollback/h/:hash", post(rollback_script))
+        .route("/draft/save", post(save_draft))
+        .route("/draft/get/p/*path", get(get_draft_by_path))
+        .route("/draft/delete/p/*path", post(delete_draft_by_path))
+        .route("/draft/deploy/p/*path", post(deploy_draft_by_path))
 }
 
 #[derive(sqlx::Type, Serialize, Deserialize, Debug, PartialEq, Clone, Hash)]
@@ -126,9 +140,13 @@ pub struct Script {
     pub lock: Option<String>,
     pub lock_error_logs: Option<String>,
     pub language: ScriptLang,
+    pub timeout: Option<i32>,
+    pub memory_limit: Option<i32>,
+    pub cpu_limit: Option<i32>,
+    pub is_library: bool,
 }
 
-#[derive(Serialize, Deserialize, sqlx::Type, Debug)]
+#[derive(Serialize, Deserialize, sqlx::Type, Debug, PartialEq)]
 #[sqlx(transparent)]
 #[serde(transparent)]
 pub struct Schema(pub serde_json::Value);
@@ -152,6 +170,30 @@ pub struct NewScript {
     pub is_template: Option<bool>,
     pub lock: Option<Vec<String>>,
     pub language: ScriptLang,
+    pub timeout: Option<i32>,
+    pub memory_limit: Option<i32>,
+    pub cpu_limit: Option<i32>,
+    pub is_library: Option<bool>,
+}
+
+#[derive(FromRow, Serialize)]
+pub struct ScriptDraft {
+    pub workspace_id: String,
+    pub path: String,
+    pub value: serde_json::Value,
+    pub created_by: String,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+    pub extra_perms: serde_json::Value,
+}
+
+#[derive(FromRow, Serialize)]
+pub struct ScriptHistory {
+    pub hash: ScriptHash,
+    pub summary: String,
+    pub created_by: String,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+    pub archived: bool,
+    pub deleted: bool,
 }
 
 #[derive(Deserialize)]
@@ -166,6 +208,7 @@ pub struct ListScriptQuery {
     pub order_by: Option<String>,
     pub order_desc: Option<bool>,
     pub is_template: Option<bool>,
+    pub is_library: Option<bool>,
 }
 
 async fn list_scripts(
@@ -196,6 +239,10 @@ async fn list_scripts(
             "null as lock",
             "CASE WHEN lock_error_logs IS NOT NULL THEN 'error' ELSE null END as lock_error_logs",
             "language",
+            "timeout",
+            "memory_limit",
+            "cpu_limit",
+            "is_library",
         ])
         .order_by("created_at", lq.order_desc.unwrap_or(true))
         .and_where("workspace_id = ? OR workspace_id = 'starter'".bind(&w_id))
@@ -233,6 +280,9 @@ async fn list_scripts(
     if let Some(it) = &lq.is_template {
         sqlb.and_where_eq("is_template", it);
     }
+    if let Some(il) = &lq.is_library {
+        sqlb.and_where_eq("is_library", il);
+    }
 
     let sql = sqlb.sql().map_err(|e| Error::InternalErr(e.to_string()))?;
     let mut tx = user_db.begin(&authed).await?;
@@ -246,6 +296,10 @@ fn hash_script(ns: &NewScript) -> i64 {
     ns.hash(&mut dh);
     dh.finish() as i64
 }
+
+/// deploy a new version of a script right away. Drafts are optional: they let a script be saved
+/// and run as a preview before it is deployed with `deploy_draft_by_path`, which deploys the same
+/// way. The clients that deploy without previewing (the cli, the imports) keep using this endpoint
 async fn create_script(
     authed: Authed,
     Tokened { token }: Tokened,
@@ -253,13 +307,29 @@ async fn create_script(
     Path(w_id): Path<String>,
     Json(ns): Json<NewScript>,
 ) -> Result<(StatusCode, String)> {
+    let tx = user_db.begin(&authed).await?;
+    let (hash, mut tx) = create_script_internal(ns, &w_id, &authed, &token, tx).await?;
+    let git_sync = git_sync::script_commit(&mut tx, &w_id, &hash).await?;
+    tx.commit().await?;
+    git_sync::spawn_commit(git_sync, &authed);
+
+    Ok((StatusCode::CREATED, format!("{}", hash)))
+}
+
+pub async fn create_script_internal<'c>(
+    ns: NewScript,
+    w_id: &str,
+    authed: &Authed,
+    token: &str,
+    mut tx: Transaction<'c, Postgres>,
+) -> Result<(ScriptHash, Transaction<'c, Postgres>)> {
+    check_limits(ns.timeout, ns.memory_limit, ns.cpu_limit)?;
     let hash = ScriptHash(hash_script(&ns));
-    let mut tx = user_db.begin(&authed).await?;
 
     if sqlx::query_scalar!(
         "SELECT 1 FROM script WHERE hash = $1 AND workspace_id = $2",
         hash.0,
-        &w_id
+        w_id
     )
     .fetch_optional(&mut tx)
     .await?
@@ -276,7 +346,7 @@ async fn create_script(
         "SELECT * FROM script WHERE path = $1 AND archived = false AND workspace_id = $2",
     )
     .bind(&ns.path)
-    .bind(&w_id)
+    .bind(w_id)
     .fetch_optional(&mut tx)
     .await?;
 
@@ -291,7 +361,7 @@ async fn create_script(
                 if sqlx::query_scalar!(
                     "SELECT 1 FROM script WHERE hash = $1 AND workspace_id = $2",
                     p_hash.0,
-                    &w_id
+                    w_id
                 )
                 .fetch_optional(&mut tx)
                 .await?
@@ -305,7 +375,7 @@ async fn create_script(
                 let clashing_hash_o = sqlx::query_scalar!(
                     "SELECT hash FROM script WHERE parent_hashes[1] = $1 AND workspace_id = $2",
                     p_hash.0,
-                    &w_id
+                    w_id
                 )
                 .fetch_optional(&mut tx)
                 .await?;
@@ -318,7 +388,7 @@ async fn create_script(
                     )));
                 };
 
-                let ps = get_script_by_hash_internal(&mut tx, &w_id, p_hash).await?;
+                let ps = get_script_by_hash_internal(&mut tx, w_id, p_hash).await?;
 
                 let ph = {
                     let v = ps.parent_hashes.map(|x| x.0).unwrap_or_default();
@@ -345,7 +415,7 @@ async fn create_script(
                 sqlx::query!(
                     "UPDATE script SET archived = true WHERE hash = $1 AND workspace_id = $2",
                     p_hash.0,
-                    &w_id
+                    w_id
                 )
                 .execute(&mut tx)
                 .await?;
@@ -367,9 +437,10 @@ async fn create_script(
     //::text::json is to ensure we use serde_json with preserve order
     sqlx::query!(
         "INSERT INTO script (workspace_id, hash, path, parent_hashes, summary, description, content, \
-         created_by, schema, is_template, extra_perms, lock, language) VALUES \
-         ($1, $2, $3, $4, $5, $6, $7, $8, $9::text::json, $10, $11, $12, $13)",
-        &w_id,
+         created_by, schema, is_template, extra_perms, lock, language, timeout, memory_limit, \
+         cpu_limit, is_library) VALUES \
+         ($1, $2, $3, $4, $5, $6, $7, $8, $9::text::json, $10, $11, $12, $13, $14, $15, $16, $17)",
+        w_id,
         &hash.0,
         ns.path,
         p_hashes,
@@ -381,16 +452,23 @@ async fn create_script(
         ns.is_template.unwrap_or(false),
         extra_perms,
         lock,
-        ns.language: ScriptLang
+        ns.language: ScriptLang,
+        ns.timeout,
+        ns.memory_limit,
+        ns.cpu_limit,
+        ns.is_library.unwrap_or(false)
     )
     .execute(&mut tx)
     .await?;
 
     let mut tx = if ns.lock.is_none() && ns.language == ScriptLang::Python3 {
-        let dependencies = parser::parse_python_imports(&ns.content)?;
+        let libraries = get_transitive_libraries(&mut tx, w_id, &ns.language, &ns.content).await?;
+        let mut dependencies = parser::parse_python_imports(&ns.content)?;
+        dependencies.extend(library_requirements(&libraries)?);
+        let dependencies = dependencies.into_iter().unique().collect();
         let (_, tx) = jobs::push(
             tx,
-            &w_id,
+            w_id,
             jobs::JobPayload::Dependencies { hash, dependencies },
             None,
             &authed.username,
@@ -412,12 +490,12 @@ async fn create_script(
             &authed.username,
             "scripts.update",
             ActionKind::Update,
-            &w_id,
+            w_id,
             Some(&ns.path),
             Some(
                 [
                     ("hash", hash.to_string().as_str()),
-                    ("token", &truncate_token(&token)),
+                    ("token", &truncate_token(token)),
                 ]
                 .into(),
             ),
@@ -429,13 +507,13 @@ async fn create_script(
             &authed.username,
             "scripts.create",
             ActionKind::Create,
-            &w_id,
+            w_id,
             Some(&ns.path),
             Some(
                 [
-                    ("workspace", w_id.as_str()),
+                    ("workspace", w_id),
                     ("hash", hash.to_string().as_str()),
-                    ("token", &truncate_token(&token)),
+                    ("token", &truncate_token(token)),
                 ]
                 .into(),
             ),
@@ -443,9 +521,87 @@ async fn create_script(
         .await?;
     }
 
-    tx.commit().await?;
+    Ok((hash, tx))
+}
 
-    Ok((StatusCode::CREATED, format!("{}", hash)))
+pub struct Library {
+    pub path: String,
+    pub content: String,
+}
+
+pub fn parse_library_imports(language: &ScriptLang, content: &str) -> Result<Vec<String>> {
+    match language {
+        ScriptLang::Python3 => parser::parse_python_library_imports(content),
+        ScriptLang::Deno => parser::parse_deno_library_imports(content),
+    }
+}
+
+/// the libraries imported by `content`, directly or through other libraries. A library is the
+/// latest non-archived version of a script marked as library in the same language
+pub async fn get_transitive_libraries<'c>(
+    db: &mut Transaction<'c, Postgres>,
+    w_id: &str,
+    language: &ScriptLang,
+    content: &str,
+) -> Result<Vec<Library>> {
+    let mut libraries = vec![];
+    let mut seen = HashSet::new();
+    let mut to_visit = parse_library_imports(language, content)?;
+    while let Some(path) = to_visit.pop() {
+        if !seen.insert(path.clone()) {
+            continue;
+        }
+        let content_o = sqlx::query_scalar!(
+            "SELECT content FROM script WHERE path = $1 AND (workspace_id = $2 OR workspace_id = \
+             'starter') AND is_library = true AND language = $3 AND archived = false AND deleted = \
+             false ORDER BY created_at DESC LIMIT 1",
+            &path,
+            w_id,
+            language: ScriptLang
+        )
+        .fetch_optional(&mut *db)
+        .await?;
+        let content = crate::utils::not_found_if_none(content_o, "Library", &path)?;
+        to_visit.extend(parse_library_imports(language, &content)?);
+        libraries.push(Library { path, content });
+    }
+    Ok(libraries)
+}
+
+/// the python requirements of the libraries, to be locked along the script importing them
+pub fn library_requirements(libraries: &[Library]) -> Result<Vec<String>> {
+    let mut requirements = vec![];
+    for library in libraries {
+        requirements.extend(parser::parse_python_imports(&library.content)?);
+    }
+    Ok(requirements)
+}
+
+pub fn check_limits(
+    timeout: Option<i32>,
+    memory_limit: Option<i32>,
+    cpu_limit: Option<i32>,
+) -> Result<()> {
+    for (name, limit) in [
+        ("timeout", timeout),
+        ("memory_limit", memory_limit),
+        ("cpu_limit", cpu_limit),
+    ] {
+        if limit.map(|x| x <= 0).unwrap_or(false) {
+            return Err(Error::BadRequest(format!(
+                "{name} must be strictly positive when set"
+            )));
+        }
+    }
+    if memory_limit
+        .map(|x| x < MIN_MEMORY_LIMIT_MB)
+        .unwrap_or(false)
+    {
+        return Err(Error::BadRequest(format!(
+            "memory_limit must be at least {MIN_MEMORY_LIMIT_MB} MB"
+        )));
+    }
+    Ok(())
 }
 
 async fn get_script_by_path(
@@ -524,6 +680,546 @@ async fn get_deployment_status(
     Ok(Json(status))
 }
 
+#[derive(Serialize)]
+pub struct OutdatedPin {
+    pub kind: PinKind,
+    pub path: String,
+    pub script_path: String,
+    pub pinned_hash: ScriptHash,
+    pub latest_hash: ScriptHash,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all(serialize = "lowercase"))]
+pub enum PinKind {
+    Flow,
+    Schedule,
+}
+
+/// list the flow steps and schedules pinned to a script hash that is not the latest version
+/// of its script anymore
+async fn list_outdated_pins(
+    authed: Authed,
+    Extension(user_db): Extension<UserDB>,
+    Path(w_id): Path<String>,
+) -> JsonResult<Vec<OutdatedPin>> {
+    let mut tx = user_db.begin(&authed).await?;
+
+    let mut pins = vec![];
+    let flows = sqlx::query!(
+        "SELECT path, value FROM flow WHERE workspace_id = $1 AND archived = false",
+        &w_id
+    )
+    .fetch_all(&mut tx)
+    .await?;
+    for flow in flows {
+        let path = flow.path;
+        if let Ok(flow) = serde_json::from_value::<FlowValue>(flow.value) {
+            for module in flow.all_modules() {
+                if let FlowModuleValue::Script {
+                    path: script_path,
+                    hash: Some(hash),
+                } = &module.value
+                {
+                    pins.push((PinKind::Flow, path.clone(), script_path.clone(), *hash));
+                }
+            }
+        }
+    }
+
+    let schedules = sqlx::query!(
+        "SELECT path, script_path, script_hash as \"script_hash!: ScriptHash\" FROM schedule \
+         WHERE workspace_id = $1 AND is_flow = false AND script_hash IS NOT NULL",
+        &w_id
+    )
+    .fetch_all(&mut tx)
+    .await?;
+    for x in schedules {
+        pins.push((PinKind::Schedule, x.path, x.script_path, x.script_hash));
+    }
+
+    let mut latest_hashes: HashMap<String, Option<ScriptHash>> = HashMap::new();
+    let mut outdated = vec![];
+    for (kind, path, script_path, pinned_hash) in pins {
+        let latest_hash = match latest_hashes.get(&script_path) {
+            Some(latest_hash) => *latest_hash,
+            None => {
+                let latest_hash = jobs::get_latest_hash_for_path(&mut tx, &w_id, &script_path)
+                    .await
+                    .ok();
+                latest_hashes.insert(script_path.clone(), latest_hash);
+                latest_hash
+            }
+        };
+        if let Some(latest_hash) = latest_hash.filter(|x| *x != pinned_hash) {
+            outdated.push(OutdatedPin {
+                kind,
+                path,
+                script_path,
+                pinned_hash,
+                latest_hash,
+            });
+        }
+    }
+    tx.commit().await?;
+
+    Ok(Json(outdated))
+}
+
+#[derive(Serialize)]
+pub struct ScriptUsage {
+    pub flows: Vec<String>,
+    pub schedules: Vec<String>,
+    pub slack_command: bool,
+    pub importing_scripts: Vec<String>,
+}
+
+/// everything that would be affected by archiving the script at `path` or changing its signature
+async fn get_script_usage(
+    authed: Authed,
+    Extension(user_db): Extension<UserDB>,
+    Path((w_id, path)): Path<(String, StripPath)>,
+) -> JsonResult<ScriptUsage> {
+    let path = path.to_path();
+    let mut tx = user_db.begin(&authed).await?;
+
+    let flows = sqlx::query!(
+        "SELECT path, value FROM flow WHERE workspace_id = $1 AND archived = false ORDER BY path",
+        w_id
+    )
+    .fetch_all(&mut tx)
+    .await?
+    .into_iter()
+    .filter(|flow| {
+        serde_json::from_value::<FlowValue>(flow.value.clone())
+            .map(|flow| {
+                flow.all_modules().any(|module| {
+                    matches!(&module.value, FlowModuleValue::Script { path: p, .. } if p == path)
+                })
+            })
+            .unwrap_or(false)
+    })
+    .map(|flow| flow.path)
+    .collect();
+
+    let schedules = sqlx::query_scalar!(
+        "SELECT path FROM schedule WHERE workspace_id = $1 AND script_path = $2 AND is_flow = \
+         false ORDER BY path",
+        w_id,
+        path
+    )
+    .fetch_all(&mut tx)
+    .await?;
+
+    let slack_command = sqlx::query_scalar!(
+        "SELECT EXISTS(SELECT 1 FROM workspace_settings WHERE workspace_id = $1 AND \
+         slack_command_script = $2)",
+        w_id,
+        path
+    )
+    .fetch_one(&mut tx)
+    .await?
+    .unwrap_or(false);
+
+    // only the scripts mentioning the name of the script are parsed
+    let name = path.rsplit('/').next().unwrap_or(path);
+    let candidates = sqlx::query!(
+        "SELECT path, language as \"language: ScriptLang\", content FROM script \
+         WHERE workspace_id = $1 AND archived = false AND deleted = false AND path != $2 \
+         AND content LIKE $3 ORDER BY path",
+        w_id,
+        path,
+        format!("%{name}%")
+    )
+    .fetch_all(&mut tx)
+    .await?;
+    tx.commit().await?;
+
+    let mut importing_scripts = vec![];
+    for script in candidates {
+        if parse_library_imports(&script.language, &script.content)
+            .map(|imports| imports.iter().any(|x| x == path))
+            .unwrap_or(false)
+        {
+            importing_scripts.push(script.path);
+        }
+    }
+
+    Ok(Json(ScriptUsage {
+        flows,
+        schedules,
+        slack_command,
+        importing_scripts,
+    }))
+}
+
+async fn get_script_history(
+    authed: Authed,
+    Extension(user_db): Extension<UserDB>,
+    Path((w_id, path)): Path<(String, StripPath)>,
+) -> JsonResult<Vec<ScriptHistory>> {
+    let path = path.to_path();
+    let mut tx = user_db.begin(&authed).await?;
+    let history = sqlx::query_as!(
+        ScriptHistory,
+        "SELECT hash as \"hash: ScriptHash\", summary, created_by, created_at, archived, deleted \
+         FROM script WHERE path = $1 AND (workspace_id = $2 OR workspace_id = 'starter') \
+         ORDER BY created_at DESC",
+        path,
+        w_id
+    )
+    .fetch_all(&mut tx)
+    .await?;
+    tx.commit().await?;
+
+    if history.is_empty() {
+        return Err(Error::NotFound(format!(
+            "Script not found at name {}",
+            path
+        )));
+    }
+    Ok(Json(history))
+}
+
+async fn diff_scripts(
+    authed: Authed,
+    Extension(user_db): Extension<UserDB>,
+    Path((w_id, hash, other_hash)): Path<(String, ScriptHash, ScriptHash)>,
+) -> Result<String> {
+    let mut tx = user_db.begin(&authed).await?;
+    let script = get_script_by_hash_internal(&mut tx, &w_id, &hash).await?;
+    let other_script = get_script_by_hash_internal(&mut tx, &w_id, &other_hash).await?;
+    tx.commit().await?;
+
+    Ok(unified_diff(
+        &script.content,
+        &other_script.content,
+        &format!("{}@{}", script.path, hash),
+        &format!("{}@{}", other_script.path, other_hash),
+    ))
+}
+
+/// the lines of `content`, the last one followed by the "\ No newline at end of file" marker
+/// when `content` does not end with a newline, so that adding or removing the final newline only
+/// changes the last line
+fn diff_lines(content: &str) -> Vec<Cow<'_, str>> {
+    if content.is_empty() {
+        return vec![];
+    }
+    let (content, trailing_newline) = match content.strip_suffix('\n') {
+        Some(content) => (content, true),
+        None => (content, false),
+    };
+    let mut lines: Vec<Cow<str>> = content.split('\n').map(Cow::Borrowed).collect();
+    if !trailing_newline {
+        if let Some(last) = lines.last_mut() {
+            *last = Cow::Owned(format!("{last}\n\\ No newline at end of file"));
+        }
+    }
+    lines
+}
+
+pub fn unified_diff(old: &str, new: &str, old_name: &str, new_name: &str) -> String {
+    let (old_lines, new_lines) = (diff_lines(old), diff_lines(new));
+    let ops = diff::slice(&old_lines, &new_lines);
+    let mut out = format!("--- {old_name}\n+++ {new_name}\n");
+
+    // line numbers (1-indexed) in the old and new content at which each op starts
+    let mut line_numbers = Vec::with_capacity(ops.len());
+    let (mut old_line, mut new_line) = (1, 1);
+    for op in &ops {
+        line_numbers.push((old_line, new_line));
+        match op {
+            diff::Result::Left(_) => old_line += 1,
+            diff::Result::Right(_) => new_line += 1,
+            diff::Result::Both(_, _) => {
+                old_line += 1;
+                new_line += 1;
+            }
+        }
+    }
+
+    let mut hunks: Vec<(usize, usize)> = vec![];
+    for (i, _) in ops
+        .iter()
+        .enumerate()
+        .filter(|(_, op)| !matches!(op, diff::Result::Both(_, _)))
+    {
+        let start = i.saturating_sub(DIFF_CONTEXT_LINES);
+        let end = (i + DIFF_CONTEXT_LINES + 1).min(ops.len());
+        match hunks.last_mut() {
+            Some(hunk) if start <= hunk.1 => hunk.1 = end,
+            _ => hunks.push((start, end)),
+        }
+    }
+
+    for (start, end) in hunks {
+        let hunk = &ops[start..end];
+        let old_len = hunk
+            .iter()
+            .filter(|op| !matches!(op, diff::Result::Right(_)))
+            .count();
+        let new_len = hunk
+            .iter()
+            .filter(|op| !matches!(op, diff::Result::Left(_)))
+            .count();
+        let (old_start, new_start) = line_numbers[start];
+        // by convention, an empty range starts at the line before it
+        let old_start = if old_len == 0 {
+            old_start - 1
+        } else {
+            old_start
+        };
+        let new_start = if new_len == 0 {
+            new_start - 1
+        } else {
+            new_start
+        };
+        out.push_str(&format!(
+            "@@ -{old_start},{old_len} +{new_start},{new_len} @@\n"
+        ));
+        for op in hunk {
+            match op {
+                diff::Result::Left(l) => out.push_str(&format!("-{l}\n")),
+                diff::Result::Right(r) => out.push_str(&format!("+{r}\n")),
+                diff::Result::Both(l, _) => out.push_str(&format!(" {l}\n")),
+            }
+        }
+    }
+    out
+}
+
+async fn rollback_script(
+    authed: Authed,
+    Tokened { token }: Tokened,
+    Extension(user_db): Extension<UserDB>,
+    Path((w_id, hash)): Path<(String, ScriptHash)>,
+) -> Result<(StatusCode, String)> {
+    let mut tx = user_db.begin(&authed).await?;
+    let script = get_script_by_hash_internal(&mut tx, &w_id, &hash).await?;
+    if script.deleted {
+        return Err(Error::BadRequest(format!(
+            "Cannot rollback to {hash} whose content has been deleted"
+        )));
+    }
+
+    let current_hash = sqlx::query_scalar!(
+        "SELECT hash FROM script WHERE path = $1 AND workspace_id = $2 AND archived = false",
+        script.path,
+        w_id
+    )
+    .fetch_optional(&mut tx)
+    .await?;
+    if current_hash == Some(hash.0) {
+        return Err(Error::BadRequest(format!(
+            "{hash} is already the current version of {}",
+            script.path
+        )));
+    }
+
+    let path = script.path.clone();
+    let ns = NewScript {
+        path: script.path,
+        parent_hash: current_hash.map(ScriptHash),
+        summary: script.summary,
+        description: script.description,
+        content: script.content,
+        schema: script.schema,
+        is_template: Some(script.is_template),
+        lock: script
+            .lock
+            .map(|x| x.lines().map(|x| x.to_string()).collect()),
+        language: script.language,
+        timeout: script.timeout,
+        memory_limit: script.memory_limit,
+        cpu_limit: script.cpu_limit,
+        is_library: Some(script.is_library),
+    };
+    let (new_hash, mut tx) = create_script_internal(ns, &w_id, &authed, &token, tx).await?;
+
+    audit_log(
+        &mut tx,
+        &authed.username,
+        "scripts.rollback",
+        ActionKind::Update,
+        &w_id,
+        Some(&path),
+        Some(
+            [
+                ("hash", new_hash.to_string().as_str()),
+                ("rollback_to", hash.to_string().as_str()),
+            ]
+            .into(),
+        ),
+    )
+    .await?;
+    let git_sync = git_sync::script_commit(&mut tx, &w_id, &new_hash).await?;
+    tx.commit().await?;
+    git_sync::spawn_commit(git_sync, &authed);
+
+    Ok((StatusCode::CREATED, format!("{}", new_hash)))
+}
+
+async fn save_draft(
+    authed: Authed,
+    Extension(user_db): Extension<UserDB>,
+    Path(w_id): Path<String>,
+    Json(ns): Json<NewScript>,
+) -> Result<(StatusCode, String)> {
+    check_limits(ns.timeout, ns.memory_limit, ns.cpu_limit)?;
+    let mut tx = user_db.begin(&authed).await?;
+
+    let extra_perms = sqlx::query_scalar!(
+        "SELECT extra_perms FROM script WHERE path = $1 AND workspace_id = $2 AND archived = false",
+        ns.path,
+        w_id
+    )
+    .fetch_optional(&mut tx)
+    .await?
+    .unwrap_or_else(|| json!({}));
+
+    sqlx::query!(
+        "INSERT INTO script_draft (workspace_id, path, value, created_by, extra_perms) \
+         VALUES ($1, $2, $3, $4, $5) ON CONFLICT (workspace_id, path) \
+         DO UPDATE SET value = EXCLUDED.value, created_by = EXCLUDED.created_by, created_at = now()",
+        w_id,
+        ns.path,
+        serde_json::to_value(&ns).map_err(|e| Error::InternalErr(e.to_string()))?,
+        authed.username,
+        extra_perms
+    )
+    .execute(&mut tx)
+    .await?;
+
+    audit_log(
+        &mut tx,
+        &authed.username,
+        "scripts.draft.save",
+        ActionKind::Update,
+        &w_id,
+        Some(&ns.path),
+        None,
+    )
+    .await?;
+    tx.commit().await?;
+
+    Ok((StatusCode::CREATED, format!("draft of {} saved", ns.path)))
+}
+
+pub async fn get_draft<'c>(
+    db: &mut Transaction<'c, Postgres>,
+    w_id: &str,
+    path: &str,
+) -> Result<NewScript> {
+    let value_o = sqlx::query_scalar!(
+        "SELECT value FROM script_draft WHERE path = $1 AND workspace_id = $2",
+        path,
+        w_id
+    )
+    .fetch_optional(db)
+    .await?;
+    let value = crate::utils::not_found_if_none(value_o, "ScriptDraft", path)?;
+    serde_json::from_value::<NewScript>(value)
+        .map_err(|e| Error::InternalErr(format!("could not convert json to draft for {path}: {e}")))
+}
+
+async fn get_draft_by_path(
+    authed: Authed,
+    Extension(user_db): Extension<UserDB>,
+    Path((w_id, path)): Path<(String, StripPath)>,
+) -> JsonResult<ScriptDraft> {
+    let path = path.to_path();
+    let mut tx = user_db.begin(&authed).await?;
+
+    let draft_o = sqlx::query_as!(
+        ScriptDraft,
+        "SELECT * FROM script_draft WHERE path = $1 AND workspace_id = $2",
+        path,
+        w_id
+    )
+    .fetch_optional(&mut tx)
+    .await?;
+    tx.commit().await?;
+
+    let draft = crate::utils::not_found_if_none(draft_o, "ScriptDraft", path)?;
+    Ok(Json(draft))
+}
+
+async fn delete_draft_by_path(
+    authed: Authed,
+    Extension(user_db): Extension<UserDB>,
+    Path((w_id, path)): Path<(String, StripPath)>,
+) -> Result<String> {
+    let path = path.to_path();
+    let mut tx = user_db.begin(&authed).await?;
+
+    let deleted = sqlx::query_scalar!(
+        "DELETE FROM script_draft WHERE path = $1 AND workspace_id = $2 RETURNING path",
+        path,
+        w_id
+    )
+    .fetch_optional(&mut tx)
+    .await?;
+    crate::utils::not_found_if_none(deleted, "ScriptDraft", path)?;
+
+    audit_log(
+        &mut tx,
+        &authed.username,
+        "scripts.draft.delete",
+        ActionKind::Delete,
+        &w_id,
+        Some(path),
+        None,
+    )
+    .await?;
+    tx.commit().await?;
+
+    Ok(format!("draft of {} deleted", path))
+}
+
+async fn deploy_draft_by_path(
+    authed: Authed,
+    Tokened { token }: Tokened,
+    Extension(user_db): Extension<UserDB>,
+    Path((w_id, path)): Path<(String, StripPath)>,
+) -> Result<(StatusCode, String)> {
+    let path = path.to_path();
+    let mut tx = user_db.begin(&authed).await?;
+
+    let ns = get_draft(&mut tx, &w_id, path).await?;
+    if ns.path != path {
+        return Err(Error::BadRequest(format!(
+            "draft at {path} targets another path: {}",
+            ns.path
+        )));
+    }
+    let (hash, mut tx) = create_script_internal(ns, &w_id, &authed, &token, tx).await?;
+
+    sqlx::query!(
+        "DELETE FROM script_draft WHERE path = $1 AND workspace_id = $2",
+        path,
+        w_id
+    )
+    .execute(&mut tx)
+    .await?;
+
+    audit_log(
+        &mut tx,
+        &authed.username,
+        "scripts.deploy",
+        ActionKind::Update,
+        &w_id,
+        Some(path),
+        Some([("hash", hash.to_string().as_str())].into()),
+    )
+    .await?;
+    let git_sync = git_sync::script_commit(&mut tx, &w_id, &hash).await?;
+    tx.commit().await?;
+    git_sync::spawn_commit(git_sync, &authed);
+
+    Ok((StatusCode::CREATED, format!("{}", hash)))
+}
+
 async fn archive_script_by_path(
     authed: Authed,
     Extension(user_db): Extension<UserDB>,
@@ -550,7 +1246,9 @@ async fn archive_script_by_path(
         Some([("workspace", w_id.as_str())].into()),
     )
     .await?;
+    let git_sync = git_sync::script_removal_commit(&mut tx, &w_id, path).await?;
     tx.commit().await?;
+    git_sync::spawn_commit(git_sync, &authed);
 
     Ok(())
 }
@@ -578,7 +1276,9 @@ async fn archive_script_by_hash(
         Some([("workspace", w_id.as_str())].into()),
     )
     .await?;
+    let git_sync = git_sync::script_removal_commit(&mut tx, &w_id, &script.path).await?;
     tx.commit().await?;
+    git_sync::spawn_commit(git_sync, &authed);
 
     Ok(Json(script))
 }
@@ -610,7 +1310,9 @@ async fn delete_script_by_hash(
         Some([("workspace", w_id.as_str())].into()),
     )
     .await?;
+    let git_sync = git_sync::script_removal_commit(&mut tx, &w_id, &script.path).await?;
     tx.commit().await?;
+    git_sync::spawn_commit(git_sync, &authed);
 
     Ok(Json(script))
 }
@@ -640,3 +1342,316 @@ pub fn to_i64(s: &str) -> Result<i64> {
 pub fn to_hex_string(i: &i64) -> String {
     hex::encode(i.to_be_bytes())
 }
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::db::{create_test_workspace, delete_test_workspace, test_db};
+
+    #[test]
+    fn test_unified_diff_insert_only() {
+        assert_eq!(
+            unified_diff("a\nb\n", "a\nx\nb\n", "old", "new"),
+            "--- old\n+++ new\n@@ -1,2 +1,3 @@\n a\n+x\n b\n"
+        );
+        assert_eq!(
+            unified_diff("", "a\nb\n", "old", "new"),
+            "--- old\n+++ new\n@@ -0,0 +1,2 @@\n+a\n+b\n"
+        );
+    }
+
+    #[test]
+    fn test_unified_diff_delete_only() {
+        assert_eq!(
+            unified_diff("a\nb\nc\n", "a\nc\n", "old", "new"),
+            "--- old\n+++ new\n@@ -1,3 +1,2 @@\n a\n-b\n c\n"
+        );
+        assert_eq!(
+            unified_diff("a\nb\n", "", "old", "new"),
+            "--- old\n+++ new\n@@ -1,2 +0,0 @@\n-a\n-b\n"
+        );
+    }
+
+    #[test]
+    fn test_unified_diff_identical() {
+        assert_eq!(
+            unified_diff("a\nb\n", "a\nb\n", "old", "new"),
+            "--- old\n+++ new\n"
+        );
+        assert_eq!(unified_diff("", "", "old", "new"), "--- old\n+++ new\n");
+    }
+
+    #[test]
+    fn test_unified_diff_trailing_newline() {
+        assert_eq!(
+            unified_diff("a\nb", "a\nb\n", "old", "new"),
+            "--- old\n+++ new\n@@ -1,2 +1,2 @@\n a\n-b\n\\ No newline at end of file\n+b\n"
+        );
+        assert_eq!(
+            unified_diff("a\nb\n", "a\nc", "old", "new"),
+            "--- old\n+++ new\n@@ -1,2 +1,2 @@\n a\n-b\n+c\n\\ No newline at end of file\n"
+        );
+    }
+
+    #[test]
+    fn test_check_limits() {
+        assert!(check_limits(None, None, None).is_ok());
+        assert!(check_limits(Some(1), Some(MIN_MEMORY_LIMIT_MB), Some(1)).is_ok());
+        assert!(check_limits(Some(0), None, None).is_err());
+        assert!(check_limits(None, None, Some(-1)).is_err());
+        assert!(check_limits(None, Some(0), None).is_err());
+        assert!(check_limits(None, Some(MIN_MEMORY_LIMIT_MB - 1), None).is_err());
+    }
+
+    #[test]
+    fn test_unified_diff_context() {
+        let old = (1..=10).map(|i| format!("{i}\n")).collect::<String>();
+        let new = old.replace("5\n", "x\n");
+        assert_eq!(
+            unified_diff(&old, &new, "old", "new"),
+            "--- old\n+++ new\n@@ -2,7 +2,7 @@\n 2\n 3\n 4\n-5\n+x\n 6\n 7\n 8\n"
+        );
+    }
+
+    fn alice() -> Authed {
+        Authed {
+            email: None,
+            username: "alice".to_string(),
+            is_admin: true,
+            groups: vec![],
+        }
+    }
+
+    fn draft_script(
+        parent_hash: Option<ScriptHash>,
+        result: i32,
+        timeout: Option<i32>,
+        memory_limit: Option<i32>,
+    ) -> NewScript {
+        NewScript {
+            path: "u/alice/draft".to_string(),
+            parent_hash,
+            summary: "".to_string(),
+            description: "".to_string(),
+            content: format!("export function main() {{ return {result} }}"),
+            schema: None,
+            is_template: None,
+            lock: None,
+            language: ScriptLang::Deno,
+            timeout,
+            memory_limit,
+            cpu_limit: None,
+            is_library: None,
+        }
+    }
+
+    /// the hash of the job the schedule of the script pushes next
+    async fn scheduled_hash(db: &DB, w_id: &str) -> i64 {
+        let mut tx = db.begin().await.unwrap();
+        let schedule = crate::schedule::get_schedule_opt(&mut tx, w_id, "u/alice/schedule")
+            .await
+            .unwrap()
+            .unwrap();
+        let mut tx = crate::schedule::push_scheduled_job(tx, schedule)
+            .await
+            .unwrap();
+        let hash: i64 = sqlx::query_scalar(
+            "DELETE FROM queue WHERE workspace_id = $1 AND schedule_path = 'u/alice/schedule' \
+             RETURNING script_hash",
+        )
+        .bind(w_id)
+        .fetch_one(&mut tx)
+        .await
+        .unwrap();
+        tx.commit().await.unwrap();
+        hash
+    }
+
+    /// a draft runs with its own content and limits and replaces the deployed script only once
+    /// deployed, the schedules of the script keep running the deployed version until then
+    #[tokio::test]
+    #[ignore = "needs DATABASE_URL, see test_db"]
+    async fn test_draft() {
+        let db = test_db().await;
+        let w_id = create_test_workspace(&db).await;
+        let user_db = UserDB::new(db.clone());
+
+        let tx = user_db.clone().begin(&alice()).await.unwrap();
+        let (deployed, tx) = create_script_internal(
+            draft_script(None, 1, None, None),
+            &w_id,
+            &alice(),
+            "test-token",
+            tx,
+        )
+        .await
+        .unwrap();
+        tx.commit().await.unwrap();
+        sqlx::query(
+            "INSERT INTO schedule (workspace_id, path, schedule, offset_, edited_by, script_path, is_flow) \
+             VALUES ($1, 'u/alice/schedule', '0 0 0 * * *', 0, 'alice', 'u/alice/draft', false)",
+        )
+        .bind(&w_id)
+        .execute(&db)
+        .await
+        .unwrap();
+
+        save_draft(
+            alice(),
+            Extension(user_db.clone()),
+            Path(w_id.clone()),
+            Json(draft_script(Some(deployed), 2, Some(10), Some(128))),
+        )
+        .await
+        .unwrap();
+        assert_eq!(scheduled_hash(&db, &w_id).await, deployed.0);
+
+        let draft_path = || serde_json::from_value::<StripPath>(json!("/u/alice/draft")).unwrap();
+        let (_, id) = jobs::run_draft_job(
+            alice(),
+            Extension(user_db.clone()),
+            Path((w_id.clone(), draft_path())),
+            Json(None),
+            Query(serde_json::from_value(json!({})).unwrap()),
+        )
+        .await
+        .unwrap();
+        let (raw_code, timeout, memory_limit): (Option<String>, Option<i32>, Option<i32>) =
+            sqlx::query_as("SELECT raw_code, timeout, memory_limit FROM queue WHERE id = $1")
+                .bind(uuid::Uuid::parse_str(&id).unwrap())
+                .fetch_one(&db)
+                .await
+                .unwrap();
+        assert_eq!(
+            raw_code.as_deref(),
+            Some("export function main() { return 2 }")
+        );
+        assert_eq!((timeout, memory_limit), (Some(10), Some(128)));
+
+        let (_, hash) = deploy_draft_by_path(
+            alice(),
+            Tokened {
+                token: "test-token".to_string(),
+            },
+            Extension(user_db.clone()),
+            Path((w_id.clone(), draft_path())),
+        )
+        .await
+        .unwrap();
+        let deployed = to_i64(&hash).unwrap();
+        let mut tx = user_db.clone().begin(&alice()).await.unwrap();
+        assert!(get_draft(&mut tx, &w_id, "u/alice/draft").await.is_err());
+        let script = sqlx::query_as::<_, Script>("SELECT * FROM script WHERE hash = $1")
+            .bind(deployed)
+            .fetch_one(&mut tx)
+            .await
+            .unwrap();
+        tx.commit().await.unwrap();
+        assert_eq!(script.content, "export function main() { return 2 }");
+        assert_eq!((script.timeout, script.memory_limit), (Some(10), Some(128)));
+        assert_eq!(scheduled_hash(&db, &w_id).await, deployed);
+
+        // the args of a run are validated against the schema of the draft
+        let mut draft = draft_script(Some(ScriptHash(deployed)), 3, None, None);
+        draft.schema = Some(Schema(json!({
+            "type": "object",
+            "properties": { "x": { "type": "integer" } },
+            "required": ["x"]
+        })));
+        save_draft(
+            alice(),
+            Extension(user_db.clone()),
+            Path(w_id.clone()),
+            Json(draft),
+        )
+        .await
+        .unwrap();
+        let run = jobs::run_draft_job(
+            alice(),
+            Extension(user_db.clone()),
+            Path((w_id.clone(), draft_path())),
+            Json(json!({ "x": "a" }).as_object().cloned()),
+            Query(serde_json::from_value(json!({})).unwrap()),
+        )
+        .await;
+        assert!(matches!(run, Err(Error::BadRequest(_))));
+
+        delete_test_workspace(&db, &w_id).await;
+    }
+
+    /// a library used as a flow step, by a schedule and by the Slack command, and imported by
+    /// another script. Mentioning its name without importing it is no usage
+    #[tokio::test]
+    #[ignore = "needs DATABASE_URL, see test_db"]
+    async fn test_script_usage() {
+        let db = test_db().await;
+        let w_id = create_test_workspace(&db).await;
+        for (hash, path, content, is_library) in [
+            (1, "u/alice/lib", "def helper():\n    return 1\n", true),
+            (2, "u/alice/importer", "import u.alice.lib\n", false),
+            (3, "u/alice/other", "# lib\nimport os\n", false),
+        ] {
+            sqlx::query(
+                "INSERT INTO script (workspace_id, hash, path, summary, description, content, \
+                 created_by, is_library) VALUES ($1, $2, $3, '', '', $4, 'alice', $5)",
+            )
+            .bind(&w_id)
+            .bind(hash)
+            .bind(path)
+            .bind(content)
+            .bind(is_library)
+            .execute(&db)
+            .await
+            .unwrap();
+        }
+        let flow = json!({
+            "modules": [{
+                "input_transform": {},
+                "value": {"type": "script", "path": "u/alice/lib"},
+            }],
+            "failure_module": null,
+        });
+        sqlx::query(
+            "INSERT INTO flow (workspace_id, path, summary, description, value, edited_by) \
+             VALUES ($1, 'u/alice/flow', '', '', $2, 'alice')",
+        )
+        .bind(&w_id)
+        .bind(flow)
+        .execute(&db)
+        .await
+        .unwrap();
+        for query in [
+            "INSERT INTO schedule (workspace_id, path, schedule, offset_, edited_by, script_path, is_flow) \
+             VALUES ($1, 'u/alice/schedule', '0 0 0 * * *', 0, 'alice', 'u/alice/lib', false)",
+            "INSERT INTO workspace_settings (workspace_id, slack_command_script) \
+             VALUES ($1, 'u/alice/lib')",
+        ] {
+            sqlx::query(query).bind(&w_id).execute(&db).await.unwrap();
+        }
+
+        let usage = |path: &str| {
+            get_script_usage(
+                alice(),
+                Extension(UserDB::new(db.clone())),
+                Path((
+                    w_id.clone(),
+                    serde_json::from_value::<StripPath>(json!(format!("/{path}"))).unwrap(),
+                )),
+            )
+        };
+        let Json(lib) = usage("u/alice/lib").await.unwrap();
+        assert_eq!(lib.flows, vec!["u/alice/flow"]);
+        assert_eq!(lib.schedules, vec!["u/alice/schedule"]);
+        assert!(lib.slack_command);
+        assert_eq!(lib.importing_scripts, vec!["u/alice/importer"]);
+
+        let Json(importer) = usage("u/alice/importer").await.unwrap();
+        assert!(importer.flows.is_empty());
+        assert!(importer.schedules.is_empty());
+        assert!(!importer.slack_command);
+        assert!(importer.importing_scripts.is_empty());
+
+        delete_test_workspace(&db, &w_id).await;
+    }
+}
@@ -0,0 +1,212 @@
+/*
+ * Author & Copyright: Ruben Fiszel 2021
+ * This file and its contents are licensed under the AGPLv3 License.
+ * Please see the included NOTICE for copyright information and
+ * LICENSE-AGPL for a copy of the license.
+ */
+
+use axum::{
+    extract::{Extension, Path, Query},
+    routing::get,
+    Json, Router,
+};
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+
+use crate::{
+    db::UserDB,
+    error::{Error, JsonResult},
+    users::Authed,
+    utils::Pagination,
+};
+
+const SEARCH_KINDS: [&str; 4] = ["script", "flow", "resource", "variable"];
+
+pub fn workspaced_service() -> Router {
+    Router::new().route("/", get(search))
+}
+
+#[derive(Deserialize)]
+pub struct SearchQuery {
+    pub q: String,
+    /// comma separated list of the kinds of items to search, all of them by default
+    pub kinds: Option<String>,
+}
+
+#[derive(FromRow, Serialize)]
+pub struct SearchResult {
+    pub kind: String,
+    pub path: String,
+    pub summary: String,
+    pub rank: f32,
+}
+
+/// rank the scripts, flows, resources and variables of a workspace matching the query. Every
+/// item is read through the user's transaction so that only the visible ones are returned.
+/// The content of scripts is searched but the values of resources and variables never are.
+async fn search(
+    authed: Authed,
+    Extension(user_db): Extension<UserDB>,
+    Path(w_id): Path<String>,
+    Query(pagination): Query<Pagination>,
+    Query(sq): Query<SearchQuery>,
+) -> JsonResult<Vec<SearchResult>> {
+    let (per_page, offset) = crate::utils::paginate(pagination);
+    let q = sq.q.trim();
+    if q.is_empty() {
+        return Err(Error::BadRequest(
+            "search query cannot be empty".to_string(),
+        ));
+    }
+    let kinds = sq
+        .kinds
+        .map(|x| {
+            x.split(',')
+                .map(|x| x.trim().to_string())
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_else(|| SEARCH_KINDS.iter().map(|x| x.to_string()).collect());
+    if let Some(kind) = kinds.iter().find(|x| !SEARCH_KINDS.contains(&x.as_str())) {
+        return Err(Error::BadRequest(format!(
+            "{kind} is not a searchable kind, expected one of {}",
+            SEARCH_KINDS.join(", ")
+        )));
+    }
+    let path_pattern = format!(
+        "%{}%",
+        q.replace('\\', "\\\\")
+            .replace('%', "\\%")
+            .replace('_', "\\_")
+    );
+
+    let mut tx = user_db.begin(&authed).await?;
+    // a match on the path ranks above any match on the text only. The text and the path matches
+    // are queried apart so that the text ones use the search indexes, an item matching both is
+    // returned once by the UNION as its rank is the same in both
+    let results = sqlx::query_as!(
+        SearchResult,
+        "WITH q AS (SELECT plainto_tsquery('simple', $2) AS query)
+        SELECT kind as \"kind!\", path as \"path!\", summary as \"summary!\", rank as \"rank!\" FROM (
+            SELECT 'script' AS kind, path, summary,
+                ts_rank(to_tsvector('simple', path || ' ' || summary || ' ' || description || ' ' || content), q.query)
+                + (CASE WHEN path ILIKE $3 THEN 1 ELSE 0 END)::real AS rank
+            FROM script, q WHERE workspace_id = $1 AND archived = false AND deleted = false
+                AND to_tsvector('simple', path || ' ' || summary || ' ' || description || ' ' || content) @@ q.query
+            UNION
+            SELECT 'script' AS kind, path, summary,
+                ts_rank(to_tsvector('simple', path || ' ' || summary || ' ' || description || ' ' || content), q.query)
+                + (CASE WHEN path ILIKE $3 THEN 1 ELSE 0 END)::real AS rank
+            FROM script, q WHERE workspace_id = $1 AND archived = false AND deleted = false
+                AND path ILIKE $3
+            UNION
+            SELECT 'flow' AS kind, path, summary,
+                ts_rank(to_tsvector('simple', path || ' ' || summary || ' ' || description), q.query)
+                + (CASE WHEN path ILIKE $3 THEN 1 ELSE 0 END)::real AS rank
+            FROM flow, q WHERE workspace_id = $1 AND archived = false
+                AND to_tsvector('simple', path || ' ' || summary || ' ' || description) @@ q.query
+            UNION
+            SELECT 'flow' AS kind, path, summary,
+                ts_rank(to_tsvector('simple', path || ' ' || summary || ' ' || description), q.query)
+                + (CASE WHEN path ILIKE $3 THEN 1 ELSE 0 END)::real AS rank
+            FROM flow, q WHERE workspace_id = $1 AND archived = false
+                AND path ILIKE $3
+            UNION
+            SELECT 'resource' AS kind, path, coalesce(description, '') AS summary,
+                ts_rank(to_tsvector('simple', path || ' ' || resource_type || ' ' || coalesce(description, '')), q.query)
+                + (CASE WHEN path ILIKE $3 THEN 1 ELSE 0 END)::real AS rank
+            FROM resource, q WHERE workspace_id = $1
+                AND to_tsvector('simple', path || ' ' || resource_type || ' ' || coalesce(description, '')) @@ q.query
+            UNION
+            SELECT 'resource' AS kind, path, coalesce(description, '') AS summary,
+                ts_rank(to_tsvector('simple', path || ' ' || resource_type || ' ' || coalesce(description, '')), q.query)
+                + (CASE WHEN path ILIKE $3 THEN 1 ELSE 0 END)::real AS rank
+            FROM resource, q WHERE workspace_id = $1
+                AND path ILIKE $3
+            UNION
+            SELECT 'variable' AS kind, path, description AS summary,
+                ts_rank(to_tsvector('simple', path || ' ' || description), q.query)
+                + (CASE WHEN path ILIKE $3 THEN 1 ELSE 0 END)::real AS rank
+            FROM variable, q WHERE workspace_id = $1
+                AND to_tsvector('simple', path || ' ' || description) @@ q.query
+            UNION
+            SELECT 'variable' AS kind, path, description AS summary,
+                ts_rank(to_tsvector('simple', path || ' ' || description), q.query)
+                + (CASE WHEN path ILIKE $3 THEN 1 ELSE 0 END)::real AS rank
+            FROM variable, q WHERE workspace_id = $1
+                AND path ILIKE $3
+        ) AS results
+        WHERE rank > 0 AND kind = ANY($4)
+        ORDER BY rank DESC, path
+        LIMIT $5 OFFSET $6",
+        &w_id,
+        q,
+        path_pattern,
+        &kinds,
+        per_page as i64,
+        offset as i64
+    )
+    .fetch_all(&mut tx)
+    .await?;
+    tx.commit().await?;
+
+    Ok(Json(results))
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::db::{create_test_workspace, delete_test_workspace, test_db};
+
+    #[tokio::test]
+    #[ignore = "needs DATABASE_URL, see test_db"]
+    async fn test_search_visibility() {
+        let db = test_db().await;
+        let w_id = create_test_workspace(&db).await;
+        for query in [
+            "INSERT INTO script (workspace_id, hash, path, summary, description, content, created_by) \
+             VALUES ($1, 1, 'u/alice/private', '', '', 'def main():\n    return \"needle\"\n', 'alice')",
+            "INSERT INTO script (workspace_id, hash, path, summary, description, content, created_by) \
+             VALUES ($1, 2, 'u/bob/own', '', '', 'def main():\n    return \"needle\"\n', 'bob')",
+            "INSERT INTO variable (workspace_id, path, value, is_secret, description) \
+             VALUES ($1, 'u/alice/needle', 'x', false, '')",
+            "INSERT INTO variable (workspace_id, path, value, is_secret, description) \
+             VALUES ($1, 'u/bob/needle', 'x', false, '')",
+        ] {
+            sqlx::query(query).bind(&w_id).execute(&db).await.unwrap();
+        }
+        let bob = Authed {
+            email: None,
+            username: "bob".to_string(),
+            is_admin: false,
+            groups: vec![],
+        };
+
+        let results = search(
+            bob,
+            Extension(UserDB::new(db.clone())),
+            Path(w_id.clone()),
+            Query(Pagination {
+                page: None,
+                per_page: None,
+            }),
+            Query(SearchQuery {
+                q: "needle".to_string(),
+                kinds: None,
+            }),
+        )
+        .await
+        .unwrap()
+        .0;
+        // the path match ranks first
+        assert_eq!(
+            results
+                .iter()
+                .map(|x| (x.kind.as_str(), x.path.as_str()))
+                .collect::<Vec<_>>(),
+            vec![("variable", "u/bob/needle"), ("script", "u/bob/own")]
+        );
+
+        delete_test_workspace(&db, &w_id).await;
+    }
+}
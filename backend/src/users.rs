@@ -10,13 +10,13 @@ use std::{sync::Arc, time::Duration};
 use crate::{
     audit::{audit_log, ActionKind},
     db::{UserDB, DB},
-    error::{Error, JsonResult, Result, self},
-    utils::{require_admin, require_super_admin, Pagination}
+    error::{self, Error, JsonResult, Result},
+    utils::{require_admin, require_super_admin, Pagination},
 };
 use argon2::{password_hash::SaltString, Argon2, PasswordHash, PasswordHasher, PasswordVerifier};
 use axum::{
     async_trait,
-    extract::{Extension, FromRequest, Path, RequestParts, Query},
+    extract::{Extension, FromRequest, Path, Query, RequestParts},
     http,
     routing::{delete, get, post},
     Json, Router,
@@ -25,7 +25,7 @@ use hyper::StatusCode;
 use rand::rngs::OsRng;
 use retainer::Cache;
 use serde::{Deserialize, Serialize};
-use sqlx::{FromRow};
+use sqlx::FromRow;
 use time::OffsetDateTime;
 use tower_cookies::{Cookie, Cookies};
 use tracing::Span;
@@ -48,8 +48,6 @@ pub fn workspaced_service() -> Router {
         .route("/leave", post(leave_workspace))
 }
 
-
-
 pub fn global_service() -> Router {
     Router::new()
         .route("/email", get(get_email))
@@ -65,13 +63,11 @@ pub fn global_service() -> Router {
         .route("/tokens/create", post(create_token))
         .route("/tokens/delete/:token_prefix", delete(delete_token))
         .route("/tokens/list", get(list_tokens))
-        // .route("/list_invite_codes", get(list_invite_codes))
-        // .route("/create_invite_code", post(create_invite_code))
-        // .route("/signup", post(signup))
-        // .route("/lost_password", post(lost_password))
-        // .route("/use_magic_link", get(use_magic_link))
-
-
+    // .route("/list_invite_codes", get(list_invite_codes))
+    // .route("/create_invite_code", post(create_invite_code))
+    // .route("/signup", post(signup))
+    // .route("/lost_password", post(lost_password))
+    // .route("/use_magic_link", get(use_magic_link))
 }
 
 pub fn make_unauthed_service() -> Router {
@@ -92,12 +88,13 @@ impl AuthCache {
     }
 
     pub async fn get_authed(&self, w_id: Option<String>, token: &str) -> Option<Authed> {
-        let key = (w_id.as_ref().unwrap_or(&"".to_string()).to_string(), token.to_string());
+        let key = (
+            w_id.as_ref().unwrap_or(&"".to_string()).to_string(),
+            token.to_string(),
+        );
         let s = self.cache.get(&key).await.map(|c| c.to_owned());
         match s {
-            a @ Some(_) => {
-                a
-            },
+            a @ Some(_) => a,
             None => {
                 let user_o = sqlx::query_as::<_, (Option<String>, Option<String>, bool)>(
                     "UPDATE token SET last_used_at = $1 WHERE token = $2 AND (expiration > NOW() OR expiration IS NULL) RETURNING owner, email, super_admin",
@@ -114,7 +111,7 @@ impl AuthCache {
                         match user {
                             (_, Some(email), super_admin) => {
                                 if w_id.is_some() {
-                                    let row_o = 
+                                    let row_o =
                                     sqlx::query_as::<_, (String, bool)>(
                                 "SELECT username, is_admin FROM usr where email = $1 AND workspace_id = $2",
                             )
@@ -124,30 +121,34 @@ impl AuthCache {
                             .await
                             .unwrap_or(Some(("error".to_string(), false)));
 
-                            match row_o {
-                                Some((username, is_admin)) => {
-                             let groups = get_groups_for_user(&w_id.as_ref().unwrap(),
-                                 &username, &self.db)
-                                .await
-                                .ok()
-                                .unwrap_or_default();
+                                    match row_o {
+                                        Some((username, is_admin)) => {
+                                            let groups = get_groups_for_user(
+                                                &w_id.as_ref().unwrap(),
+                                                &username,
+                                                &self.db,
+                                            )
+                                            .await
+                                            .ok()
+                                            .unwrap_or_default();
 
-                                    Some(Authed {
-                                        email: Some(email),
-                                        username,
-                                        is_admin: is_admin || super_admin,
-                                        groups,
-                                    })
-                                }, 
-                                None if super_admin || w_id.unwrap() == "starter" => Some(Authed {
-                                        email: Some(email.to_string()),
-                                        username: email,
-                                        is_admin: super_admin,
-                                        groups: vec![],
-                                    }),
-                                None => None
-                            
-                            }
+                                            Some(Authed {
+                                                email: Some(email),
+                                                username,
+                                                is_admin: is_admin || super_admin,
+                                                groups,
+                                            })
+                                        }
+                                        None if super_admin || w_id.unwrap() == "starter" => {
+                                            Some(Authed {
+                                                email: Some(email.to_string()),
+                                                username: email,
+                                                is_admin: super_admin,
+                                                groups: vec![],
+                                            })
+                                        }
+                                        None => None,
+                                    }
                                 } else {
                                     Some(Authed {
                                         email: Some(email.to_string()),
@@ -158,41 +159,8 @@ impl AuthCache {
                                 }
                             }
                             (Some(owner), _, super_admin) if w_id.is_some() => {
-                                if let Some((prefix, name)) = owner.split_once('/') {
-                                    if prefix == "u" {
-
-                                        let is_admin = super_admin || sqlx::query_scalar!(
-                                            "SELECT is_admin FROM usr where username = $1 AND workspace_id = $2",
-                                            name,
-                                            &w_id.as_ref().unwrap()
-                                        )
-                                        .fetch_one(&self.db)
-                                        .await
-                                        .ok()
-                                        .unwrap_or(false);
-
-                                        let groups = get_groups_for_user(&w_id.unwrap(), &name, &self.db)
-                                            .await
-                                            .ok()
-                                            .unwrap_or_default();
-
-                                        Some(Authed {
-                                            email: None,
-                                            username: name.to_string(),
-                                            is_admin,
-                                            groups,
-                                        })
-                                    } else {
-                                        Some(Authed {
-                                            email: None,
-                                            username: format!("group-{name}"),
-                                            is_admin: false,
-                                            groups: vec![name.to_string()],
-                                        })
-                                    }
-                                } else {
-                                    None
-                                }
+                                authed_for_owner(&self.db, &w_id.unwrap(), &owner, super_admin)
+                                    .await
                             }
                             _ => None,
                         }
@@ -217,7 +185,8 @@ impl AuthCache {
 
 async fn extract_token<B: Send>(req: &mut RequestParts<B>) -> Option<String> {
     let auth_header = req
-        .headers().get(http::header::AUTHORIZATION)
+        .headers()
+        .get(http::header::AUTHORIZATION)
         .and_then(|value| value.to_str().ok())
         .and_then(|s| s.strip_prefix("Bearer "));
 
@@ -248,14 +217,14 @@ where
             Ok(tokened.clone())
         } else {
             let token_o = extract_token(req).await;
-                if let Some(token) = token_o {
-                    let tokened = Self { token };
-                    req.extensions_mut().insert(tokened.clone());
-                    Ok(tokened)
-                } else {
-                    Err((StatusCode::UNAUTHORIZED, "Unauthorized".to_owned()))
-                }
+            if let Some(token) = token_o {
+                let tokened = Self { token };
+                req.extensions_mut().insert(tokened.clone());
+                Ok(tokened)
+            } else {
+                Err((StatusCode::UNAUTHORIZED, "Unauthorized".to_owned()))
             }
+        }
     }
 }
 
@@ -314,10 +283,9 @@ pub struct User {
     pub created_at: chrono::DateTime<chrono::Utc>,
     pub operator: bool,
     pub disabled: bool,
-    pub role: Option<String>
+    pub role: Option<String>,
 }
 
-
 #[derive(FromRow, Serialize)]
 pub struct GlobalUserInfo {
     email: String,
@@ -328,7 +296,6 @@ pub struct GlobalUserInfo {
     company: Option<String>,
 }
 
-
 #[derive(Serialize)]
 pub struct UserInfo {
     pub workspace_id: String,
@@ -340,7 +307,7 @@ pub struct UserInfo {
     pub groups: Vec<String>,
     pub operator: bool,
     pub disabled: bool,
-    pub role: Option<String>
+    pub role: Option<String>,
 }
 
 #[derive(FromRow, Serialize)]
@@ -382,7 +349,7 @@ pub struct NewUser {
     pub password: String,
     pub super_admin: bool,
     pub name: Option<String>,
-    pub company: Option<String>
+    pub company: Option<String>,
 }
 
 #[derive(Deserialize)]
@@ -433,7 +400,6 @@ pub struct Login {
     pub password: String,
 }
 
-
 #[derive(Deserialize)]
 pub struct Signup {
     pub email: String,
@@ -464,15 +430,17 @@ async fn exists_username(
     authed: Authed,
     Extension(user_db): Extension<UserDB>,
     Path(w_id): Path<String>,
-    Json(WorkspaceUsername { username }): Json<WorkspaceUsername>
+    Json(WorkspaceUsername { username }): Json<WorkspaceUsername>,
 ) -> JsonResult<bool> {
     let mut tx = user_db.begin(&authed).await?;
     let exists = sqlx::query_scalar!(
-        "SELECT EXISTS(SELECT 1 FROM usr WHERE workspace_id = $1 AND username = $2)", 
-        &w_id, &username)
-        .fetch_one(&mut tx)
-        .await?
-        .unwrap_or(false);
+        "SELECT EXISTS(SELECT 1 FROM usr WHERE workspace_id = $1 AND username = $2)",
+        &w_id,
+        &username
+    )
+    .fetch_one(&mut tx)
+    .await?
+    .unwrap_or(false);
     tx.commit().await?;
     Ok(Json(exists))
 }
@@ -480,7 +448,7 @@ async fn exists_username(
 async fn list_users(
     authed: Authed,
     Extension(user_db): Extension<UserDB>,
-    Path(w_id): Path<String>
+    Path(w_id): Path<String>,
 ) -> JsonResult<Vec<User>> {
     let mut tx = user_db.begin(&authed).await?;
     let rows = sqlx::query_as!(User, "SELECT * from usr WHERE workspace_id = $1", &w_id)
@@ -493,7 +461,7 @@ async fn list_users(
 async fn list_users_as_super_admin(
     authed: Authed,
     Extension(db): Extension<DB>,
-    Query(pagination): Query<Pagination>
+    Query(pagination): Query<Pagination>,
 ) -> JsonResult<Vec<GlobalUserInfo>> {
     let mut tx = db.begin().await?;
     require_super_admin(&mut tx, authed.email).await?;
@@ -506,7 +474,6 @@ async fn list_users_as_super_admin(
     Ok(Json(rows))
 }
 
-
 // async fn list_invite_codes(
 //     authed: Authed,
 //     Extension(db): Extension<DB>,
@@ -523,11 +490,10 @@ async fn list_users_as_super_admin(
 //     Ok(Json(rows))
 // }
 
-
 async fn list_usernames(
     authed: Authed,
     Extension(user_db): Extension<UserDB>,
-    Path(w_id): Path<String>
+    Path(w_id): Path<String>,
 ) -> JsonResult<Vec<String>> {
     let mut tx = user_db.begin(&authed).await?;
     let rows = sqlx::query_scalar!("SELECT username from usr WHERE workspace_id = $1", &w_id)
@@ -542,14 +508,17 @@ async fn list_invites(
     Extension(db): Extension<DB>,
 ) -> JsonResult<Vec<WorkspaceInvite>> {
     let mut tx = db.begin().await?;
-    let rows = sqlx::query_as!(WorkspaceInvite, "SELECT * from workspace_invite WHERE email = $1", authed.email)
-        .fetch_all(&mut tx)
-        .await?;
+    let rows = sqlx::query_as!(
+        WorkspaceInvite,
+        "SELECT * from workspace_invite WHERE email = $1",
+        authed.email
+    )
+    .fetch_all(&mut tx)
+    .await?;
     tx.commit().await?;
     Ok(Json(rows))
 }
 
-
 async fn logout(
     Tokened { token }: Tokened,
     cookies: Cookies,
@@ -578,7 +547,12 @@ async fn logout(
 async fn whoami(
     Extension(db): Extension<DB>,
     Path(w_id): Path<String>,
-    Authed { username, email, is_admin, groups }: Authed,
+    Authed {
+        username,
+        email,
+        is_admin,
+        groups,
+    }: Authed,
 ) -> JsonResult<UserInfo> {
     let user = get_user(&w_id, &username, &db).await?;
     if let Some(user) = user {
@@ -610,21 +584,29 @@ async fn global_whoami(
     Ok(Json(user))
 }
 
-async fn get_email(
-    Authed { email, .. }: Authed,
-) -> Result<String> {
-    let email = email.ok_or(Error::BadRequest("current session does not correspond to an user with email".to_string()))?;
+async fn get_email(Authed { email, .. }: Authed) -> Result<String> {
+    let email = email.ok_or(Error::BadRequest(
+        "current session does not correspond to an user with email".to_string(),
+    ))?;
     Ok(email)
 }
 
 async fn get_user(w_id: &str, username: &str, db: &DB) -> Result<Option<UserInfo>> {
-    let user = sqlx::query_as!(User, "SELECT * FROM usr where username = $1 AND workspace_id = $2", username, w_id)
-        .fetch_optional(db)
-        .await?;
-    let is_super_admin = sqlx::query_scalar!("SELECT super_admin FROM password WHERE email = $1", user.as_ref().map(|x| &x.email))
-        .fetch_optional(db)
-        .await?
-        .unwrap_or(false);
+    let user = sqlx::query_as!(
+        User,
+        "SELECT * FROM usr where username = $1 AND workspace_id = $2",
+        username,
+        w_id
+    )
+    .fetch_optional(db)
+    .await?;
+    let is_super_admin = sqlx::query_scalar!(
+        "SELECT super_admin FROM password WHERE email = $1",
+        user.as_ref().map(|x| &x.email)
+    )
+    .fetch_optional(db)
+    .await?
+    .unwrap_or(false);
     let groups = get_groups_for_user(&w_id, username, db).await?;
     Ok(user.map(|usr| UserInfo {
         groups,
@@ -636,33 +618,77 @@ async fn get_user(w_id: &str, username: &str, db: &DB) -> Result<Option<UserInfo
         created_at: usr.created_at,
         operator: usr.operator,
         disabled: usr.disabled,
-        role: usr.role
+        role: usr.role,
     }))
 }
 
 async fn get_groups_for_user(w_id: &str, username: &str, db: &DB) -> Result<Vec<String>> {
-    let groups = sqlx::query_scalar!("SELECT group_ FROM usr_to_group where usr = $1 AND workspace_id = $2", username, w_id)
-        .fetch_all(db)
-        .await?;
+    let groups = sqlx::query_scalar!(
+        "SELECT group_ FROM usr_to_group where usr = $1 AND workspace_id = $2",
+        username,
+        w_id
+    )
+    .fetch_all(db)
+    .await?;
     Ok(groups)
 }
 
-async fn whois(Extension(db): Extension<DB>, Path((w_id, username)): Path<(String, String)>) -> JsonResult<UserInfo> {
+/// identity a job or a token owned by `owner` (`u/<username>` or `g/<group>`) acts with
+pub async fn authed_for_owner(
+    db: &DB,
+    w_id: &str,
+    owner: &str,
+    super_admin: bool,
+) -> Option<Authed> {
+    let (prefix, name) = owner.split_once('/')?;
+    if prefix == "u" {
+        let is_admin = super_admin
+            || sqlx::query_scalar!(
+                "SELECT is_admin FROM usr where username = $1 AND workspace_id = $2",
+                name,
+                w_id
+            )
+            .fetch_one(db)
+            .await
+            .ok()
+            .unwrap_or(false);
+
+        let groups = get_groups_for_user(w_id, name, db)
+            .await
+            .ok()
+            .unwrap_or_default();
+
+        Some(Authed {
+            email: None,
+            username: name.to_string(),
+            is_admin,
+            groups,
+        })
+    } else {
+        Some(Authed {
+            email: None,
+            username: format!("group-{name}"),
+            is_admin: false,
+            groups: vec![name.to_string()],
+        })
+    }
+}
+
+async fn whois(
+    Extension(db): Extension<DB>,
+    Path((w_id, username)): Path<(String, String)>,
+) -> JsonResult<UserInfo> {
     let user_o = get_user(&w_id, &username, &db).await?;
     let user = crate::utils::not_found_if_none(user_o, "User", username)?;
     Ok(Json(user))
 }
 
-
-
-
 // async fn create_invite_code(
 //     Authed { email, .. }: Authed,
 //     Extension(db): Extension<DB>,
 //     Json(nu): Json<NewInviteCode>,
 // ) -> Result<(StatusCode, String)> {
 
-
 //     let mut tx = db.begin().await?;
 //     require_super_admin(&mut tx, email).await?;
 
@@ -689,7 +715,6 @@ async fn decline_invite(
     Extension(db): Extension<DB>,
     Json(nu): Json<DeclineInvite>,
 ) -> Result<(StatusCode, String)> {
-
     let mut tx = db.begin().await?;
 
     let email = email.unwrap_or("".to_string());
@@ -702,8 +727,8 @@ async fn decline_invite(
     .await?;
 
     audit_log(
-         &mut tx,
-         &email,
+        &mut tx,
+        &email,
         "users.decline_invite",
         ActionKind::Create,
         &nu.workspace_id,
@@ -716,7 +741,10 @@ async fn decline_invite(
     if is_admin.is_some() {
         Ok((
             StatusCode::OK,
-            format!("user {} declined invite to workspace {}", &email, nu.workspace_id),
+            format!(
+                "user {} declined invite to workspace {}",
+                &email, nu.workspace_id
+            ),
         ))
     } else {
         Err(Error::NotFound(format!("invite for {email} not found")))
@@ -729,7 +757,7 @@ async fn accept_invite(
     Json(nu): Json<AcceptInvite>,
 ) -> Result<(StatusCode, String)> {
     if &nu.username == "bot" {
-        return Err(Error::BadRequest("bot is a reserved username".to_string()))
+        return Err(Error::BadRequest("bot is a reserved username".to_string()));
     }
     let mut tx = db.begin().await?;
 
@@ -747,7 +775,7 @@ async fn accept_invite(
     }
 
     audit_log(
-         &mut tx,
+        &mut tx,
         &nu.username,
         "users.accept_invite",
         ActionKind::Create,
@@ -761,14 +789,23 @@ async fn accept_invite(
     if is_admin.is_some() {
         Ok((
             StatusCode::CREATED,
-            format!("user {} accepted invite to workspace {}", &email, nu.workspace_id),
+            format!(
+                "user {} accepted invite to workspace {}",
+                &email, nu.workspace_id
+            ),
         ))
     } else {
         Err(Error::NotFound(format!("invite for {email} not found")))
     }
 }
 
-async fn add_user_to_workspace<'c>(w_id: &str, email: &str, username: &str, is_admin: bool, mut tx: sqlx::Transaction<'c, sqlx::Postgres>) -> error::Result<sqlx::Transaction<'c, sqlx::Postgres>> {
+async fn add_user_to_workspace<'c>(
+    w_id: &str,
+    email: &str,
+    username: &str,
+    is_admin: bool,
+    mut tx: sqlx::Transaction<'c, sqlx::Postgres>,
+) -> error::Result<sqlx::Transaction<'c, sqlx::Postgres>> {
     sqlx::query!(
         "INSERT INTO usr
             (workspace_id, email, username, is_admin)
@@ -790,7 +827,7 @@ async fn add_user_to_workspace<'c>(w_id: &str, email: &str, username: &str, is_a
     .execute(&mut tx)
     .await?;
     audit_log(
-         &mut tx,
+        &mut tx,
         username,
         "users.add_to_workspace",
         ActionKind::Create,
@@ -803,7 +840,9 @@ async fn add_user_to_workspace<'c>(w_id: &str, email: &str, username: &str, is_a
 }
 
 async fn update_workspace_user(
-    Authed { username, is_admin, .. }: Authed,
+    Authed {
+        username, is_admin, ..
+    }: Authed,
     Extension(db): Extension<DB>,
     Path((w_id, username_to_update)): Path<(String, String)>,
     Json(eu): Json<EditWorkspaceUser>,
@@ -813,7 +852,7 @@ async fn update_workspace_user(
     require_admin(is_admin, &username)?;
 
     if let Some(a) = eu.is_admin {
-            sqlx::query_scalar!(
+        sqlx::query_scalar!(
             "UPDATE usr SET is_admin = $1 WHERE username = $2 AND workspace_id = $3",
             a,
             &username_to_update,
@@ -848,7 +887,7 @@ async fn update_user(
     require_super_admin(&mut tx, email.clone()).await?;
 
     if let Some(sa) = eu.is_super_admin {
-            sqlx::query_scalar!(
+        sqlx::query_scalar!(
             "UPDATE password SET super_admin = $1 WHERE email = $2",
             sa,
             &email_to_update
@@ -894,7 +933,6 @@ async fn create_user(
     .execute(&mut tx)
     .await?;
 
-
     audit_log(
         &mut tx,
         &email.unwrap(),
@@ -909,14 +947,15 @@ async fn create_user(
     Ok((StatusCode::CREATED, format!("email {} created", nu.email)))
 }
 
-
 pub fn owner_to_token_owner(user: &str, is_group: bool) -> String {
     let prefix = if is_group { 'g' } else { 'u' };
     format!("{}/{}", prefix, user)
 }
 
 async fn delete_user(
-    Authed { username, is_admin, .. }: Authed,
+    Authed {
+        username, is_admin, ..
+    }: Authed,
     Extension(db): Extension<DB>,
     Path((w_id, username_to_delete)): Path<(String, String)>,
 ) -> Result<String> {
@@ -924,7 +963,6 @@ async fn delete_user(
 
     require_admin(is_admin, &username)?;
 
-
     let email_to_delete_o = sqlx::query_scalar!(
         "SELECT email FROM usr where username = $1 AND workspace_id = $2",
         username_to_delete,
@@ -957,22 +995,29 @@ async fn delete_user(
 async fn set_password(
     Extension(db): Extension<DB>,
     Extension(argon2): Extension<Arc<Argon2<'_>>>,
-    Authed { username, email, .. }: Authed,
+    Authed {
+        username, email, ..
+    }: Authed,
     Json(EditPassword { password }): Json<EditPassword>,
 ) -> Result<String> {
     let mut tx = db.begin().await?;
-    let email = email.ok_or("no_email").map_err(|e| Error::NotAuthorized(e.to_string()))?;
+    let email = email
+        .ok_or("no_email")
+        .map_err(|e| Error::NotAuthorized(e.to_string()))?;
 
     let custom_type = sqlx::query_scalar!(
-            "SELECT login_type::TEXT FROM password WHERE email = $1",
-        &email)
-        .fetch_one(&mut tx)
-        .await?
-        .unwrap_or("".to_string());
-    
+        "SELECT login_type::TEXT FROM password WHERE email = $1",
+        &email
+    )
+    .fetch_one(&mut tx)
+    .await?
+    .unwrap_or("".to_string());
+
     if custom_type != "password".to_string() {
-        return Err(Error::BadRequest(format!("login type for {email} is of type {custom_type}. Cannot set password.")))
-    } 
+        return Err(Error::BadRequest(format!(
+            "login type for {email} is of type {custom_type}. Cannot set password."
+        )));
+    }
 
     sqlx::query!(
         "UPDATE password SET password_hash = $1 WHERE email = $2",
@@ -1013,7 +1058,6 @@ pub fn hash_password(argon2: Arc<Argon2>, password: String) -> Result<String> {
     Ok(password_hash)
 }
 
-
 // async fn lost_password(
 //     Extension(db): Extension<DB>,
 //     Extension(es): Extension<Arc<EmailSender>>,
@@ -1033,7 +1077,7 @@ pub fn hash_password(argon2: Arc<Argon2>, password: String) -> Result<String> {
 
 //     if !exists {
 //         return Err(Error::NotFound(format!("no user found at email {email}")))
-//     } 
+//     }
 
 //     let already = sqlx::query_scalar!(
 //             "SELECT EXISTS(SELECT 1 FROM magic_link WHERE email = $1)",
@@ -1083,7 +1127,6 @@ pub fn hash_password(argon2: Arc<Argon2>, password: String) -> Result<String> {
 //     }
 // }
 
-
 // async fn signup(
 //     TypedHeader(host): TypedHeader<headers::Host>,
 //     Extension(db): Extension<DB>,
@@ -1098,14 +1141,12 @@ pub fn hash_password(argon2: Arc<Argon2>, password: String) -> Result<String> {
 // ) -> Result<(StatusCode, String)> {
 //     let mut tx = db.begin().await?;
 
-
 //     let email = sqlx::query_scalar!(
 //             "INSERT INTO password (email, password_hash, name, company) VALUES ($1, $2, $3, $4) RETURNING email",
 //         &email, &hash_password(argon2, password)?, name, company)
 //         .fetch_optional(&mut tx)
 //         .await?;
 
-
 //     if let Some(email) = email {
 //         let tx = create_magic_link(&host.hostname(), &email, &es, tx).await?;
 //         tx.commit().await?;
@@ -1119,7 +1160,6 @@ pub fn hash_password(argon2: Arc<Argon2>, password: String) -> Result<String> {
 //     }
 // }
 
-
 // async fn create_magic_link<'c>(host: &str, email: &str, es: &EmailSender, mut tx: sqlx::Transaction<'c, sqlx::Postgres>) -> error::Result<sqlx::Transaction<'c, sqlx::Postgres>> {
 //     let token = gen_token();
 
@@ -1132,7 +1172,7 @@ pub fn hash_password(argon2: Arc<Argon2>, password: String) -> Result<String> {
 //     )
 //     .execute(&mut tx)
 //     .await?;
-    
+
 //     let encoded_token = urlencoding::encode(&token);
 //     let encoded_email = urlencoding::encode(email);
 //     es.send_email(Message::builder()
@@ -1158,14 +1198,10 @@ async fn login(
     cookies: Cookies,
     Extension(db): Extension<DB>,
     Extension(argon2): Extension<Arc<Argon2<'_>>>,
-    Json(Login {
-        email,
-        password,
-    }): Json<Login>,
+    Json(Login { email, password }): Json<Login>,
 ) -> Result<String> {
     let mut tx = db.begin().await?;
 
-
     let email_w_h: Option<(String,  String, bool)> = sqlx::query_as(
             "SELECT email, password_hash, super_admin FROM password WHERE email = $1 AND login_type = 'password'",
         )
@@ -1191,7 +1227,12 @@ async fn login(
     }
 }
 
-pub async fn create_session_token<'c>(email: &str, super_admin: bool, tx: &mut sqlx::Transaction<'c, sqlx::Postgres>, cookies: Cookies) -> Result<String> {
+pub async fn create_session_token<'c>(
+    email: &str,
+    super_admin: bool,
+    tx: &mut sqlx::Transaction<'c, sqlx::Postgres>,
+    cookies: Cookies,
+) -> Result<String> {
     let token = gen_token();
     sqlx::query!(
         "INSERT INTO token
@@ -1239,8 +1280,11 @@ pub async fn create_token_for_owner(
         .map(char::from)
         .collect();
     let mut tx = db.begin().await?;
-    let is_super_admin = username.contains('@') && sqlx::query_scalar!("SELECT super_admin FROM password WHERE email = $1",
-     owner.split_once('/').map(|x| x.1).unwrap_or(""))
+    let is_super_admin = username.contains('@')
+        && sqlx::query_scalar!(
+            "SELECT super_admin FROM password WHERE email = $1",
+            owner.split_once('/').map(|x| x.1).unwrap_or("")
+        )
         .fetch_optional(&mut tx)
         .await?
         .unwrap_or(false);
@@ -1285,16 +1329,19 @@ pub async fn create_token_for_owner(
 
 async fn create_token(
     Extension(db): Extension<DB>,
-    Authed { email,.. }: Authed,
+    Authed { email, .. }: Authed,
     Json(new_token): Json<NewToken>,
 ) -> Result<(StatusCode, String)> {
     let token = gen_token();
     let mut tx = db.begin().await?;
-    let email = email.ok_or_else(|| error::Error::BadRequest(format!("Only users with email can create tokens")))?;
-    let is_super_admin = sqlx::query_scalar!("SELECT super_admin FROM password WHERE email = $1", email)
-        .fetch_optional(&mut tx)
-        .await?
-        .unwrap_or(false);
+    let email = email.ok_or_else(|| {
+        error::Error::BadRequest(format!("Only users with email can create tokens"))
+    })?;
+    let is_super_admin =
+        sqlx::query_scalar!("SELECT super_admin FROM password WHERE email = $1", email)
+            .fetch_optional(&mut tx)
+            .await?
+            .unwrap_or(false);
     sqlx::query!(
         "INSERT INTO token
             (token, email, label, expiration, super_admin)
@@ -1343,7 +1390,9 @@ async fn delete_token(
     Path(token_prefix): Path<String>,
 ) -> Result<String> {
     let mut tx = db.begin().await?;
-    let email = email.ok_or_else(|| error::Error::BadRequest(format!("Only users with email can create tokens")))?;
+    let email = email.ok_or_else(|| {
+        error::Error::BadRequest(format!("Only users with email can create tokens"))
+    })?;
     let tokens_deleted: Vec<String> = sqlx::query_scalar(
         "DELETE FROM token WHERE email = $1 AND
      token LIKE concat($3, '%') RETURNING concat(substring(token for 10), '*****')",
@@ -1388,13 +1437,13 @@ async fn leave_workspace(
     .await?;
 
     audit_log(
-         &mut tx,
+        &mut tx,
         &username,
         "users.leave_workspace",
         ActionKind::Delete,
         &w_id,
-None,
-None,
+        None,
+        None,
     )
     .await?;
     tx.commit().await?;
@@ -1402,7 +1451,6 @@ None,
     Ok(format!("left workspace {w_id}"))
 }
 
-
 pub async fn delete_expired_items_perdiodically(
     db: &DB,
     mut rx: tokio::sync::broadcast::Receiver<()>,
@@ -1416,12 +1464,11 @@ pub async fn delete_expired_items_perdiodically(
         .fetch_all(db)
         .await;
 
-
         match tokens_deleted_r {
             Ok(tokens) => tracing::info!("deleted {} tokens: {:?}", tokens.len(), tokens),
             Err(e) => tracing::error!("Error deleting token: {}", e.to_string()),
         }
-        
+
         let magic_links_deleted_r: std::result::Result<Vec<String>, _> = sqlx::query_scalar(
             "DELETE FROM magic_link WHERE expiration <= $1
         RETURNING concat(substring(token for 10), '*****')",
@@ -1435,6 +1482,11 @@ pub async fn delete_expired_items_perdiodically(
             Err(e) => tracing::error!("Error deleting token: {}", e.to_string()),
         }
 
+        match crate::workspaces::delete_expired_previous_keys(db).await {
+            Ok(w_ids) => tracing::info!("deleted the expired previous keys of {:?}", w_ids),
+            Err(e) => tracing::error!("Error deleting previous keys: {}", e.to_string()),
+        }
+
         tokio::select! {
             _ = tokio::time::sleep(Duration::from_secs(600))     => (),
             _ = rx. recv() => {
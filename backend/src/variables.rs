@@ -9,8 +9,11 @@ use crate::{
     audit::{audit_log, ActionKind},
     db::{UserDB, DB},
     error::{Error, JsonResult, Result},
+    external_secrets::ExternalSource,
+    git_sync,
     users::Authed,
-    utils::StripPath,
+    utils::{require_admin, StripPath},
+    BaseUrl,
 };
 use axum::{
     extract::{Extension, Path, Query},
@@ -31,13 +34,17 @@ pub fn workspaced_service() -> Router {
         .route("/update/*path", post(update_variable))
         .route("/delete/*path", delete(delete_variable))
         .route("/create", post(create_variable))
+        .route("/history/p/*path", get(get_variable_history))
+        .route("/get/v/:version", get(get_variable_version))
+        .route("/restore/v/:version", post(restore_variable))
 }
 
-#[derive(Serialize, Clone)]
+#[derive(Serialize, Deserialize, Clone)]
 
 pub struct ContextualVariable {
     pub name: String,
     pub value: String,
+    #[serde(default)]
     pub description: String,
 }
 
@@ -50,6 +57,28 @@ pub struct ListableVariable {
     pub is_secret: bool,
     pub description: String,
     pub extra_perms: serde_json::Value,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub external: Option<serde_json::Value>,
+}
+
+#[derive(FromRow, Serialize)]
+pub struct VariableVersion {
+    pub id: i64,
+    pub workspace_id: String,
+    pub path: String,
+    pub value: Option<String>,
+    pub is_secret: bool,
+    pub description: String,
+    pub external: Option<serde_json::Value>,
+    pub created_by: String,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(FromRow, Serialize)]
+pub struct VariableHistory {
+    pub id: i64,
+    pub created_by: String,
+    pub created_at: chrono::DateTime<chrono::Utc>,
 }
 
 #[derive(Deserialize)]
@@ -58,6 +87,8 @@ pub struct CreateVariable {
     pub value: String,
     pub is_secret: bool,
     pub description: String,
+    #[serde(default)]
+    pub external: Option<ExternalSource>,
 }
 
 #[derive(Deserialize)]
@@ -66,60 +97,125 @@ struct EditVariable {
     value: Option<String>,
     is_secret: Option<bool>,
     description: Option<String>,
+    external: Option<ExternalSource>,
 }
 
-pub fn get_reserved_variables(
-    w_id: &str,
-    token: &str,
-    email: &str,
-    username: &str,
-    job_id: &str,
-) -> [ContextualVariable; 5] {
+/// what a job knows about itself, exposed to it as reserved variables. The fields that do not apply
+/// to the job are left unset
+pub struct JobContext<'a> {
+    pub w_id: &'a str,
+    pub token: &'a str,
+    pub email: &'a str,
+    pub username: &'a str,
+    pub job_id: &'a str,
+    pub flow_job_id: Option<String>,
+    pub flow_step: Option<i32>,
+    pub schedule_path: Option<&'a str>,
+    pub script_path: Option<&'a str>,
+    pub script_hash: Option<String>,
+    pub base_url: &'a str,
+}
+
+pub fn get_reserved_variables(ctx: &JobContext) -> [ContextualVariable; 11] {
     [
         ContextualVariable {
             name: "WM_WORKSPACE".to_string(),
-            value: w_id.to_string(),
+            value: ctx.w_id.to_string(),
             description: "Workspace id of the current script".to_string()
         },
         ContextualVariable {
             name: "WM_TOKEN".to_string(),
-            value: token.to_string(),
+            value: ctx.token.to_string(),
             description: "Token ephemeral to the current script with equal permission to the permission of the run (Usable as a bearer token)".to_string()
         },
         ContextualVariable {
             name: "WM_EMAIL".to_string(),
-            value: email.to_string(),
+            value: ctx.email.to_string(),
             description: "Email of the user that executed the current script".to_string()
         },
         ContextualVariable {
             name: "WM_USERNAME".to_string(),
-            value: username.to_string(),
+            value: ctx.username.to_string(),
             description: "Username of the user that executed the current script".to_string()
         },
         ContextualVariable {
             name: "WM_JOB_ID".to_string(),
-            value: job_id.to_string(),
+            value: ctx.job_id.to_string(),
             description: "Job id of the current script".to_string()
         },
+        ContextualVariable {
+            name: "WM_FLOW_JOB_ID".to_string(),
+            value: ctx.flow_job_id.clone().unwrap_or_default(),
+            description: "Job id of the flow the current script is a step of, empty outside of a flow".to_string()
+        },
+        ContextualVariable {
+            name: "WM_FLOW_STEP_INDEX".to_string(),
+            value: ctx.flow_step.map(|x| x.to_string()).unwrap_or_default(),
+            description: "Index of the step of the flow the current script runs as, empty outside of a flow".to_string()
+        },
+        ContextualVariable {
+            name: "WM_SCHEDULE_PATH".to_string(),
+            value: ctx.schedule_path.unwrap_or_default().to_string(),
+            description: "Path of the schedule that triggered the current script, empty if not scheduled".to_string()
+        },
+        ContextualVariable {
+            name: "WM_SCRIPT_PATH".to_string(),
+            value: ctx.script_path.unwrap_or_default().to_string(),
+            description: "Path of the current script, empty for a preview".to_string()
+        },
+        ContextualVariable {
+            name: "WM_SCRIPT_HASH".to_string(),
+            value: ctx.script_hash.clone().unwrap_or_default(),
+            description: "Hash of the current script, empty for a preview".to_string()
+        },
+        ContextualVariable {
+            name: "WM_BASE_URL".to_string(),
+            value: ctx.base_url.to_string(),
+            description: "Base url of windmill, as reachable from the jobs".to_string()
+        },
     ]
 }
 
+/// env vars defined by the admins of a workspace and injected into every job of the workspace
+pub async fn get_workspace_env(db: &DB, w_id: &str) -> Result<Vec<ContextualVariable>> {
+    let extra_env = sqlx::query_scalar!(
+        "SELECT extra_env FROM workspace_settings WHERE workspace_id = $1",
+        w_id
+    )
+    .fetch_optional(db)
+    .await?;
+    match extra_env {
+        Some(extra_env) => serde_json::from_value(extra_env)
+            .map_err(|e| Error::InternalErr(format!("invalid env of workspace {w_id}: {e}"))),
+        None => Ok(vec![]),
+    }
+}
+
 async fn list_contextual_variables(
     Path(w_id): Path<String>,
     Authed {
         username, email, ..
     }: Authed,
+    Extension(db): Extension<DB>,
+    Extension(base_url): Extension<BaseUrl>,
 ) -> JsonResult<Vec<ContextualVariable>> {
-    Ok(Json(
-        get_reserved_variables(
-            &w_id,
-            "q1A0qcPuO00yxioll7iph76N9CJDqn",
-            &email.unwrap_or_else(|| "no email".to_string()),
-            &username,
-            "017e0ad5-f499-73b6-5488-92a61c5196dd",
-        )
-        .to_vec(),
-    ))
+    let email = email.unwrap_or_else(|| "no email".to_string());
+    let mut variables = get_reserved_variables(&JobContext {
+        w_id: &w_id,
+        token: "q1A0qcPuO00yxioll7iph76N9CJDqn",
+        email: &email,
+        username: &username,
+        job_id: "017e0ad5-f499-73b6-5488-92a61c5196dd",
+        flow_job_id: Some("017e0ad5-f499-73b6-5488-92a61c5196de".to_string()),
+        flow_step: Some(0),
+        schedule_path: Some("u/user/schedule"),
+        script_path: Some("u/user/script"),
+        script_hash: Some("3a2b4f9c1e0d8a7b".to_string()),
+        base_url: &base_url.0,
+    })
+    .to_vec();
+    variables.extend(get_workspace_env(&db, &w_id).await?);
+    Ok(Json(variables))
 }
 
 async fn list_variables(
@@ -130,7 +226,7 @@ async fn list_variables(
     let mut tx = user_db.begin(&authed).await?;
 
     let rows = sqlx::query_as::<_, ListableVariable>(
-        "SELECT workspace_id, path, CASE WHEN is_secret IS TRUE THEN null ELSE value::text END as value, is_secret, description, extra_perms from variable
+        "SELECT workspace_id, path, CASE WHEN is_secret IS TRUE THEN null ELSE value::text END as value, is_secret, description, extra_perms, external from variable
          WHERE (workspace_id = $1 OR (is_secret IS NOT TRUE AND workspace_id = 'starter')) ORDER BY path",
     )
     .bind(&w_id)
@@ -167,7 +263,14 @@ async fn get_variable(
 
     let decrypt_secret = q.decrypt_secret.unwrap_or(true);
 
-    let r = if variable.is_secret {
+    let r = if let Some(external) = &variable.external {
+        let value = if decrypt_secret {
+            Some(read_external(&mut tx, &authed.username, &w_id, path, external, None).await?)
+        } else {
+            None
+        };
+        ListableVariable { value, ..variable }
+    } else if variable.is_secret {
         audit_log(
             &mut tx,
             &authed.username,
@@ -205,10 +308,19 @@ async fn create_variable(
     Path(w_id): Path<String>,
     Json(variable): Json<CreateVariable>,
 ) -> Result<(StatusCode, String)> {
+    if variable.external.is_some() {
+        require_admin(authed.is_admin, &authed.username)?;
+    }
+    let external = validate_external(
+        variable.external.as_ref(),
+        &variable.value,
+        variable.is_secret,
+    )?;
+
     let mut tx = user_db.begin(&authed).await?;
 
     let value = if variable.is_secret {
-        let mc = build_crypt(&mut tx, &w_id).await?;
+        let mc = build_crypt_for_write(&mut tx, &w_id).await?;
         encrypt(&mc, variable.value)
     } else {
         variable.value
@@ -216,16 +328,18 @@ async fn create_variable(
 
     sqlx::query!(
         "INSERT INTO variable
-            (workspace_id, path, value, is_secret, description)
-            VALUES ($1, $2, $3, $4, $5)",
+            (workspace_id, path, value, is_secret, description, external)
+            VALUES ($1, $2, $3, $4, $5, $6)",
         &w_id,
         variable.path,
         value,
         variable.is_secret,
-        variable.description
+        variable.description,
+        external
     )
     .execute(&mut tx)
     .await?;
+    insert_variable_version(&mut tx, &w_id, &variable.path, &authed.username).await?;
 
     audit_log(
         &mut tx,
@@ -237,8 +351,10 @@ async fn create_variable(
         None,
     )
     .await?;
+    let git_sync = git_sync::variable_commit(&mut tx, &w_id, &variable.path, None).await?;
 
     tx.commit().await?;
+    git_sync::spawn_commit(git_sync, &authed);
 
     Ok((
         StatusCode::CREATED,
@@ -272,8 +388,16 @@ async fn delete_variable(
         None,
     )
     .await?;
+    let git_sync = git_sync::removal_commit(
+        &mut tx,
+        &w_id,
+        format!("Delete variable {path}"),
+        vec![format!("variables/{path}.json")],
+    )
+    .await?;
 
     tx.commit().await?;
+    git_sync::spawn_commit(git_sync, &authed);
 
     Ok(format!("variable {} deleted", path))
 }
@@ -298,18 +422,36 @@ async fn update_variable(
     if let Some(npath) = &ns.path {
         sqlb.set_str("path", npath);
     }
-    if let Some(nvalue) = ns.value {
-        let is_secret = sqlx::query_scalar!(
-            "SELECT is_secret from variable WHERE path = $1 AND workspace_id = $2",
-            &path,
-            &w_id
-        )
-        .fetch_optional(&mut tx)
-        .await?
-        .unwrap_or(false);
-
+    let stored = sqlx::query!(
+        "SELECT is_secret, external from variable WHERE path = $1 AND workspace_id = $2",
+        &path,
+        &w_id
+    )
+    .fetch_optional(&mut tx)
+    .await?;
+    let (is_secret, stored_external) = stored
+        .map(|x| (x.is_secret, x.external))
+        .unwrap_or((false, None));
+
+    // the value of an external variable is never stored: setting an external source clears the
+    // value, and a variable that keeps its external source cannot be given a value or be secret
+    if let Some(nexternal) = &ns.external {
+        require_admin(authed.is_admin, &authed.username)?;
+        let nexternal = validate_external(
+            Some(nexternal),
+            ns.value.as_deref().unwrap_or(""),
+            is_secret || ns.is_secret.unwrap_or(false),
+        )?;
+        sqlb.set_str("value", "");
+        sqlb.set_str("external", nexternal.unwrap_or_default().to_string());
+    } else if stored_external.is_some() && (ns.value.is_some() || ns.is_secret.is_some()) {
+        return Err(Error::BadRequest(
+            "the value of an external variable is read from its source and cannot be set"
+                .to_string(),
+        ));
+    } else if let Some(nvalue) = ns.value {
         let value = if is_secret {
-            let mc = build_crypt(&mut tx, &w_id).await?;
+            let mc = build_crypt_for_write(&mut tx, &w_id).await?;
             encrypt(&mc, nvalue)
         } else {
             nvalue
@@ -332,6 +474,8 @@ async fn update_variable(
     let sql = sqlb.sql().map_err(|e| Error::InternalErr(e.to_string()))?;
 
     sqlx::query(&sql).execute(&db).await?;
+    let npath = ns.path.as_deref().unwrap_or(path);
+    insert_variable_version(&mut tx, &w_id, npath, &authed.username).await?;
     audit_log(
         &mut tx,
         &authed.username,
@@ -342,11 +486,250 @@ async fn update_variable(
         None,
     )
     .await?;
+    let git_sync = git_sync::variable_commit(&mut tx, &w_id, npath, Some(path)).await?;
     tx.commit().await?;
+    git_sync::spawn_commit(git_sync, &authed);
 
     Ok(format!("variable {} updated (npath: {:?})", path, ns.path))
 }
 
+/// every create, update or restore of a variable is kept as a version. Secret values stay encrypted
+pub async fn insert_variable_version<'c>(
+    tx: &mut Transaction<'c, Postgres>,
+    w_id: &str,
+    path: &str,
+    username: &str,
+) -> Result<()> {
+    sqlx::query!(
+        "INSERT INTO variable_version (workspace_id, path, value, is_secret, description, \
+         external, created_by) SELECT workspace_id, path, value, is_secret, description, \
+         external, $3 FROM variable WHERE workspace_id = $1 AND path = $2",
+        w_id,
+        path,
+        username
+    )
+    .execute(tx)
+    .await?;
+    Ok(())
+}
+
+async fn get_variable_history(
+    authed: Authed,
+    Extension(user_db): Extension<UserDB>,
+    Path((w_id, path)): Path<(String, StripPath)>,
+) -> JsonResult<Vec<VariableHistory>> {
+    let path = path.to_path();
+    let mut tx = user_db.begin(&authed).await?;
+    let history = sqlx::query_as!(
+        VariableHistory,
+        "SELECT id, created_by, created_at FROM variable_version \
+         WHERE path = $1 AND workspace_id = $2 ORDER BY id DESC",
+        path,
+        &w_id
+    )
+    .fetch_all(&mut tx)
+    .await?;
+    tx.commit().await?;
+
+    if history.is_empty() {
+        return Err(Error::NotFound(format!(
+            "Variable not found at name {}",
+            path
+        )));
+    }
+    Ok(Json(history))
+}
+
+async fn get_variable_version_internal<'c>(
+    tx: &mut Transaction<'c, Postgres>,
+    w_id: &str,
+    version: i64,
+) -> Result<VariableVersion> {
+    let version_o = sqlx::query_as!(
+        VariableVersion,
+        "SELECT id, workspace_id, path, value as \"value?\", is_secret, description, external, \
+         created_by, created_at FROM variable_version WHERE id = $1 AND workspace_id = $2",
+        version,
+        w_id
+    )
+    .fetch_optional(tx)
+    .await?;
+    crate::utils::not_found_if_none(version_o, "VariableVersion", version.to_string())
+}
+
+/// the values of secret versions are never returned, restoring the version is the way to read them
+async fn get_variable_version(
+    authed: Authed,
+    Extension(user_db): Extension<UserDB>,
+    Path((w_id, version)): Path<(String, i64)>,
+) -> JsonResult<VariableVersion> {
+    let mut tx = user_db.begin(&authed).await?;
+    let mut variable_version = get_variable_version_internal(&mut tx, &w_id, version).await?;
+    tx.commit().await?;
+    if variable_version.is_secret {
+        variable_version.value = None;
+    }
+    Ok(Json(variable_version))
+}
+
+/// overwrite a variable with one of its versions. Like any update, it requires write access to the
+/// variable
+async fn restore_variable(
+    authed: Authed,
+    Extension(user_db): Extension<UserDB>,
+    Path((w_id, version)): Path<(String, i64)>,
+) -> Result<String> {
+    let mut tx = user_db.begin(&authed).await?;
+    let variable_version = get_variable_version_internal(&mut tx, &w_id, version).await?;
+    let path = variable_version.path;
+    let current = sqlx::query!(
+        "SELECT is_secret, external FROM variable WHERE path = $1 AND workspace_id = $2",
+        &path,
+        &w_id
+    )
+    .fetch_optional(&mut tx)
+    .await?;
+    let current = crate::utils::not_found_if_none(current, "Variable", &path)?;
+    if current.is_secret && !variable_version.is_secret {
+        return Err(Error::BadRequest(
+            "A variable can not be updated to be non secret".to_owned(),
+        ));
+    }
+    // like an update, only admins may set or change the external source of a variable
+    if current.external != variable_version.external {
+        require_admin(authed.is_admin, &authed.username)?;
+    }
+
+    sqlx::query!(
+        "UPDATE variable SET value = $1, is_secret = $2, description = $3, external = $4 \
+         WHERE path = $5 AND workspace_id = $6",
+        variable_version.value.unwrap_or_default(),
+        variable_version.is_secret,
+        &variable_version.description,
+        variable_version.external,
+        &path,
+        &w_id
+    )
+    .execute(&mut tx)
+    .await?;
+    insert_variable_version(&mut tx, &w_id, &path, &authed.username).await?;
+
+    audit_log(
+        &mut tx,
+        &authed.username,
+        "variables.restore",
+        ActionKind::Update,
+        &w_id,
+        Some(&path),
+        Some([("version", version.to_string().as_str())].into()),
+    )
+    .await?;
+    let git_sync = git_sync::variable_commit(&mut tx, &w_id, &path, None).await?;
+    tx.commit().await?;
+    git_sync::spawn_commit(git_sync, &authed);
+
+    Ok(format!("variable {} restored to version {}", path, version))
+}
+
+/// an external variable has no value of its own and is not stored, hence cannot be secret. Returns
+/// the source to store
+fn validate_external(
+    external: Option<&ExternalSource>,
+    value: &str,
+    is_secret: bool,
+) -> Result<Option<serde_json::Value>> {
+    let external = match external {
+        Some(external) => external,
+        None => return Ok(None),
+    };
+    external.validate()?;
+    if !value.is_empty() {
+        return Err(Error::BadRequest(
+            "an external variable cannot have a value".to_string(),
+        ));
+    }
+    if is_secret {
+        return Err(Error::BadRequest(
+            "an external variable is never stored and cannot be secret".to_string(),
+        ));
+    }
+    Ok(Some(serde_json::to_value(external).map_err(|e| {
+        Error::InternalErr(format!("invalid external source: {e}"))
+    })?))
+}
+
+/// read the value of a variable from its external source, auditing the read along with the job it
+/// is done for
+async fn read_external<'c>(
+    tx: &mut Transaction<'c, Postgres>,
+    username: &str,
+    w_id: &str,
+    path: &str,
+    external: &serde_json::Value,
+    job_id: Option<&str>,
+) -> Result<String> {
+    let source = serde_json::from_value::<ExternalSource>(external.clone()).map_err(|e| {
+        Error::InternalErr(format!("invalid external source of variable {path}: {e}"))
+    })?;
+    audit_log(
+        tx,
+        username,
+        "variables.read_external",
+        ActionKind::Execute,
+        w_id,
+        Some(path),
+        job_id.map(|job_id| [("job", job_id)].into()),
+    )
+    .await?;
+    source.resolve(w_id).await
+}
+
+/// value of a variable read on behalf of a job, with the permissions of the job. Secret values
+/// are decrypted and their read is audited along with the id of the job, as are the reads of
+/// external variables
+pub async fn get_value_for_job<'c>(
+    tx: &mut Transaction<'c, Postgres>,
+    w_id: &str,
+    path: &str,
+    username: &str,
+    job_id: &str,
+) -> Result<Option<String>> {
+    let variable = sqlx::query!(
+        "SELECT value, is_secret, external from variable WHERE path = $1 AND (workspace_id = $2 OR (is_secret IS NOT TRUE AND workspace_id = 'starter'))",
+        path,
+        w_id
+    )
+    .fetch_optional(&mut *tx)
+    .await?;
+    let (value, is_secret, external) = match variable {
+        Some(variable) => (variable.value, variable.is_secret, variable.external),
+        None => return Ok(None),
+    };
+    if let Some(external) = &external {
+        let value = read_external(tx, username, w_id, path, external, Some(job_id)).await?;
+        return Ok(Some(value));
+    }
+    if !is_secret || value.is_empty() {
+        return Ok(Some(value));
+    }
+
+    audit_log(
+        tx,
+        username,
+        "variables.decrypt_secret",
+        ActionKind::Execute,
+        w_id,
+        Some(path),
+        Some([("job", job_id)].into()),
+    )
+    .await?;
+    let mc = build_crypt(tx, w_id).await?;
+    let value = mc
+        .decrypt_base64_to_string(value)
+        .map_err(|e| Error::InternalErr(e.to_string()))?;
+    Ok(Some(value))
+}
+
 pub async fn build_crypt<'c>(
     db: &mut Transaction<'c, Postgres>,
     w_id: &str,
@@ -360,6 +743,93 @@ pub async fn build_crypt<'c>(
     Ok(magic_crypt::new_magic_crypt!(key, 256))
 }
 
+/// `build_crypt` for a transaction that stores values encrypted with the key. The key of the
+/// workspace is locked in share mode until the end of the transaction: a rotation waits for the
+/// values to be stored to re-encrypt them, and values stored after it use the new key
+pub async fn build_crypt_for_write<'c>(
+    db: &mut Transaction<'c, Postgres>,
+    w_id: &str,
+) -> Result<MagicCrypt256> {
+    // an advisory lock rather than a FOR SHARE on workspace_key, which would need the app and
+    // admin roles to be granted the update of the keys
+    sqlx::query!(
+        "SELECT pg_advisory_xact_lock_shared(hashtext('workspace_key'), hashtext($1))",
+        w_id
+    )
+    .execute(&mut *db)
+    .await?;
+    build_crypt(db, w_id).await
+}
+
+/// lock the key of a workspace until the end of the transaction, before it is changed, waiting
+/// for the transactions storing values encrypted with it
+pub async fn lock_key<'c>(db: &mut Transaction<'c, Postgres>, w_id: &str) -> Result<()> {
+    sqlx::query!(
+        "SELECT pg_advisory_xact_lock(hashtext('workspace_key'), hashtext($1))",
+        w_id
+    )
+    .execute(db)
+    .await?;
+    Ok(())
+}
+
+/// re-encrypt with the key of `new` every secret variable of a workspace encrypted with the key
+/// of `old`. Returns the number of variables re-encrypted
+pub async fn reencrypt_variables<'c>(
+    tx: &mut Transaction<'c, Postgres>,
+    w_id: &str,
+    old: &MagicCrypt256,
+    new: &MagicCrypt256,
+) -> Result<usize> {
+    let variables = sqlx::query!(
+        "SELECT path, value FROM variable WHERE workspace_id = $1 AND is_secret = true \
+         AND value != '' FOR UPDATE",
+        w_id
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+    for variable in &variables {
+        let value = old.decrypt_base64_to_string(&variable.value).map_err(|e| {
+            Error::InternalErr(format!(
+                "could not decrypt secret variable {}: {e}",
+                variable.path
+            ))
+        })?;
+        sqlx::query!(
+            "UPDATE variable SET value = $1 WHERE path = $2 AND workspace_id = $3",
+            encrypt(new, value),
+            variable.path,
+            w_id
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+
+    let versions = sqlx::query!(
+        "SELECT id, path, value FROM variable_version WHERE workspace_id = $1 \
+         AND is_secret = true AND value != '' FOR UPDATE",
+        w_id
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+    for version in versions {
+        let value = old.decrypt_base64_to_string(version.value).map_err(|e| {
+            Error::InternalErr(format!(
+                "could not decrypt version {} of secret variable {}: {e}",
+                version.id, version.path
+            ))
+        })?;
+        sqlx::query!(
+            "UPDATE variable_version SET value = $1 WHERE id = $2",
+            encrypt(new, value),
+            version.id
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+    Ok(variables.len())
+}
+
 pub fn encrypt(mc: &MagicCrypt256, value: String) -> String {
     mc.encrypt_str_to_base64(value)
 }
@@ -16,20 +16,23 @@ use std::{
 };
 
 use crate::{
-    db::DB,
+    db::{UserDB, DB},
     error::Error,
+    external_secrets,
     jobs::{
         add_completed_job, add_completed_job_error, handle_flow, postprocess_queued_job, pull,
         update_flow_status_after_job_completion, update_flow_status_in_progress, JobKind,
         QueuedJob,
     },
     parser::{self, Typ},
-    scripts::{ScriptHash, ScriptLang},
-    users::{create_token_for_owner, get_email_from_username},
+    resources,
+    scripts::{get_transitive_libraries, library_requirements, Library, ScriptHash, ScriptLang},
+    users::{authed_for_owner, create_token_for_owner, get_email_from_username, Authed},
     variables,
 };
 
 use serde_json::{json, Map, Value};
+use sqlx::{Postgres, Transaction};
 
 use tokio::{
     fs::{DirBuilder, File},
@@ -46,6 +49,8 @@ const TMP_DIR: &str = "/tmp/windmill";
 const PIP_CACHE_DIR: &str = "/tmp/windmill/cache/pip";
 const DENO_CACHE_DIR: &str = "/tmp/windmill/cache/deno";
 const NUM_SECS_ENV_CHECK: u64 = 15;
+const LIBRARIES_DIR: &str = "libraries";
+const DENO_IMPORT_MAP: &str = "import_map.json";
 
 const INCLUDE_DEPS_SH_CONTENT: &str = include_str!("../../nsjail/download_deps.sh");
 const NSJAIL_CONFIG_DOWNLOAD_CONTENT: &str = include_str!("../../nsjail/download.config.proto");
@@ -53,6 +58,26 @@ const NSJAIL_CONFIG_RUN_PYTHON3_CONTENT: &str =
     include_str!("../../nsjail/run.python3.config.proto");
 const NSJAIL_CONFIG_RUN_DENO_CONTENT: &str = include_str!("../../nsjail/run.deno.config.proto");
 
+const RESULT_FILE: &str = "result.json";
+const MAX_RESULT_SIZE: u64 = 2 * 1024 * 1024;
+
+const DEFAULT_MEMORY_LIMIT_MB: i32 = 2048;
+const DEFAULT_CPU_LIMIT_S: i32 = 1000;
+// the nsjail time limit is a backstop, the worker times out the job itself beforehand so that it
+// is marked as such
+const NSJAIL_TIME_LIMIT_MARGIN_S: i32 = 30;
+// address space of a jailed process whose memory is limited by its cgroup instead
+const CGROUP_ADDRESS_SPACE_LIMIT_MB: i32 = 16000;
+const DEFAULT_CGROUP_ROOT: &str = "/sys/fs/cgroup/windmill";
+// removing a cgroup is retried every 100ms while the processes killed in it exit
+const CGROUP_REMOVE_ATTEMPTS: u32 = 20;
+// without a cgroup, allocations beyond the rlimits fail and the runtimes report it with these errors
+const PYTHON_OUT_OF_MEMORY_ERRORS: &[&str] = &["MemoryError"];
+const DENO_OUT_OF_MEMORY_ERRORS: &[&str] = &[
+    "JavaScript heap out of memory",
+    "Fatal process out of memory",
+];
+
 pub async fn run_worker(
     db: &DB,
     timeout: i32,
@@ -175,7 +200,6 @@ async fn handle_queued_job(
         }
         _ => {
             let mut logs = "".to_string();
-            let mut last_line = "{}".to_string();
 
             if job.is_flow_step {
                 update_flow_status_in_progress(
@@ -195,7 +219,6 @@ async fn handle_queued_job(
                 worker_name,
                 worker_dir,
                 &mut logs,
-                &mut last_line,
                 base_url,
             )
             .await;
@@ -226,6 +249,152 @@ struct JobResult {
     result: Option<Map<String, Value>>,
 }
 
+type ScriptContentAndLimits = (
+    String,
+    Option<String>,
+    Option<ScriptLang>,
+    Option<i32>,
+    Option<i32>,
+    Option<i32>,
+);
+
+#[derive(Clone, Copy)]
+struct ResourceLimits {
+    timeout: i32,
+    memory_limit: i32,
+    cpu_limit: i32,
+}
+
+impl ResourceLimits {
+    fn new(
+        default_timeout: i32,
+        timeout: Option<i32>,
+        memory_limit: Option<i32>,
+        cpu_limit: Option<i32>,
+    ) -> Self {
+        Self {
+            timeout: timeout.unwrap_or(default_timeout),
+            memory_limit: memory_limit.unwrap_or(DEFAULT_MEMORY_LIMIT_MB),
+            cpu_limit: cpu_limit.unwrap_or(DEFAULT_CPU_LIMIT_S),
+        }
+    }
+
+    fn apply_to_config(&self, config: &str, cgroup: Option<&JobCgroup>) -> String {
+        let (address_space_limit, cgroup_config) = match cgroup {
+            Some(cgroup) => (
+                CGROUP_ADDRESS_SPACE_LIMIT_MB,
+                format!(
+                    "use_cgroupv2: true\ncgroupv2_mount: \"{}\"\ncgroup_mem_max: {}",
+                    cgroup.path,
+                    self.memory_limit as u64 * 1024 * 1024
+                ),
+            ),
+            None => (self.memory_limit, String::new()),
+        };
+        config
+            .replace(
+                "{TIME_LIMIT}",
+                &(self.timeout + NSJAIL_TIME_LIMIT_MARGIN_S).to_string(),
+            )
+            .replace("{ADDRESS_SPACE_LIMIT}", &address_space_limit.to_string())
+            .replace("{CPU_LIMIT}", &self.cpu_limit.to_string())
+            .replace("{CGROUP_CONFIG}", &cgroup_config)
+    }
+}
+
+/// cgroup v2 of a job, in which nsjail creates the cgroup of the jailed process. The memory.events
+/// of the job cgroup account for the oom kills of the jailed process and outlive it
+struct JobCgroup {
+    path: String,
+}
+
+impl JobCgroup {
+    /// `None` when the worker cannot manage cgroups, the memory of the job is then limited by
+    /// rlimits only and running out of it is told apart by the error of the runtime, see
+    /// `rlimit_out_of_memory`
+    async fn create(job_id: uuid::Uuid) -> Option<JobCgroup> {
+        let root =
+            std::env::var("WORKER_CGROUP_ROOT").unwrap_or_else(|_| DEFAULT_CGROUP_ROOT.to_string());
+        let path = format!("{root}/{job_id}");
+        let created = async {
+            tokio::fs::create_dir_all(&path).await?;
+            tokio::fs::write(format!("{root}/cgroup.subtree_control"), "+memory").await?;
+            // only there when the memory controller is enabled for the job cgroup
+            tokio::fs::metadata(format!("{path}/memory.events"))
+                .await
+                .map(|_| ())
+        }
+        .await;
+        match created {
+            Ok(()) => Some(JobCgroup { path }),
+            Err(e) => {
+                tracing::warn!("could not create the cgroup of job {job_id} in {root}: {e}");
+                let _ = tokio::fs::remove_dir(&path).await;
+                None
+            }
+        }
+    }
+
+    /// remove the cgroup, returns whether the jailed process got killed for running out of memory
+    async fn release(self) -> bool {
+        let oom_kills = tokio::fs::read_to_string(format!("{}/memory.events", self.path))
+            .await
+            .ok()
+            .and_then(|events| {
+                events
+                    .lines()
+                    .find_map(|x| x.strip_prefix("oom_kill "))
+                    .and_then(|x| x.trim().parse::<u64>().ok())
+            })
+            .unwrap_or(0);
+        if let Err(e) = remove_cgroup(&self.path).await {
+            tracing::error!("could not remove the cgroup {}: {e}", self.path);
+        }
+        oom_kills > 0
+    }
+}
+
+/// remove a cgroup and the ones below it, deepest first. nsjail creates a `NSJAIL.<pid>` cgroup
+/// in the job one and does not remove it when it is killed on a timeout or a cancel, the processes
+/// left in them are killed and waited for since a cgroup with processes cannot be removed
+async fn remove_cgroup(path: &str) -> std::io::Result<()> {
+    // only there from linux 5.14, the removal below fails with EBUSY otherwise if processes are left
+    if let Ok(mut kill) = tokio::fs::OpenOptions::new()
+        .write(true)
+        .open(format!("{path}/cgroup.kill"))
+        .await
+    {
+        let _ = kill.write_all(b"1").await;
+    }
+    let mut cgroups = vec![path.to_string()];
+    let mut i = 0;
+    while i < cgroups.len() {
+        let mut entries = tokio::fs::read_dir(&cgroups[i]).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            if entry.file_type().await?.is_dir() {
+                cgroups.push(entry.path().to_string_lossy().to_string());
+            }
+        }
+        i += 1;
+    }
+    for cgroup in cgroups.iter().rev() {
+        let mut attempts = 0;
+        loop {
+            match tokio::fs::remove_dir(cgroup).await {
+                Ok(()) => break,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => break,
+                Err(e) if attempts < CGROUP_REMOVE_ATTEMPTS => {
+                    tracing::debug!("could not remove the cgroup {cgroup} yet: {e}");
+                    attempts += 1;
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+    Ok(())
+}
+
 async fn write_file(dir: &str, path: &str, content: &str) -> Result<File, Error> {
     let path = format!("{}/{}", dir, path);
     let mut file = File::create(&path).await?;
@@ -233,32 +402,178 @@ async fn write_file(dir: &str, path: &str, content: &str) -> Result<File, Error>
     Ok(file)
 }
 
+/// env of a job: the reserved variables, set on the nsjail process, and the `envar` entries of the
+/// env of its workspace, appended to the nsjail config so that they only reach the jailed process
+async fn job_env(
+    db: &DB,
+    job: &QueuedJob,
+    token: &str,
+    base_url: &str,
+) -> Result<(Vec<(String, String)>, String), Error> {
+    let email = get_email_from_username(&job.created_by, db)
+        .await?
+        .unwrap_or_else(|| "nosuitable@email.xyz".to_string());
+    let flow_step = match job.parent_job {
+        Some(parent_job) if job.is_flow_step => sqlx::query_scalar!(
+            "SELECT (flow_status->>'step')::int FROM queue WHERE id = $1",
+            parent_job
+        )
+        .fetch_optional(db)
+        .await?
+        .flatten(),
+        _ => None,
+    };
+    let reserved_variables = variables::get_reserved_variables(&variables::JobContext {
+        w_id: &job.workspace_id,
+        token,
+        email: &email,
+        username: &job.created_by,
+        job_id: &job.id.to_string(),
+        flow_job_id: job
+            .parent_job
+            .filter(|_| job.is_flow_step)
+            .map(|x| x.to_string()),
+        flow_step,
+        schedule_path: job.schedule_path.as_deref(),
+        script_path: job.script_path.as_deref(),
+        script_hash: job.script_hash.map(|x| x.to_string()),
+        base_url,
+    });
+    let workspace_env = variables::get_workspace_env(db, &job.workspace_id).await?;
+    Ok((
+        reserved_variables
+            .into_iter()
+            .map(|rv| (rv.name, rv.value))
+            .collect(),
+        nsjail_envars(&workspace_env),
+    ))
+}
+
+fn nsjail_envars(env: &[variables::ContextualVariable]) -> String {
+    env.iter()
+        .map(|x| format!("envar: \"{}={}\"\n", x.name, proto_escape(&x.value)))
+        .collect()
+}
+
+/// escape a value for a string of the protobuf text format of the nsjail config
+fn proto_escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '"' => escaped.push_str("\\\""),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if c.is_ascii_control() => escaped.push_str(&format!("\\{:03o}", c as u8)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// a process running the code of a job. nsjail keeps the env of the worker, without the
+/// credentials of the external secret providers: jobs read external variables through windmill
+fn job_command(program: &str) -> Command {
+    let mut command = Command::new(program);
+    for name in external_secrets::credentials_env() {
+        command.env_remove(name);
+    }
+    command
+}
+
+/// resolve in process the `$var:` and `$res:` references of the args of a job, with the
+/// permissions of the owner of the job. A reference that cannot be resolved fails the job
+async fn transform_json_value(db: &DB, job: &QueuedJob, v: Value) -> Result<Value, Error> {
+    let authed = owner_authed(db, job).await?;
+    let mut tx = UserDB::new(db.clone()).begin(&authed).await?;
+    let v = resolve_references(
+        &mut tx,
+        &job.workspace_id,
+        &job.id.to_string(),
+        &authed.username,
+        v,
+        &mut vec![],
+    )
+    .await?;
+    tx.commit().await?;
+    Ok(v)
+}
+
+/// the owner of a job, whose permissions apply to what the job reads from the workspace
+async fn owner_authed(db: &DB, job: &QueuedJob) -> Result<Authed, Error> {
+    authed_for_owner(db, &job.workspace_id, &job.permissioned_as, false)
+        .await
+        .ok_or_else(|| {
+            Error::ExecutionErr(format!(
+                "invalid owner {} of job {}",
+                job.permissioned_as, job.id
+            ))
+        })
+}
+
+/// `resources` is the chain of resources being resolved, to detect the cycles of references. The
+/// secrets read are audited along with `job_id`
 #[async_recursion]
-async fn transform_json_value(token: &str, workspace: &str, base_url: &str, v: Value) -> Value {
+async fn resolve_references<'c>(
+    tx: &mut Transaction<'c, Postgres>,
+    w_id: &str,
+    job_id: &str,
+    username: &str,
+    v: Value,
+    resources: &mut Vec<String>,
+) -> Result<Value, Error> {
     match v {
         Value::String(y) if y.starts_with("$var:") => {
             let path = y.strip_prefix("$var:").unwrap();
-            let v = crate::client::get_variable(workspace, path, token, base_url)
-                .await
-                .unwrap_or_else(|_| format!("error fetching variable {path}"));
-            Value::String(v)
+            let value = variables::get_value_for_job(tx, w_id, path, username, job_id)
+                .await?
+                .ok_or_else(|| {
+                    Error::ExecutionErr(format!(
+                        "variable {path} not found or not visible to {username}"
+                    ))
+                })?;
+            Ok(Value::String(value))
         }
         Value::String(y) if y.starts_with("$res:") => {
-            let path = y.strip_prefix("$res:").unwrap();
-            let v = crate::client::get_resource(workspace, path, token, base_url)
-                .await
-                .ok()
-                .flatten()
-                .unwrap_or_else(|| Value::String(format!("error fetching resource {path}")));
-            transform_json_value(token, workspace, base_url, v).await
+            let path = y.strip_prefix("$res:").unwrap().to_string();
+            if resources.contains(&path) {
+                return Err(Error::ExecutionErr(format!(
+                    "cycle in the references of resources: {} -> {path}",
+                    resources.join(" -> ")
+                )));
+            }
+            let value = resources::get_value_for_job(tx, w_id, &path, username, job_id)
+                .await?
+                .ok_or_else(|| {
+                    Error::ExecutionErr(format!(
+                        "resource {path} not found or not visible to {username}"
+                    ))
+                })?
+                .unwrap_or(Value::Null);
+            resources.push(path);
+            let value = resolve_references(tx, w_id, job_id, username, value, resources).await?;
+            resources.pop();
+            Ok(value)
         }
-        Value::Object(mut m) => {
-            for (a, b) in m.clone().into_iter() {
-                m.insert(a, transform_json_value(token, workspace, base_url, b).await);
+        Value::Object(m) => {
+            let mut resolved = Map::new();
+            for (k, v) in m {
+                resolved.insert(
+                    k,
+                    resolve_references(tx, w_id, job_id, username, v, resources).await?,
+                );
             }
-            Value::Object(m)
+            Ok(Value::Object(resolved))
         }
-        a @ _ => a,
+        Value::Array(items) => {
+            let mut resolved = vec![];
+            for v in items {
+                resolved.push(resolve_references(tx, w_id, job_id, username, v, resources).await?);
+            }
+            Ok(Value::Array(resolved))
+        }
+        a => Ok(a),
     }
 }
 
@@ -270,7 +585,6 @@ async fn handle_job(
     worker_name: &str,
     worker_dir: &str,
     mut logs: &mut String,
-    mut last_line: &mut String,
     base_url: &str,
 ) -> Result<JobResult, Error> {
     tracing::info!(
@@ -286,8 +600,14 @@ async fn handle_job(
         .create(&format!("{job_dir}/dependencies"))
         .await
         .expect("could not create initial job dir");
+    DirBuilder::new()
+        .recursive(true)
+        .create(&format!("{job_dir}/{LIBRARIES_DIR}"))
+        .await
+        .expect("could not create initial job dir");
 
     let mut status: Result<ExitStatus, Error>;
+    let mut out_of_memory = false;
 
     if matches!(job.job_kind, JobKind::Dependencies) {
         let requirements = job
@@ -299,14 +619,14 @@ async fn handle_job(
         let file = "requirements.in";
         write_file(&job_dir, file, &requirements).await?;
 
-        let child = Command::new("pip-compile")
+        let child = job_command("pip-compile")
             .current_dir(&job_dir)
             .args(vec!["-q", "--no-header", file])
             .stdout(Stdio::piped())
             .stderr(Stdio::piped())
             .spawn()?;
 
-        status = handle_child(job, db, &mut logs, &mut last_line, timeout, child).await;
+        status = handle_child(job, db, logs, timeout, child).await;
 
         if status.is_ok() && status.as_ref().unwrap().success() {
             let path_lock = format!("{}/requirements.txt", job_dir);
@@ -322,8 +642,14 @@ async fn handle_job(
                 .join("\n");
             let as_json = json!(content);
 
-            *last_line =
-                format!(r#"{{ "success": "Successful lock file generation", "lock": {as_json} }}"#);
+            write_file(
+                &job_dir,
+                RESULT_FILE,
+                &format!(
+                    r#"{{ "success": "Successful lock file generation", "lock": {as_json} }}"#
+                ),
+            )
+            .await?;
 
             sqlx::query!(
                 "UPDATE script SET lock = $1 WHERE hash = $2 AND workspace_id = $3",
@@ -344,27 +670,52 @@ async fn handle_job(
             .await?;
         }
     } else {
-        let (inner_content, requirements_o, language) = if matches!(job.job_kind, JobKind::Preview)
-        {
-            let code = (job.raw_code.as_ref().unwrap_or(&"no raw code".to_owned())).to_owned();
-            let reqs = if job
-                .language
-                .as_ref()
-                .map(|x| matches!(x, ScriptLang::Python3))
-                .unwrap_or(false)
-            {
-                Some(parser::parse_python_imports(&code)?.join("\n"))
+        let (inner_content, requirements_o, language, limits) =
+            if matches!(job.job_kind, JobKind::Preview) {
+                let code = (job.raw_code.as_ref().unwrap_or(&"no raw code".to_owned())).to_owned();
+                let reqs = if job
+                    .language
+                    .as_ref()
+                    .map(|x| matches!(x, ScriptLang::Python3))
+                    .unwrap_or(false)
+                {
+                    Some(parser::parse_python_imports(&code)?.join("\n"))
+                } else {
+                    None
+                };
+                (
+                    code,
+                    reqs,
+                    job.language.to_owned(),
+                    ResourceLimits::new(timeout, job.timeout, job.memory_limit, job.cpu_limit),
+                )
             } else {
-                None
+                let (content, lock, language, script_timeout, memory_limit, cpu_limit) =
+                    get_script_content_and_limits(job, db).await?;
+                (
+                    content,
+                    lock,
+                    language,
+                    ResourceLimits::new(timeout, script_timeout, memory_limit, cpu_limit),
+                )
             };
-            (code, reqs, job.language.to_owned())
+
+        let libraries = if let Some(language) = &language {
+            // libraries are read as the owner of the job so that a job, and a preview in
+            // particular, cannot import a library its owner is not allowed to see
+            let authed = owner_authed(db, job).await?;
+            let mut tx = UserDB::new(db.clone()).begin(&authed).await?;
+            let libraries =
+                get_transitive_libraries(&mut tx, &job.workspace_id, language, &inner_content)
+                    .await
+                    .map_err(|e| {
+                        Error::ExecutionErr(format!("could not resolve the libraries: {e}"))
+                    })?;
+            tx.commit().await?;
+            write_libraries(&job_dir, language, &libraries).await?;
+            libraries
         } else {
-            sqlx::query_as::<_, (String, Option<String>, Option<ScriptLang>)>("SELECT content, lock, language FROM script WHERE hash = $1 AND (workspace_id = $2 OR workspace_id = 'starter')")
-            .bind(&job.script_hash.unwrap_or(ScriptHash(0)).0)
-            .bind(&job.workspace_id)
-            .fetch_optional(db)
-            .await?
-            .ok_or_else(|| Error::InternalErr(format!("expected content and lock")))?
+            vec![]
         };
 
         match language {
@@ -374,8 +725,17 @@ async fn handle_job(
                 ))?;
             }
             Some(ScriptLang::Python3) => {
-                let requirements = requirements_o
+                let mut requirements = requirements_o
                     .ok_or_else(|| Error::InternalErr(format!("lockfile missing")))?;
+                // the lock of a deployed script already includes the requirements of its libraries
+                if matches!(job.job_kind, JobKind::Preview) {
+                    requirements = requirements
+                        .lines()
+                        .map(|x| x.to_string())
+                        .chain(library_requirements(&libraries)?)
+                        .unique()
+                        .join("\n");
+                }
 
                 let _ = write_file(
                     &job_dir,
@@ -388,7 +748,7 @@ async fn handle_job(
                 .await?;
                 let _ = write_file(&job_dir, "requirements.txt", &requirements).await?;
 
-                let child = Command::new("nsjail")
+                let child = job_command("nsjail")
                     .current_dir(&job_dir)
                     .args(vec!["--config", "download.config.proto"])
                     .stdout(Stdio::piped())
@@ -396,7 +756,7 @@ async fn handle_job(
                     .spawn()?;
 
                 logs.push_str("\n--- PIP DEPENDENCIES INSTALL ---\n");
-                status = handle_child(job, db, &mut logs, &mut last_line, timeout, child).await;
+                status = handle_child(job, db, logs, timeout, child).await;
 
                 if status.is_ok() {
                     logs.push_str("\n\n--- PTHON CODE EXECUTION ---\n");
@@ -405,13 +765,6 @@ async fn handle_job(
 
                     let _ = write_file(&job_dir, "inner.py", &inner_content).await?;
 
-                    let sig = crate::parser::parse_python_signature(&inner_content)?;
-                    let transforms = sig.args.into_iter().map(|x| match x.typ {
-        Typ::Bytes => format!("if \"{}\" in kwargs and kwargs[\"{}\"] is not None:\n    kwargs[\"{}\"] = base64.b64decode(kwargs[\"{}\"])\n", x.name, x.name, x.name, x.name),
-        Typ::Datetime => format!("if \"{}\" in kwargs and kwargs[\"{}\"] is not None:\n    kwargs[\"{}\"] = datetime.strptime(kwargs[\"{}\"], '%Y-%m-%dT%H:%M')\n", x.name, x.name, x.name, x.name),
-        _ => "".to_string()
-    }).collect::<Vec<String>>().join("");
-
                     let tx = db.begin().await?;
 
                     let token = create_token_for_owner(
@@ -422,7 +775,7 @@ async fn handle_job(
                             label: Some("ephemeral-script".to_string()),
                             expiration: Some(
                                 chrono::Utc::now()
-                                    + chrono::Duration::seconds((timeout * 2).into()),
+                                    + chrono::Duration::seconds((limits.timeout * 2).into()),
                             ),
                         },
                         &job.created_by,
@@ -430,10 +783,7 @@ async fn handle_job(
                     .await?;
 
                     let args = if let Some(args) = &job.args {
-                        Some(
-                            transform_json_value(&token, &job.workspace_id, base_url, args.clone())
-                                .await,
-                        )
+                        Some(transform_json_value(db, job, args.clone()).await?)
                     } else {
                         None
                     };
@@ -444,15 +794,61 @@ async fn handle_job(
                         r#"
 import json
 import base64
+import dataclasses
+import inspect
+import types
+import typing
 from datetime import datetime
+from enum import Enum
 
 inner_script = __import__("inner")
 
+def type_hints(obj):
+    try:
+        return typing.get_type_hints(obj)
+    except Exception:
+        return {{}}
+
+def is_optional(typ):
+    return type(None) in typing.get_args(typ)
+
+def from_json(typ, v):
+    if v is None:
+        return None
+    origin = typing.get_origin(typ)
+    if origin in (typing.Union, types.UnionType):
+        variants = [t for t in typing.get_args(typ) if t is not type(None)]
+        return from_json(variants[0], v) if len(variants) == 1 else v
+    if origin in (list, set, tuple) and typing.get_args(typ):
+        return [from_json(typing.get_args(typ)[0], x) for x in v]
+    if typ is bytes:
+        return base64.b64decode(v)
+    if typ is datetime:
+        return datetime.strptime(v, '%Y-%m-%dT%H:%M')
+    if isinstance(typ, type) and issubclass(typ, Enum):
+        return typ[v]
+    if dataclasses.is_dataclass(typ) or typing.is_typeddict(typ):
+        hints = type_hints(typ)
+        value = {{k: from_json(hints.get(k), x) for k, x in v.items()}}
+        if dataclasses.is_dataclass(typ):
+            for f in dataclasses.fields(typ):
+                if (f.name not in value and is_optional(hints.get(f.name))
+                        and f.default is dataclasses.MISSING
+                        and f.default_factory is dataclasses.MISSING):
+                    value[f.name] = None
+        return typ(**value)
+    return v
+
 kwargs = json.loads("""{ser_args}""", strict=False)
 for k, v in kwargs.items():
     if v == '<function call>':
         kwargs[k] = None
-{transforms}
+hints = type_hints(inner_script.main)
+for name, param in inspect.signature(inner_script.main).parameters.items():
+    if name in kwargs:
+        kwargs[name] = from_json(hints.get(name), kwargs[name])
+    elif param.default is inspect.Parameter.empty and is_optional(hints.get(name)):
+        kwargs[name] = None
 res = inner_script.main(**kwargs)
 if res is None:
     res = {{}}
@@ -460,34 +856,33 @@ if isinstance(res, tuple):
     res = {{f"res{{i+1}}": v for i, v in enumerate(res)}}
 if not isinstance(res, dict):
     res = {{ "res1": res }}
-res_json = json.dumps(res, separators=(',', ':'), default=str).replace('\n', '')
-print()
-print("result:")
-print(res_json)
+def to_json(o):
+    if dataclasses.is_dataclass(o):
+        return dataclasses.asdict(o)
+    if isinstance(o, Enum):
+        return o.name
+    return str(o)
+with open("/tmp/{RESULT_FILE}", "w") as f:
+    json.dump(res, f, separators=(',', ':'), default=to_json)
 "#,
                     );
                     write_file(&job_dir, "main.py", &wrapper_content).await?;
+                    write_file(&job_dir, RESULT_FILE, "").await?;
 
                     tx.commit().await?;
-                    let reserved_variables = variables::get_reserved_variables(
-                        &job.workspace_id,
-                        &token,
-                        &get_email_from_username(&job.created_by, db)
-                            .await?
-                            .unwrap_or_else(|| "nosuitable@email.xyz".to_string()),
-                        &job.created_by,
-                        &job.id.to_string(),
-                    )
-                    .into_iter()
-                    .map(|rv| (rv.name, rv.value));
+                    let (reserved_variables, envars) = job_env(db, job, &token, base_url).await?;
+                    let cgroup = JobCgroup::create(job.id).await;
                     let _ = write_file(
                         &job_dir,
                         "run.config.proto",
-                        &NSJAIL_CONFIG_RUN_PYTHON3_CONTENT.replace("{JOB_DIR}", &job_dir),
+                        &(limits.apply_to_config(
+                            &NSJAIL_CONFIG_RUN_PYTHON3_CONTENT.replace("{JOB_DIR}", &job_dir),
+                            cgroup.as_ref(),
+                        ) + &envars),
                     )
                     .await?;
 
-                    let child = Command::new("nsjail")
+                    let child = job_command("nsjail")
                         .current_dir(&job_dir)
                         .envs(reserved_variables)
                         .args(vec![
@@ -501,7 +896,11 @@ print(res_json)
                         .stdout(Stdio::piped())
                         .stderr(Stdio::piped())
                         .spawn()?;
-                    status = handle_child(job, db, &mut logs, &mut last_line, timeout, child).await;
+                    status = handle_child(job, db, logs, limits.timeout, child).await;
+                    out_of_memory = match cgroup {
+                        Some(cgroup) => cgroup.release().await,
+                        None => rlimit_out_of_memory(logs, PYTHON_OUT_OF_MEMORY_ERRORS),
+                    };
                 }
             }
             Some(ScriptLang::Deno) => {
@@ -512,11 +911,12 @@ print(res_json)
                 let _ = write_file(&job_dir, "inner.ts", &inner_content).await?;
 
                 let sig = crate::parser::parse_deno_signature(&inner_content)?;
-                //             let transforms = sig.args.clone().into_iter().map(|x| match x.typ {
-                //     Typ::Bytes => format!("if \"{}\" in kwargs and kwargs[\"{}\"] is not None:\n    kwargs[\"{}\"] = base64.b64decode(kwargs[\"{}\"])\n", x.name, x.name, x.name, x.name),
-                //     Typ::Datetime => format!("if \"{}\" in kwargs and kwargs[\"{}\"] is not None:\n    kwargs[\"{}\"] = datetime.strptime(kwargs[\"{}\"], '%Y-%m-%dT%H:%M')\n", x.name, x.name, x.name, x.name),
-                //     _ => "".to_string()
-                // }).collect::<Vec<String>>().join("");
+                let transforms = sig
+                    .args
+                    .iter()
+                    .map(deno_arg_transform)
+                    .collect::<Vec<String>>()
+                    .join("");
 
                 let tx = db.begin().await?;
 
@@ -527,7 +927,8 @@ print(res_json)
                     crate::users::NewToken {
                         label: Some("ephemeral-script".to_string()),
                         expiration: Some(
-                            chrono::Utc::now() + chrono::Duration::seconds((timeout * 2).into()),
+                            chrono::Utc::now()
+                                + chrono::Duration::seconds((limits.timeout * 2).into()),
                         ),
                     },
                     &job.created_by,
@@ -535,10 +936,7 @@ print(res_json)
                 .await?;
 
                 let args = if let Some(args) = &job.args {
-                    Some(
-                        transform_json_value(&token, &job.workspace_id, base_url, args.clone())
-                            .await,
-                    )
+                    Some(transform_json_value(db, job, args.clone()).await?)
                 } else {
                     None
                 };
@@ -549,8 +947,8 @@ print(res_json)
                 let wrapper_content: String = format!(
                     r#"
 import {{ main }} from "./inner.ts";
-const {{{spread}}}= JSON.parse(`{ser_args}`);
-
+let {{{spread}}}= JSON.parse(`{ser_args}`);
+{transforms}
 async function run() {{
     let res: any = await main({spread});
     if (res == undefined) {{
@@ -560,72 +958,77 @@ async function run() {{
         res = {{ res1: res }}
     }}
 
-    const res_json = JSON.stringify(res);
-    console.log();
-    console.log("result:");
-    console.log(res_json);
+    await Deno.writeTextFile("/tmp/{RESULT_FILE}", JSON.stringify(res));
 }}
 run();
 "#,
                 );
                 write_file(&job_dir, "main.ts", &wrapper_content).await?;
+                write_file(&job_dir, RESULT_FILE, "").await?;
 
                 tx.commit().await?;
-                let reserved_variables = variables::get_reserved_variables(
-                    &job.workspace_id,
-                    &token,
-                    &get_email_from_username(&job.created_by, db)
-                        .await?
-                        .unwrap_or_else(|| "nosuitable@email.xyz".to_string()),
-                    &job.created_by,
-                    &job.id.to_string(),
-                )
-                .into_iter()
-                .map(|rv| (rv.name, rv.value));
+                let (reserved_variables, envars) = job_env(db, job, &token, base_url).await?;
+                let cgroup = JobCgroup::create(job.id).await;
                 let _ = write_file(
                     &job_dir,
                     "run.config.proto",
-                    &NSJAIL_CONFIG_RUN_DENO_CONTENT
-                        .replace("{JOB_DIR}", &job_dir)
-                        .replace("{CACHE_DIR}", DENO_CACHE_DIR),
+                    &(limits.apply_to_config(
+                        &NSJAIL_CONFIG_RUN_DENO_CONTENT
+                            .replace("{JOB_DIR}", &job_dir)
+                            .replace("{CACHE_DIR}", DENO_CACHE_DIR),
+                        cgroup.as_ref(),
+                    ) + &envars),
                 )
                 .await?;
+                // without a cgroup, the heap of v8 is what gets limited
+                let max_heap_flag = format!("--v8-flags=--max-heap-size={}", limits.memory_limit);
+                let import_map_flag =
+                    format!("--import-map=/tmp/{LIBRARIES_DIR}/{DENO_IMPORT_MAP}");
+                let mut deno_args =
+                    vec!["--config", "run.config.proto", "--", "/usr/bin/deno", "run"];
+                if cgroup.is_none() {
+                    deno_args.push(&max_heap_flag);
+                }
+                deno_args.extend([import_map_flag.as_str(), "-A", "/tmp/main.ts"]);
 
-                let child = Command::new("nsjail")
+                let child = job_command("nsjail")
                     .current_dir(&job_dir)
                     .envs(reserved_variables)
-                    .args(vec![
-                        "--config",
-                        "run.config.proto",
-                        "--",
-                        "/usr/bin/deno",
-                        "run",
-                        "--v8-flags=--max-heap-size=2048",
-                        "-A",
-                        "/tmp/main.ts",
-                    ])
+                    .args(deno_args)
                     .stdout(Stdio::piped())
                     .stderr(Stdio::piped())
                     .spawn()?;
-                status = handle_child(job, db, &mut logs, &mut last_line, timeout, child).await;
+                status = handle_child(job, db, logs, limits.timeout, child).await;
+                out_of_memory = match cgroup {
+                    Some(cgroup) => cgroup.release().await,
+                    None => rlimit_out_of_memory(logs, DENO_OUT_OF_MEMORY_ERRORS),
+                };
             }
         }
     }
+    let result = if status.is_ok() && status.as_ref().unwrap().success() {
+        Some(read_result(&job_dir).await)
+    } else {
+        None
+    };
     tokio::fs::remove_dir_all(job_dir).await?;
 
-    if status.is_ok() && status.as_ref().unwrap().success() {
-        let result = serde_json::from_str::<Map<String, Value>>(last_line).map_err(|e| {
-            Error::ExecutionErr(format!(
-                "result {} is not parsable.\n err: {}",
-                last_line,
-                e.to_string()
-            ))
-        })?;
+    if let Some(result) = result {
         Ok(JobResult {
-            result: Some(result),
+            result: Some(result?),
         })
     } else {
         let err = match status {
+            Ok(_) if out_of_memory => {
+                let s = format!(
+                    "Memory limit exceeded: the script used more than its memory limit\nlast 5 logs lines:\n{}",
+                    logs.lines()
+                        .skip(logs.lines().count().max(5) - 5)
+                        .join("\n")
+                );
+                logs.push_str("\n\n--- OUT OF MEMORY ---\n");
+                s
+            }
             Ok(_) => {
                 let s = format!(
                     "Error during execution of the script\nlast 5 logs lines:\n{}",
@@ -642,14 +1045,126 @@ run();
     }
 }
 
+/// whether one of the last lines logged by a script limited by rlimits only is an out of memory
+/// error of its runtime
+fn rlimit_out_of_memory(logs: &str, errors: &[&str]) -> bool {
+    logs.lines()
+        .rev()
+        .filter(|x| !x.trim().is_empty())
+        .take(5)
+        .any(|x| errors.iter().any(|e| x.contains(e)))
+}
+
+/// typescript statement converting the json value of an arg back into the type it is annotated with
+fn deno_arg_transform(arg: &parser::Arg) -> String {
+    let name = &arg.name;
+    let typ = match &arg.typ {
+        Typ::Optional(t) => t.as_ref(),
+        t => t,
+    };
+    deno_convert_expr(typ, name)
+        .map(|expr| format!("if ({name} != undefined) {{\n    {name} = {expr};\n}}\n"))
+        .unwrap_or_default()
+}
+
+fn deno_convert_expr(typ: &Typ, v: &str) -> Option<String> {
+    match typ {
+        Typ::Bytes => Some(format!(
+            "Uint8Array.from(atob({v}), (c) => c.charCodeAt(0))"
+        )),
+        Typ::Datetime => Some(format!("new Date({v})")),
+        Typ::Optional(t) => {
+            deno_convert_expr(t, v).map(|e| format!("({v} != undefined ? {e} : {v})"))
+        }
+        Typ::List(t) => deno_convert_expr(t, "x").map(|e| format!("{v}.map((x: any) => {e})")),
+        _ => None,
+    }
+}
+
+/// write the sources of the libraries at their workspace path so that they can be imported as
+/// `u.alice.lib` or `f.shared.utils` in python, and as `/u/alice/lib.ts` in deno through an
+/// import map
+async fn write_libraries(
+    job_dir: &str,
+    language: &ScriptLang,
+    libraries: &[Library],
+) -> Result<(), Error> {
+    let libraries_dir = format!("{job_dir}/{LIBRARIES_DIR}");
+    let ext = match language {
+        ScriptLang::Python3 => "py",
+        ScriptLang::Deno => "ts",
+    };
+    for library in libraries {
+        if let Some((dir, _)) = library.path.rsplit_once('/') {
+            DirBuilder::new()
+                .recursive(true)
+                .create(&format!("{libraries_dir}/{dir}"))
+                .await?;
+        }
+        write_file(
+            &libraries_dir,
+            &format!("{}.{ext}", library.path),
+            &library.content,
+        )
+        .await?;
+    }
+    if matches!(language, ScriptLang::Deno) {
+        let import_map = json!({ "imports": { "/u/": "./u/", "/g/": "./g/", "/f/": "./f/" } });
+        write_file(&libraries_dir, DENO_IMPORT_MAP, &import_map.to_string()).await?;
+    }
+    Ok(())
+}
+
+async fn get_script_content_and_limits(
+    job: &QueuedJob,
+    db: &DB,
+) -> Result<ScriptContentAndLimits, Error> {
+    sqlx::query_as::<_, ScriptContentAndLimits>(
+        "SELECT content, lock, language, timeout, memory_limit, cpu_limit FROM script \
+         WHERE hash = $1 AND (workspace_id = $2 OR workspace_id = 'starter')",
+    )
+    .bind(job.script_hash.unwrap_or(ScriptHash(0)).0)
+    .bind(&job.workspace_id)
+    .fetch_optional(db)
+    .await?
+    .ok_or_else(|| Error::InternalErr("expected content and lock".to_string()))
+}
+
+async fn read_result(job_dir: &str) -> Result<Map<String, Value>, Error> {
+    let path = format!("{job_dir}/{RESULT_FILE}");
+    let size = tokio::fs::metadata(&path)
+        .await
+        .map_err(|_| Error::ExecutionErr("the script did not write any result".to_string()))?
+        .len();
+    if size == 0 {
+        return Err(Error::ExecutionErr(
+            "the script did not write any result".to_string(),
+        ));
+    }
+    if size > MAX_RESULT_SIZE {
+        return Err(Error::ExecutionErr(format!(
+            "result is too large: {size} bytes, maximum is {MAX_RESULT_SIZE} bytes"
+        )));
+    }
+    let mut content = "".to_string();
+    File::open(&path)
+        .await?
+        .read_to_string(&mut content)
+        .await?;
+    serde_json::from_str::<Map<String, Value>>(&content).map_err(|e| {
+        Error::ExecutionErr(format!("result is not a parsable json object.\n err: {e}"))
+    })
+}
+
 async fn handle_child(
     job: &QueuedJob,
     db: &DB,
     logs: &mut String,
-    last_line: &mut String,
     timeout: i32,
     mut child: Child,
 ) -> crate::error::Result<ExitStatus> {
+    // the time spent before the process, such as installing the dependencies, does not count
+    let started_at = Instant::now();
     let stderr = child
         .stderr
         .take()
@@ -756,12 +1271,7 @@ async fn handle_child(
                     done.store(true, Ordering::Relaxed);
                 }
 
-                let has_timeout = job
-                    .started_at
-                    .map(|sa| (chrono::Utc::now() - sa).num_seconds() > timeout as i64)
-                    .unwrap_or(false);
-
-                if has_timeout {
+                if started_at.elapsed().as_secs() > timeout as u64 {
                     let q = sqlx::query(&format!(
                         "UPDATE queue SET canceled = true, canceled_by = 'timeout', \
                             canceled_reason = 'duration > {}' WHERE id = $1",
@@ -780,7 +1290,6 @@ async fn handle_child(
                 if let Some(nl) = nl {
                     logs.push('\n');
                     logs.push_str(&nl);
-                    *last_line = nl;
                 } else {
                     let to_send = logs.chars().skip(start).collect::<String>();
                     concat_logs(&to_send, id, db).await;
@@ -852,3 +1361,335 @@ pub async fn restart_zombie_jobs_periodically(
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::db::{create_test_workspace, delete_test_workspace, test_db};
+
+    #[test]
+    fn test_apply_to_config() {
+        let config = "time_limit: {TIME_LIMIT}\nrlimit_as: {ADDRESS_SPACE_LIMIT}\nrlimit_cpu: {CPU_LIMIT}\n{CGROUP_CONFIG}";
+        let limits = ResourceLimits::new(60, Some(10), Some(128), None);
+        assert_eq!(
+            limits.apply_to_config(config, None),
+            format!("time_limit: 40\nrlimit_as: 128\nrlimit_cpu: {DEFAULT_CPU_LIMIT_S}\n")
+        );
+        let cgroup = JobCgroup {
+            path: "/sys/fs/cgroup/windmill/job".to_string(),
+        };
+        assert_eq!(
+            limits.apply_to_config(config, Some(&cgroup)),
+            format!(
+                "time_limit: 40\nrlimit_as: {CGROUP_ADDRESS_SPACE_LIMIT_MB}\nrlimit_cpu: {DEFAULT_CPU_LIMIT_S}\n\
+                 use_cgroupv2: true\ncgroupv2_mount: \"/sys/fs/cgroup/windmill/job\"\ncgroup_mem_max: 134217728"
+            )
+        );
+        let defaults = ResourceLimits::new(60, None, None, None);
+        assert_eq!(
+            defaults.apply_to_config("{TIME_LIMIT} {ADDRESS_SPACE_LIMIT}", None),
+            format!("90 {DEFAULT_MEMORY_LIMIT_MB}")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_remove_cgroup() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = format!("{}/job", dir.path().display());
+        std::fs::create_dir_all(format!("{path}/NSJAIL.42/inner")).unwrap();
+        std::fs::create_dir(format!("{path}/NSJAIL.43")).unwrap();
+        remove_cgroup(&path).await.unwrap();
+        assert!(!std::path::Path::new(&path).exists());
+    }
+
+    #[test]
+    fn test_rlimit_out_of_memory() {
+        let python = "Traceback (most recent call last):\n  File \"/tmp/main.py\", line 12, in <module>\n    res = inner.main()\nMemoryError\n\n";
+        assert!(rlimit_out_of_memory(python, PYTHON_OUT_OF_MEMORY_ERRORS));
+        let deno = "\n<--- JS stacktrace --->\n\nFATAL ERROR: Reached heap limit Allocation failed - JavaScript heap out of memory\n";
+        assert!(rlimit_out_of_memory(deno, DENO_OUT_OF_MEMORY_ERRORS));
+        let other = "MemoryError\n1\n2\n3\n4\nValueError: invalid literal for int()";
+        assert!(!rlimit_out_of_memory(other, PYTHON_OUT_OF_MEMORY_ERRORS));
+        assert!(!rlimit_out_of_memory(deno, PYTHON_OUT_OF_MEMORY_ERRORS));
+    }
+
+    #[tokio::test]
+    async fn test_read_result() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let job_dir = dir.path().display().to_string();
+        let path = format!("{job_dir}/{RESULT_FILE}");
+        let error = |r: Result<Map<String, Value>, Error>| match r {
+            Err(Error::ExecutionErr(e)) => e,
+            r => panic!("expected an execution error, got {r:?}"),
+        };
+
+        assert!(error(read_result(&job_dir).await).contains("did not write any result"));
+
+        std::fs::write(&path, "").unwrap();
+        assert!(error(read_result(&job_dir).await).contains("did not write any result"));
+
+        std::fs::write(&path, r#"{"a": 1}"#).unwrap();
+        assert_eq!(
+            Value::Object(read_result(&job_dir).await.unwrap()),
+            json!({"a": 1})
+        );
+
+        for invalid in [r#"{"a": "#, "[1, 2]", "not json"] {
+            std::fs::write(&path, invalid).unwrap();
+            assert!(error(read_result(&job_dir).await).contains("not a parsable json object"));
+        }
+
+        std::fs::File::create(&path)
+            .unwrap()
+            .set_len(MAX_RESULT_SIZE + 1)
+            .unwrap();
+        assert!(error(read_result(&job_dir).await).contains("result is too large"));
+        std::fs::File::create(&path)
+            .unwrap()
+            .set_len(MAX_RESULT_SIZE)
+            .unwrap();
+        assert!(error(read_result(&job_dir).await).contains("not a parsable json object"));
+    }
+
+    async fn insert_variable(db: &DB, w_id: &str, path: &str, value: &str, is_secret: bool) {
+        let value = if is_secret {
+            let mut tx = db.begin().await.unwrap();
+            let mc = variables::build_crypt(&mut tx, w_id).await.unwrap();
+            tx.commit().await.unwrap();
+            variables::encrypt(&mc, value.to_string())
+        } else {
+            value.to_string()
+        };
+        sqlx::query(
+            "INSERT INTO variable (workspace_id, path, value, is_secret) VALUES ($1, $2, $3, $4)",
+        )
+        .bind(w_id)
+        .bind(path)
+        .bind(value)
+        .bind(is_secret)
+        .execute(db)
+        .await
+        .unwrap();
+    }
+
+    async fn insert_resource(db: &DB, w_id: &str, path: &str, value: Value) {
+        sqlx::query(
+            "INSERT INTO resource (workspace_id, path, value, resource_type) \
+             VALUES ($1, $2, $3, 'object')",
+        )
+        .bind(w_id)
+        .bind(path)
+        .bind(value)
+        .execute(db)
+        .await
+        .unwrap();
+    }
+
+    /// resolve `v` as the non admin user alice for the job `job_id`
+    async fn resolve(db: &DB, w_id: &str, job_id: &str, v: Value) -> Result<Value, Error> {
+        let authed = Authed {
+            email: None,
+            username: "alice".to_string(),
+            is_admin: false,
+            groups: vec![],
+        };
+        let mut tx = UserDB::new(db.clone()).begin(&authed).await?;
+        let v = resolve_references(&mut tx, w_id, job_id, "alice", v, &mut vec![]).await?;
+        tx.commit().await?;
+        Ok(v)
+    }
+
+    #[tokio::test]
+    #[ignore = "needs DATABASE_URL, see test_db"]
+    async fn test_resolve_references() {
+        let db = test_db().await;
+        let w_id = create_test_workspace(&db).await;
+        insert_variable(&db, &w_id, "u/alice/host", "db.example.com", false).await;
+        insert_variable(&db, &w_id, "u/alice/password", "s3cret", true).await;
+        insert_resource(
+            &db,
+            &w_id,
+            "u/alice/db",
+            json!({ "host": "$var:u/alice/host", "password": "$var:u/alice/password" }),
+        )
+        .await;
+
+        let job_id = uuid::Uuid::new_v4().to_string();
+        let resolved = resolve(
+            &db,
+            &w_id,
+            &job_id,
+            json!({ "db": "$res:u/alice/db", "port": 5432, "tags": ["$var:u/alice/host"] }),
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            resolved,
+            json!({
+                "db": { "host": "db.example.com", "password": "s3cret" },
+                "port": 5432,
+                "tags": ["db.example.com"]
+            })
+        );
+
+        // only the secret read is audited, along with the job it is done for
+        let audited = sqlx::query_scalar::<_, i64>(
+            "SELECT count(*) FROM audit WHERE workspace_id = $1 AND username = 'alice' \
+             AND operation = 'variables.decrypt_secret' AND resource = 'u/alice/password' \
+             AND parameters->>'job' = $2",
+        )
+        .bind(&w_id)
+        .bind(&job_id)
+        .fetch_one(&db)
+        .await
+        .unwrap();
+        assert_eq!(audited, 1);
+
+        delete_test_workspace(&db, &w_id).await;
+    }
+
+    #[tokio::test]
+    #[ignore = "needs DATABASE_URL, see test_db"]
+    async fn test_resolve_references_cycle() {
+        let db = test_db().await;
+        let w_id = create_test_workspace(&db).await;
+        insert_resource(&db, &w_id, "u/alice/a", json!({ "b": "$res:u/alice/b" })).await;
+        insert_resource(&db, &w_id, "u/alice/b", json!({ "a": ["$res:u/alice/a"] })).await;
+
+        let job_id = uuid::Uuid::new_v4().to_string();
+        match resolve(&db, &w_id, &job_id, json!("$res:u/alice/a")).await {
+            Err(Error::ExecutionErr(e)) => assert_eq!(
+                e,
+                "cycle in the references of resources: u/alice/a -> u/alice/b -> u/alice/a"
+            ),
+            r => panic!("expected a cycle error, got {:?}", r.map(|x| x.to_string())),
+        }
+
+        delete_test_workspace(&db, &w_id).await;
+    }
+
+    #[tokio::test]
+    #[ignore = "needs DATABASE_URL, see test_db"]
+    async fn test_resolve_references_not_visible() {
+        let db = test_db().await;
+        let w_id = create_test_workspace(&db).await;
+        insert_variable(&db, &w_id, "u/bob/token", "bob-token", true).await;
+        insert_resource(
+            &db,
+            &w_id,
+            "u/alice/api",
+            json!({ "token": "$var:u/bob/token" }),
+        )
+        .await;
+
+        let job_id = uuid::Uuid::new_v4().to_string();
+        for (v, expected) in [
+            (
+                json!("$var:u/alice/missing"),
+                "variable u/alice/missing not found or not visible to alice",
+            ),
+            (
+                json!("$res:u/alice/missing"),
+                "resource u/alice/missing not found or not visible to alice",
+            ),
+            // another user's variable, even through a resource of alice
+            (
+                json!({ "api": "$res:u/alice/api" }),
+                "variable u/bob/token not found or not visible to alice",
+            ),
+        ] {
+            match resolve(&db, &w_id, &job_id, v).await {
+                Err(Error::ExecutionErr(e)) => assert_eq!(e, expected),
+                r => panic!("expected {expected}, got {:?}", r.map(|x| x.to_string())),
+            }
+        }
+
+        delete_test_workspace(&db, &w_id).await;
+    }
+
+    #[tokio::test]
+    #[ignore = "needs DATABASE_URL, see test_db"]
+    async fn test_libraries_not_visible() {
+        let db = test_db().await;
+        let w_id = create_test_workspace(&db).await;
+        for (hash, path) in [
+            (1, "u/alice/lib"),
+            (2, "u/bob/private"),
+            (3, "f/shared/utils"),
+        ] {
+            sqlx::query(
+                "INSERT INTO script (workspace_id, hash, path, summary, description, content, \
+                 created_by, is_library) VALUES ($1, $2, $3, '', '', 'x = 1', 'admin', true)",
+            )
+            .bind(&w_id)
+            .bind(hash)
+            .bind(path)
+            .execute(&db)
+            .await
+            .unwrap();
+        }
+        let authed = Authed {
+            email: None,
+            username: "alice".to_string(),
+            is_admin: false,
+            groups: vec![],
+        };
+
+        let mut tx = UserDB::new(db.clone()).begin(&authed).await.unwrap();
+        let libraries =
+            get_transitive_libraries(&mut tx, &w_id, &ScriptLang::Python3, "import u.alice.lib")
+                .await
+                .unwrap();
+        assert_eq!(
+            libraries.into_iter().map(|x| x.path).collect::<Vec<_>>(),
+            vec!["u/alice/lib"]
+        );
+        // the libraries of a folder are visible to every member
+        let libraries = get_transitive_libraries(
+            &mut tx,
+            &w_id,
+            &ScriptLang::Python3,
+            "from f.shared.utils import x",
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            libraries.into_iter().map(|x| x.path).collect::<Vec<_>>(),
+            vec!["f/shared/utils"]
+        );
+        // the private library of bob is not visible to a job owned by alice
+        assert!(get_transitive_libraries(
+            &mut tx,
+            &w_id,
+            &ScriptLang::Python3,
+            "import u.bob.private"
+        )
+        .await
+        .is_err());
+        tx.commit().await.unwrap();
+
+        delete_test_workspace(&db, &w_id).await;
+    }
+
+    #[test]
+    fn test_nsjail_envars() {
+        let env = vec![
+            variables::ContextualVariable {
+                name: "API_URL".to_string(),
+                value: "https://api.example.com".to_string(),
+                description: String::new(),
+            },
+            variables::ContextualVariable {
+                name: "GREETING".to_string(),
+                value: "say \"hi\"\\\nenvar: \"LD_PRELOAD=/tmp/x.so\"\u{7}".to_string(),
+                description: String::new(),
+            },
+        ];
+        assert_eq!(
+            nsjail_envars(&env),
+            "envar: \"API_URL=https://api.example.com\"\n\
+             envar: \"GREETING=say \\\"hi\\\"\\\\\\nenvar: \\\"LD_PRELOAD=/tmp/x.so\\\"\\007\"\n"
+        );
+    }
+}
@@ -5,20 +5,60 @@
  * LICENSE-AGPL for a copy of the license.
  */
 
+use std::{
+    collections::{HashMap, HashSet},
+    io::Cursor,
+};
+
 use crate::{
+    audit::{audit_log, ActionKind},
     db::{UserDB, DB},
     error::{Error, JsonResult, Result},
-    users::{Authed, WorkspaceInvite}, utils::{require_admin, require_super_admin, Pagination}, audit::{audit_log, ActionKind}, scripts::{Script, Schema}, resources::{Resource, ResourceType}, flow::Flow, variables::ListableVariable,
+    flow::{insert_flow_version, Flow, NewFlow},
+    git_sync,
+    jobs::check_hash_for_path,
+    resources::{
+        has_encrypted_fields, insert_resource_version, keep_redacted_fields, map_encrypted_fields,
+        prepare_resource_value, reencrypt_fields, reencrypt_resources, CreateResource,
+        CreateResourceType, Resource, ResourceType,
+    },
+    scripts::{
+        create_script_internal, parse_library_imports, NewScript, Schema, Script, ScriptHash,
+        ScriptHashes, ScriptLang,
+    },
+    users::{truncate_token, Authed, Tokened, WorkspaceInvite},
+    utils::{require_admin, require_super_admin, Pagination},
+    variables::{
+        build_crypt, build_crypt_for_write, encrypt, insert_variable_version, lock_key,
+        reencrypt_variables, ContextualVariable, CreateVariable, ListableVariable,
+    },
+};
+use argon2::{password_hash::SaltString, Argon2, PasswordHasher};
+use axum::{
+    body::{Bytes, StreamBody},
+    extract::{Extension, Path, Query},
+    response::IntoResponse,
+    routing::{delete, get, post},
+    Json, Router,
 };
-use axum::{extract::{Extension, Path, Query}, routing::{get, post, delete}, Json, Router, response::{IntoResponse}, body::StreamBody};
 
-use hyper::{StatusCode, header};
+use futures::StreamExt;
+use hyper::{header, HeaderMap, StatusCode};
+use magic_crypt::{MagicCrypt256, MagicCryptTrait};
+use rand::rngs::OsRng;
+use regex::Regex;
 use serde::{Deserialize, Serialize};
-use sqlx::{FromRow};
+use sqlx::{FromRow, Postgres, Transaction};
 use tempfile::TempDir;
-use tokio::fs::File;
+use tokio::{fs::File, io::AsyncReadExt};
 use tokio_util::io::ReaderStream;
 
+const SECRETS_FILE: &str = "secrets.json";
+const SECRETS_CHECK: &str = "windmill";
+const SECRETS_PASSPHRASE_HEADER: &str = "x-secrets-passphrase";
+const MIN_PASSPHRASE_LENGTH: usize = 8;
+const KEY_ROLLBACK_WINDOW_DAYS: i32 = 7;
+
 pub fn workspaced_service() -> Router {
     Router::new()
         .route("/list_pending_invites", get(list_pending_invites))
@@ -28,23 +68,29 @@ pub fn workspaced_service() -> Router {
         .route("/delete_invite", post(delete_invite))
         .route("/get_settings", get(get_settings))
         .route("/edit_slack_command", post(edit_slack_command))
-        .route("/tarball", get(tarball_workspace))
-
-
-
+        .route("/edit_extra_env", post(edit_extra_env))
+        .route(
+            "/tarball",
+            get(tarball_workspace).post(tarball_workspace_with_secrets),
+        )
+        .route("/import", post(import_workspace))
+        .route("/rotate_key", post(rotate_key))
+        .route("/rollback_key", post(rollback_key))
+        .route(
+            "/previous_key",
+            get(get_previous_key).delete(discard_previous_key),
+        )
 }
 
 pub fn global_service() -> Router {
     Router::new()
-    .route("/list_as_superadmin", get(list_workspaces_as_super_admin))
-    .route("/list", get(list_workspaces))
-    .route("/users", get(user_workspaces))
-    .route("/create", post(create_workspace))
-    .route("/exists", post(exists_workspace))
-    .route("/validate_username", post(validate_username))
-    .route("/validate_id", post(validate_id))
-
-
+        .route("/list_as_superadmin", get(list_workspaces_as_super_admin))
+        .route("/list", get(list_workspaces))
+        .route("/users", get(user_workspaces))
+        .route("/create", post(create_workspace))
+        .route("/exists", post(exists_workspace))
+        .route("/validate_username", post(validate_username))
+        .route("/validate_id", post(validate_id))
 }
 
 #[derive(FromRow, Serialize)]
@@ -53,7 +99,7 @@ struct Workspace {
     name: String,
     owner: String,
     domain: Option<String>,
-    deleted: bool
+    deleted: bool,
 }
 
 #[derive(FromRow, Serialize, Debug)]
@@ -61,20 +107,26 @@ pub struct WorkspaceSettings {
     pub workspace_id: String,
     pub slack_team_id: Option<String>,
     pub slack_name: Option<String>,
-    pub slack_command_script: Option<String>
+    pub slack_command_script: Option<String>,
+    pub git_sync_repo: Option<String>,
+    pub git_sync_branch: Option<String>,
+    pub extra_env: serde_json::Value,
 }
 
-
 #[derive(sqlx::Type, Serialize, Deserialize, Debug)]
 #[sqlx(type_name = "WORKSPACE_KEY_KIND", rename_all = "lowercase")]
 pub enum WorkspaceKeyKind {
-    Cloud
+    Cloud,
 }
 
-
 #[derive(Deserialize)]
 struct EditCommandScript {
-    slack_command_script: Option<String>
+    slack_command_script: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct EditExtraEnv {
+    extra_env: Vec<ContextualVariable>,
 }
 #[derive(Deserialize)]
 struct CreateWorkspace {
@@ -84,15 +136,13 @@ struct CreateWorkspace {
     domain: Option<String>,
 }
 
-
 #[derive(Deserialize)]
 struct EditWorkspace {
     name: String,
     owner: String,
-    domain: Option<String>
+    domain: Option<String>,
 }
 
-
 #[derive(Serialize)]
 struct WorkspaceList {
     pub email: String,
@@ -106,7 +156,6 @@ struct UserWorkspace {
     pub username: String,
 }
 
-
 #[derive(Deserialize)]
 struct WorkspaceId {
     pub id: String,
@@ -118,14 +167,12 @@ struct ValidateUsername {
     pub username: String,
 }
 
-
 #[derive(Deserialize)]
 pub struct NewWorkspaceInvite {
     pub email: String,
     pub is_admin: bool,
 }
 
-
 async fn list_pending_invites(
     authed: Authed,
     Extension(user_db): Extension<UserDB>,
@@ -133,26 +180,30 @@ async fn list_pending_invites(
 ) -> JsonResult<Vec<WorkspaceInvite>> {
     require_admin(authed.is_admin, &authed.username)?;
     let mut tx = user_db.begin(&authed).await?;
-    let rows = sqlx::query_as!(WorkspaceInvite, "SELECT * from workspace_invite WHERE workspace_id = $1", w_id)
-        .fetch_all(&mut tx)
-        .await?;
+    let rows = sqlx::query_as!(
+        WorkspaceInvite,
+        "SELECT * from workspace_invite WHERE workspace_id = $1",
+        w_id
+    )
+    .fetch_all(&mut tx)
+    .await?;
     tx.commit().await?;
     Ok(Json(rows))
 }
 
-
 async fn exists_workspace(
     authed: Authed,
     Extension(user_db): Extension<UserDB>,
-    Json(WorkspaceId { id }): Json<WorkspaceId>
+    Json(WorkspaceId { id }): Json<WorkspaceId>,
 ) -> JsonResult<bool> {
     let mut tx = user_db.begin(&authed).await?;
     let exists = sqlx::query_scalar!(
-        "SELECT EXISTS(SELECT 1 FROM workspace WHERE workspace.id = $1)", 
-        id)
-        .fetch_one(&mut tx)
-        .await?
-        .unwrap_or(false);
+        "SELECT EXISTS(SELECT 1 FROM workspace WHERE workspace.id = $1)",
+        id
+    )
+    .fetch_one(&mut tx)
+    .await?
+    .unwrap_or(false);
     tx.commit().await?;
     Ok(Json(exists))
 }
@@ -163,7 +214,7 @@ async fn list_workspaces(
 ) -> JsonResult<Vec<Workspace>> {
     let mut tx = user_db.begin(&authed).await?;
     let workspaces = sqlx::query_as!(
-        Workspace, 
+        Workspace,
         "SELECT workspace.* FROM workspace, usr WHERE usr.workspace_id = workspace.id AND usr.email = $1 AND deleted = false", 
         authed.email.as_ref())
         .fetch_all(&mut tx)
@@ -172,20 +223,25 @@ async fn list_workspaces(
     Ok(Json(workspaces))
 }
 
-
 async fn get_settings(
     authed: Authed,
     Path(w_id): Path<String>,
     Extension(user_db): Extension<UserDB>,
 ) -> JsonResult<WorkspaceSettings> {
     let mut tx = user_db.begin(&authed).await?;
-    let settings = sqlx::query_as!(
-        WorkspaceSettings, 
-        "SELECT * FROM workspace_settings WHERE workspace_id = $1", 
+    let mut settings = sqlx::query_as!(
+        WorkspaceSettings,
+        "SELECT workspace_id, slack_team_id, slack_name, slack_command_script, git_sync_repo, git_sync_branch, extra_env \
+         FROM workspace_settings WHERE workspace_id = $1", 
         &w_id)
         .fetch_one(&mut tx)
         .await?;
     tx.commit().await?;
+    // the credentials of the repository are only readable by git sync
+    settings.git_sync_repo = settings
+        .git_sync_repo
+        .as_deref()
+        .map(git_sync::redact_credentials);
     Ok(Json(settings))
 }
 
@@ -193,8 +249,10 @@ async fn edit_slack_command(
     authed: Authed,
     Extension(db): Extension<DB>,
     Path(w_id): Path<String>,
-    Authed { is_admin, username, .. }: Authed,
-    Json(es): Json<EditCommandScript>
+    Authed {
+        is_admin, username, ..
+    }: Authed,
+    Json(es): Json<EditCommandScript>,
 ) -> Result<String> {
     require_admin(is_admin, &username)?;
     let mut tx = db.begin().await?;
@@ -207,15 +265,21 @@ async fn edit_slack_command(
     .await?;
 
     audit_log(
-         &mut tx,
+        &mut tx,
         &authed.username,
         "workspaces.edit_command_script",
         ActionKind::Update,
         &w_id,
-Some(&authed.email.unwrap()),
-Some([
-            ("script", es.slack_command_script.unwrap_or("NO_SCRIPT".to_string()).as_str())
-        ].into()),
+        Some(&authed.email.unwrap()),
+        Some(
+            [(
+                "script",
+                es.slack_command_script
+                    .unwrap_or("NO_SCRIPT".to_string())
+                    .as_str(),
+            )]
+            .into(),
+        ),
     )
     .await?;
     tx.commit().await?;
@@ -223,6 +287,86 @@ Some([
     Ok(format!("Edit command script {}", &w_id))
 }
 
+/// env vars that windmill, the loader or the runtimes of the jobs rely on. The env of a workspace
+/// can not override them
+const DENIED_ENV_PREFIXES: [&str; 4] = ["WM_", "LD_", "PYTHON", "DENO_"];
+const DENIED_ENV_NAMES: [&str; 6] = [
+    "PATH",
+    "HOME",
+    "TMPDIR",
+    "NO_COLOR",
+    "SSL_CERT_FILE",
+    "SSL_CERT_DIR",
+];
+
+fn check_env_name(name: &str) -> Result<()> {
+    let name_re = Regex::new(r"^[A-Za-z_][A-Za-z0-9_]*$").unwrap();
+    if !name_re.is_match(name) {
+        return Err(Error::BadRequest(format!(
+            "invalid env var name {name}: it must be a valid identifier"
+        )));
+    }
+    let upper = name.to_uppercase();
+    if DENIED_ENV_PREFIXES.iter().any(|x| upper.starts_with(x))
+        || DENIED_ENV_NAMES.contains(&upper.as_str())
+    {
+        return Err(Error::BadRequest(format!(
+            "env var {name} is reserved: it can not be one of {} nor start with {}",
+            DENIED_ENV_NAMES.join(", "),
+            DENIED_ENV_PREFIXES.join(", ")
+        )));
+    }
+    Ok(())
+}
+
+/// env vars injected into every job of the workspace, along with the reserved variables
+async fn edit_extra_env(
+    Authed {
+        is_admin, username, ..
+    }: Authed,
+    Extension(db): Extension<DB>,
+    Path(w_id): Path<String>,
+    Json(ee): Json<EditExtraEnv>,
+) -> Result<String> {
+    require_admin(is_admin, &username)?;
+    for var in &ee.extra_env {
+        check_env_name(&var.name)?;
+        if ee.extra_env.iter().filter(|x| x.name == var.name).count() > 1 {
+            return Err(Error::BadRequest(format!(
+                "env var {} is defined more than once",
+                var.name
+            )));
+        }
+    }
+
+    let mut tx = db.begin().await?;
+    sqlx::query!(
+        "UPDATE workspace_settings SET extra_env = $1 WHERE workspace_id = $2",
+        serde_json::to_value(&ee.extra_env).map_err(|e| Error::InternalErr(e.to_string()))?,
+        &w_id
+    )
+    .execute(&mut tx)
+    .await?;
+    let names = ee
+        .extra_env
+        .iter()
+        .map(|x| x.name.as_str())
+        .collect::<Vec<_>>()
+        .join(",");
+    audit_log(
+        &mut tx,
+        &username,
+        "workspaces.edit_extra_env",
+        ActionKind::Update,
+        &w_id,
+        None,
+        Some([("names", names.as_str())].into()),
+    )
+    .await?;
+    tx.commit().await?;
+
+    Ok(format!("Edit extra env {}", &w_id))
+}
 
 async fn list_workspaces_as_super_admin(
     authed: Authed,
@@ -235,10 +379,13 @@ async fn list_workspaces_as_super_admin(
     let (per_page, offset) = crate::utils::paginate(pagination);
 
     let workspaces = sqlx::query_as!(
-        Workspace, 
-        "SELECT * FROM workspace LIMIT $1 OFFSET $2", per_page as i32, offset as i32)
-        .fetch_all(&mut tx)
-        .await?;
+        Workspace,
+        "SELECT * FROM workspace LIMIT $1 OFFSET $2",
+        per_page as i32,
+        offset as i32
+    )
+    .fetch_all(&mut tx)
+    .await?;
     tx.commit().await?;
     Ok(Json(workspaces))
 }
@@ -252,7 +399,7 @@ async fn user_workspaces(
         .map_err(|x| Error::NotAuthorized(x.to_string()))?;
     let mut tx = db.begin().await?;
     let workspaces = sqlx::query_as!(
-        UserWorkspace, 
+        UserWorkspace,
     "SELECT workspace.id, workspace.name, usr.username
      FROM workspace, usr WHERE usr.workspace_id = workspace.id AND usr.email = $1 AND deleted = false", 
      email)
@@ -265,10 +412,10 @@ async fn user_workspaces(
 async fn create_workspace(
     authed: Authed,
     Extension(db): Extension<DB>,
-    Json(nw): Json<CreateWorkspace>
+    Json(nw): Json<CreateWorkspace>,
 ) -> Result<String> {
     if &nw.username == "bot" {
-        return Err(Error::BadRequest("bot is a reserved username".to_string()))
+        return Err(Error::BadRequest("bot is a reserved username".to_string()));
     }
     let mut tx = db.begin().await?;
     sqlx::query!(
@@ -295,7 +442,8 @@ async fn create_workspace(
         "INSERT INTO workspace_key
             (workspace_id, kind, key)
             VALUES ($1, 'cloud', $2)",
-        nw.id, &key
+        nw.id,
+        &key
     )
     .execute(&mut tx)
     .await?;
@@ -343,7 +491,7 @@ async fn create_workspace(
     .await?;
 
     audit_log(
-         &mut tx,
+        &mut tx,
         &authed.username,
         "workspaces.create",
         ActionKind::Create,
@@ -353,7 +501,6 @@ async fn create_workspace(
     )
     .await?;
     tx.commit().await?;
-    
 
     Ok(format!("Created workspace {}", &nw.id))
 }
@@ -362,8 +509,10 @@ async fn edit_workspace(
     authed: Authed,
     Extension(db): Extension<DB>,
     Path(w_id): Path<String>,
-    Authed { is_admin, username, .. }: Authed,
-    Json(ew): Json<EditWorkspace>
+    Authed {
+        is_admin, username, ..
+    }: Authed,
+    Json(ew): Json<EditWorkspace>,
 ) -> Result<String> {
     require_admin(is_admin, &username)?;
     let mut tx = db.begin().await?;
@@ -378,15 +527,19 @@ async fn edit_workspace(
     .await?;
 
     audit_log(
-         &mut tx,
+        &mut tx,
         &authed.username,
         "workspaces.update",
         ActionKind::Update,
         &w_id,
-Some(&authed.email.unwrap()),
-Some([
-            ("domain", ew.domain.unwrap_or("NO_DOMAIN".to_string()).as_str())
-        ].into()),
+        Some(&authed.email.unwrap()),
+        Some(
+            [(
+                "domain",
+                ew.domain.unwrap_or("NO_DOMAIN".to_string()).as_str(),
+            )]
+            .into(),
+        ),
     )
     .await?;
     tx.commit().await?;
@@ -397,25 +550,27 @@ Some([
 async fn delete_workspace(
     Extension(db): Extension<DB>,
     Path(w_id): Path<String>,
-    Authed { is_admin, username, email, .. }: Authed,
+    Authed {
+        is_admin,
+        username,
+        email,
+        ..
+    }: Authed,
 ) -> Result<String> {
     require_admin(is_admin, &username)?;
     let mut tx = db.begin().await?;
-    sqlx::query!(
-        "UPDATE workspace SET deleted = true WHERE id = $1",
-        &w_id
-    )
-    .execute(&mut tx)
-    .await?;
+    sqlx::query!("UPDATE workspace SET deleted = true WHERE id = $1", &w_id)
+        .execute(&mut tx)
+        .await?;
 
     audit_log(
-         &mut tx,
+        &mut tx,
         &username,
         "workspaces.delete",
         ActionKind::Update,
         &w_id,
-Some(&email.unwrap_or("noemail".to_string())),
-None,
+        Some(&email.unwrap_or("noemail".to_string())),
+        None,
     )
     .await?;
     tx.commit().await?;
@@ -423,14 +578,14 @@ None,
     Ok(format!("Deleted workspace {}", &w_id))
 }
 
-
 async fn invite_user(
-    Authed { username, is_admin, .. }: Authed,
+    Authed {
+        username, is_admin, ..
+    }: Authed,
     Extension(db): Extension<DB>,
     Path(w_id): Path<String>,
     Json(nu): Json<NewWorkspaceInvite>,
 ) -> Result<(StatusCode, String)> {
-
     require_admin(is_admin, &username)?;
 
     let mut tx = db.begin().await?;
@@ -454,14 +609,14 @@ async fn invite_user(
     ))
 }
 
-
 async fn delete_invite(
-    Authed { username, is_admin, .. }: Authed,
+    Authed {
+        username, is_admin, ..
+    }: Authed,
     Extension(db): Extension<DB>,
     Path(w_id): Path<String>,
     Json(nu): Json<NewWorkspaceInvite>,
 ) -> Result<(StatusCode, String)> {
-
     require_admin(is_admin, &username)?;
 
     let mut tx = db.begin().await?;
@@ -488,7 +643,6 @@ async fn validate_username(
     Extension(db): Extension<DB>,
     Json(vu): Json<ValidateUsername>,
 ) -> Result<String> {
-
     let exists = sqlx::query_scalar!(
         "SELECT EXISTS(SELECT 1 FROM usr WHERE username = $1 AND workspace_id = $2)",
         vu.username,
@@ -499,18 +653,13 @@ async fn validate_username(
     .unwrap_or(true);
 
     if exists {
-        return Err(Error::BadRequest("username already taken".to_string()))
+        return Err(Error::BadRequest("username already taken".to_string()));
     }
 
     Ok("valid username".to_string())
 }
 
-
-async fn validate_id(
-    Extension(db): Extension<DB>,
-    Json(wi): Json<WorkspaceId>,
-) -> Result<String> {
-
+async fn validate_id(Extension(db): Extension<DB>, Json(wi): Json<WorkspaceId>) -> Result<String> {
     let exists = sqlx::query_scalar!(
         "SELECT EXISTS(SELECT 1 FROM workspace WHERE id = $1)",
         wi.id
@@ -520,119 +669,270 @@ async fn validate_id(
     .unwrap_or(true);
 
     if exists {
-        return Err(Error::BadRequest("id already taken".to_string()))
+        return Err(Error::BadRequest("id already taken".to_string()));
     }
 
     Ok("valid workspace".to_string())
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
 struct ScriptMetadata {
+    /// hash of the version in the exported workspace, to point the flows pinned to it at the
+    /// imported version
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    hash: Option<ScriptHash>,
     summary: String,
     description: String,
     schema: Option<Schema>,
     is_template: bool,
-    lock: Vec<String>
+    lock: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    timeout: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    memory_limit: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cpu_limit: Option<i32>,
+    #[serde(default)]
+    is_library: bool,
 }
 
-async fn tarball_workspace(
-    authed: Authed,
-    Extension(db): Extension<DB>,
-    Path(w_id): Path<String>,
-) ->  Result<([(headers::HeaderName, String); 2], impl IntoResponse)> {
-    require_admin(authed.is_admin, &authed.username)?;
+/// everything an archive keeps of a script besides its content and its hash
+fn script_metadata(script: &Script) -> ScriptMetadata {
+    let lock = script
+        .lock
+        .as_deref()
+        .unwrap_or("")
+        .lines()
+        .map(|x| x.to_string())
+        .collect();
+    ScriptMetadata {
+        hash: None,
+        summary: script.summary.clone(),
+        description: script.description.clone(),
+        schema: script.schema.as_ref().map(|x| Schema(x.0.clone())),
+        is_template: script.is_template,
+        lock,
+        timeout: script.timeout,
+        memory_limit: script.memory_limit,
+        cpu_limit: script.cpu_limit,
+        is_library: script.is_library,
+    }
+}
 
-    let tmp_dir = TempDir::new_in(".")?;
-    
-    let name = format!("windmill-{w_id}.tar");
-    let file_path = tmp_dir.path().join(&name);
-    let file = File::create(&file_path).await?;
-    let mut a = tokio_tar::Builder::new(file);
-     
+/// files of a script in the layout of `tarball_workspace`: its content and its metadata
+pub fn script_files(script: Script) -> Vec<(String, String)> {
+    let ext = match script.language {
+        ScriptLang::Python3 => "py",
+        ScriptLang::Deno => "ts",
+    };
+    let metadata = ScriptMetadata {
+        hash: Some(script.hash),
+        ..script_metadata(&script)
+    };
+    let metadata_str = serde_json::to_string_pretty(&metadata).unwrap();
+    vec![
+        (format!("scripts/{}.{ext}", script.path), script.content),
+        (format!("scripts/{}.json", script.path), metadata_str),
+    ]
+}
 
-    {
-        let scripts = sqlx::query_as::<_, Script>(
+/// the secret fields of the resource are redacted to null, they are only exported with a passphrase
+pub fn resource_file(mut resource: Resource) -> Result<(String, String)> {
+    if let Some(value) = resource.value.as_mut() {
+        map_encrypted_fields(None, value)?;
+    }
+    Ok((
+        format!("resources/{}.json", resource.path),
+        serde_json::to_string_pretty(&resource).unwrap(),
+    ))
+}
+
+pub fn variable_file(variable: &ListableVariable) -> (String, String) {
+    (
+        format!("variables/{}.json", variable.path),
+        serde_json::to_string_pretty(variable).unwrap(),
+    )
+}
+
+pub fn flow_file(flow: &Flow) -> (String, String) {
+    (
+        format!("flows/{}.json", flow.path),
+        serde_json::to_string_pretty(flow).unwrap(),
+    )
+}
+
+/// all the files of the workspace in the layout of `tarball_workspace`. Secret variables are never
+/// part of it
+pub async fn workspace_files(db: &DB, w_id: &str) -> Result<Vec<(String, String)>> {
+    let mut files = vec![];
+
+    let scripts = sqlx::query_as::<_, Script>(
         "SELECT * FROM script as o WHERE workspace_id = $1 AND archived = false
             AND created_at = (select max(created_at) from script where path = o.path AND workspace_id = $1)"
     )
-    .bind(&w_id)
-    .fetch_all(&db)
+    .bind(w_id)
+    .fetch_all(db)
     .await?;
-
     for script in scripts {
-        write_to_archive(script.content, format!("scripts/{}.py", script.path), &mut a).await?;
-
-        let lock = script.lock.unwrap_or_else(|| "".to_string())
-        .lines()
-        .map(|x| x.to_string())
-        .collect();
-        let metadata = ScriptMetadata { 
-            summary: script.summary, description: script.description, schema: script.schema, is_template: script.is_template, lock };
-        let metadata_str = serde_json::to_string_pretty(&metadata).unwrap();
-        write_to_archive(metadata_str, format!("scripts/{}.json", script.path), &mut a).await?;
+        files.extend(script_files(script));
+    }
 
-    };
-}
+    let resources = sqlx::query_as!(
+        Resource,
+        "SELECT * FROM resource WHERE workspace_id = $1",
+        w_id
+    )
+    .fetch_all(db)
+    .await?;
+    for resource in resources {
+        files.push(resource_file(resource)?);
+    }
 
+    let resource_types = sqlx::query_as!(
+        ResourceType,
+        "SELECT * FROM resource_type WHERE workspace_id = $1",
+        w_id
+    )
+    .fetch_all(db)
+    .await?;
+    for resource_type in resource_types {
+        let resource_str = serde_json::to_string_pretty(&resource_type).unwrap();
+        files.push((
+            format!("resource_types/{}.json", resource_type.name),
+            resource_str,
+        ));
+    }
 
+    let flows = sqlx::query_as::<_, Flow>(
+        "SELECT * FROM flow WHERE workspace_id = $1 AND archived = false",
+    )
+    .bind(w_id)
+    .fetch_all(db)
+    .await?;
+    files.extend(flows.iter().map(flow_file));
 
+    let variables = sqlx::query_as::<_, ListableVariable>(
+        "SELECT * FROM variable WHERE workspace_id = $1 AND is_secret = false",
+    )
+    .bind(w_id)
+    .fetch_all(db)
+    .await?;
+    files.extend(variables.iter().map(variable_file));
 
+    Ok(files)
+}
 
-    {
+async fn tarball_workspace(
+    authed: Authed,
+    Extension(db): Extension<DB>,
+    Path(w_id): Path<String>,
+) -> Result<([(headers::HeaderName, String); 2], impl IntoResponse)> {
+    require_admin(authed.is_admin, &authed.username)?;
+    let files = workspace_files(&db, &w_id).await?;
+    tarball(&w_id, files).await
+}
 
-        let resources = sqlx::query_as!(Resource,
-            "SELECT * FROM resource WHERE workspace_id = $1",
-            &w_id
-        )
-        .fetch_all(&db)
-        .await?;
+/// the passphrase secrets are exported and imported with, in the `x-secrets-passphrase` header
+fn secrets_passphrase(headers: &HeaderMap) -> Result<Option<&str>> {
+    headers
+        .get(SECRETS_PASSPHRASE_HEADER)
+        .map(|x| x.to_str())
+        .transpose()
+        .map_err(|e| Error::BadRequest(format!("invalid passphrase: {e}")))
+}
 
-        for resource in resources {
-            let resource_str = serde_json::to_string_pretty(&resource).unwrap();
-            write_to_archive(resource_str, format!("resources/{}.json", resource.path), &mut a).await?;
-        }
+/// same archive as `tarball_workspace` with the secret variables and the secret fields of the
+/// resources, encrypted with the passphrase of the `x-secrets-passphrase` header instead of the key
+/// of the workspace so that they can be imported in any other workspace
+async fn tarball_workspace_with_secrets(
+    authed: Authed,
+    Extension(db): Extension<DB>,
+    Path(w_id): Path<String>,
+    headers: HeaderMap,
+) -> Result<([(headers::HeaderName, String); 2], impl IntoResponse)> {
+    require_admin(authed.is_admin, &authed.username)?;
+    let passphrase = secrets_passphrase(&headers)?
+        .ok_or_else(|| Error::BadRequest(format!("missing {SECRETS_PASSPHRASE_HEADER} header")))?;
+    if passphrase.chars().count() < MIN_PASSPHRASE_LENGTH {
+        return Err(Error::BadRequest(format!(
+            "the passphrase must be at least {MIN_PASSPHRASE_LENGTH} characters long"
+        )));
     }
+    let mut files = workspace_files(&db, &w_id).await?;
 
-    {
-        let resource_types = sqlx::query_as!(ResourceType,
-        "SELECT * FROM resource_type WHERE workspace_id = $1",
+    let salt = SaltString::generate(&mut OsRng);
+    let export_mc = passphrase_crypt(passphrase, salt.as_str())?;
+    let secrets_metadata = SecretsMetadata {
+        salt: salt.as_str().to_string(),
+        check: encrypt(&export_mc, SECRETS_CHECK.to_string()),
+    };
+    files.push((
+        SECRETS_FILE.to_string(),
+        serde_json::to_string_pretty(&secrets_metadata).unwrap(),
+    ));
+
+    let mut tx = db.begin().await?;
+    let mc = build_crypt(&mut tx, &w_id).await?;
+    let secrets = sqlx::query_as::<_, ListableVariable>(
+        "SELECT * FROM variable WHERE workspace_id = $1 AND is_secret = true",
+    )
+    .bind(&w_id)
+    .fetch_all(&mut tx)
+    .await?;
+    for mut var in secrets {
+        var.value = var
+            .value
+            .map(|value| {
+                mc.decrypt_base64_to_string(value)
+                    .map(|value| encrypt(&export_mc, value))
+                    .map_err(|e| Error::InternalErr(format!("could not decrypt {}: {e}", var.path)))
+            })
+            .transpose()?;
+        files.push(variable_file(&var));
+    }
+    let resources = sqlx::query_as!(
+        Resource,
+        "SELECT * FROM resource WHERE workspace_id = $1",
         &w_id
     )
-    .fetch_all(&db)
+    .fetch_all(&mut tx)
     .await?;
-
-        for resource_type in resource_types {
-            let resource_str = serde_json::to_string_pretty(&resource_type).unwrap();
-            write_to_archive(resource_str, format!("resource_types/{}.json", resource_type.name), &mut a).await?;
+    for mut resource in resources {
+        if let Some(value) = resource.value.as_mut().filter(|x| has_encrypted_fields(x)) {
+            reencrypt_fields(&resource.path, &mc, &export_mc, value)?;
+            let file = format!("resources/{}.json", resource.path);
+            files.retain(|x| x.0 != file);
+            files.push((file, serde_json::to_string_pretty(&resource).unwrap()));
         }
     }
+    audit_log(
+        &mut tx,
+        &authed.username,
+        "workspaces.export_secrets",
+        ActionKind::Execute,
+        &w_id,
+        None,
+        None,
+    )
+    .await?;
+    tx.commit().await?;
 
-    {
-        let flows = sqlx::query_as::<_, Flow>(
-            "SELECT * FROM flow WHERE workspace_id = $1 AND archived = false"
-        )
-        .bind(&w_id)
-        .fetch_all(&db)
-        .await?;
+    tarball(&w_id, files).await
+}
 
-        for flow in flows {
-            let flow_str = serde_json::to_string_pretty(&flow).unwrap();
-            write_to_archive(flow_str, format!("flows/{}.json", flow.path), &mut a).await?;
-        }
-    }
+async fn tarball(
+    w_id: &str,
+    files: Vec<(String, String)>,
+) -> Result<([(headers::HeaderName, String); 2], impl IntoResponse)> {
+    let tmp_dir = TempDir::new_in(".")?;
 
-    {
-       let variables = sqlx::query_as::<_, ListableVariable>(
-        "SELECT * FROM variable WHERE workspace_id = $1 AND is_secret = false"
-        )
-        .bind(&w_id)
-        .fetch_all(&db)
-        .await?;
+    let name = format!("windmill-{w_id}.tar");
+    let file_path = tmp_dir.path().join(&name);
+    let file = File::create(&file_path).await?;
+    let mut a = tokio_tar::Builder::new(file);
 
-        for var in variables {
-            let flow_str = serde_json::to_string_pretty(&var).unwrap();
-            write_to_archive(flow_str, format!("variables/{}.json", var.path), &mut a).await?;
-        }
+    for (path, content) in files {
+        write_to_archive(content, path, &mut a).await?;
     }
     a.into_inner().await?;
 
@@ -652,7 +952,11 @@ async fn tarball_workspace(
     Ok((headers, body))
 }
 
-async fn write_to_archive(content: String, path: String, a: &mut tokio_tar::Builder<File>) -> Result<()> {
+async fn write_to_archive(
+    content: String,
+    path: String,
+    a: &mut tokio_tar::Builder<File>,
+) -> Result<()> {
     let bytes = content.as_bytes();
     let mut header = tokio_tar::Header::new_gnu();
     header.set_size(bytes.len() as u64);
@@ -664,3 +968,1467 @@ async fn write_to_archive(content: String, path: String, a: &mut tokio_tar::Buil
     a.append_data(&mut header, path, bytes).await?;
     Ok(())
 }
+
+#[derive(Deserialize)]
+pub struct ImportQuery {
+    pub dry_run: Option<bool>,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+#[serde(rename_all(serialize = "lowercase"))]
+pub enum ImportChangeKind {
+    Create,
+    Update,
+    Unchanged,
+    Remove,
+}
+
+#[derive(Serialize)]
+pub struct ImportChange {
+    pub kind: &'static str,
+    pub path: String,
+    pub change: ImportChangeKind,
+}
+
+/// how an archive is applied to a workspace
+pub struct ImportOptions {
+    /// report the changes without applying them
+    pub dry_run: bool,
+    /// archive the scripts and flows and delete the resources and non secret variables of the
+    /// workspace that the archive does not have
+    pub remove_missing: bool,
+}
+
+#[derive(Serialize)]
+pub struct ImportReport {
+    pub dry_run: bool,
+    pub changes: Vec<ImportChange>,
+    /// what could not be imported as is
+    pub warnings: Vec<String>,
+}
+
+/// content of an archive produced by `tarball_workspace`
+#[derive(Default)]
+pub struct WorkspaceArchive {
+    scripts: Vec<(String, ScriptLang, String)>,
+    script_metadata: HashMap<String, ScriptMetadata>,
+    resource_types: Vec<CreateResourceType>,
+    resources: Vec<CreateResource>,
+    flows: Vec<NewFlow>,
+    variables: Vec<CreateVariable>,
+    secrets: Option<SecretsMetadata>,
+    /// the key of the passphrase of the export, once validated
+    export_mc: Option<MagicCrypt256>,
+    errors: Vec<String>,
+}
+
+impl WorkspaceArchive {
+    /// kind and path of every script, resource, flow and variable of the archive
+    fn paths(&self) -> HashSet<(&'static str, String)> {
+        self.scripts
+            .iter()
+            .map(|x| ("script", x.0.clone()))
+            .chain(self.resources.iter().map(|x| ("resource", x.path.clone())))
+            .chain(self.flows.iter().map(|x| ("flow", x.path.clone())))
+            .chain(self.variables.iter().map(|x| ("variable", x.path.clone())))
+            .collect()
+    }
+
+    /// parse a file of the archive given its path relative to the root of the archive
+    pub fn add_file(&mut self, file: &str, content: String) {
+        let (dir, name) = file.split_once('/').unwrap_or(("", file));
+        let parsed = match (dir, name.rsplit_once('.')) {
+            ("scripts", Some((path, "py"))) => {
+                self.scripts
+                    .push((path.to_string(), ScriptLang::Python3, content));
+                Ok(())
+            }
+            ("scripts", Some((path, "ts"))) => {
+                self.scripts
+                    .push((path.to_string(), ScriptLang::Deno, content));
+                Ok(())
+            }
+            ("scripts", Some((path, "json"))) => serde_json::from_str(&content).map(|x| {
+                self.script_metadata.insert(path.to_string(), x);
+            }),
+            ("resource_types", Some((_, "json"))) => {
+                serde_json::from_str(&content).map(|x| self.resource_types.push(x))
+            }
+            ("resources", Some((_, "json"))) => {
+                serde_json::from_str(&content).map(|x| self.resources.push(x))
+            }
+            ("flows", Some((_, "json"))) => {
+                serde_json::from_str(&content).map(|x| self.flows.push(x))
+            }
+            ("variables", Some((_, "json"))) => {
+                serde_json::from_str(&content).map(|x| self.variables.push(x))
+            }
+            ("", _) if file == SECRETS_FILE => {
+                serde_json::from_str(&content).map(|x| self.secrets = Some(x))
+            }
+            _ => {
+                self.errors.push(format!("- {file}: unexpected file"));
+                Ok(())
+            }
+        };
+        if let Err(e) = parsed {
+            self.errors.push(format!("- {file}: {e}"));
+        }
+    }
+
+    /// check that every item of the archive can be imported, listing all the errors at once. The
+    /// secret variables are decrypted with the passphrase they were exported with, which is kept to
+    /// re-encrypt the secret fields of the resources
+    pub fn validate(mut self, passphrase: Option<&str>) -> Result<Self> {
+        let path_re = Regex::new(r"^[ug](/[\w-]+){2,}$").unwrap();
+        // only scripts can be in a folder
+        let script_path_re = Regex::new(r"^[ugf](/[\w-]+){2,}$").unwrap();
+        let name_re = Regex::new(r"^[\w-]+$").unwrap();
+        let mut errors = std::mem::take(&mut self.errors);
+        let paths = self
+            .scripts
+            .iter()
+            .map(|x| ("script", &x.0))
+            .chain(self.resources.iter().map(|x| ("resource", &x.path)))
+            .chain(self.flows.iter().map(|x| ("flow", &x.path)))
+            .chain(self.variables.iter().map(|x| ("variable", &x.path)));
+        for (kind, path) in paths {
+            let re = if kind == "script" {
+                &script_path_re
+            } else {
+                &path_re
+            };
+            if !re.is_match(path) {
+                errors.push(format!("- {kind} {path}: invalid path"));
+            }
+        }
+        for (path, _, _) in &self.scripts {
+            if !self.script_metadata.contains_key(path) {
+                errors.push(format!(
+                    "- script {path}: missing metadata scripts/{path}.json"
+                ));
+            }
+        }
+        for rt in &self.resource_types {
+            if !name_re.is_match(&rt.name) {
+                errors.push(format!("- resource type {}: invalid name", rt.name));
+            }
+        }
+        let has_secret_fields =
+            |x: &CreateResource| x.value.as_ref().map(has_encrypted_fields).unwrap_or(false);
+        if self.variables.iter().any(|x| x.is_secret)
+            || self.resources.iter().any(has_secret_fields)
+        {
+            match (passphrase, &self.secrets) {
+                (Some(passphrase), Some(secrets)) => {
+                    let mc = passphrase_crypt(passphrase, &secrets.salt)?;
+                    if mc.decrypt_base64_to_string(&secrets.check).ok().as_deref()
+                        != Some(SECRETS_CHECK)
+                    {
+                        return Err(Error::BadRequest(
+                            "the passphrase does not match the one of the export".to_string(),
+                        ));
+                    }
+                    for variable in self.variables.iter_mut().filter(|x| x.is_secret) {
+                        match mc.decrypt_base64_to_string(&variable.value) {
+                            Ok(value) => variable.value = value,
+                            Err(e) => errors.push(format!("- variable {}: {e}", variable.path)),
+                        }
+                    }
+                    for resource in self.resources.iter().filter(|x| has_secret_fields(x)) {
+                        let mut value = resource.value.clone().unwrap_or_default();
+                        if let Err(e) = map_encrypted_fields(Some(&mc), &mut value) {
+                            errors.push(format!("- resource {}: {e}", resource.path));
+                        }
+                    }
+                    self.export_mc = Some(mc);
+                }
+                (Some(_), None) => errors.push(format!(
+                    "- {SECRETS_FILE}: missing, the secrets cannot be decrypted"
+                )),
+                (None, _) => {
+                    for variable in self.variables.iter().filter(|x| x.is_secret) {
+                        errors.push(format!(
+                            "- variable {}: secret variables can only be imported with the passphrase of the export",
+                            variable.path
+                        ));
+                    }
+                    for resource in self.resources.iter().filter(|x| has_secret_fields(x)) {
+                        errors.push(format!(
+                            "- resource {}: secret fields can only be imported with the passphrase of the export",
+                            resource.path
+                        ));
+                    }
+                }
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(self)
+        } else {
+            Err(Error::BadRequest(format!(
+                "invalid archive:\n{}",
+                errors.join("\n")
+            )))
+        }
+    }
+}
+
+/// how the secret variables of an archive are encrypted. `check` is `SECRETS_CHECK` encrypted with
+/// the passphrase, to tell a wrong passphrase from a corrupted secret
+#[derive(Serialize, Deserialize)]
+struct SecretsMetadata {
+    salt: String,
+    check: String,
+}
+
+fn passphrase_crypt(passphrase: &str, salt: &str) -> Result<MagicCrypt256> {
+    let salt =
+        SaltString::new(salt).map_err(|e| Error::BadRequest(format!("invalid salt: {e}")))?;
+    let hash = Argon2::default()
+        .hash_password(passphrase.as_bytes(), &salt)
+        .map_err(|e| Error::InternalErr(e.to_string()))?;
+    let key = hash
+        .hash
+        .ok_or_else(|| Error::InternalErr("passphrase hash has no output".to_string()))?
+        .to_string();
+    Ok(magic_crypt::new_magic_crypt!(key, 256))
+}
+
+async fn read_archive(body: Bytes) -> Result<WorkspaceArchive> {
+    let mut archive = WorkspaceArchive::default();
+    let mut tar = tokio_tar::Archive::new(Cursor::new(body));
+    let mut entries = tar.entries()?;
+    while let Some(entry) = entries.next().await {
+        let mut entry = entry?;
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+        let file = entry.path()?.to_string_lossy().to_string();
+        let mut content = String::new();
+        entry.read_to_string(&mut content).await?;
+        archive.add_file(&file, content);
+    }
+    Ok(archive)
+}
+
+/// order the scripts of an archive so that the libraries come first, each after the libraries of
+/// the archive it imports: creating a script resolves the libraries it imports
+fn libraries_first(
+    scripts: Vec<(String, ScriptLang, String)>,
+    metadata: &HashMap<String, ScriptMetadata>,
+) -> Result<Vec<(String, ScriptLang, String)>> {
+    let (libraries, scripts): (Vec<_>, Vec<_>) = scripts
+        .into_iter()
+        .partition(|x| metadata.get(&x.0).map(|m| m.is_library).unwrap_or(false));
+    let mut paths = libraries.iter().map(|x| x.0.clone()).collect::<Vec<_>>();
+    paths.sort();
+    let mut pending = libraries
+        .into_iter()
+        .map(|x| (x.0.clone(), x))
+        .collect::<HashMap<_, _>>();
+    let mut ordered = vec![];
+    for path in paths {
+        push_library(&path, &mut pending, &mut ordered)?;
+    }
+    ordered.extend(scripts);
+    Ok(ordered)
+}
+
+fn push_library(
+    path: &str,
+    pending: &mut HashMap<String, (String, ScriptLang, String)>,
+    ordered: &mut Vec<(String, ScriptLang, String)>,
+) -> Result<()> {
+    // removed before visiting its imports so that an import cycle terminates
+    if let Some(library) = pending.remove(path) {
+        for import in parse_library_imports(&library.1, &library.2)? {
+            push_library(&import, pending, ordered)?;
+        }
+        ordered.push(library);
+    }
+    Ok(())
+}
+
+fn import_change<T: PartialEq>(existing: Option<T>, imported: T) -> ImportChangeKind {
+    match existing {
+        None => ImportChangeKind::Create,
+        Some(existing) if existing == imported => ImportChangeKind::Unchanged,
+        Some(_) => ImportChangeKind::Update,
+    }
+}
+
+/// load an archive produced by `tarball_workspace` into the workspace, creating the missing items
+/// and updating the ones that differ. Nothing is written in dry run mode. The secret variables and
+/// the secret fields of the resources of the archive need the passphrase of the export in the
+/// `x-secrets-passphrase` header
+async fn import_workspace(
+    authed: Authed,
+    Tokened { token }: Tokened,
+    Extension(db): Extension<DB>,
+    Path(w_id): Path<String>,
+    Query(iq): Query<ImportQuery>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> JsonResult<ImportReport> {
+    require_admin(authed.is_admin, &authed.username)?;
+    let passphrase = secrets_passphrase(&headers)?;
+    let archive = read_archive(body).await?.validate(passphrase)?;
+    let report = import_archive(
+        &db,
+        &w_id,
+        archive,
+        ImportOptions {
+            dry_run: iq.dry_run.unwrap_or(false),
+            remove_missing: false,
+        },
+        &authed,
+        &token,
+        "workspaces.import",
+    )
+    .await?;
+    if !report.dry_run
+        && report
+            .changes
+            .iter()
+            .any(|x| x.change != ImportChangeKind::Unchanged)
+    {
+        let git_sync =
+            git_sync::workspace_commit(&db, &w_id, format!("Import archive into workspace {w_id}"))
+                .await?;
+        git_sync::spawn_commit(git_sync, &authed);
+    }
+    Ok(Json(report))
+}
+
+/// apply a validated archive to the workspace in a single transaction, audited under `operation`.
+/// The transaction is not restricted by RLS (`UserDB::begin`): an import writes the items of every
+/// user and group of the workspace, so its callers require an admin, whose role bypasses RLS anyway
+pub async fn import_archive(
+    db: &DB,
+    w_id: &str,
+    archive: WorkspaceArchive,
+    options: ImportOptions,
+    authed: &Authed,
+    token: &str,
+    operation: &str,
+) -> Result<ImportReport> {
+    let dry_run = options.dry_run;
+    let imported = archive.paths();
+    let mut changes = vec![];
+    let mut tx = db.begin().await?;
+
+    for rt in archive.resource_types {
+        let existing = sqlx::query!(
+            "SELECT schema, description FROM resource_type WHERE workspace_id = $1 AND name = $2",
+            w_id,
+            rt.name
+        )
+        .fetch_optional(&mut tx)
+        .await?
+        .map(|x| (x.schema, x.description));
+        let change = import_change(existing, (rt.schema.clone(), rt.description.clone()));
+        if !dry_run && change != ImportChangeKind::Unchanged {
+            sqlx::query!(
+                "INSERT INTO resource_type (workspace_id, name, schema, description) VALUES ($1, $2, $3, $4) \
+                 ON CONFLICT (workspace_id, name) DO UPDATE SET schema = $3, description = $4",
+                w_id,
+                rt.name,
+                rt.schema,
+                rt.description
+            )
+            .execute(&mut tx)
+            .await?;
+        }
+        changes.push(ImportChange {
+            kind: "resource_type",
+            path: rt.name,
+            change,
+        });
+    }
+
+    // secret fields of a validated archive are encrypted with the passphrase of the export, they are
+    // re-encrypted with the key of the destination workspace
+    let resource_mc = match &archive.export_mc {
+        Some(_) => Some(build_crypt_for_write(&mut tx, w_id).await?),
+        None => None,
+    };
+    for mut resource in archive.resources {
+        let existing = sqlx::query!(
+            "SELECT value, description, resource_type FROM resource WHERE workspace_id = $1 AND path = $2",
+            w_id,
+            resource.path
+        )
+        .fetch_optional(&mut tx)
+        .await?
+        .map(|x| (x.value, x.description, x.resource_type));
+        match (&archive.export_mc, &resource_mc, resource.value.as_mut()) {
+            (Some(export_mc), Some(mc), Some(value)) => {
+                reencrypt_fields(&resource.path, export_mc, mc, value)?
+            }
+            // an archive without secrets has the secret fields redacted, the ones of the
+            // destination are kept
+            (_, _, Some(value)) => {
+                if let Some(existing) = existing.as_ref().and_then(|x| x.0.as_ref()) {
+                    keep_redacted_fields(existing, value);
+                }
+            }
+            _ => (),
+        }
+        prepare_resource_value(
+            &mut tx,
+            w_id,
+            &resource.resource_type,
+            &resource.path,
+            &mut resource.value,
+        )
+        .await?;
+        let change = import_change(
+            existing,
+            (
+                resource.value.clone(),
+                resource.description.clone(),
+                resource.resource_type.clone(),
+            ),
+        );
+        if !dry_run && change != ImportChangeKind::Unchanged {
+            sqlx::query!(
+                "INSERT INTO resource (workspace_id, path, value, description, resource_type) VALUES ($1, $2, $3, $4, $5) \
+                 ON CONFLICT (workspace_id, path) DO UPDATE SET value = $3, description = $4, resource_type = $5",
+                w_id,
+                resource.path,
+                resource.value,
+                resource.description,
+                resource.resource_type
+            )
+            .execute(&mut tx)
+            .await?;
+            insert_resource_version(&mut tx, w_id, &resource.path, &authed.username).await?;
+        }
+        changes.push(ImportChange {
+            kind: "resource",
+            path: resource.path,
+            change,
+        });
+    }
+
+    // secrets of a validated archive are in plain text, they are encrypted with the key of the
+    // destination workspace
+    let mc = if archive.variables.iter().any(|x| x.is_secret) {
+        Some(build_crypt_for_write(&mut tx, w_id).await?)
+    } else {
+        None
+    };
+    let mut secrets = 0;
+    for variable in archive.variables {
+        let existing = sqlx::query!(
+            "SELECT value, description, is_secret, external FROM variable WHERE workspace_id = $1 AND path = $2",
+            w_id,
+            variable.path
+        )
+        .fetch_optional(&mut tx)
+        .await?
+        .map(|x| (x.value, x.description, x.is_secret, x.external));
+        let value = match &mc {
+            Some(mc) if variable.is_secret => {
+                secrets += 1;
+                encrypt(mc, variable.value.clone())
+            }
+            _ => {
+                if existing.as_ref().map(|x| x.2).unwrap_or(false) {
+                    return Err(Error::BadRequest(format!(
+                        "variable {} is secret in the target workspace and cannot be overwritten by a non secret variable",
+                        variable.path
+                    )));
+                }
+                variable.value.clone()
+            }
+        };
+        let external = variable
+            .external
+            .as_ref()
+            .map(|x| {
+                x.validate()?;
+                serde_json::to_value(x).map_err(|e| Error::InternalErr(e.to_string()))
+            })
+            .transpose()?;
+        let change = import_change(
+            existing,
+            (
+                value.clone(),
+                variable.description.clone(),
+                variable.is_secret,
+                external.clone(),
+            ),
+        );
+        if !dry_run && change != ImportChangeKind::Unchanged {
+            sqlx::query!(
+                "INSERT INTO variable (workspace_id, path, value, is_secret, description, external) VALUES ($1, $2, $3, $4, $5, $6) \
+                 ON CONFLICT (workspace_id, path) DO UPDATE SET value = $3, is_secret = $4, description = $5, external = $6",
+                w_id,
+                variable.path,
+                value,
+                variable.is_secret,
+                variable.description,
+                external
+            )
+            .execute(&mut tx)
+            .await?;
+            insert_variable_version(&mut tx, w_id, &variable.path, &authed.username).await?;
+        }
+        changes.push(ImportChange {
+            kind: "variable",
+            path: variable.path,
+            change,
+        });
+    }
+
+    // the hash of each script of the archive in the exported workspace, to the hash of its version
+    // in this workspace. `None` for a script that would be created or updated in dry run mode
+    let mut hashes: HashMap<i64, Option<ScriptHash>> = HashMap::new();
+    let mut metadata_by_path = archive.script_metadata;
+    for (path, language, content) in libraries_first(archive.scripts, &metadata_by_path)? {
+        let mut metadata = metadata_by_path.remove(&path).unwrap();
+        let exported_hash = metadata.hash.take();
+        let existing = sqlx::query_as!(
+            Script,
+            "SELECT workspace_id, hash as \"hash: ScriptHash\", path, parent_hashes as \"parent_hashes: ScriptHashes\", \
+             summary, description, content, created_by, created_at, archived, schema as \"schema: Schema\", deleted, \
+             is_template as \"is_template!\", extra_perms, lock, lock_error_logs, language as \"language: ScriptLang\", timeout, \
+             memory_limit, cpu_limit, is_library FROM script WHERE workspace_id = $1 AND path = $2 \
+             AND archived = false AND deleted = false",
+            w_id,
+            path
+        )
+        .fetch_optional(&mut tx)
+        .await?;
+        let parent_hash = existing.as_ref().map(|x| x.hash);
+        let change = match &existing {
+            None => ImportChangeKind::Create,
+            Some(existing)
+                if existing.content == content
+                    && existing.language == language
+                    && script_metadata(existing) == metadata =>
+            {
+                ImportChangeKind::Unchanged
+            }
+            Some(_) => ImportChangeKind::Update,
+        };
+        let mut hash = parent_hash.filter(|_| change == ImportChangeKind::Unchanged);
+        if !dry_run && change != ImportChangeKind::Unchanged {
+            let ns = NewScript {
+                path: path.clone(),
+                parent_hash,
+                summary: metadata.summary,
+                description: metadata.description,
+                content,
+                schema: metadata.schema,
+                is_template: Some(metadata.is_template),
+                lock: Some(metadata.lock).filter(|x| !x.is_empty()),
+                language,
+                timeout: metadata.timeout,
+                memory_limit: metadata.memory_limit,
+                cpu_limit: metadata.cpu_limit,
+                is_library: Some(metadata.is_library),
+            };
+            let (new_hash, new_tx) = create_script_internal(ns, w_id, authed, token, tx).await?;
+            hash = Some(new_hash);
+            tx = new_tx;
+        }
+        if let Some(exported_hash) = exported_hash {
+            hashes.insert(exported_hash.0, hash);
+        }
+        changes.push(ImportChange {
+            kind: "script",
+            path,
+            change,
+        });
+    }
+
+    let mut warnings = vec![];
+    for mut nf in archive.flows {
+        // once the scripts of the archive are imported, a flow may be pinned to them
+        remap_pinned_hashes(
+            &mut tx,
+            w_id,
+            &nf.path,
+            &mut nf.value,
+            &hashes,
+            &mut warnings,
+        )
+        .await?;
+        let existing = sqlx::query!(
+            "SELECT summary, description, value, schema FROM flow WHERE workspace_id = $1 AND path = $2",
+            w_id,
+            nf.path
+        )
+        .fetch_optional(&mut tx)
+        .await?
+        .map(|x| (x.summary, x.description, x.value, x.schema));
+        let schema = nf.schema.as_ref().map(|x| x.0.clone());
+        let change = import_change(
+            existing,
+            (
+                nf.summary.clone(),
+                nf.description.clone(),
+                nf.value.clone(),
+                schema.clone(),
+            ),
+        );
+        if !dry_run && change != ImportChangeKind::Unchanged {
+            sqlx::query!(
+                "INSERT INTO flow (workspace_id, path, summary, description, value, edited_by, schema) \
+                 VALUES ($1, $2, $3, $4, $5, $6, $7) ON CONFLICT (workspace_id, path) DO UPDATE SET \
+                 summary = $3, description = $4, value = $5, edited_by = $6, edited_at = now(), schema = $7, \
+                 archived = false",
+                w_id,
+                nf.path,
+                nf.summary,
+                nf.description,
+                nf.value,
+                authed.username,
+                schema
+            )
+            .execute(&mut tx)
+            .await?;
+            insert_flow_version(&mut tx, w_id, &nf, &authed.username).await?;
+        }
+        changes.push(ImportChange {
+            kind: "flow",
+            path: nf.path,
+            change,
+        });
+    }
+
+    if options.remove_missing {
+        changes.extend(remove_missing(&mut tx, w_id, &imported, dry_run).await?);
+    }
+
+    if dry_run {
+        tx.rollback().await?;
+    } else {
+        let count = |kind: ImportChangeKind| {
+            changes
+                .iter()
+                .filter(|x| x.change == kind)
+                .count()
+                .to_string()
+        };
+        audit_log(
+            &mut tx,
+            &authed.username,
+            operation,
+            ActionKind::Update,
+            w_id,
+            None,
+            Some(
+                [
+                    ("created", count(ImportChangeKind::Create).as_str()),
+                    ("updated", count(ImportChangeKind::Update).as_str()),
+                    ("removed", count(ImportChangeKind::Remove).as_str()),
+                    ("secrets", secrets.to_string().as_str()),
+                    ("token", &truncate_token(token)),
+                ]
+                .into(),
+            ),
+        )
+        .await?;
+        tx.commit().await?;
+    }
+
+    Ok(ImportReport {
+        dry_run,
+        changes,
+        warnings,
+    })
+}
+
+/// point the steps of a flow pinned to a script of the archive at the version imported in the
+/// workspace, imported scripts get new hashes. A pin to a version the workspace does not have, an
+/// older version of a script of the archive typically, is dropped with a warning: the step then
+/// runs the latest version of its script
+async fn remap_pinned_hashes<'c>(
+    tx: &mut Transaction<'c, Postgres>,
+    w_id: &str,
+    flow_path: &str,
+    value: &mut serde_json::Value,
+    hashes: &HashMap<i64, Option<ScriptHash>>,
+    warnings: &mut Vec<String>,
+) -> Result<()> {
+    let mut steps = vec![];
+    if let Some(flow) = value.as_object_mut() {
+        for (key, v) in flow.iter_mut() {
+            match (key.as_str(), v) {
+                ("modules", serde_json::Value::Array(modules)) => {
+                    steps.extend(modules.iter_mut().filter_map(|x| x.get_mut("value")))
+                }
+                ("failure_module", module) => steps.extend(module.get_mut("value")),
+                _ => (),
+            }
+        }
+    }
+    for step in steps {
+        if step.get("type").and_then(|x| x.as_str()) != Some("script") {
+            continue;
+        }
+        let pinned = match step
+            .get("hash")
+            .cloned()
+            .map(serde_json::from_value::<ScriptHash>)
+        {
+            Some(Ok(pinned)) => pinned,
+            _ => continue,
+        };
+        let path = step
+            .get("path")
+            .and_then(|x| x.as_str())
+            .unwrap_or_default()
+            .to_string();
+        match hashes.get(&pinned.0) {
+            Some(Some(hash)) => step["hash"] = serde_json::json!(hash),
+            // the script is not imported in dry run mode, the pin would be remapped
+            Some(None) => (),
+            None => {
+                if check_hash_for_path(tx, w_id, &path, &pinned).await.is_err() {
+                    if let Some(step) = step.as_object_mut() {
+                        step.remove("hash");
+                    }
+                    warnings.push(format!(
+                        "flow {flow_path}: the step running {path} was pinned to {pinned}, a version \
+                         the workspace does not have, it now runs the latest version"
+                    ));
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// archive or delete the items of the workspace missing from `imported`. Secret variables are never
+/// part of an archive and resource types may still be used by other workspaces, they are kept
+async fn remove_missing<'c>(
+    tx: &mut Transaction<'c, Postgres>,
+    w_id: &str,
+    imported: &HashSet<(&'static str, String)>,
+    dry_run: bool,
+) -> Result<Vec<ImportChange>> {
+    let scripts = sqlx::query_scalar!(
+        "SELECT DISTINCT path FROM script WHERE workspace_id = $1 AND archived = false AND deleted = false",
+        w_id
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+    let flows = sqlx::query_scalar!(
+        "SELECT path FROM flow WHERE workspace_id = $1 AND archived = false",
+        w_id
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+    let resources = sqlx::query_scalar!("SELECT path FROM resource WHERE workspace_id = $1", w_id)
+        .fetch_all(&mut *tx)
+        .await?;
+    let variables = sqlx::query_scalar!(
+        "SELECT path FROM variable WHERE workspace_id = $1 AND is_secret = false",
+        w_id
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+
+    let mut changes = vec![];
+    let missing = |kind: &'static str, paths: Vec<String>| {
+        paths
+            .into_iter()
+            .filter(move |x| !imported.contains(&(kind, x.clone())))
+            .map(move |path| ImportChange {
+                kind,
+                path,
+                change: ImportChangeKind::Remove,
+            })
+    };
+    changes.extend(missing("script", scripts));
+    changes.extend(missing("flow", flows));
+    changes.extend(missing("resource", resources));
+    changes.extend(missing("variable", variables));
+    if dry_run {
+        return Ok(changes);
+    }
+
+    for change in &changes {
+        match change.kind {
+            "script" => {
+                sqlx::query!(
+                    "UPDATE script SET archived = true WHERE workspace_id = $1 AND path = $2",
+                    w_id,
+                    change.path
+                )
+                .execute(&mut *tx)
+                .await?
+            }
+            "flow" => {
+                sqlx::query!(
+                    "UPDATE flow SET archived = true WHERE workspace_id = $1 AND path = $2",
+                    w_id,
+                    change.path
+                )
+                .execute(&mut *tx)
+                .await?
+            }
+            "resource" => {
+                sqlx::query!(
+                    "DELETE FROM resource WHERE workspace_id = $1 AND path = $2",
+                    w_id,
+                    change.path
+                )
+                .execute(&mut *tx)
+                .await?
+            }
+            _ => {
+                sqlx::query!(
+                    "DELETE FROM variable WHERE workspace_id = $1 AND path = $2",
+                    w_id,
+                    change.path
+                )
+                .execute(&mut *tx)
+                .await?
+            }
+        };
+    }
+    Ok(changes)
+}
+
+#[derive(Serialize, FromRow)]
+struct PreviousKey {
+    rotated_by: String,
+    rotated_at: chrono::DateTime<chrono::Utc>,
+    expires_at: chrono::DateTime<chrono::Utc>,
+}
+
+/// re-encrypt every secret of a workspace from `from_key` to `to_key` and make `to_key` the key of
+/// the workspace. Returns the number of secret variables and resources re-encrypted
+async fn switch_key<'c>(
+    tx: &mut Transaction<'c, Postgres>,
+    w_id: &str,
+    from_key: &str,
+    to_key: &str,
+) -> Result<(usize, usize)> {
+    let from = magic_crypt::new_magic_crypt!(from_key, 256);
+    let to = magic_crypt::new_magic_crypt!(to_key, 256);
+    let variables = reencrypt_variables(tx, w_id, &from, &to).await?;
+    let resources = reencrypt_resources(tx, w_id, &from, &to).await?;
+    crate::oauth2::reencrypt_accounts(tx, w_id, &from, &to).await?;
+    git_sync::reencrypt_credentials(tx, w_id, &from, &to).await?;
+    sqlx::query!(
+        "UPDATE workspace_key SET key = $1 WHERE workspace_id = $2 AND kind = 'cloud'",
+        to_key,
+        w_id
+    )
+    .execute(&mut *tx)
+    .await?;
+    Ok((variables, resources))
+}
+
+async fn delete_expired_previous_key<'c>(
+    tx: &mut Transaction<'c, Postgres>,
+    w_id: &str,
+) -> Result<()> {
+    sqlx::query!(
+        "DELETE FROM workspace_previous_key WHERE workspace_id = $1 AND expires_at <= now()",
+        w_id
+    )
+    .execute(&mut *tx)
+    .await?;
+    Ok(())
+}
+
+/// delete the previous keys whose rollback window is over in every workspace, returns the workspaces
+/// they belonged to
+pub async fn delete_expired_previous_keys(db: &DB) -> Result<Vec<String>> {
+    let w_ids = sqlx::query_scalar!(
+        "DELETE FROM workspace_previous_key WHERE expires_at <= now() RETURNING workspace_id"
+    )
+    .fetch_all(db)
+    .await?;
+    Ok(w_ids)
+}
+
+/// generate a new key for the workspace and re-encrypt every secret with it. The previous key is
+/// kept for `KEY_ROLLBACK_WINDOW_DAYS` to allow a rollback
+async fn rotate_key(
+    Authed {
+        is_admin, username, ..
+    }: Authed,
+    Extension(db): Extension<DB>,
+    Path(w_id): Path<String>,
+) -> Result<String> {
+    require_admin(is_admin, &username)?;
+    let mut tx = db.begin().await?;
+    lock_key(&mut tx, &w_id).await?;
+    let old_key = sqlx::query_scalar!(
+        "SELECT key FROM workspace_key WHERE workspace_id = $1 AND kind = 'cloud' FOR UPDATE",
+        &w_id
+    )
+    .fetch_one(&mut tx)
+    .await?;
+    let new_key = crate::utils::rd_string(64);
+    let (variables, resources) = switch_key(&mut tx, &w_id, &old_key, &new_key).await?;
+    sqlx::query!(
+        "INSERT INTO workspace_previous_key
+            (workspace_id, key, rotated_by, expires_at)
+            VALUES ($1, $2, $3, now() + make_interval(days => $4))
+            ON CONFLICT (workspace_id) DO UPDATE
+            SET key = EXCLUDED.key, rotated_by = EXCLUDED.rotated_by, rotated_at = now(),
+                expires_at = EXCLUDED.expires_at",
+        &w_id,
+        &old_key,
+        &username,
+        KEY_ROLLBACK_WINDOW_DAYS
+    )
+    .execute(&mut tx)
+    .await?;
+    audit_log(
+        &mut tx,
+        &username,
+        "workspaces.rotate_key",
+        ActionKind::Update,
+        &w_id,
+        None,
+        Some(
+            [
+                ("variables", variables.to_string().as_str()),
+                ("resources", resources.to_string().as_str()),
+            ]
+            .into(),
+        ),
+    )
+    .await?;
+    tx.commit().await?;
+
+    Ok(format!(
+        "Rotated the key of workspace {w_id}, re-encrypted {variables} variables and {resources} resources"
+    ))
+}
+
+/// restore the previous key of the workspace, as long as the rollback window is not over, and
+/// re-encrypt every secret with it. The rotated out key is discarded
+async fn rollback_key(
+    Authed {
+        is_admin, username, ..
+    }: Authed,
+    Extension(db): Extension<DB>,
+    Path(w_id): Path<String>,
+) -> Result<String> {
+    require_admin(is_admin, &username)?;
+    let mut tx = db.begin().await?;
+    lock_key(&mut tx, &w_id).await?;
+    delete_expired_previous_key(&mut tx, &w_id).await?;
+    let previous_key = sqlx::query_scalar!(
+        "DELETE FROM workspace_previous_key WHERE workspace_id = $1 RETURNING key",
+        &w_id
+    )
+    .fetch_optional(&mut tx)
+    .await?;
+    let previous_key = crate::utils::not_found_if_none(previous_key, "Previous key", &w_id)?;
+    let key = sqlx::query_scalar!(
+        "SELECT key FROM workspace_key WHERE workspace_id = $1 AND kind = 'cloud' FOR UPDATE",
+        &w_id
+    )
+    .fetch_one(&mut tx)
+    .await?;
+    let (variables, resources) = switch_key(&mut tx, &w_id, &key, &previous_key).await?;
+    audit_log(
+        &mut tx,
+        &username,
+        "workspaces.rollback_key",
+        ActionKind::Update,
+        &w_id,
+        None,
+        Some(
+            [
+                ("variables", variables.to_string().as_str()),
+                ("resources", resources.to_string().as_str()),
+            ]
+            .into(),
+        ),
+    )
+    .await?;
+    tx.commit().await?;
+
+    Ok(format!(
+        "Rolled back the key of workspace {w_id}, re-encrypted {variables} variables and {resources} resources"
+    ))
+}
+
+async fn get_previous_key(
+    Authed {
+        is_admin, username, ..
+    }: Authed,
+    Extension(db): Extension<DB>,
+    Path(w_id): Path<String>,
+) -> JsonResult<PreviousKey> {
+    require_admin(is_admin, &username)?;
+    let mut tx = db.begin().await?;
+    delete_expired_previous_key(&mut tx, &w_id).await?;
+    let previous_key = sqlx::query_as!(
+        PreviousKey,
+        "SELECT rotated_by, rotated_at, expires_at FROM workspace_previous_key WHERE workspace_id = $1",
+        &w_id
+    )
+    .fetch_optional(&mut tx)
+    .await?;
+    tx.commit().await?;
+    let previous_key = crate::utils::not_found_if_none(previous_key, "Previous key", &w_id)?;
+    Ok(Json(previous_key))
+}
+
+/// end the rollback window early, typically when the previous key is known to be compromised
+async fn discard_previous_key(
+    Authed {
+        is_admin, username, ..
+    }: Authed,
+    Extension(db): Extension<DB>,
+    Path(w_id): Path<String>,
+) -> Result<String> {
+    require_admin(is_admin, &username)?;
+    let mut tx = db.begin().await?;
+    let deleted = sqlx::query!(
+        "DELETE FROM workspace_previous_key WHERE workspace_id = $1",
+        &w_id
+    )
+    .execute(&mut tx)
+    .await?
+    .rows_affected();
+    if deleted == 0 {
+        return Err(Error::NotFound(format!(
+            "Previous key of workspace {w_id} not found"
+        )));
+    }
+    audit_log(
+        &mut tx,
+        &username,
+        "workspaces.discard_previous_key",
+        ActionKind::Delete,
+        &w_id,
+        None,
+        None,
+    )
+    .await?;
+    tx.commit().await?;
+
+    Ok(format!("Discarded the previous key of workspace {w_id}"))
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::{
+        db::{create_test_workspace, delete_test_workspace, test_db},
+        resources::ENCRYPTED_PREFIX,
+    };
+    use serde_json::json;
+
+    /// the key of the workspace and every secret of the workspace, decrypted with it: the secret
+    /// variable and its version, the resource and its version, and the refresh token of the account
+    async fn read_secrets(db: &DB, w_id: &str) -> (String, Vec<serde_json::Value>) {
+        let mut tx = db.begin().await.unwrap();
+        let key = sqlx::query_scalar::<_, String>(
+            "SELECT key FROM workspace_key WHERE workspace_id = $1 AND kind = 'cloud'",
+        )
+        .bind(w_id)
+        .fetch_one(&mut tx)
+        .await
+        .unwrap();
+        let mc = build_crypt(&mut tx, w_id).await.unwrap();
+        let decrypt = |x: String| json!(mc.decrypt_base64_to_string(x).unwrap());
+
+        let mut secrets = vec![];
+        for query in [
+            "SELECT value FROM variable WHERE workspace_id = $1",
+            "SELECT value FROM variable_version WHERE workspace_id = $1",
+            "SELECT refresh_token FROM account WHERE workspace_id = $1",
+        ] {
+            let value = sqlx::query_scalar::<_, String>(query)
+                .bind(w_id)
+                .fetch_one(&mut tx)
+                .await
+                .unwrap();
+            secrets.push(decrypt(value));
+        }
+        for query in [
+            "SELECT value FROM resource WHERE workspace_id = $1",
+            "SELECT value FROM resource_version WHERE workspace_id = $1",
+        ] {
+            let mut value = sqlx::query_scalar::<_, serde_json::Value>(query)
+                .bind(w_id)
+                .fetch_one(&mut tx)
+                .await
+                .unwrap();
+            map_encrypted_fields(Some(&mc), &mut value).unwrap();
+            secrets.push(value);
+        }
+        tx.commit().await.unwrap();
+        (key, secrets)
+    }
+
+    #[tokio::test]
+    #[ignore = "needs DATABASE_URL, see test_db"]
+    async fn test_rotate_and_rollback_key() {
+        let db = test_db().await;
+        let w_id = create_test_workspace(&db).await;
+
+        let mut tx = db.begin().await.unwrap();
+        let mc = build_crypt(&mut tx, &w_id).await.unwrap();
+        tx.commit().await.unwrap();
+        let password = encrypt(&mc, "s3cret".to_string());
+        let resource = json!({
+            "host": "db.example.com",
+            "password": format!("{ENCRYPTED_PREFIX}{}", encrypt(&mc, json!("db-s3cret").to_string()))
+        });
+        for query in [
+            "INSERT INTO variable (workspace_id, path, value, is_secret) \
+             VALUES ($1, 'u/alice/password', $2, true)",
+            "INSERT INTO variable_version (workspace_id, path, value, is_secret, description, created_by) \
+             VALUES ($1, 'u/alice/password', $2, true, '', 'alice')",
+            "INSERT INTO account (workspace_id, path, client, refresh_token) \
+             VALUES ($1, 'u/alice/password', 'github', $2)",
+        ] {
+            sqlx::query(query)
+                .bind(&w_id)
+                .bind(&password)
+                .execute(&db)
+                .await
+                .unwrap();
+        }
+        for query in [
+            "INSERT INTO resource (workspace_id, path, value, resource_type) \
+             VALUES ($1, 'u/alice/db', $2, 'postgresql')",
+            "INSERT INTO resource_version (workspace_id, path, value, resource_type, created_by) \
+             VALUES ($1, 'u/alice/db', $2, 'postgresql', 'alice')",
+        ] {
+            sqlx::query(query)
+                .bind(&w_id)
+                .bind(&resource)
+                .execute(&db)
+                .await
+                .unwrap();
+        }
+        let secrets = vec![
+            json!("s3cret"),
+            json!("s3cret"),
+            json!("s3cret"),
+            json!({ "host": "db.example.com", "password": "db-s3cret" }),
+            json!({ "host": "db.example.com", "password": "db-s3cret" }),
+        ];
+        assert_eq!(
+            read_secrets(&db, &w_id).await,
+            ("test-key".to_string(), secrets.clone())
+        );
+
+        let admin = || Authed {
+            email: None,
+            username: "alice".to_string(),
+            is_admin: true,
+            groups: vec![],
+        };
+        rotate_key(admin(), Extension(db.clone()), Path(w_id.clone()))
+            .await
+            .unwrap();
+        let (key, rotated) = read_secrets(&db, &w_id).await;
+        assert_ne!(key, "test-key");
+        assert_eq!(rotated, secrets);
+        let stored =
+            sqlx::query_scalar::<_, String>("SELECT value FROM variable WHERE workspace_id = $1")
+                .bind(&w_id)
+                .fetch_one(&db)
+                .await
+                .unwrap();
+        assert_ne!(stored, password);
+
+        rollback_key(admin(), Extension(db.clone()), Path(w_id.clone()))
+            .await
+            .unwrap();
+        assert_eq!(
+            read_secrets(&db, &w_id).await,
+            ("test-key".to_string(), secrets)
+        );
+        // the rotated out key is discarded, there is nothing left to roll back to
+        assert!(matches!(
+            rollback_key(admin(), Extension(db.clone()), Path(w_id.clone())).await,
+            Err(Error::NotFound(_))
+        ));
+
+        // an expired previous key is deleted without waiting for a request on its workspace
+        rotate_key(admin(), Extension(db.clone()), Path(w_id.clone()))
+            .await
+            .unwrap();
+        sqlx::query(
+            "UPDATE workspace_previous_key SET expires_at = now() - interval '1 day' \
+             WHERE workspace_id = $1",
+        )
+        .bind(&w_id)
+        .execute(&db)
+        .await
+        .unwrap();
+        assert!(delete_expired_previous_keys(&db)
+            .await
+            .unwrap()
+            .contains(&w_id));
+        assert!(matches!(
+            rollback_key(admin(), Extension(db.clone()), Path(w_id.clone())).await,
+            Err(Error::NotFound(_))
+        ));
+
+        delete_test_workspace(&db, &w_id).await;
+    }
+
+    fn metadata(is_library: bool) -> ScriptMetadata {
+        serde_json::from_value(json!({
+            "summary": "",
+            "description": "",
+            "schema": null,
+            "is_template": false,
+            "lock": [],
+            "is_library": is_library
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_libraries_first() {
+        let scripts = vec![
+            (
+                "u/alice/script".to_string(),
+                ScriptLang::Python3,
+                "import u.alice.b\n".to_string(),
+            ),
+            (
+                "u/alice/b".to_string(),
+                ScriptLang::Python3,
+                "from u.alice import a\n".to_string(),
+            ),
+            (
+                "u/alice/a".to_string(),
+                ScriptLang::Python3,
+                "import requests\n".to_string(),
+            ),
+        ];
+        let metadata = [
+            ("u/alice/script", false),
+            ("u/alice/b", true),
+            ("u/alice/a", true),
+        ]
+        .into_iter()
+        .map(|(path, is_library)| (path.to_string(), metadata(is_library)))
+        .collect();
+        let ordered = libraries_first(scripts, &metadata).unwrap();
+        assert_eq!(
+            ordered.into_iter().map(|x| x.0).collect::<Vec<_>>(),
+            vec!["u/alice/a", "u/alice/b", "u/alice/script"]
+        );
+    }
+
+    /// the archive of a workspace, as read by an import
+    async fn export(db: &DB, w_id: &str) -> WorkspaceArchive {
+        let mut archive = WorkspaceArchive::default();
+        for (file, content) in workspace_files(db, w_id).await.unwrap() {
+            archive.add_file(&file, content);
+        }
+        archive.validate(None).unwrap()
+    }
+
+    async fn import(db: &DB, w_id: &str, archive: WorkspaceArchive, dry_run: bool) -> ImportReport {
+        let admin = Authed {
+            email: None,
+            username: "alice".to_string(),
+            is_admin: true,
+            groups: vec![],
+        };
+        import_archive(
+            db,
+            w_id,
+            archive,
+            ImportOptions {
+                dry_run,
+                remove_missing: false,
+            },
+            &admin,
+            "test-token",
+            "workspaces.import",
+        )
+        .await
+        .unwrap()
+    }
+
+    fn changes(report: &ImportReport) -> Vec<(&'static str, &str, &ImportChangeKind)> {
+        let mut changes = report
+            .changes
+            .iter()
+            .map(|x| (x.kind, x.path.as_str(), &x.change))
+            .collect::<Vec<_>>();
+        changes.sort_by_key(|x| (x.0, x.1));
+        changes
+    }
+
+    async fn pinned_hash(db: &DB, w_id: &str, path: &str) -> Option<String> {
+        let value = sqlx::query_scalar::<_, serde_json::Value>(
+            "SELECT value FROM flow WHERE workspace_id = $1 AND path = $2",
+        )
+        .bind(w_id)
+        .bind(path)
+        .fetch_one(db)
+        .await
+        .unwrap();
+        value["modules"][0]["value"]["hash"]
+            .as_str()
+            .map(|x| x.to_string())
+    }
+
+    #[tokio::test]
+    #[ignore = "needs DATABASE_URL, see test_db"]
+    async fn test_import_round_trip() {
+        let db = test_db().await;
+        let source = create_test_workspace(&db).await;
+        let target = create_test_workspace(&db).await;
+        let flow = |hash: i64| {
+            json!({
+                "modules": [{
+                    "input_transform": {},
+                    "value": { "type": "script", "path": "u/alice/script", "hash": ScriptHash(hash).to_string() }
+                }],
+                "failure_module": null
+            })
+        };
+        for (hash, archived) in [(1, true), (2, false)] {
+            sqlx::query(
+                "INSERT INTO script (workspace_id, hash, path, summary, description, content, created_by, \
+                 language, lock, memory_limit, archived) \
+                 VALUES ($1, $2, 'u/alice/script', '', '', 'export function main() { return 1 }', 'alice', \
+                 'deno', '', 128, $3)",
+            )
+            .bind(&source)
+            .bind(hash)
+            .bind(archived)
+            .execute(&db)
+            .await
+            .unwrap();
+        }
+        // pinned to the current version of the script, and to an older one
+        for (path, hash) in [("u/alice/flow", 2), ("u/alice/old", 1)] {
+            sqlx::query(
+                "INSERT INTO flow (workspace_id, path, summary, description, value, edited_by) \
+                 VALUES ($1, $2, '', '', $3, 'alice')",
+            )
+            .bind(&source)
+            .bind(path)
+            .bind(flow(hash))
+            .execute(&db)
+            .await
+            .unwrap();
+        }
+        sqlx::query(
+            "INSERT INTO variable (workspace_id, path, value, is_secret, description) \
+             VALUES ($1, 'u/alice/host', 'db.example.com', false, '')",
+        )
+        .bind(&source)
+        .execute(&db)
+        .await
+        .unwrap();
+
+        // nothing is written in dry run mode
+        let report = import(&db, &target, export(&db, &source).await, true).await;
+        assert!(report.dry_run);
+        let created = vec![
+            ("flow", "u/alice/flow", &ImportChangeKind::Create),
+            ("flow", "u/alice/old", &ImportChangeKind::Create),
+            ("script", "u/alice/script", &ImportChangeKind::Create),
+            ("variable", "u/alice/host", &ImportChangeKind::Create),
+        ];
+        assert_eq!(changes(&report), created);
+        assert_eq!(report.warnings.len(), 1);
+        for table in ["script", "flow", "variable"] {
+            let count = sqlx::query_scalar::<_, i64>(&format!(
+                "SELECT count(*) FROM {table} WHERE workspace_id = $1"
+            ))
+            .bind(&target)
+            .fetch_one(&db)
+            .await
+            .unwrap();
+            assert_eq!(count, 0, "{table}");
+        }
+
+        let report = import(&db, &target, export(&db, &source).await, false).await;
+        assert_eq!(changes(&report), created);
+        let hash = sqlx::query_scalar::<_, ScriptHash>(
+            "SELECT hash FROM script WHERE workspace_id = $1 AND path = 'u/alice/script' \
+             AND memory_limit = 128",
+        )
+        .bind(&target)
+        .fetch_one(&db)
+        .await
+        .unwrap();
+        // the pin to the exported version follows the imported one, the pin to a version the
+        // target does not have is dropped with a warning
+        assert_eq!(
+            pinned_hash(&db, &target, "u/alice/flow").await,
+            Some(hash.to_string())
+        );
+        assert_eq!(pinned_hash(&db, &target, "u/alice/old").await, None);
+        assert_eq!(
+            report.warnings,
+            vec![format!(
+                "flow u/alice/old: the step running u/alice/script was pinned to {}, a version the \
+                 workspace does not have, it now runs the latest version",
+                ScriptHash(1)
+            )]
+        );
+
+        // the archive round trips
+        let report = import(&db, &target, export(&db, &source).await, false).await;
+        assert!(report
+            .changes
+            .iter()
+            .all(|x| x.change == ImportChangeKind::Unchanged));
+
+        // a change of the limits alone is a change of the script
+        sqlx::query("UPDATE script SET memory_limit = 256 WHERE workspace_id = $1 AND hash = 2")
+            .bind(&source)
+            .execute(&db)
+            .await
+            .unwrap();
+        let report = import(&db, &target, export(&db, &source).await, false).await;
+        assert!(changes(&report).contains(&(
+            "script",
+            "u/alice/script",
+            &ImportChangeKind::Update
+        )));
+
+        delete_test_workspace(&db, &source).await;
+        delete_test_workspace(&db, &target).await;
+    }
+
+    #[test]
+    fn test_check_env_name() {
+        for name in ["API_URL", "_private", "wm", "PATHS"] {
+            assert!(check_env_name(name).is_ok(), "{name}");
+        }
+        for name in [
+            "",
+            "1ST",
+            "A-B",
+            "A=B",
+            "WM_TOKEN",
+            "wm_token",
+            "LD_PRELOAD",
+            "ld_library_path",
+            "PYTHONPATH",
+            "DENO_DIR",
+            "PATH",
+            "Home",
+        ] {
+            assert!(
+                matches!(check_env_name(name), Err(Error::BadRequest(_))),
+                "{name}"
+            );
+        }
+    }
+}
@@ -10,7 +10,10 @@ export interface SchemaProperty {
 	enum?: string[]
 	contentEncoding?: 'base64' | 'binary'
 	format?: string
-	items?: { type?: 'string' | 'number' }
+	items?: Partial<SchemaProperty>
+	properties?: { [name: string]: SchemaProperty }
+	required?: string[]
+	nullable?: boolean
 }
 
 export type Schema = {
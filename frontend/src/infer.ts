@@ -1,5 +1,5 @@
 import type { Schema, SchemaProperty } from './common'
-import { ScriptService, type MainArgSignature } from './gen'
+import { ScriptService, type MainArgSignature, type ArgTyp } from './gen'
 import { sendUserToast } from './utils'
 
 export async function inferArgs(language: "python3" | "deno", code: string, schema: Schema): Promise<void> {
@@ -22,15 +22,17 @@ export async function inferArgs(language: "python3" | "deno", code: string, sche
 		schema.properties = {}
 
 		for (const arg of inferedSchema.args) {
-			if (!(arg.name in oldProperties)) {
-				schema.properties[arg.name] = { description: '', type: '' }
-			} else {
-				schema.properties[arg.name] = oldProperties[arg.name]
+			const previous: SchemaProperty | undefined = oldProperties[arg.name]
+			const property: SchemaProperty = { description: previous?.description ?? '', type: '' }
+			argSigToJsonSchemaType(arg.typ, property)
+			if (previous && previous.type == property.type) {
+				keepNarrowing(previous, property)
 			}
-			pythonToJsonSchemaType(arg.typ, schema.properties[arg.name])
-			schema.properties[arg.name].default = arg.default
+			property.default = arg.default
+			schema.properties[arg.name] = property
 
-			if (!arg.has_default) {
+			// a nullable arg without default is passed null
+			if (!arg.has_default && !property.nullable) {
 				schema.required.push(arg.name)
 			}
 		}
@@ -40,25 +42,59 @@ export async function inferArgs(language: "python3" | "deno", code: string, sche
 	}
 }
 
-function pythonToJsonSchemaType(t: string, s: SchemaProperty): void {
+// keep the narrowing set by the user on an arg whose type did not change
+function keepNarrowing(previous: SchemaProperty, s: SchemaProperty): void {
+	if (s.pattern === undefined) {
+		s.pattern = previous.pattern
+	}
+	if (s.enum === undefined) {
+		s.enum = previous.enum
+	}
+	if (s.format === undefined && previous.format != 'date-time') {
+		s.format = previous.format
+	}
+}
+
+function argSigToJsonSchemaType(t: ArgTyp, s: SchemaProperty): void {
 	if (t === 'int') {
 		s.type = 'integer'
 	} else if (t === 'float') {
 		s.type = 'number'
 	} else if (t === 'bool') {
 		s.type = 'boolean'
-	} else if (t === 'str') {
-		s.type = 'string'
 	} else if (t === 'dict') {
 		s.type = 'object'
-	} else if (t === 'list') {
-		s.type = 'array'
 	} else if (t === 'bytes') {
 		s.type = 'string'
 		s.contentEncoding = 'base64'
 	} else if (t === 'datetime') {
 		s.type = 'string'
 		s.format = 'date-time'
+	} else if (typeof t === 'object' && 'str' in t) {
+		s.type = 'string'
+		if (t.str) {
+			s.enum = t.str
+		}
+	} else if (typeof t === 'object' && 'list' in t) {
+		s.type = 'array'
+		const items: SchemaProperty = { type: undefined, description: '' }
+		argSigToJsonSchemaType(t.list, items)
+		s.items = items
+	} else if (typeof t === 'object' && 'object' in t) {
+		s.type = 'object'
+		s.properties = {}
+		s.required = []
+		for (const prop of t.object) {
+			const p: SchemaProperty = { type: undefined, description: '' }
+			argSigToJsonSchemaType(prop.typ, p)
+			s.properties[prop.key] = p
+			if (!p.nullable) {
+				s.required.push(prop.key)
+			}
+		}
+	} else if (typeof t === 'object' && 'optional' in t) {
+		argSigToJsonSchemaType(t.optional, s)
+		s.nullable = true
 	} else {
 		s.type = undefined
 	}